@@ -0,0 +1,68 @@
+use std::path::Path;
+use std::sync::Arc;
+use bitcoin::block::Block;
+use bitcoin::{ScriptBuf, Transaction};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use tweak_indexer::chain::{Chain, ChainSource, PreviousScript};
+use tweak_indexer::fixture::ReplaySource;
+
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/regtest_block_1.json");
+// The recorded block has a single eligible spend, it is repeated to get a block sized workload
+const BLOCK_SPENDS: usize = 2000;
+
+struct Recorded {
+    source: Arc<dyn ChainSource>,
+    block: Block,
+    previous_scripts: Vec<PreviousScript>,
+}
+
+fn load(runtime: &tokio::runtime::Runtime) -> Recorded {
+    let source: Arc<dyn ChainSource> = Arc::new(ReplaySource::load(Path::new(FIXTURE)).unwrap());
+    runtime.block_on(async {
+        let block_hash = source.get_block_hash(1).await.unwrap();
+        let (raw_block, previous_scripts) = source.get_block_with_prevouts(&block_hash).await.unwrap();
+        Recorded { source: source.clone(), block: raw_block.decode().unwrap(), previous_scripts }
+    })
+}
+
+fn spend(block: &Block) -> &Transaction {
+    block.txdata.iter().find(|tx| !tx.is_coinbase()).unwrap()
+}
+
+// Block processing with every prevout known up front, the --prevouts block path
+fn bench_process_block(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let recorded = load(&runtime);
+    let mut block = recorded.block.clone();
+    block.txdata = [vec![block.txdata[0].clone()], vec![spend(&recorded.block).clone(); BLOCK_SPENDS]].concat();
+
+    let mut group = c.benchmark_group("process_block");
+    group.throughput(Throughput::Elements(BLOCK_SPENDS as u64));
+    group.bench_function("recorded_spends", |b| {
+        b.iter_batched(
+            || {
+                let mut chain = Chain::new(recorded.source.clone());
+                chain.set_previous_scripts(recorded.previous_scripts.clone());
+                chain
+            },
+            |mut chain| runtime.block_on(chain.process_block(&block)).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+// Input key extraction and tweak computation of a single transaction
+fn bench_compute_tweak(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let recorded = load(&runtime);
+    let mut chain = Chain::new(recorded.source.clone());
+    chain.set_previous_scripts(recorded.previous_scripts.clone());
+    let tx = spend(&recorded.block);
+    let previous_scripts: Vec<ScriptBuf> = tx.input.iter().map(|input| chain.find_previous_script(&input.previous_output).unwrap().clone()).collect();
+
+    c.bench_function("compute_tweak", |b| b.iter(|| chain.compute_tweak(1, tx, &previous_scripts).unwrap()));
+}
+
+criterion_group!(benches, bench_process_block, bench_compute_tweak);
+criterion_main!(benches);
@@ -0,0 +1,102 @@
+use std::fs;
+use std::sync::Arc;
+use bitcoin::consensus::encode::deserialize_hex;
+use bitcoin::{Amount, OutPoint, ScriptBuf, Transaction, TxIn, TxOut, Witness};
+use serde::Deserialize;
+use crate::chain::Chain;
+use crate::mock::{self, MockChain};
+
+// The official BIP352 send_and_receive_test_vectors.json from bitcoin/bips. It isn't committed yet, the test is
+// ignored until the file is copied unmodified to tests/fixtures. Only the receiving fields the indexer needs are
+// read: the inputs with their prevouts and the expected tweak.
+const VECTORS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/send_and_receive_test_vectors.json");
+
+#[derive(Deserialize)]
+struct TestCase {
+    comment: String,
+    receiving: Vec<ReceivingVector>,
+}
+
+#[derive(Deserialize)]
+struct ReceivingVector {
+    given: Given,
+    expected: Expected,
+}
+
+#[derive(Deserialize)]
+struct Given {
+    vin: Vec<Vin>,
+    outputs: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Vin {
+    txid: String,
+    vout: u32,
+    #[serde(rename = "scriptSig")]
+    script_sig: String,
+    txinwitness: String,
+    prevout: Prevout,
+}
+
+#[derive(Deserialize)]
+struct Prevout {
+    #[serde(rename = "scriptPubKey")]
+    script_pubkey: ScriptPubKey,
+}
+
+#[derive(Deserialize)]
+struct ScriptPubKey {
+    hex: String,
+}
+
+// Vectors without eligible inputs have no tweak
+#[derive(Deserialize)]
+struct Expected {
+    tweak: Option<String>,
+}
+
+// Transaction spending the vector's inputs to its x-only output keys, with the prevout script of each input
+fn transaction(given: &Given) -> (Transaction, Vec<ScriptBuf>) {
+    let input = given
+        .vin
+        .iter()
+        .map(|vin| TxIn {
+            previous_output: OutPoint::new(vin.txid.parse().unwrap(), vin.vout),
+            script_sig: ScriptBuf::from_hex(&vin.script_sig).unwrap(),
+            witness: if vin.txinwitness.is_empty() { Witness::new() } else { deserialize_hex(&vin.txinwitness).unwrap() },
+            ..Default::default()
+        })
+        .collect();
+    let output = given
+        .outputs
+        .iter()
+        .map(|key| TxOut { value: Amount::from_sat(1000), script_pubkey: ScriptBuf::from_hex(&format!("5120{}", key)).unwrap() })
+        .collect();
+    let previous_scripts = given.vin.iter().map(|vin| ScriptBuf::from_hex(&vin.prevout.script_pubkey.hex).unwrap()).collect();
+    (mock::transaction(input, output), previous_scripts)
+}
+
+#[test]
+#[ignore = "needs tests/fixtures/send_and_receive_test_vectors.json from bitcoin/bips"]
+fn test_receiving_vectors() {
+    let vectors = fs::read_to_string(VECTORS).unwrap_or_else(|e| panic!("{} (bip-0352/send_and_receive_test_vectors.json from bitcoin/bips): {}", VECTORS, e));
+    let cases: Vec<TestCase> = serde_json::from_str(&vectors).unwrap();
+    let chain = Chain::new(Arc::new(MockChain::default()));
+    let mut checked = 0;
+
+    for case in cases.iter() {
+        for vector in case.receiving.iter() {
+            let (tx, previous_scripts) = transaction(&vector.given);
+            // Ineligible transactions either get no tweak or are rejected, both mean nothing is stored
+            let tweak = chain
+                .compute_tweak(0, &tx, &previous_scripts)
+                .ok()
+                .and_then(|tweaks| tweaks.into_iter().next())
+                .map(|tweak| tweak.tweak);
+            assert_eq!(tweak, vector.expected.tweak, "{}", case.comment);
+            checked += 1;
+        }
+    }
+    assert!(checked > 0);
+}
@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use bitcoin::block::{Block, Header};
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::p2p::Magic;
+use bitcoin::{BlockHash, Network, OutPoint, Script, ScriptBuf};
+use tracing::{debug, info};
+
+const HEADER_SIZE: usize = 80;
+
+// Bitcoin Core 28+ obfuscates block files with the key in blocks/xor.dat, an all zero key leaves them readable as is
+struct XorReader<R> {
+    inner: R,
+    key: [u8; 8],
+    position: u64,
+}
+
+impl<R> XorReader<R> {
+    fn new(inner: R, key: [u8; 8]) -> Self {
+        Self { inner, key, position: 0 }
+    }
+}
+
+impl<R: Read> Read for XorReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        for (i, byte) in buf[..read].iter_mut().enumerate() {
+            *byte ^= self.key[((self.position + i as u64) % 8) as usize];
+        }
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for XorReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = self.inner.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+// Offset and header of every block record in a block file, records are magic, size and the serialized block.
+// Core preallocates files, a zeroed record marks the end of the written part.
+fn scan_records<R: Read + Seek>(reader: &mut R, magic: Magic) -> Result<Vec<(u64, Header)>, String> {
+    let mut records = Vec::new();
+    loop {
+        let mut record = [0u8; 8];
+        match reader.read_exact(&mut record) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.to_string()),
+        }
+        if record[..4] == [0; 4] {
+            break;
+        }
+        if record[..4] != magic.to_bytes() {
+            return Err(format!("Unexpected record magic {}", hex::encode(&record[..4])));
+        }
+        let size = u32::from_le_bytes([record[4], record[5], record[6], record[7]]) as u64;
+        let offset = reader.stream_position().map_err(|e| e.to_string())?;
+
+        let mut header = [0u8; HEADER_SIZE];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            // Block still being written
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.to_string()),
+        }
+        records.push((offset, deserialize::<Header>(&header).map_err(|e| e.to_string())?));
+        reader.seek(SeekFrom::Start(offset + size)).map_err(|e| e.to_string())?;
+    }
+    Ok(records)
+}
+
+// Heights follow from the parent links, the best chain is the highest block traced back to genesis.
+// Blocks whose ancestry is missing from the files get no height.
+fn best_chain(parents: &HashMap<BlockHash, BlockHash>, genesis_hash: BlockHash) -> Vec<BlockHash> {
+    let mut heights: HashMap<BlockHash, u32> = HashMap::from([(genesis_hash, 0)]);
+    for block_hash in parents.keys() {
+        let mut path = Vec::new();
+        let mut current = *block_hash;
+        while !heights.contains_key(&current) {
+            match parents.get(&current) {
+                Some(parent) => {
+                    path.push(current);
+                    current = *parent;
+                }
+                None => break,
+            }
+        }
+        if let Some(&height) = heights.get(&current) {
+            for (i, hash) in path.iter().rev().enumerate() {
+                heights.insert(*hash, height + 1 + i as u32);
+            }
+        }
+    }
+
+    let Some((&tip, &tip_height)) = heights.iter().max_by_key(|(hash, height)| (**height, **hash)) else {
+        return vec![];
+    };
+    let mut chain = vec![tip; tip_height as usize + 1];
+    let mut current = tip;
+    for height in (0..tip_height as usize).rev() {
+        current = parents[&current];
+        chain[height] = current;
+    }
+    chain
+}
+
+// Block files of a node's blocks directory, indexed by block hash
+pub struct BlockFiles {
+    files: Vec<PathBuf>,
+    xor_key: [u8; 8],
+    locations: HashMap<BlockHash, (usize, u64)>,
+    parents: HashMap<BlockHash, BlockHash>,
+    genesis_hash: BlockHash,
+}
+
+impl BlockFiles {
+    // Scan the header of every block in blk*.dat, reading only 88 bytes per block
+    pub fn open(dir: &Path, network: Network) -> Result<Self, String> {
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)
+            .map_err(|e| format!("Unable to read blocks dir {}: {}", dir.display(), e))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+                name.starts_with("blk") && name.ends_with(".dat")
+            })
+            .collect();
+        files.sort();
+        if files.is_empty() {
+            return Err(format!("No blk*.dat files in {}", dir.display()));
+        }
+
+        let xor_key = match fs::read(dir.join("xor.dat")) {
+            Ok(key) => key.try_into().map_err(|_| String::from("xor.dat is not an 8 byte key"))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => [0; 8],
+            Err(err) => return Err(format!("Unable to read xor.dat: {}", err)),
+        };
+
+        let mut locations = HashMap::new();
+        let mut parents = HashMap::new();
+        for (file_index, path) in files.iter().enumerate() {
+            let file = File::open(path).map_err(|e| format!("Unable to open {}: {}", path.display(), e))?;
+            let mut reader = XorReader::new(BufReader::new(file), xor_key);
+            let records = scan_records(&mut reader, network.magic()).map_err(|e| format!("{}: {}", path.display(), e))?;
+            debug!("Scanned {} blocks in {}", records.len(), path.display());
+            for (offset, header) in records {
+                let block_hash = header.block_hash();
+                locations.insert(block_hash, (file_index, offset));
+                parents.insert(block_hash, header.prev_blockhash);
+            }
+        }
+        info!("Found {} blocks in {} block files", locations.len(), files.len());
+
+        let genesis_hash = bitcoin::blockdata::constants::genesis_block(network).block_hash();
+        Ok(Self { files, xor_key, locations, parents, genesis_hash })
+    }
+
+    // Block hashes of the best chain by height
+    pub fn best_chain(&self) -> Vec<BlockHash> {
+        if !self.locations.contains_key(&self.genesis_hash) {
+            return vec![];
+        }
+        best_chain(&self.parents, self.genesis_hash)
+    }
+
+    pub fn read_block(&self, block_hash: &BlockHash) -> Result<Block, String> {
+        let (file_index, offset) = self.locations.get(block_hash).ok_or_else(|| format!("Block {} not in block files", block_hash))?;
+        let path = &self.files[*file_index];
+        let file = File::open(path).map_err(|e| format!("Unable to open {}: {}", path.display(), e))?;
+        let mut reader = XorReader::new(file, self.xor_key);
+
+        reader.seek(SeekFrom::Start(offset - 4)).map_err(|e| e.to_string())?;
+        let mut size = [0u8; 4];
+        reader.read_exact(&mut size).map_err(|e| e.to_string())?;
+        let mut block = vec![0u8; u32::from_le_bytes(size) as usize];
+        reader.read_exact(&mut block).map_err(|e| format!("{}: {}", path.display(), e))?;
+        deserialize(&block).map_err(|e| format!("Invalid block {} in {}: {}", block_hash, path.display(), e))
+    }
+}
+
+// Scripts BIP352 reads input keys from or that invalidate the spending transaction, other outputs are kept as an
+// empty script so spends of them still resolve without holding the full script in memory
+pub fn relevant_script(script: &Script) -> ScriptBuf {
+    let future_segwit = script.witness_version().is_some_and(|version| version.to_num() >= 2);
+    if script.is_p2pkh() || script.is_p2sh() || script.is_p2wpkh() || script.is_p2tr() || future_segwit {
+        script.to_owned()
+    } else {
+        ScriptBuf::new()
+    }
+}
+
+// Unspent outputs built up block by block from genesis or loaded from a UTXO snapshot
+#[derive(Default)]
+pub struct UtxoMap {
+    outputs: HashMap<OutPoint, ScriptBuf>,
+}
+
+impl UtxoMap {
+    pub fn count(&self) -> usize {
+        self.outputs.len()
+    }
+
+    // Spend the block's inputs and add its outputs, returning the scripts the inputs spent in block order.
+    // Transactions may spend outputs created earlier in the same block.
+    pub fn connect_block(&mut self, block: &Block) -> Vec<(OutPoint, ScriptBuf)> {
+        let mut spent = Vec::new();
+        for tx in block.txdata.iter() {
+            for input in tx.input.iter().filter(|input| !input.previous_output.is_null()) {
+                if let Some(script) = self.outputs.remove(&input.previous_output) {
+                    spent.push((input.previous_output, script));
+                }
+            }
+            let txid = tx.compute_txid();
+            for (vout, output) in tx.output.iter().enumerate() {
+                self.insert(OutPoint::new(txid, vout as u32), &output.script_pubkey);
+            }
+        }
+        spent
+    }
+
+    // Track an unspent output, OP_RETURN outputs can never be spent
+    pub fn insert(&mut self, outpoint: OutPoint, script: &Script) {
+        if !script.is_op_return() {
+            self.outputs.insert(outpoint, relevant_script(script));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::hashes::Hash;
+
+    fn record(magic: Magic, block: &Block) -> Vec<u8> {
+        let block = serialize(block);
+        [magic.to_bytes().to_vec(), (block.len() as u32).to_le_bytes().to_vec(), block].concat()
+    }
+
+    #[test]
+    fn test_scan_and_read_obfuscated_records() {
+        let magic = Network::Regtest.magic();
+        let genesis = bitcoin::blockdata::constants::genesis_block(Network::Regtest);
+        let key = [1, 2, 3, 4, 5, 6, 7, 8];
+
+        // Two records followed by preallocated zeroes, obfuscated the way Core writes them
+        let mut file = [record(magic, &genesis), record(magic, &genesis), vec![0; 64]].concat();
+        for (i, byte) in file.iter_mut().enumerate() {
+            *byte ^= key[i % 8];
+        }
+
+        let mut reader = XorReader::new(Cursor::new(file.clone()), key);
+        let records = scan_records(&mut reader, magic).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].1.block_hash(), genesis.block_hash());
+
+        // Blocks read back at the recorded offset
+        let block_len = serialize(&genesis).len();
+        reader.seek(SeekFrom::Start(records[1].0)).unwrap();
+        let mut block = vec![0u8; block_len];
+        reader.read_exact(&mut block).unwrap();
+        assert_eq!(deserialize::<Block>(&block).unwrap(), genesis);
+
+        // Records of another network are refused
+        let mut reader = XorReader::new(Cursor::new(file), key);
+        assert!(scan_records(&mut reader, Network::Bitcoin.magic()).is_err());
+    }
+
+    #[test]
+    fn test_best_chain() {
+        let hash = |i: u8| BlockHash::from_byte_array([i; 32]);
+        // 0 <- 1 <- 2 <- 3 with a stale 2b, and an orphan whose parent is missing
+        let parents = HashMap::from([
+            (hash(1), hash(0)),
+            (hash(2), hash(1)),
+            (hash(3), hash(2)),
+            (hash(22), hash(1)),
+            (hash(9), hash(8)),
+        ]);
+        assert_eq!(best_chain(&parents, hash(0)), vec![hash(0), hash(1), hash(2), hash(3)]);
+    }
+
+    #[test]
+    fn test_connect_block() {
+        let taproot = ScriptBuf::from_hex("512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798").unwrap();
+        let p2wsh = ScriptBuf::from_hex(&format!("0020{}", "11".repeat(32))).unwrap();
+        let output = |script: &ScriptBuf| bitcoin::TxOut { value: bitcoin::Amount::from_sat(1000), script_pubkey: script.clone() };
+        let tx = |inputs: Vec<OutPoint>, outputs: Vec<bitcoin::TxOut>| bitcoin::Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: inputs.into_iter().map(|previous_output| bitcoin::TxIn { previous_output, ..Default::default() }).collect(),
+            output: outputs,
+        };
+
+        let mut block = bitcoin::blockdata::constants::genesis_block(Network::Regtest);
+        let funding = tx(vec![OutPoint::new(bitcoin::Txid::from_byte_array([7; 32]), 0)], vec![output(&taproot), output(&p2wsh)]);
+        let funding_txid = funding.compute_txid();
+        let spending = tx(vec![OutPoint::new(funding_txid, 0), OutPoint::new(funding_txid, 1)], vec![output(&taproot)]);
+        block.txdata = vec![funding, spending];
+
+        // Spends within the block resolve, the irrelevant P2WSH script is not kept
+        let mut utxos = UtxoMap::default();
+        let spent = utxos.connect_block(&block);
+        assert_eq!(spent, vec![(OutPoint::new(funding_txid, 0), taproot), (OutPoint::new(funding_txid, 1), ScriptBuf::new())]);
+        assert_eq!(utxos.count(), 1);
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::{HashMap, VecDeque};
+use bitcoin::block::Block;
+use bitcoin::{OutPoint, ScriptBuf};
+use crate::blkfile::relevant_script;
+
+// Outputs kept by default, roughly 150 bytes each with the bookkeeping
+pub const DEFAULT_SCRIPT_CACHE_ENTRIES: usize = 5_000_000;
+
+// Output scripts of the blocks indexed so far, so spends within the indexed range resolve without the backend.
+// Most outputs are spent soon after they are created, when the cache is full the oldest blocks' outputs go first.
+pub struct ScriptCache {
+    outputs: HashMap<OutPoint, ScriptBuf>,
+    // Outputs added by each cached block, oldest first
+    blocks: VecDeque<Vec<OutPoint>>,
+    max_entries: usize,
+}
+
+impl ScriptCache {
+    pub fn new(max_entries: usize) -> Self {
+        Self { outputs: HashMap::new(), blocks: VecDeque::new(), max_entries }
+    }
+
+    pub fn count(&self) -> usize {
+        self.outputs.len()
+    }
+
+    // Scripts of the block's inputs that were found in the cache, then the block's own outputs are added.
+    // Spent outputs are dropped, transactions may spend outputs created earlier in the same block.
+    pub fn connect_block(&mut self, block: &Block) -> Vec<(OutPoint, ScriptBuf)> {
+        if self.max_entries == 0 {
+            return Vec::new();
+        }
+        let mut spent = Vec::new();
+        let mut added = Vec::new();
+        for tx in block.txdata.iter() {
+            for input in tx.input.iter().filter(|input| !input.previous_output.is_null()) {
+                if let Some(script) = self.outputs.remove(&input.previous_output) {
+                    spent.push((input.previous_output, script));
+                }
+            }
+            let txid = tx.compute_txid();
+            for (vout, output) in tx.output.iter().enumerate().filter(|(_, output)| !output.script_pubkey.is_op_return()) {
+                let outpoint = OutPoint::new(txid, vout as u32);
+                self.outputs.insert(outpoint, relevant_script(&output.script_pubkey));
+                added.push(outpoint);
+            }
+        }
+        self.blocks.push_back(added);
+
+        while self.outputs.len() > self.max_entries {
+            let Some(oldest) = self.blocks.pop_front() else {
+                break;
+            };
+            for outpoint in oldest {
+                self.outputs.remove(&outpoint);
+            }
+        }
+        spent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{Amount, TxOut};
+    use crate::mock::{self, MockChain};
+
+    #[test]
+    fn test_connect_block() {
+        let funding = mock::funding(vec![mock::p2wpkh_script(1), mock::p2wpkh_script(2)]);
+        let txid = funding.compute_txid();
+        let spend = mock::transaction(vec![mock::p2wpkh_input(OutPoint::new(txid, 0), 1)], vec![mock::taproot_output()]);
+        let later_spend = mock::transaction(vec![mock::p2wpkh_input(OutPoint::new(txid, 1), 2)], vec![mock::taproot_output()]);
+        let chain = MockChain::default().with_block(vec![funding, spend]).with_block(vec![later_spend]);
+
+        // Each block adds its coinbase and transaction outputs, spends within a block resolve right away
+        let mut cache = ScriptCache::new(100);
+        let spent = cache.connect_block(chain.block(1));
+        assert_eq!(spent, vec![(OutPoint::new(txid, 0), mock::p2wpkh_script(1))]);
+        assert_eq!(cache.count(), 3);
+
+        assert_eq!(cache.connect_block(chain.block(2)), vec![(OutPoint::new(txid, 1), mock::p2wpkh_script(2))]);
+        assert_eq!(cache.count(), 4);
+    }
+
+    #[test]
+    fn test_oldest_blocks_evicted() {
+        let funding = mock::funding(vec![mock::p2wpkh_script(1)]);
+        let txid = funding.compute_txid();
+        let spend = mock::transaction(vec![mock::p2wpkh_input(OutPoint::new(txid, 0), 1)], vec![TxOut { value: Amount::from_sat(1), script_pubkey: ScriptBuf::new_op_return([1u8; 4]) }]);
+        let chain = MockChain::default().with_block(vec![funding]).with_block(vec![]).with_block(vec![spend]);
+
+        // Block 1's outputs make room for block 2's coinbase, so its spend in block 3 is a miss
+        let mut cache = ScriptCache::new(2);
+        cache.connect_block(chain.block(1));
+        cache.connect_block(chain.block(2));
+        assert_eq!(cache.count(), 1);
+        assert!(cache.connect_block(chain.block(3)).is_empty());
+
+        // A zero sized cache stays empty
+        let mut disabled = ScriptCache::new(0);
+        disabled.connect_block(chain.block(1));
+        assert_eq!(disabled.count(), 0);
+    }
+}
@@ -1,36 +1,229 @@
 use secp256k1::XOnlyPublicKey;
-use bitcoin::consensus::encode::deserialize_hex;
+use async_trait::async_trait;
+use bitcoin::consensus::encode::{deserialize, deserialize_hex};
 use bitcoin::block::Block;
-use bitcoin::{ScriptBuf, Transaction, WitnessVersion};
+use bitcoin::bip158::BlockFilterWriter;
+use bitcoin::hashes::{sha256, sha256d, Hash, HashEngine};
+use bitcoin::{OutPoint, ScriptBuf, Transaction, TxOut};
+use bitcoincore_rpc::RpcApi;
 use silentpayments::utils::receiving;
 use silentpayments::secp256k1::PublicKey;
 use silentpayments::Error as SPError;
 use silentpayments::secp256k1::Error as SECPError;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
-use std::process::{Command, Stdio};
-use tracing::{error,warn,debug};
+use std::sync::Arc;
+use std::time::Instant;
+use rayon::prelude::*;
+use tracing::{warn,debug};
 use serde::{Serialize, Deserialize};
-use serde_json;
-use tokio::task;
+use tokio::task::{self, JoinSet};
+use tokio::sync::Semaphore;
+use crate::eligibility::{self, input_type_by_shape, may_be_eligible_input, InputType};
+use crate::rpc;
+
+// getblock with a numeric verbosity argument (0.15.0)
+pub const MIN_NODE_VERSION: u32 = 150000;
+// getblock verbosity 3 with prevout scripts (23.0), older nodes look prevouts up with getrawtransaction
+pub const MIN_PREVOUT_NODE_VERSION: u32 = 230000;
+
+// Share of a block's transactions with a Taproot output above which fetching every prevout with the block
+// (getblock verbosity 3) is cheaper than looking up the spent transactions of each candidate
+pub const BLOCK_PREVOUTS_TAPROOT_SHARE: f64 = 0.25;
+
+// bitcoind serves 4 RPC threads unless rpcthreads is raised
+pub const DEFAULT_MAX_INFLIGHT_RPC: usize = 4;
+
+// Enough transaction workers to keep every core busy while some wait on fallback RPC calls
+fn default_tx_workers() -> usize {
+    std::thread::available_parallelism().map_or(4, |cores| cores.get()) * 2
+}
+
+// Rough heap size of a decoded transaction with its prevout scripts, used against the memory budget
+fn tx_memory(tx: &Transaction, previous_scripts: &[ScriptBuf]) -> usize {
+    tx.total_size() + (tx.input.len() + tx.output.len()) * 64 + previous_scripts.iter().map(|script| script.len() + 24).sum::<usize>()
+}
+
+// Split items into consecutive chunks whose sizes add up to at most the budget, an item above it gets a chunk of its own
+fn chunk_lengths(sizes: impl Iterator<Item = usize>, budget: usize) -> Vec<usize> {
+    let mut chunks = Vec::new();
+    let (mut length, mut used) = (0, 0usize);
+    for size in sizes {
+        if length > 0 && used.saturating_add(size) > budget {
+            chunks.push(length);
+            (length, used) = (0, 0);
+        }
+        length += 1;
+        used = used.saturating_add(size);
+    }
+    if length > 0 {
+        chunks.push(length);
+    }
+    chunks
+}
+
+// Block in the encoding the backend delivered it, so large blocks are decoded without a second copy in another encoding
+pub enum RawBlock {
+    Hex(String),
+    Bytes(Vec<u8>),
+    Decoded(Block),
+}
+
+impl RawBlock {
+    // Consumes the raw data, hex is converted to bytes as it is read so the only full copy is the hex string
+    pub fn decode(self) -> Result<Block, String> {
+        match self {
+            RawBlock::Hex(hex) => deserialize_hex::<Block>(&hex).map_err(|e| e.to_string()),
+            RawBlock::Bytes(bytes) => deserialize::<Block>(&bytes).map_err(|e| e.to_string()),
+            RawBlock::Decoded(block) => Ok(block),
+        }
+    }
+}
+
+// The header of a decoded block must hash to the hash it was requested or is stored by, a node or proxy returning
+// another block would otherwise be indexed under the wrong hash
+pub fn check_block_hash(block: &Block, expected: &str) -> Result<(), String> {
+    let actual = block.block_hash().to_string();
+    if actual != expected {
+        return Err(format!("Expected block {} but the header hashes to {}", expected, actual));
+    }
+    Ok(())
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PreviousScript {
-    txid: String,
-    vout: u32,
-    script: String,
+    pub txid: String,
+    pub vout: u32,
+    pub script: String,
+}
+
+// Everything the indexer needs from a chain backend, blocks and transactions are hex encoded.
+// Heights past the tip must fail with "Block height out of range" so callers detect reaching the tip the same way.
+#[async_trait]
+pub trait ChainSource: Send + Sync {
+    async fn get_block_count(&self) -> Result<u32, String>;
+
+    async fn get_block_hash(&self, height: u32) -> Result<String, String>;
+
+    async fn get_block(&self, block_hash: &str) -> Result<String, String>;
+
+    // Block for decoding, backends that download bytes or decoded blocks return them without hex encoding
+    async fn get_raw_block(&self, block_hash: &str) -> Result<RawBlock, String> {
+        self.get_block(block_hash).await.map(RawBlock::Hex)
+    }
+
+    // Prevout script of every non coinbase input in the block
+    async fn get_prevouts(&self, block_hash: &str) -> Result<Vec<PreviousScript>, String>;
+
+    // Block and its prevout scripts, requested concurrently unless the backend returns both at once
+    async fn get_block_with_prevouts(&self, block_hash: &str) -> Result<(RawBlock, Vec<PreviousScript>), String> {
+        tokio::try_join!(self.get_raw_block(block_hash), self.get_prevouts(block_hash))
+    }
+
+    async fn get_transaction(&self, txid: &str) -> Result<String, String>;
+
+    // Many transactions at once, None for any the backend could not return
+    async fn get_transactions(&self, txids: Vec<String>) -> Result<Vec<Option<String>>, String> {
+        let mut transactions = Vec::with_capacity(txids.len());
+        for txid in txids.iter() {
+            transactions.push(self.get_transaction(txid).await.ok());
+        }
+        Ok(transactions)
+    }
+
+    // Relay a raw transaction, returning its txid
+    async fn broadcast(&self, tx_hex: &str) -> Result<String, String>;
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NetworkInfo {
+    pub version: u32,
+    pub subversion: String,
+}
+
+// Fields of getblockchaininfo the indexer acts on, parsed directly since the response changes between node versions
+#[derive(Deserialize, Debug)]
+pub struct BlockchainInfo {
+    // main, test, signet or regtest
+    pub chain: String,
+    pub blocks: u32,
+    pub headers: u32,
+    #[serde(rename = "initialblockdownload")]
+    pub initial_block_download: bool,
+    #[serde(rename = "verificationprogress")]
+    pub verification_progress: f64,
+    pub pruned: bool,
+    // Lowest height with block data, only reported by pruned nodes
+    #[serde(rename = "pruneheight")]
+    pub prune_height: Option<u32>,
+}
+
+// txindex entry of getindexinfo, absent when the node runs without -txindex
+#[derive(Deserialize, Debug)]
+pub struct TxIndexInfo {
+    pub synced: bool,
+    pub best_block_height: u32,
 }
 
 pub struct Tweak {
+    pub tx_index: u32,
     pub tx_id: String,
     pub tweak: String,
+    // Value in sats of the transaction's largest Taproot output
+    pub max_output_value: u64,
+    pub outputs: Vec<TaprootOutput>,
+}
+
+// Taproot output of a tweaked transaction, what a light client matches its derived keys against
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaprootOutput {
+    pub vout: u32,
+    // x-only output key, hex encoded
+    pub pubkey: String,
+    // Value in sats, None for outputs stored before it was recorded
+    pub value: Option<u64>,
+}
+
+// Failure while indexing one block. Fetch and compute failures are retryable: the block is recorded for a later
+// retry and indexing moves on. A database that can't be written is fatal, continuing would only lose more blocks.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockError {
+    Fetch(String),
+    Compute(String),
+    Store(String),
+}
+
+impl BlockError {
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, BlockError::Store(_))
+    }
+}
+
+impl std::fmt::Display for BlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BlockError::Fetch(err) => write!(f, "Fetching block failed: {}", err),
+            BlockError::Compute(err) => write!(f, "Computing tweaks failed: {}", err),
+            BlockError::Store(err) => write!(f, "Storing block failed: {}", err),
+        }
+    }
+}
+
+// Transaction left out of its block because a prevout could not be resolved, retried in a later pass
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeferredTransaction {
+    pub tx_index: u32,
+    pub tx_id: String,
+    pub error: String,
 }
 
 #[derive(Debug)]
-enum ChainError {
+pub(crate) enum ChainError {
     TxOutputNotFound,
     PubKeyFromInput,
     SegWitVersionGE2,
-    ParseInputTransaction,
+    NullPrevout,
+    UnexpectedPrevoutTx,
 }
 impl std::error::Error for ChainError {}
 
@@ -40,159 +233,454 @@ impl std::fmt::Display for ChainError {
             ChainError::TxOutputNotFound => write!(f, "Could not find previous output transaction"),
             ChainError::PubKeyFromInput => write!(f, "Pub Key From Input error"),
             ChainError::SegWitVersionGE2 => write!(f, "Segwit version 2 or higher not allowed"),
-            ChainError::ParseInputTransaction => write!(f, "Unable to parse previous output transaction")
+            ChainError::NullPrevout => write!(f, "Null previous output in a transaction that is not a coinbase"),
+            ChainError::UnexpectedPrevoutTx => write!(f, "Backend returned another transaction than the previous output transaction"),
         }
     }
 }
 
-// take json transaction output and parse with serde to product Vec<PreviousScript>
-pub fn get_block_input_transactions(block_hash: &str) -> Result<Vec<PreviousScript>, Box<dyn Error>> {
-    let transactions_json = match get_block_with_input(&block_hash) {
-        Ok(block_str) => block_str,
-        Err(err) => {
-            error!("Error fetching block: {}", err);
-            return Err(Box::new(ChainError::ParseInputTransaction));
-        }
-    };
-    
-    let previous_scripts: Vec<PreviousScript> = match serde_json::from_str(&transactions_json) {
-        Ok(scripts) => scripts,
-        Err(err) => {
-            error!("Error parsing json transactions: {}", err);
-            return Err(Box::new(ChainError::ParseInputTransaction));
+// Node status calls, only meaningful for backends backed by a bitcoind
+pub async fn get_network_info() -> Result<NetworkInfo, String> {
+    let network_info = rpc::call(|client| client.get_network_info()).await?;
+    Ok(NetworkInfo {
+        version: network_info.version as u32,
+        subversion: network_info.subversion,
+    })
+}
+
+pub async fn get_blockchain_info() -> Result<BlockchainInfo, String> {
+    rpc::call(|client| client.call("getblockchaininfo", &[])).await
+}
+
+// State of the node's transaction index, needed by getrawtransaction for confirmed transactions (getindexinfo is 0.21+)
+pub async fn get_txindex_info() -> Result<Option<TxIndexInfo>, String> {
+    let mut indexes: HashMap<String, TxIndexInfo> = rpc::call(|client| client.call("getindexinfo", &["txindex".into()])).await?;
+    Ok(indexes.remove("txindex"))
+}
+
+// Issue bursts of get_block_count at increasing concurrency, returning calls per second for each level
+pub async fn probe_rpc_concurrency(source: Arc<dyn ChainSource>, max_level: usize, calls_per_task: usize) -> Result<Vec<(usize, f64)>, String> {
+    let mut results = Vec::new();
+    let mut level = 1;
+
+    while level <= max_level {
+        let started = Instant::now();
+        let workers: Vec<_> = (0..level)
+            .map(|_| {
+                let source = source.clone();
+                task::spawn(async move {
+                    for _ in 0..calls_per_task {
+                        source.get_block_count().await?;
+                    }
+                    Ok::<(), String>(())
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.await.map_err(|_| String::from("probe task panicked"))??;
         }
-    };
-    
-    Ok(previous_scripts)
+
+        let calls_per_sec = (level * calls_per_task) as f64 / started.elapsed().as_secs_f64();
+        debug!("RPC probe concurrency {}: {:.1} calls/sec", level, calls_per_sec);
+        results.push((level, calls_per_sec));
+        level *= 2;
+    }
+
+    Ok(results)
 }
 
-pub fn get_block_count() -> Result<String, String> {
-    bcli(&["getblockcount"])
+// Lowest concurrency reaching 90% of the best observed throughput, more parallelism than that only queues on the node
+pub fn recommend_concurrency(results: &[(usize, f64)]) -> usize {
+    let best = results.iter().map(|(_, rate)| *rate).fold(0.0, f64::max);
+    results
+        .iter()
+        .find(|(_, rate)| *rate >= best * 0.9)
+        .map_or(DEFAULT_MAX_INFLIGHT_RPC, |(level, _)| *level)
 }
 
-pub fn get_block_hash(height: u32) -> Result<String, String> {
-    bcli(&["getblockhash", &height.to_string()])
+// BIP352 only considers transactions with at least one valid P2TR output
+fn has_taproot_output(tx: &Transaction) -> bool {
+    tx.output.iter().any(is_taproot_output)
 }
 
-pub fn get_block(block_hash: &str) -> Result<String, String> {
-    bcli(&["getblock", block_hash, "0"])
+fn is_taproot_output(output: &TxOut) -> bool {
+    output.script_pubkey.is_p2tr() && XOnlyPublicKey::from_slice(&output.script_pubkey.as_bytes()[2..]).is_ok()
 }
 
-// Fetch the long form output to include input previous out (faster than using RPC for each transaction in a block)
-pub fn get_block_with_input(block_hash: &str) -> Result<String, String> {
-    let first_cmd = Command::new("bitcoin-cli")
-        .args(["getblock", block_hash, "3"]) 
-        .stdout(Stdio::piped())
-        .spawn()
-        .map_err(|e| format!("Failed to execute bitcoin-cli: {}", e))?;
+// Taproot outputs of the transaction in output order
+pub fn taproot_outputs(tx: &Transaction) -> Vec<TaprootOutput> {
+    tx.output
+        .iter()
+        .enumerate()
+        .filter(|(_, output)| is_taproot_output(output))
+        .map(|(vout, output)| TaprootOutput {
+            vout: vout as u32,
+            pubkey: hex::encode(&output.script_pubkey.as_bytes()[2..]),
+            value: Some(output.value.to_sat()),
+        })
+        .collect()
+}
 
-    // Second command: Processing JSON with jq
-    let result = Command::new("jq")
-        .args(&["-c", "[.tx[].vin[] | select(.txid != null) | {txid, vout, script: .prevout.scriptPubKey.hex}]"])
-        .stdin(Stdio::from(first_cmd.stdout.unwrap())) // Pipe stdout from first command
-        .output()
-        .map_err(|e| format!("Failed to execute jq: {}", e))?;
+// Taproot outputs the block creates with their outpoints, in block order
+pub fn created_taproot_outputs(block: &Block) -> Vec<(OutPoint, &TxOut)> {
+    block
+        .txdata
+        .iter()
+        .flat_map(|tx| {
+            let txid = tx.compute_txid();
+            tx.output.iter().enumerate().map(move |(vout, output)| (OutPoint::new(txid, vout as u32), output))
+        })
+        .filter(|(_, output)| is_taproot_output(output))
+        .collect()
+}
+
+// Outpoints spent by the block's non coinbase inputs as (txid, vout)
+pub fn spent_outpoints(block: &Block) -> Vec<(String, u32)> {
+    block
+        .txdata
+        .iter()
+        .flat_map(|tx| tx.input.iter())
+        .filter(|input| !input.previous_output.is_null())
+        .map(|input| (input.previous_output.txid.to_string(), input.previous_output.vout))
+        .collect()
+}
 
-    if !result.status.success() {
-        return Err(format!(
-            "bitcoin-cli error: {}",
-            String::from_utf8_lossy(&result.stderr)
-        ));
+// BIP158 filter (P = 19, M = 784931, keyed by the block hash) over the x-only keys of the Taproot outputs of
+// transactions that may carry a tweak, a light client matches its candidate output keys before fetching anything else
+pub fn new_utxo_filter(block: &Block) -> Vec<u8> {
+    let mut filter = Vec::new();
+    let mut writer = BlockFilterWriter::new(&mut filter, block);
+    for tx in block.txdata.iter().filter(|tx| is_candidate(tx)) {
+        for output in tx.output.iter().filter(|output| is_taproot_output(output)) {
+            writer.add_element(&output.script_pubkey.as_bytes()[2..]);
+        }
     }
+    // Writing to a Vec can't fail
+    let _ = writer.finish();
+    filter
+}
+
+// Bytes of an outpoint's hash kept in the spent outpoint index
+pub const SPENT_OUTPOINT_HASH_LEN: usize = 8;
 
-    return Ok(String::from_utf8(result.stdout).unwrap().trim().to_string());
+// Spent outpoint index of the block: for every non coinbase input, the first 8 bytes of
+// SHA256(block hash || txid || vout LE) with hashes in internal byte order, concatenated in input order.
+// Salting with the block hash keeps a collision from repeating across blocks.
+pub fn spent_outpoint_index(block: &Block) -> Vec<u8> {
+    let block_hash = block.block_hash();
+    let mut index = Vec::new();
+    for input in block.txdata.iter().flat_map(|tx| tx.input.iter()).filter(|input| !input.previous_output.is_null()) {
+        let mut engine = sha256::Hash::engine();
+        engine.input(block_hash.as_byte_array());
+        engine.input(input.previous_output.txid.as_byte_array());
+        engine.input(&input.previous_output.vout.to_le_bytes());
+        index.extend_from_slice(&sha256::Hash::from_engine(engine).as_byte_array()[..SPENT_OUTPOINT_HASH_LEN]);
+    }
+    index
+}
+
+// BIP158 filter over the entries of the block's spent outpoint index, a light client hashes its own outpoints
+// the same way to learn whether the block spends any of them
+pub fn spent_filter(block: &Block) -> Vec<u8> {
+    let mut filter = Vec::new();
+    let mut writer = BlockFilterWriter::new(&mut filter, block);
+    for entry in spent_outpoint_index(block).chunks(SPENT_OUTPOINT_HASH_LEN) {
+        writer.add_element(entry);
+    }
+    // Writing to a Vec can't fail
+    let _ = writer.finish();
+    filter
 }
 
-pub fn get_transaction(txid: &str) -> Result<String, String> {
-    bcli(&["getrawtransaction", txid])
+// Merkle tree of a block's txids, kept while the block is stored to extract the branch of each tweaked transaction
+pub struct MerkleTree {
+    // Hashes of each level in internal byte order, from the txids up to the root
+    levels: Vec<Vec<[u8; 32]>>,
 }
 
-pub fn bcli(args: &[&str]) -> Result<String, String> {
-    let result = Command::new("bitcoin-cli")
-        .args(args)
-        .output()
-        .map_err(|e| format!("Failed to execute bitcoin-cli: {}", e))?;
+impl MerkleTree {
+    pub fn new(block: &Block) -> Self {
+        let mut levels = vec![block.txdata.iter().map(|tx| tx.compute_txid().to_byte_array()).collect::<Vec<_>>()];
+        while let Some(level) = levels.last().filter(|level| level.len() > 1) {
+            // A level with an odd count pairs its last hash with itself
+            let next = level
+                .chunks(2)
+                .map(|pair| {
+                    let mut engine = sha256d::Hash::engine();
+                    engine.input(&pair[0]);
+                    engine.input(pair.get(1).unwrap_or(&pair[0]));
+                    sha256d::Hash::from_engine(engine).to_byte_array()
+                })
+                .collect();
+            levels.push(next);
+        }
+        Self { levels }
+    }
 
-    if !result.status.success() {
-        return Err(format!(
-            "bitcoin-cli error: {}",
-            String::from_utf8_lossy(&result.stderr)
-        ));
+    // Sibling hash at each level from the transaction at index up to the root, 32 bytes each in internal byte order.
+    // Hashing the txid with each sibling, on the left when that level's index is odd, gives the header's merkle root.
+    pub fn branch(&self, index: u32) -> Vec<u8> {
+        let mut branch = Vec::new();
+        let mut index = index as usize;
+        for level in self.levels[..self.levels.len() - 1].iter() {
+            branch.extend_from_slice(level.get(index ^ 1).unwrap_or(&level[index]));
+            index /= 2;
+        }
+        branch
     }
+}
 
-    return Ok(String::from_utf8(result.stdout).unwrap().trim().to_string());
+// Value of the largest Taproot output, what a wallet scanning with a dust limit compares against
+pub fn max_taproot_output_value(tx: &Transaction) -> u64 {
+    tx.output.iter().filter(|output| is_taproot_output(output)).map(|output| output.value.to_sat()).max().unwrap_or(0)
+}
+
+// Transactions that can carry a tweak: a Taproot output and at least one input that may provide a key
+fn is_candidate(tx: &Transaction) -> bool {
+    has_taproot_output(tx) && tx.input.iter().any(may_be_eligible_input)
+}
+
+// Transactions that pass the BIP352 eligibility filter before their prevouts are known
+pub fn candidate_count(block: &Block) -> usize {
+    block.txdata.iter().filter(|tx| is_candidate(tx)).count()
+}
+
+// Per block counts for Silent Payments adoption research. Inputs of candidate transactions are counted by the type
+// their shape suggests, the same judgement the candidate filter makes before prevouts are known.
+#[derive(Debug, Default, PartialEq)]
+pub struct BlockStats {
+    pub taproot_outputs: u32,
+    pub candidate_txs: u32,
+    pub p2tr_inputs: u32,
+    pub p2wpkh_inputs: u32,
+    pub p2sh_p2wpkh_inputs: u32,
+    pub p2pkh_inputs: u32,
+}
+
+pub fn block_stats(block: &Block) -> BlockStats {
+    let mut stats = BlockStats::default();
+    for tx in block.txdata.iter() {
+        stats.taproot_outputs += tx.output.iter().filter(|output| is_taproot_output(output)).count() as u32;
+        if !is_candidate(tx) {
+            continue;
+        }
+        stats.candidate_txs += 1;
+        for input_type in tx.input.iter().filter_map(input_type_by_shape) {
+            match input_type {
+                InputType::P2tr => stats.p2tr_inputs += 1,
+                InputType::P2wpkh => stats.p2wpkh_inputs += 1,
+                InputType::P2shP2wpkh => stats.p2sh_p2wpkh_inputs += 1,
+                InputType::P2pkh => stats.p2pkh_inputs += 1,
+            }
+        }
+    }
+    stats
+}
+
+// Whether the block's transaction mix favours fetching all its prevouts at once over per transaction lookups.
+// Candidates whose prevouts are all cached need neither, so they do not count.
+pub fn prefers_block_prevouts(block: &Block, cached: impl Fn(&OutPoint) -> bool) -> bool {
+    let uncached = |tx: &Transaction| tx.input.iter().any(|input| !cached(&input.previous_output));
+    let transactions = block.txdata.iter().filter(|tx| !tx.is_coinbase());
+    let (total, candidates) = transactions.fold((0, 0), |(total, candidates), tx| (total + 1, candidates + (is_candidate(tx) && uncached(tx)) as usize));
+    total > 0 && candidates as f64 >= total as f64 * BLOCK_PREVOUTS_TAPROOT_SHARE
 }
 
 #[derive(Clone)]
 pub struct Chain {
-    previous_scripts: Option<Vec<PreviousScript>>
+    source: Arc<dyn ChainSource>,
+    // Read only while a block is processed, shared with every transaction task
+    previous_scripts: Arc<HashMap<OutPoint, ScriptBuf>>,
+    // Prevouts missing from previous_scripts, batch fetched for the block being processed
+    fetched_scripts: Arc<HashMap<OutPoint, ScriptBuf>>,
+    rpc_permits: Arc<Semaphore>,
+    // Transactions processed concurrently within a block
+    tx_workers: usize,
+    // Bytes of transactions and prevout scripts copied for tweak computation at once, unlimited when None
+    memory_budget: Option<usize>,
+    // Transactions of the last processed block whose prevouts could not be resolved
+    deferred: Vec<DeferredTransaction>,
+    // Transactions whose Taproot outputs are all below this many sats get no tweak
+    dust_limit: Option<u64>,
 }
 
 impl Chain {
-    pub fn new() -> Self {
-        Self { previous_scripts: None }
+    pub fn new(source: Arc<dyn ChainSource>) -> Self {
+        Self {
+            source,
+            previous_scripts: Arc::new(HashMap::new()),
+            fetched_scripts: Arc::new(HashMap::new()),
+            rpc_permits: Arc::new(Semaphore::new(DEFAULT_MAX_INFLIGHT_RPC)),
+            tx_workers: default_tx_workers(),
+            memory_budget: None,
+            deferred: Vec::new(),
+            dust_limit: None,
+        }
+    }
+
+    //Limit how many fallback RPC calls transaction tasks may have in flight at once
+    pub fn set_max_inflight_rpc(&mut self, max_inflight_rpc: usize) {
+        self.rpc_permits = Arc::new(Semaphore::new(max_inflight_rpc.max(1)));
+    }
+
+    // Transactions of a block waiting on backend lookups at once
+    pub fn set_tx_workers(&mut self, tx_workers: usize) {
+        self.tx_workers = tx_workers.max(1);
+    }
+
+    // Skip transactions whose Taproot outputs are all worth less than the limit, before their prevouts are fetched
+    pub fn set_dust_limit(&mut self, sats: u64) {
+        self.dust_limit = Some(sats);
+    }
+
+    fn is_dust(&self, tx: &Transaction) -> bool {
+        self.dust_limit.is_some_and(|limit| max_taproot_output_value(tx) < limit)
+    }
+
+    // Large blocks are computed in chunks that fit the budget instead of all at once
+    pub fn set_memory_budget(&mut self, bytes: usize) {
+        self.memory_budget = Some(bytes.max(1));
     }
 
-    //Should be set once per block
+    //Should be set once per block, indexed by outpoint so each input is a single lookup
     pub fn set_previous_scripts(&mut self, previous_scripts: Vec<PreviousScript>) {
-        self.previous_scripts = Some(previous_scripts);
+        self.previous_scripts = Arc::new(previous_scripts
+            .into_iter()
+            .filter_map(|ps| match (ps.txid.parse(), ScriptBuf::from_hex(&ps.script)) {
+                (Ok(txid), Ok(script)) => Some((OutPoint::new(txid, ps.vout), script)),
+                _ => {
+                    warn!("Ignoring invalid previous script {}:{}", ps.txid, ps.vout);
+                    None
+                }
+            })
+            .collect());
+    }
+
+    //Return the script of the previous output, if known
+    pub fn find_previous_script(&self, outpoint: &OutPoint) -> Option<&ScriptBuf> {
+        self.previous_scripts.get(outpoint).or_else(|| self.fetched_scripts.get(outpoint))
     }
 
-    //Return the matching previous output string given txid and vout
-    pub fn find_previous_script(&self, tx_id: &str, vout: u32) -> Option<&PreviousScript> {
-        self.previous_scripts.as_ref()?.iter().find(|ps| ps.txid == tx_id && ps.vout == vout)
+    //Previous outputs of candidate transactions in the block that have no known script, grouped by txid
+    fn missing_prevouts(&self, block: &Block) -> BTreeMap<String, Vec<u32>> {
+        let mut missing: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+        for tx in block.txdata.iter().filter(|tx| is_candidate(tx)) {
+            for input in tx.input.iter().filter(|input| !input.previous_output.is_null()) {
+                if self.find_previous_script(&input.previous_output).is_none() {
+                    missing.entry(input.previous_output.txid.to_string()).or_default().push(input.previous_output.vout);
+                }
+            }
+        }
+        missing
     }
 
-    //Determine if this spend script is using segwit version 2 or higher
-    fn is_segwit_gt_v1(&self, script_pubkey: &ScriptBuf) -> bool {
-        if let Some(version) = script_pubkey.witness_version() {
-            match version {
-                WitnessVersion::V0 | WitnessVersion::V1 => false, // v0 and v1 accepted
-                _ => true, // reject all other versions v2, ...
+    //Fetch every missing prevout script of the block in one batched call, anything left is fetched per transaction
+    async fn prefetch_missing_prevouts(&mut self, block: &Block) {
+        self.fetched_scripts = Arc::new(HashMap::new());
+        let missing = self.missing_prevouts(block);
+        if missing.is_empty() {
+            return;
+        }
+
+        let txids: Vec<String> = missing.keys().cloned().collect();
+        let transactions = match self.source.get_transactions(txids).await {
+            Ok(transactions) => transactions,
+            Err(err) => {
+                warn!("Unable to batch fetch {} previous transactions: {}", missing.len(), err);
+                return;
+            }
+        };
+        debug!("Batch fetched {} previous transactions", missing.len());
+
+        let mut fetched_scripts = HashMap::new();
+        for (vouts, previous_tx_hex) in missing.into_values().zip(transactions) {
+            let Some(previous_tx) = previous_tx_hex.and_then(|hex| deserialize_hex::<Transaction>(&hex).ok()) else {
+                continue;
+            };
+            let txid = previous_tx.compute_txid();
+            for vout in vouts {
+                if let Some(output) = previous_tx.output.get(vout as usize) {
+                    fetched_scripts.insert(OutPoint::new(txid, vout), output.script_pubkey.clone());
+                }
             }
-        } else {
-            false // Not segwit pass
         }
+        self.fetched_scripts = Arc::new(fetched_scripts);
+    }
+
+    // Transactions of the last processed block that were left out for a later retry, see process_transaction
+    pub fn take_deferred(&mut self) -> Vec<DeferredTransaction> {
+        std::mem::take(&mut self.deferred)
     }
 
     // Heavy inspiration from sp-client (https://github.com/cygnet3/sp-client) and rust-silentpayments (https://github.com/cygnet3/rust-silentpayments)
-    async fn process_transaction(&self, transaction: &Transaction) -> Result<Vec<Tweak>, Box<dyn Error + Send + Sync>> {
-        let mut tweaks = Vec::new();
+    pub async fn process_transaction(&self, tx_index: u32, transaction: &Transaction) -> Result<Vec<Tweak>, Box<dyn Error + Send + Sync>> {
+        // The coinbase has no prevouts to take keys from
+        if transaction.is_coinbase() || self.is_dust(transaction) {
+            return Ok(Vec::new());
+        }
+        let previous_scripts = self.resolve_previous_scripts(transaction).await?;
+        self.compute_tweak(tx_index, transaction, &previous_scripts)
+    }
 
-        //Calculate input pub keys
-        let mut input_pubkeys: Vec<PublicKey> = vec![];    
+    // Prevout script of every input, from the known maps or looked up on the backend
+    async fn resolve_previous_scripts(&self, transaction: &Transaction) -> Result<Vec<ScriptBuf>, Box<dyn Error + Send + Sync>> {
+        let mut previous_scripts = Vec::with_capacity(transaction.input.len());
         for input in transaction.input.iter() {
+            // Only the coinbase may spend the null outpoint, anywhere else the transaction is malformed
             if input.previous_output.is_null() {
-                return Ok(tweaks);
+                return Err(Box::new(ChainError::NullPrevout));
             }
 
             // Fetch the previous transaction
-            let previous_script = if let Some(prev_script) = self.find_previous_script(&input.previous_output.txid.to_string(), input.previous_output.vout) {
-                ScriptBuf::from_hex(&prev_script.script)?
+            let previous_script = if let Some(prev_script) = self.find_previous_script(&input.previous_output) {
+                prev_script.clone()
             } else {
-                warn!("Had to fetch previous input transaction using RPC (txid): {}",transaction.compute_txid());
-                let previous_tx_hex = get_transaction(&input.previous_output.txid.to_string())?;
+                warn!("Had to fetch previous input transaction from the backend (txid): {}",transaction.compute_txid());
+                let previous_tx_hex = {
+                    let _permit = self.rpc_permits.acquire().await?;
+                    self.source.get_transaction(&input.previous_output.txid.to_string()).await?
+                };
                 let previous_tx: Transaction = deserialize_hex::<Transaction>(&previous_tx_hex)?;
-                assert!(previous_tx.compute_txid() == input.previous_output.txid);
+                // A bad backend response fails only this transaction, it is deferred and retried like a missing prevout
+                if previous_tx.compute_txid() != input.previous_output.txid {
+                    return Err(Box::new(ChainError::UnexpectedPrevoutTx));
+                }
 
                 match previous_tx.output.get(input.previous_output.vout as usize) {
                     Some(output) => output.script_pubkey.clone(),
                     None => return Err(Box::new(ChainError::TxOutputNotFound)),
                 }
             };
-            
-            // Filter transactions by BIP352 consensus on allowed transactions
-            if self.is_segwit_gt_v1(&previous_script) {
-                warn!("Segwit > v1: {}",previous_script.to_hex_string());
-                return Err(Box::new(ChainError::SegWitVersionGE2));
-            }
+            previous_scripts.push(previous_script);
+        }
+        Ok(previous_scripts)
+    }
 
+    // Prevout scripts of every input when all are known without asking the backend, a null outpoint is never known
+    // so its transaction goes through resolve_previous_scripts and fails there
+    fn known_previous_scripts(&self, transaction: &Transaction) -> Option<Vec<ScriptBuf>> {
+        transaction
+            .input
+            .iter()
+            .map(|input| self.find_previous_script(&input.previous_output).cloned())
+            .collect()
+    }
+
+    // Tweak of a transaction whose prevout scripts are known, pure computation so it can run on the rayon pool
+    pub fn compute_tweak(&self, tx_index: u32, transaction: &Transaction, previous_scripts: &[ScriptBuf]) -> Result<Vec<Tweak>, Box<dyn Error + Send + Sync>> {
+        let mut tweaks = Vec::new();
+        if transaction.is_coinbase() {
+            return Ok(tweaks);
+        }
+        if transaction.input.iter().any(|input| input.previous_output.is_null()) {
+            return Err(Box::new(ChainError::NullPrevout));
+        }
+
+        //Calculate input pub keys
+        let mut input_pubkeys: Vec<PublicKey> = vec![];
+        for (input, previous_script) in transaction.input.iter().zip(previous_scripts) {
             // Collect all input pub keys
-            match receiving::get_pubkey_from_input(
-                &input.script_sig.to_bytes(), 
-                &input.witness.to_vec(), 
-                &previous_script.to_bytes(),
-            ) {
+            match eligibility::input_pubkey(input, previous_script) {
                 Ok(Some(pubkey)) => {
                     input_pubkeys.push(pubkey);
                     debug!("Input Previous Output: {}:{} -> {}", input.previous_output.txid, input.previous_output.vout, pubkey.to_string());
@@ -200,8 +688,8 @@ impl Chain {
                 Ok(None) => {
                     debug!("No public key found in input {}:{}", input.previous_output.txid, input.previous_output.vout);
                 }
-                Err(_) => {
-                    return Err(Box::new(ChainError::PubKeyFromInput));
+                Err(err) => {
+                    return Err(Box::new(err));
                 }
             }
         }
@@ -234,67 +722,129 @@ impl Chain {
         };
 
         tweaks.push(Tweak {
+            tx_index,
             tx_id: transaction.compute_txid().to_string(),
             tweak: tweak_data.to_string(),
+            max_output_value: max_taproot_output_value(transaction),
+            outputs: taproot_outputs(transaction),
         });
 
         Ok(tweaks)
     }
 
-    /// Deserializes a block but tracks how much data was consumed
-    pub async fn process_transactions(&mut self, block_hex: &String) -> Result<Vec<Tweak>, Box<dyn Error + Send + Sync>>{
-        let block = deserialize_hex::<Block>(block_hex)
-            .map_err(|e| format!("Failed to decode block: {}", e))?;
-        
-        let mut tasks = vec![];
-        let mut block_tweaks = vec![];
-
-        for tx in block.txdata.iter() {
-            let chain = self.clone();
-            let tx = tx.clone();
-            let task = task::spawn(async move {
-                // Filter transactions by BIP352 consensus on allowed transactions
-                // Only process transactions with outputs that have a valid P2TR scriptpubkey
-                debug!("Spawning process tx tasks {}", tx.compute_txid());
-                let mut has_taproot: bool = false;
-                for output in tx.output.iter() {
-                    if output.script_pubkey.is_p2tr() && XOnlyPublicKey::from_slice(&output.script_pubkey.as_bytes()[2..]).is_ok()  {
-                        has_taproot = true;
+    /// Tweaks of a decoded block
+    /// Tweaks are returned in block order (by transaction index) so every indexer stores them identically
+    pub async fn process_block(&mut self, block: &Block) -> Result<Vec<Tweak>, Box<dyn Error + Send + Sync>>{
+        self.prefetch_missing_prevouts(block).await;
+        let block_hash = block.block_hash();
+
+        // Filter transactions by BIP352 consensus on allowed transactions:
+        // only transactions with outputs that have a valid P2TR scriptpubkey are processed,
+        // and only when an input looks like an eligible spend, so the rest never cost a prevout fetch.
+        // Those with every prevout known are CPU bound and computed on the rayon pool, the others wait on the
+        // backend in at most tx_workers tasks, each task's Chain clone shares the prevout maps.
+        let mut resolved = Vec::new();
+        let mut unresolved = Vec::new();
+        for (tx_index, tx) in block.txdata.iter().enumerate().filter(|(_, tx)| is_candidate(tx) && !self.is_dust(tx)) {
+            match self.known_previous_scripts(tx) {
+                Some(previous_scripts) => resolved.push((tx_index as u32, tx, previous_scripts)),
+                None => unresolved.push((tx_index as u32, tx)),
+            }
+        }
+        let budget = self.memory_budget.unwrap_or(usize::MAX);
+
+        // Transactions are copied to the rayon pool one budget sized chunk at a time
+        let chunks = chunk_lengths(resolved.iter().map(|(_, tx, previous_scripts)| tx_memory(tx, previous_scripts)), budget);
+        if chunks.len() > 1 {
+            debug!("Computing {} transactions of block {} in {} chunks to stay within the memory budget", resolved.len(), block_hash, chunks.len());
+        }
+        let parallel = async {
+            let mut resolved = resolved.into_iter();
+            let mut results = Vec::new();
+            for length in chunks {
+                let chunk: Vec<_> = resolved.by_ref().take(length).map(|(tx_index, tx, previous_scripts)| (tx_index, tx.clone(), previous_scripts)).collect();
+                let chain = self.clone();
+                let computed = task::spawn_blocking(move || {
+                    chunk
+                        .par_iter()
+                        .map(|(tx_index, tx, previous_scripts)| (tx.compute_txid(), chain.compute_tweak(*tx_index, tx, previous_scripts)))
+                        .collect::<Vec<_>>()
+                });
+                match computed.await {
+                    Ok(computed) => results.extend(computed),
+                    Err(err) => warn!("Tweak computation panicked: {}", err),
+                }
+            }
+            results
+        };
+
+        // Tasks waiting on the backend hold copies of their transactions too, at least one always runs
+        let sequential = async {
+            let mut candidates = unresolved.into_iter().peekable();
+            let mut workers = JoinSet::new();
+            let mut inflight = 0;
+            let mut tweaks = vec![];
+            let mut deferred = vec![];
+            loop {
+                while workers.len() < self.tx_workers {
+                    let Some(&(tx_index, tx)) = candidates.peek() else {
+                        break;
+                    };
+                    let size = tx_memory(tx, &[]);
+                    if !workers.is_empty() && inflight + size > budget {
                         break;
                     }
-                }
-                if has_taproot {
-                    match chain.process_transaction(&tx).await {
-                        Ok(tweaks) => {
-                            debug!("Completed process tx tasks {}", tx.compute_txid());
-                            Ok(tweaks)
-                        },
-                        Err(err) => {
-                            debug!("Error processing tx: {}, block: {}: err: {}", tx.compute_txid(), block.header.block_hash(), err);
-                            Err(err)
+                    candidates.next();
+                    inflight += size;
+                    let chain = self.clone();
+                    let tx = tx.clone();
+                    workers.spawn(async move {
+                        debug!("Spawning process tx tasks {}", tx.compute_txid());
+                        // Only unresolved prevouts are worth retrying, invalid inputs fail the same way every time
+                        let result = match chain.resolve_previous_scripts(&tx).await {
+                            Ok(previous_scripts) => chain.compute_tweak(tx_index, &tx, &previous_scripts).map_err(|err| (err.to_string(), false)),
+                            Err(err) => Err((err.to_string(), true)),
+                        };
+                        match &result {
+                            Ok(_) => debug!("Completed process tx tasks {}", tx.compute_txid()),
+                            Err((err, _)) => debug!("Error processing tx: {}, block: {}: err: {}", tx.compute_txid(), block_hash, err),
                         }
-                    }
-                } else {
-                    debug!("Completed process tx tasks {}, no tweaks found", tx.compute_txid());
-                    Ok(vec![])
+                        (size, tx_index, tx.compute_txid(), result)
+                    });
                 }
-                
-            });
-            tasks.push(task);
-        }
 
-        for task in tasks {
-            match task.await {
-                Ok(Ok(tweaks)) => {
-                    if !tweaks.is_empty() {
-                        block_tweaks.extend(tweaks);
+                match workers.join_next().await {
+                    Some(Ok((size, tx_index, txid, result))) => {
+                        inflight -= size;
+                        match result {
+                            Ok(tx_tweaks) => tweaks.extend(tx_tweaks),
+                            Err((error, true)) => {
+                                warn!("Deferring tx {} of block {}, prevouts unresolved: {}", txid, block_hash, error);
+                                deferred.push(DeferredTransaction { tx_index, tx_id: txid.to_string(), error });
+                            }
+                            Err((error, false)) => warn!("Error in task: {}", error),
+                        }
                     }
+                    Some(Err(err)) => warn!("Task panicked: {}", err),
+                    None => break,
                 }
-                Ok(Err(err)) => warn!("Error in task: {}", err),
-                Err(err) => warn!("Task panicked: {}", err),
+            }
+            deferred.sort_by_key(|tx: &DeferredTransaction| tx.tx_index);
+            (tweaks, deferred)
+        };
+
+        let ((mut block_tweaks, deferred), results) = tokio::join!(sequential, parallel);
+        self.deferred = deferred;
+        for (txid, result) in results {
+            match result {
+                Ok(tweaks) => block_tweaks.extend(tweaks),
+                Err(err) => warn!("Error processing tx: {}, block: {}: err: {}", txid, block_hash, err),
             }
         }
 
+        // Canonical order is position within the block, independent of task scheduling
+        block_tweaks.sort_by_key(|tweak| tweak.tx_index);
+
         Ok(block_tweaks)
     }
 }
@@ -304,34 +854,450 @@ impl Chain {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use bitcoin::blockdata::script::Builder;
-    use bitcoin::blockdata::opcodes::all::{*};
+    use crate::mock::{self, MockChain};
+
+    fn spend(outpoints: &[(&str, u32)], script_pubkey_hex: &str) -> Transaction {
+        Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: outpoints
+                .iter()
+                .map(|(txid, vout)| bitcoin::TxIn {
+                    previous_output: bitcoin::OutPoint::new(txid.parse().unwrap(), *vout),
+                    // Taproot key path spend
+                    witness: bitcoin::Witness::from_slice(&[[1u8; 64]]),
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![bitcoin::TxOut {
+                value: bitcoin::Amount::from_sat(1000),
+                script_pubkey: ScriptBuf::from_hex(script_pubkey_hex).unwrap(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_missing_prevouts() {
+        let taproot = "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        let known = "11".repeat(32);
+        let unknown = "22".repeat(32);
+        let non_taproot = "33".repeat(32);
+
+        let mut block = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+        block.txdata = vec![
+            spend(&[(&known, 0), (&unknown, 1), (&unknown, 3)], taproot),
+            spend(&[(&non_taproot, 0)], "0014751e76e8199196d454941c45d1b3a323f1433bd6"),
+        ];
+
+        let mut chain = Chain::new(Arc::new(MockChain::default()));
+        chain.set_previous_scripts(vec![PreviousScript { txid: known, vout: 0, script: String::from("0014") }]);
+
+        // Only unknown prevouts of transactions with taproot outputs need fetching
+        let missing = chain.missing_prevouts(&block);
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing.get(&unknown), Some(&vec![1, 3]));
+    }
+
+    #[test]
+    fn test_clone_shares_previous_scripts() {
+        let mut chain = Chain::new(Arc::new(MockChain::default()));
+        chain.set_previous_scripts(vec![PreviousScript { txid: "11".repeat(32), vout: 0, script: String::from("0014") }]);
+
+        // Transaction tasks get a clone of the chain, the prevout map itself is not copied
+        let task_chain = chain.clone();
+        assert!(Arc::ptr_eq(&chain.previous_scripts, &task_chain.previous_scripts));
+        assert_eq!(task_chain.previous_scripts.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_missing_prevouts() {
+        let taproot = "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        let previous_tx = spend(&[(&"44".repeat(32), 0)], taproot);
+        let previous_txid = previous_tx.compute_txid().to_string();
+        let unknown = "55".repeat(32);
+
+        let mut block = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+        block.txdata = vec![spend(&[(&previous_txid, 0), (&unknown, 0)], taproot)];
+
+        let mut chain = Chain::new(Arc::new(MockChain::default().with_transaction(previous_tx)));
+        chain.prefetch_missing_prevouts(&block).await;
+
+        // Prevouts the backend knows are cached, the rest stay missing for the per transaction fallback
+        let outpoint = |txid: &str| OutPoint::new(txid.parse().unwrap(), 0);
+        assert_eq!(chain.find_previous_script(&outpoint(&previous_txid)).map(|script| script.to_hex_string()), Some(taproot.to_string()));
+        assert!(chain.find_previous_script(&outpoint(&unknown)).is_none());
+    }
+
+    #[test]
+    fn test_block_error_is_fatal() {
+        assert!(!BlockError::Fetch(String::from("connection refused")).is_fatal());
+        assert!(!BlockError::Compute(String::from("invalid witness")).is_fatal());
+        assert!(BlockError::Store(String::from("disk I/O error")).is_fatal());
+    }
+
+    #[test]
+    fn test_chunk_lengths() {
+        assert_eq!(chunk_lengths([40, 40, 40, 40].into_iter(), 100), vec![2, 2]);
+        // Oversized items still get processed, alone
+        assert_eq!(chunk_lengths([10, 500, 10, 10].into_iter(), 100), vec![1, 1, 2]);
+        assert_eq!(chunk_lengths([40, 40].into_iter(), usize::MAX), vec![2]);
+        assert!(chunk_lengths(std::iter::empty(), 100).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_memory_budget_same_tweaks() {
+        let funding = mock::funding((1..=8).map(mock::p2wpkh_script).collect());
+        let txid = funding.compute_txid();
+        let spends = (1..=8u8)
+            .map(|secret| mock::transaction(vec![mock::p2wpkh_input(OutPoint::new(txid, secret as u32 - 1), secret)], vec![mock::taproot_output()]))
+            .collect();
+        let source = Arc::new(MockChain::default().with_transaction(funding).with_block(spends));
+
+        let unlimited = Chain::new(source.clone()).process_block(source.block(1)).await.unwrap();
+        // A budget below one transaction computes them one at a time
+        let mut chunked = Chain::new(source.clone());
+        chunked.set_memory_budget(1);
+        let tweaks = chunked.process_block(source.block(1)).await.unwrap();
+        assert_eq!(tweaks.len(), 8);
+        assert_eq!(tweaks.iter().map(|tweak| &tweak.tweak).collect::<Vec<_>>(), unlimited.iter().map(|tweak| &tweak.tweak).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_raw_block_encodings_decode_alike() {
+        let source = MockChain::default().with_block(vec![mock::transaction(vec![mock::p2wpkh_input(OutPoint::null(), 1)], vec![mock::taproot_output()])]);
+        let block = source.block(1);
+        let hash = block.block_hash().to_string();
+        let bytes = bitcoin::consensus::serialize(&block);
+
+        assert_eq!(source.get_raw_block(&hash).await.unwrap().decode().unwrap(), *block);
+        assert_eq!(RawBlock::Bytes(bytes.clone()).decode().unwrap(), *block);
+        assert_eq!(RawBlock::Decoded(block.clone()).decode().unwrap(), *block);
+        assert!(RawBlock::Bytes(bytes[..bytes.len() - 1].to_vec()).decode().is_err());
+    }
 
     #[test]
-    fn test_is_segwit_gt_v1() {
-        let chain = Chain::new();
+    fn test_check_block_hash() {
+        let source = MockChain::default().with_block(vec![]);
+        assert!(check_block_hash(source.block(1), &source.block(1).block_hash().to_string()).is_ok());
+        assert!(check_block_hash(source.block(1), &source.block(0).block_hash().to_string()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dust_limit() {
+        let funding = mock::funding(vec![mock::p2wpkh_script(1), mock::p2wpkh_script(2)]);
+        let txid = funding.compute_txid();
+        let dust = bitcoin::TxOut { value: bitcoin::Amount::from_sat(546), ..mock::taproot_output() };
+        let spends = vec![
+            mock::transaction(vec![mock::p2wpkh_input(OutPoint::new(txid, 0), 1)], vec![dust.clone()]),
+            mock::transaction(vec![mock::p2wpkh_input(OutPoint::new(txid, 1), 2)], vec![dust, mock::taproot_output()]),
+        ];
+        let source = Arc::new(MockChain::default().with_transaction(funding).with_block(spends));
+
+        // Without a limit every tweak is kept with its largest Taproot output
+        let tweaks = Chain::new(source.clone()).process_block(source.block(1)).await.unwrap();
+        assert_eq!(tweaks.iter().map(|tweak| tweak.max_output_value).collect::<Vec<_>>(), vec![546, 90_000]);
+        // Every Taproot output is recorded with its vout and value
+        assert_eq!(tweaks[1].outputs.iter().map(|output| (output.vout, output.value)).collect::<Vec<_>>(), vec![(0, Some(546)), (1, Some(90_000))]);
+        assert_eq!(tweaks[1].outputs[1].pubkey, hex::encode(&mock::taproot_output().script_pubkey.as_bytes()[2..]));
+
+        // Only the transaction with an output at or above the limit is kept
+        let mut chain = Chain::new(source.clone());
+        chain.set_dust_limit(1000);
+        let tweaks = chain.process_block(source.block(1)).await.unwrap();
+        assert_eq!(tweaks.iter().map(|tweak| tweak.tx_index).collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn test_spent_outpoint_index() {
+        let funding = mock::funding(vec![mock::p2wpkh_script(1), mock::p2wpkh_script(2)]);
+        let txid = funding.compute_txid();
+        let spend = mock::transaction(
+            vec![mock::p2wpkh_input(OutPoint::new(txid, 0), 1), mock::p2wpkh_input(OutPoint::new(txid, 1), 2)],
+            vec![mock::taproot_output()],
+        );
+        let source = MockChain::default().with_block(vec![]).with_block(vec![spend.clone()]).with_block(vec![spend]);
+
+        // One hash per spent outpoint, the coinbase spends none
+        assert!(spent_outpoint_index(source.block(1)).is_empty());
+        let index = spent_outpoint_index(source.block(2));
+        assert_eq!(index.len(), 2 * SPENT_OUTPOINT_HASH_LEN);
+        assert_ne!(index[..SPENT_OUTPOINT_HASH_LEN], index[SPENT_OUTPOINT_HASH_LEN..]);
+        // The same outpoint hashes differently in another block
+        assert_ne!(spent_outpoint_index(source.block(3)), index);
+    }
 
-        // Test empty script
-        assert_eq!(chain.is_segwit_gt_v1(&Builder::new().into_script()), false);
+    #[test]
+    fn test_new_utxo_filter() {
+        let funding = mock::funding(vec![mock::p2wpkh_script(1)]);
+        let spend = mock::transaction(vec![mock::p2wpkh_input(OutPoint::new(funding.compute_txid(), 0), 1)], vec![mock::taproot_output()]);
+        let source = MockChain::default().with_block(vec![]).with_block(vec![spend]);
+        let key = mock::taproot_output().script_pubkey.as_bytes()[2..].to_vec();
+        let other_key = [7u8; 32];
+
+        // The coinbase's Taproot output can't carry a tweak and is left out
+        let block = source.block(1);
+        let filter = bitcoin::bip158::BlockFilter::new(&new_utxo_filter(block));
+        assert!(!filter.match_any(&block.block_hash(), [key.as_slice()].into_iter()).unwrap());
+
+        let block = source.block(2);
+        let filter = bitcoin::bip158::BlockFilter::new(&new_utxo_filter(block));
+        assert!(filter.match_any(&block.block_hash(), [key.as_slice()].into_iter()).unwrap());
+        assert!(!filter.match_any(&block.block_hash(), [other_key.as_slice()].into_iter()).unwrap());
+    }
+
+    #[test]
+    fn test_spent_filter() {
+        let funding = mock::funding(vec![mock::p2wpkh_script(1)]);
+        let spend = mock::transaction(vec![mock::p2wpkh_input(OutPoint::new(funding.compute_txid(), 0), 1)], vec![mock::taproot_output()]);
+        let source = MockChain::default().with_block(vec![spend]);
+        let block = source.block(1);
+        let index = spent_outpoint_index(block);
+        let filter = bitcoin::bip158::BlockFilter::new(&spent_filter(block));
+
+        // Every index entry matches, an entry salted with another block's hash doesn't
+        assert!(filter.match_all(&block.block_hash(), index.chunks(SPENT_OUTPOINT_HASH_LEN)).unwrap());
+        assert!(!filter.match_any(&block.block_hash(), [[9u8; SPENT_OUTPOINT_HASH_LEN].as_slice()].into_iter()).unwrap());
+    }
 
-        // Test with SegWit version 0
-        let script_pubkey_v0 = Builder::new().push_opcode(OP_PUSHBYTES_0).into_script();
-        assert_eq!(chain.is_segwit_gt_v1(&script_pubkey_v0), false);
+    #[test]
+    fn test_merkle_branch() {
+        let funding = mock::funding((1..=4).map(mock::p2wpkh_script).collect());
+        let spends = (0..4).map(|vout| mock::transaction(vec![mock::p2wpkh_input(OutPoint::new(funding.compute_txid(), vout), vout as u8 + 1)], vec![mock::taproot_output()])).collect();
+        // The coinbase and four spends, the odd count makes levels pair a hash with itself
+        let source = MockChain::default().with_block(spends);
+        let block = source.block(1);
+        let tree = MerkleTree::new(block);
+
+        for (index, tx) in block.txdata.iter().enumerate() {
+            let branch = tree.branch(index as u32);
+            assert_eq!(branch.len(), 3 * 32);
+            let (mut hash, mut position) = (tx.compute_txid().to_byte_array(), index);
+            for sibling in branch.chunks(32) {
+                let mut engine = sha256d::Hash::engine();
+                if position % 2 == 1 {
+                    engine.input(sibling);
+                    engine.input(&hash);
+                } else {
+                    engine.input(&hash);
+                    engine.input(sibling);
+                }
+                hash = sha256d::Hash::from_engine(engine).to_byte_array();
+                position /= 2;
+            }
+            assert_eq!(hash, block.header.merkle_root.to_byte_array());
+        }
+
+        // A coinbase only block's root is the coinbase txid
+        let source = MockChain::default().with_block(vec![]);
+        assert!(MerkleTree::new(source.block(1)).branch(0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_coinbase_only_block() {
+        let source = Arc::new(MockChain::default().with_block(vec![]));
+        let mut chain = Chain::new(source.clone());
+        // The coinbase pays to taproot but has no inputs to take keys from
+        assert!(chain.process_block(source.block(1)).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_null_prevout_outside_coinbase() {
+        let funding = mock::funding(vec![mock::p2wpkh_script(1)]);
+        let spend = mock::transaction(
+            vec![mock::p2wpkh_input(OutPoint::new(funding.compute_txid(), 0), 1), mock::p2wpkh_input(OutPoint::null(), 2)],
+            vec![mock::taproot_output()],
+        );
+        let chain = Chain::new(Arc::new(MockChain::default().with_transaction(funding)));
+
+        // The coinbase is skipped, a null outpoint after a regular input is an error rather than no tweak
+        assert!(chain.process_transaction(0, &mock::coinbase(1)).await.unwrap().is_empty());
+        assert!(chain.process_transaction(1, &spend).await.is_err());
+        assert!(chain.compute_tweak(1, &spend, &[mock::p2wpkh_script(1), mock::p2wpkh_script(2)]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wrong_prevout_tx_defers_transaction() {
+        let funding = mock::funding(vec![mock::p2wpkh_script(1)]);
+        let other = mock::funding(vec![mock::p2wpkh_script(2)]);
+        let spend = mock::transaction(vec![mock::p2wpkh_input(OutPoint::new(funding.compute_txid(), 0), 1)], vec![mock::taproot_output()]);
+        let source = Arc::new(MockChain::default().with_transaction_for(funding.compute_txid(), other).with_block(vec![spend.clone()]));
+
+        // The wrong transaction is an error for the spend, the block is still processed and the spend deferred
+        let mut chain = Chain::new(source.clone());
+        assert!(chain.process_transaction(1, &spend).await.is_err());
+        assert!(chain.process_block(source.block(1)).await.unwrap().is_empty());
+        assert_eq!(chain.take_deferred().len(), 1);
+    }
 
-        // Test with 0x0101
-        let script_pubkey_v1 = Builder::new().push_opcode(OP_PUSHBYTES_1).push_slice([0]).into_script();
-        assert_eq!(chain.is_segwit_gt_v1(&script_pubkey_v1), false);
+    #[tokio::test]
+    async fn test_segwit_v2_input_skips_transaction() {
+        let funding = mock::funding(vec![mock::p2wpkh_script(1), mock::segwit_v2_script()]);
+        let txid = funding.compute_txid();
+        let eligible = mock::transaction(vec![mock::p2wpkh_input(OutPoint::new(txid, 0), 1)], vec![mock::taproot_output()]);
+        let v2_spend = mock::transaction(vec![mock::p2wpkh_input(OutPoint::new(txid, 1), 1)], vec![mock::taproot_output()]);
+        let source = Arc::new(MockChain::default().with_transaction(funding).with_block(vec![eligible.clone(), v2_spend]));
 
-        // Test with Taproot version 1
-        let script_pubkey_v1 = Builder::new().push_opcode(OP_PUSHNUM_1).push_slice([1,2,3,4]).into_script();
-        assert_eq!(chain.is_segwit_gt_v1(&script_pubkey_v1), false);
+        let mut chain = Chain::new(source.clone());
+        let tweaks = chain.process_block(source.block(1)).await.unwrap();
+        assert_eq!(tweaks.len(), 1);
+        assert_eq!((tweaks[0].tx_index, tweaks[0].tx_id.clone()), (1, eligible.compute_txid().to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_missing_prevout_skips_transaction() {
+        let known = mock::funding(vec![mock::p2wpkh_script(1)]);
+        let unknown = mock::funding(vec![mock::p2wpkh_script(2), mock::p2wpkh_script(2)]);
+        let resolvable = mock::transaction(vec![mock::p2wpkh_input(OutPoint::new(known.compute_txid(), 0), 1)], vec![mock::taproot_output()]);
+        let unresolvable = mock::transaction(vec![mock::p2wpkh_input(OutPoint::new(unknown.compute_txid(), 0), 2)], vec![mock::taproot_output()]);
+        let source = Arc::new(MockChain::default().with_transaction(known).with_block(vec![unresolvable.clone(), resolvable.clone()]));
+
+        // The block is still processed, only the transaction whose prevout can't be found is left out
+        let mut chain = Chain::new(source.clone());
+        let tweaks = chain.process_block(source.block(1)).await.unwrap();
+        assert_eq!(tweaks.len(), 1);
+        assert_eq!(tweaks[0].tx_id, resolvable.compute_txid().to_string());
+
+        // and deferred, a retry once the backend knows the prevout recovers its tweak
+        let deferred = chain.take_deferred();
+        assert_eq!(deferred.len(), 1);
+        assert_eq!((deferred[0].tx_index, deferred[0].tx_id.clone()), (1, unresolvable.compute_txid().to_string()));
+        assert!(chain.take_deferred().is_empty());
+        let retry = Chain::new(Arc::new(MockChain::default().with_transaction(unknown)));
+        assert_eq!(retry.process_transaction(1, &unresolvable).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_prevouts_with_block_match_per_transaction_lookup() {
+        let funding = mock::funding(vec![mock::p2wpkh_script(1), mock::p2wpkh_script(2)]);
+        let txid = funding.compute_txid();
+        let spend = mock::transaction(
+            vec![mock::p2wpkh_input(OutPoint::new(txid, 0), 1), mock::p2wpkh_input(OutPoint::new(txid, 1), 2)],
+            vec![mock::taproot_output()],
+        );
+        let source = Arc::new(MockChain::default().with_transaction(funding).with_block(vec![spend]));
+        let block_hash = source.get_block_hash(1).await.unwrap();
+
+        let mut per_transaction = Chain::new(source.clone());
+        let looked_up = per_transaction.process_block(source.block(1)).await.unwrap();
+
+        let mut with_block = Chain::new(source.clone());
+        with_block.set_previous_scripts(source.get_prevouts(&block_hash).await.unwrap());
+        let prefetched = with_block.process_block(source.block(1)).await.unwrap();
+
+        assert_eq!(looked_up.len(), 1);
+        assert_eq!(looked_up.iter().map(|t| &t.tweak).collect::<Vec<_>>(), prefetched.iter().map(|t| &t.tweak).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_large_block_bounded_workers() {
+        let funding = mock::funding((0..300).map(|_| mock::p2wpkh_script(1)).collect());
+        let txid = funding.compute_txid();
+        let spends: Vec<Transaction> = (0..300)
+            .map(|vout| mock::transaction(vec![mock::p2wpkh_input(OutPoint::new(txid, vout), 1)], vec![mock::taproot_output()]))
+            .collect();
+        let source = Arc::new(MockChain::default().with_transaction(funding).with_block(spends));
+
+        let mut chain = Chain::new(source.clone());
+        chain.set_tx_workers(3);
+        let tweaks = chain.process_block(source.block(1)).await.unwrap();
+        // Every transaction after the coinbase, in block order
+        assert_eq!(tweaks.iter().map(|t| t.tx_index).collect::<Vec<_>>(), (1..=300).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_block_stats() {
+        let funding = mock::funding(vec![mock::p2wpkh_script(1), mock::p2wpkh_script(2)]);
+        let txid = funding.compute_txid();
+        let key_path = bitcoin::TxIn { previous_output: OutPoint::new(txid, 1), witness: bitcoin::Witness::from_slice(&[vec![1; 64]]), ..Default::default() };
+        let spends = vec![
+            mock::transaction(vec![mock::p2wpkh_input(OutPoint::new(txid, 0), 1), key_path], vec![mock::taproot_output(), mock::taproot_output()]),
+            // Without a Taproot output the transaction is no candidate and its inputs are not counted
+            mock::transaction(vec![mock::p2wpkh_input(OutPoint::new(txid, 1), 2)], vec![bitcoin::TxOut { value: bitcoin::Amount::from_sat(1000), script_pubkey: mock::p2wpkh_script(3) }]),
+        ];
+        let source = MockChain::default().with_block(spends);
+
+        // The coinbase pays to Taproot too
+        let expected = BlockStats { taproot_outputs: 3, candidate_txs: 1, p2tr_inputs: 1, p2wpkh_inputs: 1, ..Default::default() };
+        assert_eq!(block_stats(source.block(1)), expected);
+    }
+
+    #[tokio::test]
+    async fn test_ineligible_inputs_skip_prevout_fetch() {
+        // The funding transaction isn't on the chain, a lookup would fail the transaction rather than skip it
+        let multisig = mock::transaction(
+            vec![bitcoin::TxIn {
+                previous_output: OutPoint::new(mock::funding(vec![]).compute_txid(), 0),
+                witness: bitcoin::Witness::from_slice(&[vec![], vec![0x30; 71], vec![0x30; 71], vec![0x52; 105]]),
+                ..Default::default()
+            }],
+            vec![mock::taproot_output()],
+        );
+        let source = Arc::new(MockChain::default().with_block(vec![multisig]));
+        let chain = Chain::new(source.clone());
+        assert!(chain.missing_prevouts(source.block(1)).is_empty());
+        assert!(!prefers_block_prevouts(source.block(1), |_| false));
+    }
+
+    #[test]
+    fn test_prefers_block_prevouts() {
+        let funding = mock::funding(vec![mock::p2wpkh_script(1); 4]);
+        let txid = funding.compute_txid();
+        let spend = |vout, output| mock::transaction(vec![mock::p2wpkh_input(OutPoint::new(txid, vout), 1)], vec![output]);
+        let p2wpkh_output = || bitcoin::TxOut { value: bitcoin::Amount::from_sat(90_000), script_pubkey: mock::p2wpkh_script(2) };
+
+        // The coinbase pays to taproot but is not counted
+        let source = MockChain::default()
+            .with_block(vec![])
+            .with_block(vec![spend(0, mock::taproot_output()), spend(1, p2wpkh_output()), spend(2, p2wpkh_output()), spend(3, p2wpkh_output())])
+            .with_block(vec![spend(0, mock::taproot_output()), spend(1, p2wpkh_output()), spend(2, p2wpkh_output()), spend(3, p2wpkh_output()), spend(0, p2wpkh_output())]);
+        assert!(!prefers_block_prevouts(source.block(1), |_| false));
+        assert!(prefers_block_prevouts(source.block(2), |_| false));
+        assert!(!prefers_block_prevouts(source.block(3), |_| false));
+        // Spends of cached outputs resolve without the block's prevouts
+        assert!(!prefers_block_prevouts(source.block(2), |outpoint| outpoint.txid == txid));
+    }
+
+    #[test]
+    fn test_parse_blockchain_info() {
+        let info: BlockchainInfo = serde_json::from_str(r#"{
+            "chain": "main", "blocks": 850000, "headers": 870000, "bestblockhash": "00",
+            "difficulty": 1.0, "time": 0, "mediantime": 0, "verificationprogress": 0.9712,
+            "initialblockdownload": true, "chainwork": "00", "size_on_disk": 0, "pruned": false,
+            "warnings": []
+        }"#).unwrap();
+        assert_eq!(info.chain, "main");
+        assert_eq!((info.blocks, info.headers), (850000, 870000));
+        assert!(info.initial_block_download);
+        assert_eq!(info.verification_progress, 0.9712);
+        assert!(!info.pruned);
+        assert_eq!(info.prune_height, None);
+    }
+
+    #[test]
+    fn test_parse_txindex_info() {
+        let mut indexes: HashMap<String, TxIndexInfo> = serde_json::from_str(r#"{
+            "txindex": {"synced": false, "best_block_height": 412000}
+        }"#).unwrap();
+        let txindex = indexes.remove("txindex").unwrap();
+        assert!(!txindex.synced);
+        assert_eq!(txindex.best_block_height, 412000);
+
+        // A node without -txindex reports no indexes
+        let indexes: HashMap<String, TxIndexInfo> = serde_json::from_str("{}").unwrap();
+        assert!(indexes.is_empty());
+    }
+
+    #[test]
+    fn test_recommend_concurrency() {
+        // Throughput flattens out after 4 concurrent calls
+        let results = vec![(1, 100.0), (2, 190.0), (4, 350.0), (8, 370.0), (16, 360.0)];
+        assert_eq!(recommend_concurrency(&results), 4);
 
-        // Test with future version 2
-        let script_pubkey_v2 = Builder::new().push_opcode(OP_PUSHNUM_2).push_slice([1,2,3,4,5,6]).into_script();
-        assert_eq!(chain.is_segwit_gt_v1(&script_pubkey_v2), true);
+        // Single threaded node gains nothing from concurrency
+        let results = vec![(1, 100.0), (2, 95.0), (4, 98.0)];
+        assert_eq!(recommend_concurrency(&results), 1);
 
-        // Test with P2SH script
-        let p2sh_script = Builder::new().push_opcode(OP_HASH160).push_slice(&[0x8b, 0xc9, 0xba, 0xf0, 0xcc, 0x16, 0x73, 0xad, 0x8e, 0xdd, 0x14, 0xbe, 0x27, 0xff, 0x2f, 0x07, 0x2f, 0x92, 0xb1, 0x05]).push_opcode(OP_EQUAL).into_script();
-        assert_eq!(chain.is_segwit_gt_v1(&p2sh_script), false);
+        // No measurements falls back to the default
+        assert_eq!(recommend_concurrency(&[]), DEFAULT_MAX_INFLIGHT_RPC);
     }
 }
\ No newline at end of file
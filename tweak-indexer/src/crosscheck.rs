@@ -0,0 +1,92 @@
+use serde_json::Value;
+use crate::http;
+
+// Remote URL of a block's tweaks. {height} and {hash} in the template are replaced, a template without either is
+// the base URL of a tweak-service and gets /tweaks/<hash> appended. BlindBit oracles serve /tweaks/{height}.
+pub fn remote_url(template: &str, height: u32, block_hash: &str) -> String {
+    if template.contains("{height}") || template.contains("{hash}") {
+        return template.replace("{height}", &height.to_string()).replace("{hash}", block_hash);
+    }
+    format!("{}/tweaks/{}", template.trim_end_matches('/'), block_hash)
+}
+
+// Tweaks of a remote response: an array of hex tweaks (BlindBit), an array of objects with a tweak field
+// (tweak-service) or an envelope with such an array under tweaks
+pub fn parse_tweaks(body: &[u8]) -> Result<Vec<String>, String> {
+    let value: Value = serde_json::from_slice(body).map_err(|e| format!("Invalid JSON: {}", e))?;
+    let items = match &value {
+        Value::Array(items) => items,
+        Value::Object(envelope) => match envelope.get("tweaks") {
+            Some(Value::Array(items)) => items,
+            _ => return Err(String::from("Expected a tweaks array in the response object")),
+        },
+        _ => return Err(String::from("Expected a JSON array of tweaks")),
+    };
+    items
+        .iter()
+        .map(|item| {
+            let tweak = match item {
+                Value::String(tweak) => Some(tweak.as_str()),
+                Value::Object(fields) => fields.get("tweak").and_then(Value::as_str),
+                _ => None,
+            };
+            tweak.map(str::to_lowercase).ok_or_else(|| format!("Unexpected tweak entry {}", item))
+        })
+        .collect()
+}
+
+pub async fn fetch_remote_tweaks(url: String) -> Result<Vec<String>, String> {
+    let body = http::get(url).await.map_err(|e| e.to_string())?;
+    parse_tweaks(&body)
+}
+
+// Tweaks of a block found on one side only, the remote may not report txids so sets of tweaks are compared
+#[derive(Debug, Default, PartialEq)]
+pub struct TweakSetDiff {
+    pub local_only: Vec<String>,
+    pub remote_only: Vec<String>,
+}
+
+impl TweakSetDiff {
+    pub fn new(mut local: Vec<String>, mut remote: Vec<String>) -> Self {
+        local.sort();
+        remote.sort();
+        Self {
+            local_only: local.iter().filter(|tweak| remote.binary_search(tweak).is_err()).cloned().collect(),
+            remote_only: remote.iter().filter(|tweak| local.binary_search(tweak).is_err()).cloned().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.local_only.is_empty() && self.remote_only.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remote_url() {
+        assert_eq!(remote_url("https://oracle.example/tweaks/{height}", 800000, "aa"), "https://oracle.example/tweaks/800000");
+        assert_eq!(remote_url("http://host:8080/", 800000, "aa"), "http://host:8080/tweaks/aa");
+    }
+
+    #[test]
+    fn test_parse_tweaks() {
+        assert_eq!(parse_tweaks(br#"["02AA", "03bb"]"#).unwrap(), vec!["02aa", "03bb"]);
+        let objects = br#"[{"block_hash": "h", "tx_id": "01", "tweak": "02aa", "max_output_value": 1000}]"#;
+        assert_eq!(parse_tweaks(objects).unwrap(), vec!["02aa"]);
+        assert_eq!(parse_tweaks(br#"{"block_hash": "h", "count": 1, "tweaks": [{"tweak": "02aa"}]}"#).unwrap(), vec!["02aa"]);
+        assert!(parse_tweaks(br#""Block not found""#).is_err());
+        assert!(parse_tweaks(br#"[1]"#).is_err());
+    }
+
+    #[test]
+    fn test_tweak_set_diff() {
+        let set = |tweaks: &[&str]| tweaks.iter().map(|tweak| tweak.to_string()).collect::<Vec<_>>();
+        assert!(TweakSetDiff::new(set(&["02aa", "03bb"]), set(&["03bb", "02aa"])).is_empty());
+        let diff = TweakSetDiff::new(set(&["02aa", "03bb"]), set(&["03bb", "02cc"]));
+        assert_eq!(diff, TweakSetDiff { local_only: set(&["02aa"]), remote_only: set(&["02cc"]) });
+    }
+}
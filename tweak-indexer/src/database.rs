@@ -1,18 +1,120 @@
 
-use rusqlite::{params, Connection, Result};
+use std::collections::HashMap;
+use std::time::Duration;
+use rusqlite::{params, Connection, OptionalExtension, Result, Row, ToSql, Transaction, TransactionBehavior};
+use rusqlite::types::ValueRef;
+pub use crate::chain::TaprootOutput;
+
+// Lowest height this database holds complete tweak data from
+pub const META_COVERAGE_START: &str = "coverage_start";
+// Version of the node the indexer last ran against (getnetworkinfo version)
+pub const META_NODE_VERSION: &str = "node_version";
+// Highest spent height prune_spent_tweaks has removed tweaks up to
+pub const META_PRUNED_SPENT_BY: &str = "pruned_spent_by";
+// Rows converted per transaction by the binary storage migration
+const BINARY_MIGRATION_BATCH: u32 = 10000;
+// How long a write waits for a checkpoint or another writer before failing with SQLITE_BUSY
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+// Filter over the x-only keys of a block's new Taproot outputs, see chain::new_utxo_filter
+pub const FILTER_NEW_UTXOS: &str = "new-utxos";
+// Filter over the entries of a block's spent outpoint index, see chain::spent_filter
+pub const FILTER_SPENT: &str = "spent";
 
 #[derive(Debug)]
 pub struct Block {
     pub height: u32,
     pub hash: String,
     pub has_tweaks: bool,
+    /// Hash of the parent block, None for blocks indexed before it was recorded
+    pub prev_hash: Option<String>,
+    /// Header timestamp (unix seconds), None for blocks indexed before it was recorded
+    pub time: Option<u32>,
 }
 
 #[derive(Debug)]
 pub struct Tweak {
     pub block_hash: String,
+    /// Position of the transaction within its block, None for rows indexed before it was recorded
+    pub tx_index: Option<u32>,
     pub tx_id: String,
     pub tweak: String,
+    /// Sats of the transaction's largest Taproot output, None for rows indexed before it was recorded
+    pub max_output_value: Option<u64>,
+    /// Merkle branch of the transaction, see chain::MerkleTree, None unless indexed with --merkle-proofs, only loaded by get_tweaks and get_stale_tweaks
+    pub merkle_proof: Option<Vec<u8>>,
+    /// Taproot outputs of the transaction, only loaded by get_tweaks and get_stale_tweaks
+    pub outputs: Vec<TaprootOutput>,
+}
+
+// Outputs a block creates and spends, stored with it
+#[derive(Debug, Default)]
+pub struct BlockOutpoints {
+    /// Taproot outputs the block creates
+    pub created: Vec<TaprootUtxo>,
+    /// Spent outpoints as (txid, vout), stored Taproot outputs among them are marked spent
+    pub spent: Vec<(String, u32)>,
+    /// Spent outpoint index served to light clients, see chain::spent_outpoint_index
+    pub spent_index: Vec<u8>,
+    /// Compact filters of the block by filter type
+    pub filters: Vec<(&'static str, Vec<u8>)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaprootUtxo {
+    pub tx_id: String,
+    pub vout: u32,
+    pub script_pubkey: String,
+    /// Sats
+    pub value: u64,
+    /// Height of the block creating the output
+    pub height: u32,
+    /// Height of the block spending it, None while unspent
+    pub spent_height: Option<u32>,
+}
+
+#[derive(Debug)]
+pub struct BlockTiming {
+    pub height: u32,
+    pub fetch_ms: u64,
+    pub extract_ms: u64,
+    pub write_ms: u64,
+    pub tx_count: u32,
+    /// Transactions with a Taproot output and an input that may be eligible
+    pub eligible_tx_count: u32,
+}
+
+// Input types are judged by the shape of the spend, see chain::block_stats
+#[derive(Debug, PartialEq)]
+pub struct EligibilityStats {
+    pub height: u32,
+    pub taproot_outputs: u32,
+    /// Transactions with a Taproot output and an input that may be eligible
+    pub candidate_txs: u32,
+    /// Candidates that produced a tweak
+    pub tweaked_txs: u32,
+    pub p2tr_inputs: u32,
+    pub p2wpkh_inputs: u32,
+    pub p2sh_p2wpkh_inputs: u32,
+    pub p2pkh_inputs: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FailedBlock {
+    pub height: u32,
+    pub hash: String,
+    pub error: String,
+    pub attempts: u32,
+}
+
+// Transaction stored without its tweak because a prevout could not be resolved
+#[derive(Debug, PartialEq)]
+pub struct DeferredTx {
+    pub block_hash: String,
+    pub height: u32,
+    pub tx_index: u32,
+    pub tx_id: String,
+    pub error: String,
+    pub attempts: u32,
 }
 
 pub struct Database {
@@ -22,58 +124,429 @@ pub struct Database {
 impl Database {
     pub fn new(db_path: &str) -> Result<Self> {
         let conn = Connection::open(db_path)?;
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS blocks (
-                height INTEGER PRIMARY KEY,
-                hash TEXT NOT NULL,
-                has_tweaks BOOLEAN NOT NULL
-            )",
-            [],
+        // WAL lets tweak-service read while the indexer writes, and with it synchronous=NORMAL only syncs at
+        // checkpoints: a power loss can drop the last commits but never corrupts the file
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        migrate(&conn)?;
+        Ok(Self { conn })
+    }
+
+    pub fn insert_block_with_tweaks(&self, block: &Block, tweaks: &[Tweak]) -> Result<()> {
+        self.insert_block_with_outpoints(block, tweaks, &BlockOutpoints::default())
+    }
+
+    // Store a block, its tweaks, Taproot outputs and spent outpoint index in one transaction, a failure leaves none behind.
+    // Stored Taproot outputs the block spends are marked spent at its height, for cut-through and the UTXO set.
+    // Storing a block that is already recorded is a no-op so a resumed run can repeat its last blocks.
+    pub fn insert_block_with_outpoints(&self, block: &Block, tweaks: &[Tweak], outpoints: &BlockOutpoints) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        let stored: Option<String> = tx
+            .query_row("SELECT hash FROM blocks WHERE height = ?1", params![block.height], |row| row.get(0))
+            .optional()?;
+        if stored.as_deref() == Some(block.hash.as_str()) {
+            return Ok(());
+        }
+        {
+            // Tweaks left without their block row by an older interrupted write are replaced
+            tx.execute("DELETE FROM tweaks WHERE block_hash = ?1", params![block.hash])?;
+            tx.execute("DELETE FROM tweak_outputs WHERE block_hash = ?1", params![block.hash])?;
+            insert_tweaks(&tx, "INSERT", tweaks)?;
+            let mut insert_utxo = tx.prepare_cached(
+                "INSERT OR REPLACE INTO taproot_utxos (tx_id, vout, script_pubkey, value, height, spent_height) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for utxo in outpoints.created.iter() {
+                insert_utxo.execute(params![hex_bytes(&utxo.tx_id)?, utxo.vout, utxo.script_pubkey, utxo.value, utxo.height, utxo.spent_height])?;
+            }
+            // Outputs created earlier in the same block are already inserted and get marked too
+            let mark_spent = "UPDATE {} SET spent_height = ?1 WHERE tx_id = ?2 AND vout = ?3";
+            let mut mark_tweak_output = tx.prepare_cached(&mark_spent.replace("{}", "tweak_outputs"))?;
+            let mut mark_utxo = tx.prepare_cached(&mark_spent.replace("{}", "taproot_utxos"))?;
+            for (tx_id, vout) in outpoints.spent.iter() {
+                let tx_id = hex_bytes(tx_id)?;
+                mark_tweak_output.execute(params![block.height, tx_id, vout])?;
+                mark_utxo.execute(params![block.height, tx_id, vout])?;
+            }
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO spent_outpoints (block_hash, height, data) VALUES (?1, ?2, ?3)",
+            params![block.hash, block.height, outpoints.spent_index],
         )?;
-        
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS tweaks (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                block_hash TEXT NOT NULL,
-                tx_id TEXT NOT NULL,
-                tweak TEXT NOT NULL,
-                FOREIGN KEY(block_hash) REFERENCES blocks(hash)
-            )",
-            [],
+        {
+            let mut insert_filter = tx.prepare_cached("INSERT OR REPLACE INTO block_filters (block_hash, filter_type, height, data) VALUES (?1, ?2, ?3, ?4)")?;
+            for (filter_type, data) in outpoints.filters.iter() {
+                insert_filter.execute(params![block.hash, filter_type, block.height, data])?;
+            }
+        }
+        tx.execute(
+            "INSERT INTO blocks (height, hash, has_tweaks, prev_hash, time) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![block.height, block.hash, block.has_tweaks, block.prev_hash, block.time],
         )?;
+        tx.execute("DELETE FROM failed_blocks WHERE height = ?1", params![block.height])?;
+        tx.commit()
+    }
 
-        Ok(Self { conn })
+    // Remember a block that could not be processed, counting the attempts
+    pub fn record_failed_block(&self, height: u32, hash: &str, error: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO failed_blocks (height, hash, error, attempts, failed_at) VALUES (?1, ?2, ?3, 1, CAST(strftime('%s', 'now') AS INTEGER))
+             ON CONFLICT(height) DO UPDATE SET hash = excluded.hash, error = excluded.error, attempts = attempts + 1, failed_at = excluded.failed_at",
+            params![height, hash, error],
+        )?;
+        Ok(())
     }
 
-    pub fn insert_block(&self, block: &Block) -> Result<()> {
+    pub fn get_failed_blocks(&self) -> Result<Vec<FailedBlock>> {
+        let mut stmt = self.conn.prepare("SELECT height, hash, error, attempts FROM failed_blocks ORDER BY height")?;
+        let failed_iter = stmt.query_map([], |row| {
+            Ok(FailedBlock {
+                height: row.get(0)?,
+                hash: row.get(1)?,
+                error: row.get(2)?,
+                attempts: row.get(3)?,
+            })
+        })?;
+
+        Ok(failed_iter.filter_map(Result::ok).collect())
+    }
+
+    // Remember a transaction to retry, counting the attempts
+    pub fn record_deferred_tx(&self, deferred: &DeferredTx) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO deferred_txs (block_hash, height, tx_index, tx_id, error, attempts) VALUES (?1, ?2, ?3, ?4, ?5, 1)
+             ON CONFLICT(block_hash, tx_id) DO UPDATE SET error = excluded.error, attempts = attempts + 1",
+            params![deferred.block_hash, deferred.height, deferred.tx_index, hex_bytes(&deferred.tx_id)?, deferred.error],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_deferred_txs(&self) -> Result<Vec<DeferredTx>> {
+        let mut stmt = self.conn.prepare("SELECT block_hash, height, tx_index, tx_id, error, attempts FROM deferred_txs ORDER BY height, tx_index")?;
+        let deferred_iter = stmt.query_map([], |row| {
+            Ok(DeferredTx {
+                block_hash: row.get(0)?,
+                height: row.get(1)?,
+                tx_index: row.get(2)?,
+                tx_id: hex_column(row, 3)?,
+                error: row.get(4)?,
+                attempts: row.get(5)?,
+            })
+        })?;
+
+        Ok(deferred_iter.filter_map(Result::ok).collect())
+    }
+
+    // Add the tweaks of a deferred transaction to its block and take it off the retry list
+    pub fn resolve_deferred_tx(&self, block_hash: &str, tx_id: &str, tweaks: &[Tweak]) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        {
+            insert_tweaks(&tx, "INSERT OR IGNORE", tweaks)?;
+        }
+        if !tweaks.is_empty() {
+            tx.execute("UPDATE blocks SET has_tweaks = 1 WHERE hash = ?1", params![block_hash])?;
+        }
+        tx.execute("DELETE FROM deferred_txs WHERE block_hash = ?1 AND tx_id = ?2", params![block_hash, hex_bytes(tx_id)?])?;
+        tx.commit()
+    }
+
+    pub fn insert_block_timing(&self, timing: &BlockTiming) -> Result<()> {
         self.conn.execute(
-            "INSERT INTO blocks (height, hash, has_tweaks) VALUES (?1, ?2, ?3)",
-            params![block.height, block.hash, block.has_tweaks],
+            "INSERT OR REPLACE INTO block_timings (height, fetch_ms, extract_ms, write_ms, tx_count, eligible_tx_count) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![timing.height, timing.fetch_ms, timing.extract_ms, timing.write_ms, timing.tx_count, timing.eligible_tx_count],
         )?;
         Ok(())
     }
 
-    pub fn insert_tweak(&self, tweak: &Tweak) -> Result<()> {
+    pub fn insert_eligibility_stats(&self, stats: &EligibilityStats) -> Result<()> {
         self.conn.execute(
-            "INSERT INTO tweaks (block_hash, tx_id, tweak) VALUES (?1, ?2, ?3)",
-            params![tweak.block_hash, tweak.tx_id, tweak.tweak],
+            "INSERT OR REPLACE INTO eligibility_stats (height, taproot_outputs, candidate_txs, tweaked_txs, p2tr_inputs, p2wpkh_inputs, p2sh_p2wpkh_inputs, p2pkh_inputs)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                stats.height,
+                stats.taproot_outputs,
+                stats.candidate_txs,
+                stats.tweaked_txs,
+                stats.p2tr_inputs,
+                stats.p2wpkh_inputs,
+                stats.p2sh_p2wpkh_inputs,
+                stats.p2pkh_inputs
+            ],
         )?;
         Ok(())
     }
 
     pub fn get_block(&self, block_hash: &str) -> Result<Vec<Block>> {
-        let mut stmt = self.conn.prepare("SELECT height, hash, has_tweaks FROM blocks WHERE hash = ?1")?;
+        let mut stmt = self.conn.prepare("SELECT height, hash, has_tweaks, prev_hash, time FROM blocks WHERE hash = ?1")?;
         let blocks_iter = stmt.query_map(params![block_hash], |row| {
             Ok(Block {
                 height: row.get(0)?,
                 hash: row.get(1)?,
                 has_tweaks: row.get(2)?,
+                prev_hash: row.get(3)?,
+                time: row.get(4)?,
             })
         })?;
 
         Ok(blocks_iter.filter_map(Result::ok).collect())
     }
 
+    // Height of a stored or failed block, for backends that address blocks by height
+    pub fn get_block_height(&self, block_hash: &str) -> Result<Option<u32>> {
+        self.conn
+            .query_row(
+                "SELECT height FROM blocks WHERE hash = ?1 UNION ALL SELECT height FROM failed_blocks WHERE hash = ?1 LIMIT 1",
+                params![block_hash],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    pub fn get_tweaks(&self, block_hash: &str) -> Result<Vec<Tweak>> {
+        let mut stmt = self.conn.prepare("SELECT block_hash, tx_index, tx_id, tweak, max_output_value, merkle_proof FROM tweaks WHERE block_hash = ?1 ORDER BY tx_index, id")?;
+        let tweaks_iter = stmt.query_map(params![block_hash], |row| {
+            Ok(Tweak {
+                block_hash: row.get(0)?,
+                tx_index: row.get(1)?,
+                tx_id: hex_column(row, 2)?,
+                tweak: hex_column(row, 3)?,
+                max_output_value: row.get(4)?,
+                merkle_proof: row.get(5)?,
+                outputs: Vec::new(),
+            })
+        })?;
+        let mut tweaks: Vec<Tweak> = tweaks_iter.filter_map(Result::ok).collect();
+
+        self.attach_outputs("tweak_outputs", block_hash, &mut tweaks)?;
+        Ok(tweaks)
+    }
+
+    // Fill in the outputs of a block's tweaks from tweak_outputs or its archive
+    fn attach_outputs(&self, table: &str, block_hash: &str, tweaks: &mut [Tweak]) -> Result<()> {
+        let mut stmt = self.conn.prepare(&format!("SELECT tx_id, vout, pubkey, value FROM {} WHERE block_hash = ?1 ORDER BY tx_id, vout", table))?;
+        let outputs_iter = stmt.query_map(params![block_hash], |row| {
+            Ok((hex_column(row, 0)?, TaprootOutput { vout: row.get(1)?, pubkey: row.get(2)?, value: row.get(3)? }))
+        })?;
+        let mut outputs: HashMap<String, Vec<TaprootOutput>> = HashMap::new();
+        for (tx_id, output) in outputs_iter.filter_map(Result::ok) {
+            outputs.entry(tx_id).or_default().push(output);
+        }
+        for tweak in tweaks.iter_mut() {
+            tweak.outputs = outputs.remove(&tweak.tx_id).unwrap_or_default();
+        }
+        Ok(())
+    }
+
+    // Tweaks for all blocks between the heights (inclusive) paired with their block height
+    pub fn get_tweaks_in_range(&self, from_height: u32, to_height: u32) -> Result<Vec<(u32, Tweak)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT blocks.height, tweaks.block_hash, tweaks.tx_index, tweaks.tx_id, tweaks.tweak, tweaks.max_output_value FROM tweaks
+             JOIN blocks ON blocks.hash = tweaks.block_hash
+             WHERE blocks.height BETWEEN ?1 AND ?2
+             ORDER BY blocks.height, tweaks.tx_index, tweaks.id")?;
+        let tweaks_iter = stmt.query_map(params![from_height, to_height], |row| {
+            Ok((row.get(0)?, Tweak {
+                block_hash: row.get(1)?,
+                tx_index: row.get(2)?,
+                tx_id: hex_column(row, 3)?,
+                tweak: hex_column(row, 4)?,
+                max_output_value: row.get(5)?,
+                merkle_proof: None,
+                outputs: Vec::new(),
+            }))
+        })?;
+
+        Ok(tweaks_iter.filter_map(Result::ok).collect())
+    }
+
+    // Tweaks with an id above the cursor in id order, paired with their id and block height
+    pub fn get_tweaks_after_id(&self, since_id: i64) -> Result<Vec<(i64, u32, Tweak)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT tweaks.id, blocks.height, tweaks.block_hash, tweaks.tx_index, tweaks.tx_id, tweaks.tweak, tweaks.max_output_value FROM tweaks
+             JOIN blocks ON blocks.hash = tweaks.block_hash
+             WHERE tweaks.id > ?1
+             ORDER BY tweaks.id")?;
+        let tweaks_iter = stmt.query_map(params![since_id], |row| {
+            Ok((row.get(0)?, row.get(1)?, Tweak {
+                block_hash: row.get(2)?,
+                tx_index: row.get(3)?,
+                tx_id: hex_column(row, 4)?,
+                tweak: hex_column(row, 5)?,
+                max_output_value: row.get(6)?,
+                merkle_proof: None,
+                outputs: Vec::new(),
+            }))
+        })?;
+
+        Ok(tweaks_iter.filter_map(Result::ok).collect())
+    }
+
+    // Spent outpoint index of a stored block, None for blocks indexed before it was recorded
+    pub fn get_spent_outpoints(&self, block_hash: &str) -> Result<Option<Vec<u8>>> {
+        self.conn
+            .query_row("SELECT data FROM spent_outpoints WHERE block_hash = ?1", params![block_hash], |row| row.get(0))
+            .optional()
+    }
+
+    pub fn get_filter(&self, block_hash: &str, filter_type: &str) -> Result<Option<Vec<u8>>> {
+        self.conn
+            .query_row(
+                "SELECT data FROM block_filters WHERE block_hash = ?1 AND filter_type = ?2",
+                params![block_hash, filter_type],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    pub fn get_taproot_utxo(&self, tx_id: &str, vout: u32) -> Result<Option<TaprootUtxo>> {
+        self.conn
+            .query_row(
+                "SELECT tx_id, vout, script_pubkey, value, height, spent_height FROM taproot_utxos WHERE tx_id = ?1 AND vout = ?2",
+                params![hex_bytes(tx_id)?, vout],
+                |row| {
+                    Ok(TaprootUtxo {
+                        tx_id: hex_column(row, 0)?,
+                        vout: row.get(1)?,
+                        script_pubkey: row.get(2)?,
+                        value: row.get(3)?,
+                        height: row.get(4)?,
+                        spent_height: row.get(5)?,
+                    })
+                },
+            )
+            .optional()
+    }
+
+    // Stored block hashes between the heights (inclusive) in height order
+    pub fn get_block_hashes_in_range(&self, from_height: u32, to_height: u32) -> Result<Vec<(u32, String)>> {
+        let mut stmt = self.conn.prepare("SELECT height, hash FROM blocks WHERE height BETWEEN ?1 AND ?2 ORDER BY height")?;
+        let blocks_iter = stmt.query_map(params![from_height, to_height], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        Ok(blocks_iter.filter_map(Result::ok).collect())
+    }
+
+    // Lowest stored height whose recorded parent is not the block stored one height below, the stored chain is
+    // inconsistent from there. Blocks without a recorded parent or a stored parent height are not compared.
+    pub fn get_lowest_unlinked_height(&self) -> Result<Option<u32>> {
+        self.conn.query_row(
+            "SELECT min(child.height) FROM blocks child JOIN blocks parent ON parent.height = child.height - 1
+             WHERE child.prev_hash IS NOT NULL AND child.prev_hash != parent.hash",
+            [],
+            |row| row.get(0),
+        )
+    }
+
+    pub fn get_block_count_in_range(&self, from_height: u32, to_height: u32) -> Result<u32> {
+        self.conn.query_row(
+            "SELECT count(*) FROM blocks WHERE height BETWEEN ?1 AND ?2",
+            params![from_height, to_height],
+            |row| row.get(0),
+        )
+    }
+
+    // Remove the blocks in a height range with their tweaks and timings, returns the number of blocks removed
+    pub fn delete_blocks_in_range(&self, from_height: u32, to_height: u32) -> Result<usize> {
+        let tx = self.conn.unchecked_transaction()?;
+        let removed = delete_blocks(&tx, from_height, to_height)?;
+        tx.commit()?;
+        Ok(removed)
+    }
+
+    // Roll back the blocks of a height range a reorg orphaned, like delete_blocks_in_range but the blocks, their
+    // tweaks and the tweaks' outputs are moved to stale_blocks, stale_tweaks and stale_tweak_outputs. A block
+    // orphaned again replaces its earlier archived copy.
+    pub fn archive_blocks_in_range(&self, from_height: u32, to_height: u32) -> Result<usize> {
+        let tx = self.conn.unchecked_transaction()?;
+        for table in ["stale_tweaks", "stale_tweak_outputs"] {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE block_hash IN (SELECT hash FROM blocks WHERE height BETWEEN ?1 AND ?2)", table),
+                params![from_height, to_height],
+            )?;
+        }
+        tx.execute(
+            "INSERT INTO stale_tweaks (block_hash, tx_index, tx_id, tweak, max_output_value, merkle_proof, reorged_at)
+             SELECT block_hash, tx_index, tx_id, tweak, max_output_value, merkle_proof, CAST(strftime('%s', 'now') AS INTEGER) FROM tweaks
+             WHERE block_hash IN (SELECT hash FROM blocks WHERE height BETWEEN ?1 AND ?2) ORDER BY id",
+            params![from_height, to_height],
+        )?;
+        tx.execute(
+            "INSERT INTO stale_tweak_outputs (block_hash, tx_id, vout, pubkey, value)
+             SELECT block_hash, tx_id, vout, pubkey, value FROM tweak_outputs
+             WHERE block_hash IN (SELECT hash FROM blocks WHERE height BETWEEN ?1 AND ?2)",
+            params![from_height, to_height],
+        )?;
+        tx.execute(
+            "INSERT OR REPLACE INTO stale_blocks (hash, height, prev_hash, has_tweaks, time, reorged_at)
+             SELECT hash, height, prev_hash, has_tweaks, time, CAST(strftime('%s', 'now') AS INTEGER) FROM blocks WHERE height BETWEEN ?1 AND ?2",
+            params![from_height, to_height],
+        )?;
+        let removed = delete_blocks(&tx, from_height, to_height)?;
+        tx.commit()?;
+        Ok(removed)
+    }
+
+    // Cut-through pruning: delete the tweaks whose Taproot outputs were all spent at or below the height, with their
+    // outputs, returns the number of tweaks removed. Tweaks stored without outputs are kept.
+    pub fn prune_spent_tweaks(&self, spent_by_height: u32) -> Result<usize> {
+        let tx = self.conn.unchecked_transaction()?;
+        let removed = tx.execute(
+            "DELETE FROM tweaks WHERE EXISTS (SELECT 1 FROM tweak_outputs o WHERE o.block_hash = tweaks.block_hash AND o.tx_id = tweaks.tx_id)
+             AND NOT EXISTS (SELECT 1 FROM tweak_outputs o WHERE o.block_hash = tweaks.block_hash AND o.tx_id = tweaks.tx_id
+                 AND (o.spent_height IS NULL OR o.spent_height > ?1))",
+            params![spent_by_height],
+        )?;
+        tx.execute(
+            "DELETE FROM tweak_outputs WHERE spent_height <= ?1
+             AND NOT EXISTS (SELECT 1 FROM tweaks t WHERE t.block_hash = tweak_outputs.block_hash AND t.tx_id = tweak_outputs.tx_id)",
+            params![spent_by_height],
+        )?;
+        // Verification needs the height to tell pruned tweaks from missing ones
+        tx.execute(
+            "INSERT INTO meta (key, value) VALUES (?1, ?2)
+             ON CONFLICT(key) DO UPDATE SET value = MAX(CAST(value AS INTEGER), CAST(excluded.value AS INTEGER))",
+            params![META_PRUNED_SPENT_BY, spent_by_height.to_string()],
+        )?;
+        tx.commit()?;
+        Ok(removed)
+    }
+
+    // Blocks archived by archive_blocks_in_range with their rollback time
+    pub fn get_stale_blocks(&self) -> Result<Vec<(Block, i64)>> {
+        let mut stmt = self.conn.prepare("SELECT height, hash, has_tweaks, prev_hash, time, reorged_at FROM stale_blocks ORDER BY reorged_at, height")?;
+        let blocks_iter = stmt.query_map([], |row| {
+            Ok((Block { height: row.get(0)?, hash: row.get(1)?, has_tweaks: row.get(2)?, prev_hash: row.get(3)?, time: row.get(4)? }, row.get(5)?))
+        })?;
+
+        Ok(blocks_iter.filter_map(Result::ok).collect())
+    }
+
+    // Tweaks of an archived block in block order with their outputs
+    pub fn get_stale_tweaks(&self, block_hash: &str) -> Result<Vec<Tweak>> {
+        let mut stmt = self.conn.prepare("SELECT block_hash, tx_index, tx_id, tweak, max_output_value, merkle_proof FROM stale_tweaks WHERE block_hash = ?1 ORDER BY tx_index, rowid")?;
+        let tweaks_iter = stmt.query_map(params![block_hash], |row| {
+            Ok(Tweak {
+                block_hash: row.get(0)?,
+                tx_index: row.get(1)?,
+                tx_id: hex_column(row, 2)?,
+                tweak: hex_column(row, 3)?,
+                max_output_value: row.get(4)?,
+                merkle_proof: row.get(5)?,
+                outputs: Vec::new(),
+            })
+        })?;
+        let mut tweaks: Vec<Tweak> = tweaks_iter.filter_map(Result::ok).collect();
+
+        self.attach_outputs("stale_tweak_outputs", block_hash, &mut tweaks)?;
+        Ok(tweaks)
+    }
+
+    pub fn get_lowest_block(&self) -> Result<u32> {
+        let mut stmt = self.conn.prepare("SELECT min(height) FROM blocks")?;
+        let lowest_block: Option<u32> = stmt.query_row([], |row| row.get(0)).ok();
+
+        Ok(lowest_block.unwrap_or(0))
+    }
+
     pub fn get_highest_block(&self) -> Result<u32> {
         let mut stmt = self.conn.prepare("SELECT max(height) FROM blocks")?;
         let highest_block: Option<u32> = stmt.query_row([], |row| row.get(0)).ok();
@@ -81,7 +554,1035 @@ impl Database {
         Ok(highest_block.unwrap_or(0))
     }
 
+    pub fn set_meta(&self, key: &str, value: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+            params![key, value],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_meta(&self, key: &str) -> Result<Option<String>> {
+        let mut stmt = self.conn.prepare("SELECT value FROM meta WHERE key = ?1")?;
+        let mut rows = stmt.query(params![key])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(row.get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    // How long a write waits for another process holding the database lock before failing
+    pub fn set_busy_timeout(&self, timeout: Duration) -> Result<()> {
+        self.conn.busy_timeout(timeout)
+    }
+
+    // Claim up to `size` heights between from and to that no other worker holds, returns the claimed range or None
+    // when everything is claimed. The worker's own unfinished claims and claims not touched for `stale_after` are
+    // taken over first so ranges of crashed workers get finished.
+    pub fn claim_range(&self, worker: &str, from: u32, to: u32, size: u32, stale_after: Duration) -> Result<Option<(u32, u32)>> {
+        // Immediate so two workers can't read the same free range before either writes its claim
+        let tx = Transaction::new_unchecked(&self.conn, TransactionBehavior::Immediate)?;
+        let abandoned: Option<(u32, u32)> = tx
+            .query_row(
+                "SELECT first_height, last_height FROM work_claims
+                 WHERE completed = 0 AND first_height BETWEEN ?1 AND ?2
+                   AND (worker = ?3 OR claimed_at < CAST(strftime('%s', 'now') AS INTEGER) - ?4)
+                 ORDER BY first_height LIMIT 1",
+                params![from, to, worker, stale_after.as_secs()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        if let Some((first, last)) = abandoned {
+            tx.execute(
+                "UPDATE work_claims SET worker = ?1, claimed_at = CAST(strftime('%s', 'now') AS INTEGER) WHERE first_height = ?2",
+                params![worker, first],
+            )?;
+            tx.commit()?;
+            return Ok(Some((first, last)));
+        }
+
+        // Lowest height not covered by an existing claim, the new range stops short of the next claim
+        let claims: Vec<(u32, u32)> = {
+            let mut stmt = tx.prepare("SELECT first_height, last_height FROM work_claims WHERE last_height >= ?1 AND first_height <= ?2 ORDER BY first_height")?;
+            let rows = stmt.query_map(params![from, to], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<Result<_>>()?
+        };
+        let mut first = from as u64;
+        let mut last = to as u64;
+        for (claim_first, claim_last) in claims {
+            if (claim_first as u64) > first {
+                last = claim_first as u64 - 1;
+                break;
+            }
+            first = first.max(claim_last as u64 + 1);
+        }
+        if first > to as u64 {
+            return Ok(None);
+        }
+        let last = last.min(to as u64).min(first + size.max(1) as u64 - 1) as u32;
+        let first = first as u32;
+        tx.execute(
+            "INSERT INTO work_claims (first_height, last_height, worker, claimed_at) VALUES (?1, ?2, ?3, CAST(strftime('%s', 'now') AS INTEGER))",
+            params![first, last, worker],
+        )?;
+        tx.commit()?;
+        Ok(Some((first, last)))
+    }
+
+    // Keep a claim from going stale while its range is being indexed
+    pub fn touch_claim(&self, first_height: u32, worker: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE work_claims SET claimed_at = CAST(strftime('%s', 'now') AS INTEGER) WHERE first_height = ?1 AND worker = ?2",
+            params![first_height, worker],
+        )?;
+        Ok(())
+    }
+
+    pub fn complete_claim(&self, first_height: u32) -> Result<()> {
+        self.conn.execute("UPDATE work_claims SET completed = 1 WHERE first_height = ?1", params![first_height])?;
+        Ok(())
+    }
+
+    // Queue the heights between from and to (inclusive) that aren't indexed yet, returns how many were added
+    pub fn enqueue_heights(&self, from_height: u32, to_height: u32) -> Result<usize> {
+        self.conn.execute(
+            "WITH RECURSIVE heights(height) AS (SELECT ?1 UNION ALL SELECT height + 1 FROM heights WHERE height < ?2)
+             INSERT OR IGNORE INTO queue (height) SELECT height FROM heights WHERE height NOT IN (SELECT height FROM blocks)",
+            params![from_height, to_height],
+        )
+    }
+
+    // Lease the lowest run of consecutive queued heights, at most `max_heights` long, that no other worker holds.
+    // Leases that ran out are handed out again. Returns None when nothing is left to lease.
+    pub fn lease_heights(&self, worker: &str, max_heights: u32, lease: Duration) -> Result<Option<(u32, u32)>> {
+        let tx = Transaction::new_unchecked(&self.conn, TransactionBehavior::Immediate)?;
+        let heights: Vec<u32> = {
+            let mut stmt = tx.prepare(
+                "SELECT height FROM queue WHERE lease_until IS NULL OR lease_until < CAST(strftime('%s', 'now') AS INTEGER)
+                 ORDER BY height LIMIT ?1",
+            )?;
+            let rows = stmt.query_map(params![max_heights.max(1)], |row| row.get(0))?;
+            rows.collect::<Result<_>>()?
+        };
+        let Some(&first) = heights.first() else {
+            return Ok(None);
+        };
+        let last = heights.iter().zip(first..).take_while(|(height, expected)| **height == *expected).last().map_or(first, |(height, _)| *height);
+        tx.execute(
+            "UPDATE queue SET worker = ?1, lease_until = CAST(strftime('%s', 'now') AS INTEGER) + ?2 WHERE height BETWEEN ?3 AND ?4",
+            params![worker, lease.as_secs(), first, last],
+        )?;
+        tx.commit()?;
+        Ok(Some((first, last)))
+    }
+
+    // Extend the leases a worker holds while it is still working through them
+    pub fn renew_leases(&self, worker: &str, lease: Duration) -> Result<()> {
+        self.conn.execute(
+            "UPDATE queue SET lease_until = CAST(strftime('%s', 'now') AS INTEGER) + ?2 WHERE worker = ?1",
+            params![worker, lease.as_secs()],
+        )?;
+        Ok(())
+    }
+
+    pub fn dequeue_height(&self, height: u32) -> Result<()> {
+        self.conn.execute("DELETE FROM queue WHERE height = ?1", params![height])?;
+        Ok(())
+    }
+
     pub fn close(self) { 
         let _ = self.conn.close();
     }
 }
+
+// Rows of the blocks in a height range and everything stored with them, outputs they spent are unmarked
+fn delete_blocks(tx: &Transaction, from_height: u32, to_height: u32) -> Result<usize> {
+    tx.execute(
+        "DELETE FROM tweaks WHERE block_hash IN (SELECT hash FROM blocks WHERE height BETWEEN ?1 AND ?2)",
+        params![from_height, to_height],
+    )?;
+    tx.execute(
+        "DELETE FROM tweak_outputs WHERE block_hash IN (SELECT hash FROM blocks WHERE height BETWEEN ?1 AND ?2)",
+        params![from_height, to_height],
+    )?;
+    tx.execute("UPDATE tweak_outputs SET spent_height = NULL WHERE spent_height BETWEEN ?1 AND ?2", params![from_height, to_height])?;
+    tx.execute("DELETE FROM spent_outpoints WHERE height BETWEEN ?1 AND ?2", params![from_height, to_height])?;
+    tx.execute("DELETE FROM block_filters WHERE height BETWEEN ?1 AND ?2", params![from_height, to_height])?;
+    tx.execute("DELETE FROM taproot_utxos WHERE height BETWEEN ?1 AND ?2", params![from_height, to_height])?;
+    tx.execute("UPDATE taproot_utxos SET spent_height = NULL WHERE spent_height BETWEEN ?1 AND ?2", params![from_height, to_height])?;
+    tx.execute("DELETE FROM block_timings WHERE height BETWEEN ?1 AND ?2", params![from_height, to_height])?;
+    tx.execute("DELETE FROM eligibility_stats WHERE height BETWEEN ?1 AND ?2", params![from_height, to_height])?;
+    tx.execute("DELETE FROM deferred_txs WHERE height BETWEEN ?1 AND ?2", params![from_height, to_height])?;
+    tx.execute("DELETE FROM blocks WHERE height BETWEEN ?1 AND ?2", params![from_height, to_height])
+}
+
+// Tweak rows with their Taproot outputs, insert is the statement's verb so a retry can ignore rows already stored
+fn insert_tweaks(tx: &Transaction, insert: &str, tweaks: &[Tweak]) -> Result<()> {
+    let mut insert_tweak = tx.prepare_cached(&format!("{} INTO tweaks (block_hash, tx_index, tx_id, tweak, max_output_value, merkle_proof) VALUES (?1, ?2, ?3, ?4, ?5, ?6)", insert))?;
+    let mut insert_output = tx.prepare_cached(&format!("{} INTO tweak_outputs (block_hash, tx_id, vout, pubkey, value) VALUES (?1, ?2, ?3, ?4, ?5)", insert))?;
+    for tweak in tweaks {
+        insert_tweak.execute(params![tweak.block_hash, tweak.tx_index, hex_bytes(&tweak.tx_id)?, hex_bytes(&tweak.tweak)?, tweak.max_output_value, tweak.merkle_proof])?;
+        for output in tweak.outputs.iter() {
+            insert_output.execute(params![tweak.block_hash, hex_bytes(&tweak.tx_id)?, output.vout, output.pubkey, output.value])?;
+        }
+    }
+    Ok(())
+}
+
+fn hex_bytes(value: &str) -> Result<Vec<u8>> {
+    hex::decode(value).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+}
+
+// Hex of a BLOB column, rows the binary storage migration could not convert still hold hex TEXT
+fn hex_column(row: &Row, index: usize) -> Result<String> {
+    match row.get_ref(index)? {
+        ValueRef::Blob(bytes) => Ok(hex::encode(bytes)),
+        ValueRef::Text(text) => Ok(String::from_utf8_lossy(text).into_owned()),
+        value => Err(rusqlite::Error::InvalidColumnType(index, String::from("hex"), value.data_type())),
+    }
+}
+
+// Schema changes in the order they were made, numbered from 1. A database records how many it has applied in
+// PRAGMA user_version and Database::new applies the rest. Append new migrations, never edit or reorder applied ones.
+// A migration interrupted part way runs again on the next start, so each must be safe to repeat.
+const MIGRATIONS: &[fn(&Connection) -> Result<()>] = &[
+    initial_schema,
+    added_before_versioning,
+    remove_partial_writes,
+    migrate_tweaks_to_binary,
+    lookup_indexes,
+];
+
+// Databases from before versioning are at version 0 and run every migration, each checks what is already there
+fn migrate(conn: &Connection) -> Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_ERROR),
+            Some(format!("Database schema version {} is newer than this indexer supports ({})", version, MIGRATIONS.len())),
+        ));
+    }
+    for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        migration(conn)?;
+        conn.execute_batch(&format!("PRAGMA user_version = {}", applied + 1))?;
+    }
+    Ok(())
+}
+
+// 1: the original schema, blocks and their tweaks
+fn initial_schema(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS blocks (
+            height INTEGER PRIMARY KEY,
+            hash TEXT NOT NULL,
+            has_tweaks BOOLEAN NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS tweaks (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            block_hash TEXT NOT NULL,
+            tx_id TEXT NOT NULL,
+            tweak TEXT NOT NULL,
+            FOREIGN KEY(block_hash) REFERENCES blocks(hash)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+// 2: tables, columns and indexes added before versioning, older databases are upgraded in place
+fn added_before_versioning(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS meta (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS block_timings (
+            height INTEGER PRIMARY KEY,
+            fetch_ms INTEGER NOT NULL,
+            extract_ms INTEGER NOT NULL,
+            write_ms INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    // Candidates minus tweaked are the transactions skipped once their prevouts were known
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS eligibility_stats (
+            height INTEGER PRIMARY KEY,
+            taproot_outputs INTEGER NOT NULL,
+            candidate_txs INTEGER NOT NULL,
+            tweaked_txs INTEGER NOT NULL,
+            p2tr_inputs INTEGER NOT NULL,
+            p2wpkh_inputs INTEGER NOT NULL,
+            p2sh_p2wpkh_inputs INTEGER NOT NULL,
+            p2pkh_inputs INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    // Height ranges claimed by cooperating indexer processes, see claim_range
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS work_claims (
+            first_height INTEGER PRIMARY KEY,
+            last_height INTEGER NOT NULL,
+            worker TEXT NOT NULL,
+            claimed_at INTEGER NOT NULL,
+            completed BOOLEAN NOT NULL DEFAULT 0
+        )",
+        [],
+    )?;
+
+    // Blocks whose tweaks could not be computed, retried until they are stored
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS failed_blocks (
+            height INTEGER PRIMARY KEY,
+            hash TEXT NOT NULL,
+            error TEXT NOT NULL,
+            attempts INTEGER NOT NULL,
+            failed_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    // Transactions whose tweaks are missing from their stored block until a retry resolves their prevouts
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS deferred_txs (
+            block_hash TEXT NOT NULL,
+            height INTEGER NOT NULL,
+            tx_index INTEGER NOT NULL,
+            tx_id TEXT NOT NULL,
+            error TEXT NOT NULL,
+            attempts INTEGER NOT NULL,
+            PRIMARY KEY (block_hash, tx_id)
+        )",
+        [],
+    )?;
+
+    // x-only Taproot output keys of every transaction with a tweak
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS tweak_outputs (
+            block_hash TEXT NOT NULL,
+            tx_id TEXT NOT NULL,
+            vout INTEGER NOT NULL,
+            pubkey TEXT NOT NULL,
+            PRIMARY KEY (block_hash, tx_id, vout)
+        )",
+        [],
+    )?;
+
+    // Shortened hashes of every outpoint a block spends, concatenated, for light clients watching their UTXOs
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS spent_outpoints (
+            block_hash TEXT PRIMARY KEY,
+            height INTEGER NOT NULL,
+            data BLOB NOT NULL
+        )",
+        [],
+    )?;
+
+    // Compact filters light clients download instead of a block's tweaks and outputs
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS block_filters (
+            block_hash TEXT NOT NULL,
+            filter_type TEXT NOT NULL,
+            height INTEGER NOT NULL,
+            data BLOB NOT NULL,
+            PRIMARY KEY (block_hash, filter_type)
+        )",
+        [],
+    )?;
+
+    // Every Taproot output of the indexed blocks, spent ones keep the height that spent them
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS taproot_utxos (
+            tx_id TEXT NOT NULL,
+            vout INTEGER NOT NULL,
+            script_pubkey TEXT NOT NULL,
+            value INTEGER NOT NULL,
+            height INTEGER NOT NULL,
+            spent_height INTEGER,
+            PRIMARY KEY (tx_id, vout)
+        )",
+        [],
+    )?;
+    // Rolling back blocks finds their outputs and spends without a full scan
+    conn.execute("CREATE INDEX IF NOT EXISTS taproot_utxos_height ON taproot_utxos (height)", [])?;
+    conn.execute("CREATE INDEX IF NOT EXISTS taproot_utxos_spent_height ON taproot_utxos (spent_height) WHERE spent_height IS NOT NULL", [])?;
+
+    // Heights waiting for a worker, leased while one indexes them and removed once stored
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS queue (
+            height INTEGER PRIMARY KEY,
+            worker TEXT,
+            lease_until INTEGER
+        )",
+        [],
+    )?;
+
+    // Blocks a reorg rolled back and their tweaks, kept with the time of the rollback instead of being deleted
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS stale_blocks (
+            hash TEXT PRIMARY KEY,
+            height INTEGER NOT NULL,
+            prev_hash TEXT,
+            has_tweaks BOOLEAN NOT NULL,
+            reorged_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS stale_tweaks (
+            block_hash TEXT NOT NULL,
+            tx_index INTEGER,
+            tx_id BLOB NOT NULL,
+            tweak BLOB NOT NULL,
+            max_output_value INTEGER,
+            merkle_proof BLOB,
+            reorged_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS stale_tweak_outputs (
+            block_hash TEXT NOT NULL,
+            tx_id BLOB NOT NULL,
+            vout INTEGER NOT NULL,
+            pubkey TEXT NOT NULL,
+            value INTEGER,
+            PRIMARY KEY (block_hash, tx_id, vout)
+        )",
+        [],
+    )?;
+
+    add_column_if_missing(conn, "blocks", "prev_hash", "TEXT")?;
+    add_column_if_missing(conn, "blocks", "time", "INTEGER")?;
+    conn.execute("CREATE INDEX IF NOT EXISTS blocks_time ON blocks (time)", [])?;
+    add_column_if_missing(conn, "stale_blocks", "time", "INTEGER")?;
+    add_column_if_missing(conn, "tweaks", "tx_index", "INTEGER")?;
+    add_column_if_missing(conn, "tweaks", "max_output_value", "INTEGER")?;
+    add_column_if_missing(conn, "tweaks", "merkle_proof", "BLOB")?;
+    // Height of the block spending the output, NULL while unspent
+    add_column_if_missing(conn, "tweak_outputs", "spent_height", "INTEGER")?;
+    add_column_if_missing(conn, "tweak_outputs", "value", "INTEGER")?;
+    conn.execute("CREATE INDEX IF NOT EXISTS tweak_outputs_outpoint ON tweak_outputs (tx_id, vout)", [])?;
+    add_column_if_missing(conn, "block_timings", "tx_count", "INTEGER")?;
+    add_column_if_missing(conn, "block_timings", "eligible_tx_count", "INTEGER")?;
+    Ok(())
+}
+
+// 3: databases written before blocks were stored atomically can hold tweaks of a block that was never
+// recorded, or the same tweak twice after a restart. Both are cleaned up once before the unique index
+// that rejects duplicates from then on is created.
+fn remove_partial_writes(conn: &Connection) -> Result<()> {
+    let indexed: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'index' AND name = 'tweaks_block_tx')",
+        [],
+        |row| row.get(0),
+    )?;
+    if indexed {
+        return Ok(());
+    }
+    let tx = conn.unchecked_transaction()?;
+    tx.execute("DELETE FROM tweaks WHERE block_hash NOT IN (SELECT hash FROM blocks)", [])?;
+    tx.execute("DELETE FROM tweaks WHERE id NOT IN (SELECT MAX(id) FROM tweaks GROUP BY block_hash, tx_id)", [])?;
+    tx.execute("CREATE UNIQUE INDEX tweaks_block_tx ON tweaks (block_hash, tx_id)", [])?;
+    tx.commit()
+}
+
+// 4: convert the hex TEXT tweaks and txids of a database from before binary storage to BLOBs, the structs keep
+// carrying them as hex. Converted in batches so a full-chain database doesn't need one huge transaction. Rows that
+// aren't valid hex are left as they are. The tables are then rebuilt to declare the columns BLOB like a new database.
+// The freed pages are only returned to the file system by a VACUUM.
+fn migrate_tweaks_to_binary(conn: &Connection) -> Result<()> {
+    convert_hex_columns(conn, "tweaks", &["tx_id", "tweak"])?;
+    declare_blob_columns(conn, "tweaks", &["tx_id", "tweak"])?;
+    // Txids of the tables joined with tweaks are BLOBs too, so joins compare them as stored and can use an index
+    for table in ["tweak_outputs", "taproot_utxos", "deferred_txs"] {
+        convert_hex_columns(conn, table, &["tx_id"])?;
+        declare_blob_columns(conn, table, &["tx_id"])?;
+    }
+    Ok(())
+}
+
+// SQLite can't change the type of a column, the table is recreated from its own CREATE statement with the columns
+// declared BLOB and its rows and indexes copied over, in one transaction. Tables already declaring them are skipped.
+fn declare_blob_columns(conn: &Connection, table: &str, columns: &[&str]) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let declared_text = stmt
+        .query_map([], |row| Ok((row.get::<_, String>(1)?, row.get::<_, String>(2)?)))?
+        .filter_map(Result::ok)
+        .any(|(name, declared)| columns.contains(&name.as_str()) && declared.eq_ignore_ascii_case("TEXT"));
+    if !declared_text {
+        return Ok(());
+    }
+
+    let tx = conn.unchecked_transaction()?;
+    let mut create: String = tx.query_row("SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?1", params![table], |row| row.get(0))?;
+    for column in columns {
+        create = create.replace(&format!(" {} TEXT", column), &format!(" {} BLOB", column));
+    }
+    let indexes: Vec<String> = {
+        let mut stmt = tx.prepare("SELECT sql FROM sqlite_master WHERE type = 'index' AND tbl_name = ?1 AND sql IS NOT NULL")?;
+        let indexes = stmt.query_map(params![table], |row| row.get(0))?;
+        indexes.collect::<Result<_>>()?
+    };
+    tx.execute(&format!("ALTER TABLE {0} RENAME TO {0}_text", table), [])?;
+    tx.execute(&create, [])?;
+    tx.execute(&format!("INSERT INTO {0} SELECT * FROM {0}_text", table), [])?;
+    tx.execute(&format!("DROP TABLE {}_text", table), [])?;
+    for index in indexes {
+        tx.execute(&index, [])?;
+    }
+    tx.commit()
+}
+
+// Rows of the table with a hex TEXT value in any of the columns get all of them as BLOBs
+fn convert_hex_columns(conn: &Connection, table: &str, columns: &[&str]) -> Result<()> {
+    let is_text = columns.iter().map(|column| format!("typeof({}) = 'text'", column)).collect::<Vec<_>>().join(" OR ");
+    let select = format!("SELECT rowid, {} FROM {} WHERE rowid > ?1 AND ({}) ORDER BY rowid LIMIT ?2", columns.join(", "), table, is_text);
+    let assignments = columns.iter().enumerate().map(|(index, column)| format!("{} = ?{}", column, index + 2)).collect::<Vec<_>>().join(", ");
+    let update = format!("UPDATE {} SET {} WHERE rowid = ?1", table, assignments);
+
+    let mut last_rowid = 0i64;
+    loop {
+        let tx = conn.unchecked_transaction()?;
+        let rows: Vec<(i64, Vec<String>)> = {
+            let mut select = tx.prepare_cached(&select)?;
+            let rows = select.query_map(params![last_rowid, BINARY_MIGRATION_BATCH], |row| {
+                Ok((row.get(0)?, (1..=columns.len()).map(|index| hex_column(row, index)).collect::<Result<Vec<_>>>()?))
+            })?;
+            rows.collect::<Result<_>>()?
+        };
+        let Some((rowid, _)) = rows.last() else {
+            break;
+        };
+        last_rowid = *rowid;
+        {
+            let mut update = tx.prepare_cached(&update)?;
+            for (rowid, values) in rows.iter() {
+                let Ok(values) = values.iter().map(hex::decode).collect::<std::result::Result<Vec<_>, _>>() else {
+                    continue;
+                };
+                let mut params: Vec<&dyn ToSql> = vec![rowid as &dyn ToSql];
+                params.extend(values.iter().map(|value| value as &dyn ToSql));
+                update.execute(params.as_slice())?;
+            }
+        }
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+// 5: indexes for the per block lookups, blocks by hash (get_block, /tweaks/{hash}) and tweaks by block in block
+// order. The unique tweaks_block_tx index finds a block's tweaks too but leaves them to be sorted.
+fn lookup_indexes(conn: &Connection) -> Result<()> {
+    conn.execute("CREATE INDEX IF NOT EXISTS blocks_hash ON blocks (hash)", [])?;
+    conn.execute("CREATE INDEX IF NOT EXISTS tweaks_block_order ON tweaks (block_hash, tx_index)", [])?;
+    Ok(())
+}
+
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .filter_map(Result::ok)
+        .any(|name| name == column);
+
+    if !exists {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tweak(block_hash: &str, tx_index: u32) -> Tweak {
+        Tweak { block_hash: block_hash.to_string(), tx_index: Some(tx_index), tx_id: format!("{:02x}", tx_index), tweak: String::from("02aa"), max_output_value: Some(1000), merkle_proof: None, outputs: vec![TaprootOutput { vout: 0, pubkey: String::from("aa"), value: Some(1000) }] }
+    }
+
+    #[test]
+    fn test_insert_block_with_tweaks() {
+        let db = Database::new(":memory:").unwrap();
+        let block = Block { height: 10, hash: String::from("aa"), has_tweaks: true, prev_hash: None, time: Some(1_700_000_000) };
+        db.insert_block_with_tweaks(&block, &[tweak("aa", 1), tweak("aa", 2)]).unwrap();
+        assert_eq!(db.get_block("aa").unwrap()[0].time, Some(1_700_000_000));
+        assert_eq!(db.get_tweaks("aa").unwrap().len(), 2);
+        assert_eq!(db.get_tweaks("aa").unwrap()[0].max_output_value, Some(1000));
+        assert_eq!(db.get_tweaks("aa").unwrap()[1].outputs, vec![TaprootOutput { vout: 0, pubkey: String::from("aa"), value: Some(1000) }]);
+        assert_eq!(db.get_highest_block().unwrap(), 10);
+
+        // A second block at the same height fails on the blocks row and rolls its tweaks back with it
+        let duplicate = Block { height: 10, hash: String::from("bb"), has_tweaks: true, prev_hash: None, time: None };
+        assert!(db.insert_block_with_tweaks(&duplicate, &[tweak("bb", 1)]).is_err());
+        assert!(db.get_tweaks("bb").unwrap().is_empty());
+        assert!(db.get_block("bb").unwrap().is_empty());
+
+        // Storing the same block again leaves its tweaks as they were
+        db.insert_block_with_tweaks(&block, &[tweak("aa", 1), tweak("aa", 2)]).unwrap();
+        assert_eq!(db.get_tweaks("aa").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_spent_outputs() {
+        let db = Database::new(":memory:").unwrap();
+        let spent_height = |vout: u32| -> Option<u32> {
+            db.conn.query_row("SELECT spent_height FROM tweak_outputs WHERE tx_id = X'01' AND vout = ?1", params![vout], |row| row.get(0)).unwrap()
+        };
+        let mut created = tweak("aa", 1);
+        created.outputs.push(TaprootOutput { vout: 1, pubkey: String::from("bb"), value: None });
+        db.insert_block_with_tweaks(&Block { height: 10, hash: String::from("aa"), has_tweaks: true, prev_hash: None, time: None }, &[created]).unwrap();
+
+        // Each output is marked at the height of the block spending it, other outpoints are ignored
+        let spends = BlockOutpoints { spent: vec![(String::from("01"), 0), (String::from("09"), 0)], spent_index: vec![1; 16], ..Default::default() };
+        db.insert_block_with_outpoints(&Block { height: 11, hash: String::from("bb"), has_tweaks: false, prev_hash: None, time: None }, &[], &spends).unwrap();
+        let spends = BlockOutpoints { spent: vec![(String::from("01"), 1)], spent_index: vec![2; 8], filters: vec![(FILTER_NEW_UTXOS, vec![3])], ..Default::default() };
+        db.insert_block_with_outpoints(&Block { height: 12, hash: String::from("cc"), has_tweaks: false, prev_hash: None, time: None }, &[], &spends).unwrap();
+        assert_eq!((spent_height(0), spent_height(1)), (Some(11), Some(12)));
+        assert_eq!(db.get_spent_outpoints("bb").unwrap(), Some(vec![1; 16]));
+        assert_eq!(db.get_filter("cc", FILTER_NEW_UTXOS).unwrap(), Some(vec![3]));
+
+        // Rolling back the spending block makes the output unspent again and drops its index and filters
+        db.delete_blocks_in_range(12, 12).unwrap();
+        assert_eq!((spent_height(0), spent_height(1)), (Some(11), None));
+        assert_eq!(db.get_spent_outpoints("cc").unwrap(), None);
+        assert_eq!(db.get_filter("cc", FILTER_NEW_UTXOS).unwrap(), None);
+    }
+
+    #[test]
+    fn test_prune_spent_tweaks() {
+        let db = Database::new(":memory:").unwrap();
+        let mut partly_spent = tweak("aa", 3);
+        partly_spent.outputs.push(TaprootOutput { vout: 1, pubkey: String::from("bb"), value: None });
+        let unspent = Tweak { outputs: Vec::new(), ..tweak("aa", 4) };
+        db.insert_block_with_tweaks(&Block { height: 10, hash: String::from("aa"), has_tweaks: true, prev_hash: None, time: None }, &[tweak("aa", 1), tweak("aa", 2), partly_spent, unspent]).unwrap();
+        let spends = BlockOutpoints { spent: vec![(String::from("01"), 0), (String::from("03"), 0)], ..Default::default() };
+        db.insert_block_with_outpoints(&Block { height: 11, hash: String::from("bb"), has_tweaks: false, prev_hash: None, time: None }, &[], &spends).unwrap();
+        let spends = BlockOutpoints { spent: vec![(String::from("02"), 0)], ..Default::default() };
+        db.insert_block_with_outpoints(&Block { height: 15, hash: String::from("cc"), has_tweaks: false, prev_hash: None, time: None }, &[], &spends).unwrap();
+
+        // Only the tweak spent by the cutoff goes; one spent later, one with an unspent output and one without outputs stay
+        assert_eq!(db.prune_spent_tweaks(12).unwrap(), 1);
+        let remaining = |db: &Database| db.get_tweaks("aa").unwrap().into_iter().map(|tweak| tweak.tx_id).collect::<Vec<_>>();
+        assert_eq!(remaining(&db), vec!["02", "03", "04"]);
+        let outputs: u32 = db.conn.query_row("SELECT count(*) FROM tweak_outputs WHERE tx_id = X'01'", [], |row| row.get(0)).unwrap();
+        assert_eq!(outputs, 0);
+
+        assert_eq!(db.prune_spent_tweaks(15).unwrap(), 1);
+        assert_eq!(remaining(&db), vec!["03", "04"]);
+    }
+
+    #[test]
+    fn test_taproot_utxos() {
+        let db = Database::new(":memory:").unwrap();
+        let utxo = |tx_id: &str, vout: u32, height: u32| TaprootUtxo {
+            tx_id: tx_id.to_string(),
+            vout,
+            script_pubkey: String::from("5120aa"),
+            value: 1000,
+            height,
+            spent_height: None,
+        };
+        // An output spent within the block that created it is stored spent
+        let outpoints = BlockOutpoints { created: vec![utxo("a1", 0, 10), utxo("a2", 0, 10)], spent: vec![(String::from("a2"), 0)], ..Default::default() };
+        db.insert_block_with_outpoints(&Block { height: 10, hash: String::from("aa"), has_tweaks: false, prev_hash: None, time: None }, &[], &outpoints).unwrap();
+        assert_eq!(db.get_taproot_utxo("a1", 0).unwrap(), Some(utxo("a1", 0, 10)));
+        assert_eq!(db.get_taproot_utxo("a2", 0).unwrap().unwrap().spent_height, Some(10));
+
+        let outpoints = BlockOutpoints { created: vec![utxo("a3", 1, 11)], spent: vec![(String::from("a1"), 0)], ..Default::default() };
+        db.insert_block_with_outpoints(&Block { height: 11, hash: String::from("bb"), has_tweaks: false, prev_hash: None, time: None }, &[], &outpoints).unwrap();
+        assert_eq!(db.get_taproot_utxo("a1", 0).unwrap().unwrap().spent_height, Some(11));
+
+        // Rolling back a block removes its outputs and restores those it spent
+        db.delete_blocks_in_range(11, 11).unwrap();
+        assert_eq!(db.get_taproot_utxo("a1", 0).unwrap(), Some(utxo("a1", 0, 10)));
+        assert_eq!(db.get_taproot_utxo("a3", 1).unwrap(), None);
+    }
+
+    #[test]
+    fn test_block_timing_counts() {
+        let db = Database::new(":memory:").unwrap();
+        let timing = BlockTiming { height: 10, fetch_ms: 5, extract_ms: 7, write_ms: 1, tx_count: 3000, eligible_tx_count: 420 };
+        db.insert_block_timing(&timing).unwrap();
+        let counts: (u32, u32) = db.conn
+            .query_row("SELECT tx_count, eligible_tx_count FROM block_timings WHERE height = 10", [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        assert_eq!(counts, (3000, 420));
+    }
+
+    #[test]
+    fn test_eligibility_stats() {
+        let db = Database::new(":memory:").unwrap();
+        let stats = |height| EligibilityStats {
+            height,
+            taproot_outputs: 900,
+            candidate_txs: 120,
+            tweaked_txs: 100,
+            p2tr_inputs: 80,
+            p2wpkh_inputs: 150,
+            p2sh_p2wpkh_inputs: 10,
+            p2pkh_inputs: 5,
+        };
+        db.insert_eligibility_stats(&stats(10)).unwrap();
+        db.insert_eligibility_stats(&stats(11)).unwrap();
+        let skipped: u32 = db.conn
+            .query_row("SELECT candidate_txs - tweaked_txs FROM eligibility_stats WHERE height = 10", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(skipped, 20);
+
+        // Rolled back blocks lose their stats
+        db.delete_blocks_in_range(11, 11).unwrap();
+        let heights: u32 = db.conn.query_row("SELECT COUNT(*) FROM eligibility_stats", [], |row| row.get(0)).unwrap();
+        assert_eq!(heights, 1);
+    }
+
+    #[test]
+    fn test_failed_blocks() {
+        let db = Database::new(":memory:").unwrap();
+        db.record_failed_block(10, "aa", "prevout not found").unwrap();
+        db.record_failed_block(10, "aa", "timed out").unwrap();
+        db.record_failed_block(12, "cc", "prevout not found").unwrap();
+        let failed = db.get_failed_blocks().unwrap();
+        assert_eq!(failed.len(), 2);
+        assert_eq!(failed[0], FailedBlock { height: 10, hash: String::from("aa"), error: String::from("timed out"), attempts: 2 });
+
+        // Storing the block takes it off the list
+        db.insert_block_with_tweaks(&Block { height: 10, hash: String::from("aa"), has_tweaks: false, prev_hash: None, time: None }, &[]).unwrap();
+        assert_eq!(db.get_failed_blocks().unwrap().iter().map(|block| block.height).collect::<Vec<_>>(), vec![12]);
+
+        // Stored and failed blocks both resolve to their height
+        assert_eq!(db.get_block_height("aa").unwrap(), Some(10));
+        assert_eq!(db.get_block_height("cc").unwrap(), Some(12));
+        assert_eq!(db.get_block_height("dd").unwrap(), None);
+    }
+
+    #[test]
+    fn test_deferred_txs() {
+        let db = Database::new(":memory:").unwrap();
+        db.insert_block_with_tweaks(&Block { height: 10, hash: String::from("aa"), has_tweaks: false, prev_hash: None, time: None }, &[]).unwrap();
+        let deferred = DeferredTx { block_hash: String::from("aa"), height: 10, tx_index: 3, tx_id: String::from("03"), error: String::from("not found"), attempts: 1 };
+        db.record_deferred_tx(&deferred).unwrap();
+        db.record_deferred_tx(&deferred).unwrap();
+        assert_eq!(db.get_deferred_txs().unwrap(), vec![DeferredTx { attempts: 2, ..deferred }]);
+
+        // The recovered tweak joins its block, which now has tweaks
+        db.resolve_deferred_tx("aa", "03", &[tweak("aa", 3)]).unwrap();
+        assert!(db.get_deferred_txs().unwrap().is_empty());
+        assert_eq!(db.get_tweaks("aa").unwrap().len(), 1);
+        assert!(db.get_block("aa").unwrap()[0].has_tweaks);
+    }
+
+    #[test]
+    fn test_tweaks_in_block_order() {
+        let db = Database::new(":memory:").unwrap();
+        db.insert_block_with_tweaks(&Block { height: 10, hash: String::from("aa"), has_tweaks: true, prev_hash: None, time: None }, &[tweak("aa", 3)]).unwrap();
+
+        // A deferred transaction resolved later still comes back in block order, resolving it twice stores it once
+        db.resolve_deferred_tx("aa", "01", &[tweak("aa", 1)]).unwrap();
+        db.resolve_deferred_tx("aa", "01", &[tweak("aa", 1)]).unwrap();
+        let order = |tweaks: Vec<Tweak>| tweaks.into_iter().map(|tweak| tweak.tx_index).collect::<Vec<_>>();
+        assert_eq!(order(db.get_tweaks("aa").unwrap()), vec![Some(1), Some(3)]);
+        assert_eq!(order(db.get_tweaks_in_range(10, 10).unwrap().into_iter().map(|(_, tweak)| tweak).collect()), vec![Some(1), Some(3)]);
+    }
+
+    #[test]
+    fn test_lowest_unlinked_height() {
+        let db = Database::new(":memory:").unwrap();
+        let store = |height: u32, hash: &str, prev_hash: Option<&str>| {
+            let block = Block { height, hash: hash.to_string(), has_tweaks: false, prev_hash: prev_hash.map(String::from), time: None };
+            db.insert_block_with_tweaks(&block, &[]).unwrap();
+        };
+        // Heights 10-12 link, 11 has no recorded parent, 14 has no stored parent height
+        store(10, "aa", Some("00"));
+        store(11, "bb", None);
+        store(12, "cc", Some("bb"));
+        store(14, "ee", Some("dd"));
+        assert_eq!(db.get_lowest_unlinked_height().unwrap(), None);
+
+        // A block built on another parent breaks the chain
+        store(15, "ff", Some("zz"));
+        store(16, "gg", Some("ff"));
+        assert_eq!(db.get_lowest_unlinked_height().unwrap(), Some(15));
+    }
+
+    #[test]
+    fn test_archive_blocks_in_range() {
+        let db = Database::new(":memory:").unwrap();
+        for (height, hash) in [(10, "aa"), (11, "bb")] {
+            let block = Block { height, hash: hash.to_string(), has_tweaks: true, prev_hash: Some(String::from("00")), time: Some(1_700_000_000 + height) };
+            let proven = Tweak { merkle_proof: Some(vec![0xab; 33]), ..tweak(hash, 1) };
+            db.insert_block_with_tweaks(&block, &[tweak(hash, 2), proven]).unwrap();
+        }
+
+        // The orphaned block leaves the index with its tweaks and is kept in the archive
+        assert_eq!(db.archive_blocks_in_range(11, u32::MAX).unwrap(), 1);
+        assert!(db.get_block("bb").unwrap().is_empty());
+        assert!(db.get_tweaks("bb").unwrap().is_empty());
+        let stale = db.get_stale_blocks().unwrap();
+        assert_eq!(stale.iter().map(|(block, _)| (block.height, block.hash.as_str(), block.prev_hash.as_deref())).collect::<Vec<_>>(), vec![(11, "bb", Some("00"))]);
+        assert_eq!(stale[0].0.time, Some(1_700_000_011));
+        assert!(stale[0].1 > 0);
+        let order = db.get_stale_tweaks("bb").unwrap().into_iter().map(|tweak| (tweak.tx_index, tweak.tx_id)).collect::<Vec<_>>();
+        assert_eq!(order, vec![(Some(1), String::from("01")), (Some(2), String::from("02"))]);
+        // Archived as served, with the merkle proof and the outputs
+        let archived = db.get_stale_tweaks("bb").unwrap();
+        assert_eq!(archived[0].merkle_proof, Some(vec![0xab; 33]));
+        assert_eq!(archived[1].outputs, vec![TaprootOutput { vout: 0, pubkey: String::from("aa"), value: Some(1000) }]);
+
+        // Orphaned again after being reindexed, the archive keeps one copy
+        db.insert_block_with_tweaks(&Block { height: 11, hash: String::from("bb"), has_tweaks: true, prev_hash: None, time: None }, &[tweak("bb", 1)]).unwrap();
+        db.archive_blocks_in_range(11, 11).unwrap();
+        assert_eq!(db.get_stale_blocks().unwrap().len(), 1);
+        assert_eq!(db.get_stale_tweaks("bb").unwrap().len(), 1);
+        let outputs: u32 = db.conn.query_row("SELECT count(*) FROM stale_tweak_outputs", [], |row| row.get(0)).unwrap();
+        assert_eq!(outputs, 1);
+        assert_eq!(db.get_tweaks("aa").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_delete_blocks_in_range() {
+        let db = Database::new(":memory:").unwrap();
+        for (height, hash) in [(10, "aa"), (11, "bb"), (12, "cc")] {
+            let block = Block { height, hash: hash.to_string(), has_tweaks: true, prev_hash: None, time: None };
+            db.insert_block_with_tweaks(&block, &[tweak(hash, 1)]).unwrap();
+        }
+
+        assert_eq!(db.delete_blocks_in_range(11, 20).unwrap(), 2);
+        assert_eq!(db.get_highest_block().unwrap(), 10);
+        assert_eq!(db.get_tweaks("aa").unwrap().len(), 1);
+        assert!(db.get_tweaks("bb").unwrap().is_empty());
+        assert!(db.get_tweaks("cc").unwrap().is_empty());
+        let outputs: u32 = db.conn.query_row("SELECT count(*) FROM tweak_outputs", [], |row| row.get(0)).unwrap();
+        assert_eq!(outputs, 1);
+    }
+
+    #[test]
+    fn test_claim_range() {
+        let db = Database::new(":memory:").unwrap();
+        let stale_after = Duration::from_secs(600);
+
+        // Workers get disjoint ranges until the heights run out, the last range is cut at the end height
+        assert_eq!(db.claim_range("a", 100, 349, 100, stale_after).unwrap(), Some((100, 199)));
+        assert_eq!(db.claim_range("b", 100, 349, 100, stale_after).unwrap(), Some((200, 299)));
+        db.complete_claim(100).unwrap();
+        assert_eq!(db.claim_range("a", 100, 349, 100, stale_after).unwrap(), Some((300, 349)));
+        db.complete_claim(300).unwrap();
+        assert_eq!(db.claim_range("c", 100, 349, 100, stale_after).unwrap(), None);
+
+        // A restarted worker resumes its own claim, one that went stale goes to whoever asks next
+        assert_eq!(db.claim_range("b", 100, 349, 100, stale_after).unwrap(), Some((200, 299)));
+        db.conn.execute("UPDATE work_claims SET claimed_at = claimed_at - 3600 WHERE first_height = 200", []).unwrap();
+        assert_eq!(db.claim_range("c", 100, 349, 100, stale_after).unwrap(), Some((200, 299)));
+        db.touch_claim(200, "b").unwrap();
+        assert_eq!(db.claim_range("a", 100, 349, 100, stale_after).unwrap(), None);
+
+        // Gaps below existing claims are filled up to the next claim
+        assert_eq!(db.claim_range("a", 0, 349, 150, stale_after).unwrap(), Some((0, 99)));
+    }
+
+    #[test]
+    fn test_queue_leases() {
+        let db = Database::new(":memory:").unwrap();
+        let lease = Duration::from_secs(600);
+        db.insert_block_with_tweaks(&Block { height: 12, hash: String::from("aa"), has_tweaks: false, prev_hash: None, time: None }, &[]).unwrap();
+
+        // Indexed heights are skipped and queueing twice adds nothing
+        assert_eq!(db.enqueue_heights(10, 15).unwrap(), 5);
+        assert_eq!(db.enqueue_heights(10, 15).unwrap(), 0);
+
+        // Leases stop at the gap left by height 12 and never overlap
+        assert_eq!(db.lease_heights("a", 100, lease).unwrap(), Some((10, 11)));
+        assert_eq!(db.lease_heights("b", 2, lease).unwrap(), Some((13, 14)));
+        assert_eq!(db.lease_heights("c", 100, lease).unwrap(), Some((15, 15)));
+        assert_eq!(db.lease_heights("c", 100, lease).unwrap(), None);
+
+        // Stored heights leave the queue, expired leases are handed out again
+        db.dequeue_height(10).unwrap();
+        db.conn.execute("UPDATE queue SET lease_until = lease_until - 3600 WHERE worker = 'b'", []).unwrap();
+        db.renew_leases("a", lease).unwrap();
+        assert_eq!(db.lease_heights("c", 100, lease).unwrap(), Some((13, 14)));
+        assert_eq!(db.lease_heights("c", 100, lease).unwrap(), None);
+    }
+
+    #[test]
+    fn test_lookup_indexes_migration() {
+        let path = std::env::temp_dir().join(format!("tweak-indexer-indexes-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+        let index_exists = |db: &Database, name: &str| -> bool {
+            db.conn.query_row("SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'index' AND name = ?1)", params![name], |row| row.get(0)).unwrap()
+        };
+
+        // A database migrated up to version 4, before the lookup indexes
+        let db = Database::new(path).unwrap();
+        db.conn.execute_batch("DROP INDEX blocks_hash; DROP INDEX tweaks_block_order; PRAGMA user_version = 4").unwrap();
+        db.close();
+
+        let db = Database::new(path).unwrap();
+        assert!(index_exists(&db, "blocks_hash"));
+        assert!(index_exists(&db, "tweaks_block_order"));
+        let version: usize = db.conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, 5);
+        db.close();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_lookup_indexes() {
+        let db = Database::new(":memory:").unwrap();
+        let plan = |sql: &str| -> String {
+            let mut stmt = db.conn.prepare(&format!("EXPLAIN QUERY PLAN {}", sql)).unwrap();
+            let details = stmt.query_map([], |row| row.get::<_, String>(3)).unwrap();
+            details.filter_map(Result::ok).collect::<Vec<_>>().join("; ")
+        };
+        assert!(plan("SELECT height FROM blocks WHERE hash = 'aa'").contains("USING INDEX blocks_hash"));
+        let tweaks = plan("SELECT tweak FROM tweaks WHERE block_hash = 'aa' ORDER BY tx_index, id");
+        assert!(tweaks.contains("USING INDEX tweaks_block_order"), "{}", tweaks);
+        assert!(!tweaks.contains("TEMP B-TREE"), "{}", tweaks);
+    }
+
+    #[test]
+    fn test_wal_mode() {
+        let path = std::env::temp_dir().join(format!("tweak-indexer-wal-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+
+        // A reader on its own connection sees committed blocks while the indexer holds a write transaction
+        let db = Database::new(path).unwrap();
+        let journal_mode: String = db.conn.query_row("PRAGMA journal_mode", [], |row| row.get(0)).unwrap();
+        assert_eq!(journal_mode, "wal");
+        db.insert_block_with_tweaks(&Block { height: 1, hash: String::from("aa"), has_tweaks: false, prev_hash: None, time: None }, &[]).unwrap();
+        let write = db.conn.unchecked_transaction().unwrap();
+        write.execute("INSERT INTO blocks (height, hash, has_tweaks) VALUES (2, 'bb', 0)", []).unwrap();
+        let reader = Connection::open(path).unwrap();
+        let blocks: u32 = reader.query_row("SELECT COUNT(*) FROM blocks", [], |row| row.get(0)).unwrap();
+        assert_eq!(blocks, 1);
+        write.commit().unwrap();
+        drop(reader);
+        db.close();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_schema_version() {
+        let path = std::env::temp_dir().join(format!("tweak-indexer-version-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+
+        let db = Database::new(path).unwrap();
+        let version: usize = db.conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+
+        // A database migrated by a newer indexer is refused rather than written with an older schema
+        db.conn.execute_batch(&format!("PRAGMA user_version = {}", MIGRATIONS.len() + 1)).unwrap();
+        db.close();
+        assert!(Database::new(path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_upgrade_from_original_schema() {
+        let path = std::env::temp_dir().join(format!("tweak-indexer-original-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+        let schema = |conn: &Connection| -> Vec<(String, String, String)> {
+            let mut stmt = conn
+                .prepare("SELECT m.name, coalesce(p.name, ''), coalesce(p.type, '') FROM sqlite_master m LEFT JOIN pragma_table_info(m.name) p WHERE m.name NOT LIKE 'sqlite_%' ORDER BY 1, 2")
+                .unwrap();
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap();
+            rows.map(Result::unwrap).collect()
+        };
+
+        // A database of the original release, before any migration was recorded
+        let conn = Connection::open(path).unwrap();
+        initial_schema(&conn).unwrap();
+        conn.execute("INSERT INTO blocks (height, hash, has_tweaks) VALUES (1, 'aa', 1)", []).unwrap();
+        conn.execute("INSERT INTO tweaks (block_hash, tx_id, tweak) VALUES ('aa', '01ab', '02cd')", []).unwrap();
+        drop(conn);
+
+        // Upgrading it ends at the schema of a new database with its tweaks kept
+        let upgraded = Database::new(path).unwrap();
+        let fresh = Database::new(":memory:").unwrap();
+        assert_eq!(schema(&upgraded.conn), schema(&fresh.conn));
+        assert_eq!(upgraded.get_tweaks("aa").unwrap()[0].tweak, "02cd");
+        // Both declare the binary columns BLOB
+        for conn in [&upgraded.conn, &fresh.conn] {
+            let mut stmt = conn
+                .prepare("SELECT m.name, p.type FROM sqlite_master m JOIN pragma_table_info(m.name) p WHERE m.type = 'table' AND p.name IN ('tx_id', 'tweak')")
+                .unwrap();
+            let declared: Vec<(String, String)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap().map(Result::unwrap).collect();
+            assert!(declared.iter().all(|(_, declared)| declared == "BLOB"), "{:?}", declared);
+        }
+        upgraded.close();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_remove_partial_writes() {
+        let path = std::env::temp_dir().join(format!("tweak-indexer-db-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+
+        // Schema from before the unique index: a duplicated tweak and tweaks of a block that was never stored
+        let db = Database::new(path).unwrap();
+        db.conn.execute("DROP INDEX tweaks_block_tx", []).unwrap();
+        db.conn.execute("INSERT INTO blocks (height, hash, has_tweaks) VALUES (1, 'aa', 1)", []).unwrap();
+        for (block_hash, tx_id) in [("aa", "tx1"), ("aa", "tx1"), ("aa", "tx2"), ("bb", "tx1")] {
+            db.conn.execute("INSERT INTO tweaks (block_hash, tx_id, tweak) VALUES (?1, ?2, '02aa')", params![block_hash, tx_id]).unwrap();
+        }
+        db.conn.execute_batch("PRAGMA user_version = 0").unwrap();
+        db.close();
+
+        let db = Database::new(path).unwrap();
+        assert_eq!(db.get_tweaks("aa").unwrap().len(), 2);
+        assert!(db.get_tweaks("bb").unwrap().is_empty());
+        assert!(db.conn.execute("INSERT INTO tweaks (block_hash, tx_id, tweak) VALUES ('aa', 'tx1', '02aa')", []).is_err());
+        db.close();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_tweaks_to_binary() {
+        let path = std::env::temp_dir().join(format!("tweak-indexer-binary-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+
+        // Tweaks stored as hex text before binary storage
+        let db = Database::new(path).unwrap();
+        db.conn.execute("INSERT INTO blocks (height, hash, has_tweaks) VALUES (1, 'aa', 1)", []).unwrap();
+        db.conn.execute("INSERT INTO tweaks (block_hash, tx_index, tx_id, tweak) VALUES ('aa', 1, '01ab', '02cd')", []).unwrap();
+        db.conn.execute("INSERT INTO tweak_outputs (block_hash, tx_id, vout, pubkey) VALUES ('aa', '01ab', 0, 'ee')", []).unwrap();
+        db.conn.execute("INSERT INTO taproot_utxos (tx_id, vout, script_pubkey, value, height) VALUES ('01ab', 0, '5120ee', 1000, 1)", []).unwrap();
+        db.conn.execute("INSERT INTO deferred_txs (block_hash, height, tx_index, tx_id, error, attempts) VALUES ('aa', 1, 2, '03ef', 'not found', 1)", []).unwrap();
+        db.conn.execute_batch("PRAGMA user_version = 0").unwrap();
+        db.close();
+
+        // Reopening converts them, reads return the same hex
+        let db = Database::new(path).unwrap();
+        let types: (String, String) = db.conn.query_row("SELECT typeof(tx_id), typeof(tweak) FROM tweaks", [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        assert_eq!(types, (String::from("blob"), String::from("blob")));
+        let tweaks = db.get_tweaks("aa").unwrap();
+        assert_eq!((tweaks[0].tx_id.as_str(), tweaks[0].tweak.as_str()), ("01ab", "02cd"));
+        for table in ["tweak_outputs", "taproot_utxos", "deferred_txs"] {
+            let tx_id_type: String = db.conn.query_row(&format!("SELECT typeof(tx_id) FROM {}", table), [], |row| row.get(0)).unwrap();
+            assert_eq!(tx_id_type, "blob", "{}", table);
+            let declared: String = db.conn.query_row(&format!("SELECT type FROM pragma_table_info('{}') WHERE name = 'tx_id'", table), [], |row| row.get(0)).unwrap();
+            assert_eq!(declared, "BLOB", "{}", table);
+        }
+        // The outputs still join their tweak
+        assert_eq!(tweaks[0].outputs.len(), 1);
+        assert_eq!(db.get_taproot_utxo("01ab", 0).unwrap().unwrap().height, 1);
+        assert_eq!(db.get_deferred_txs().unwrap()[0].tx_id, "03ef");
+        db.close();
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+use bitcoin::block::{Block, Header};
+use bitcoin::consensus::encode::{deserialize, serialize_hex};
+use async_trait::async_trait;
+use bitcoin::{Transaction, Txid};
+use electrum_client::{Batch, Client, ConfigBuilder, ElectrumApi, Param};
+use tokio::task;
+use crate::chain::{ChainSource, PreviousScript};
+use crate::database::Database;
+use crate::rpc;
+
+// Positions requested per id_from_pos batch while listing a block's transactions
+const TXID_BATCH_SIZE: usize = 100;
+// Headers requested per blockchain.block.headers call while searching for a block's height, the protocol maximum
+const HEADER_BATCH_SIZE: usize = 2016;
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+fn client(url: &str) -> Result<&'static Client, String> {
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
+
+    let config = ConfigBuilder::new()
+        .retry(rpc::retry_policy().max_retries.min(u8::MAX as u32) as u8)
+        // The client counts the timeout in whole seconds up to 255
+        .timeout(Some(rpc::request_timeout().as_secs().clamp(1, u8::MAX as u64) as u8))
+        .build();
+    let client = Client::from_config(url, config).map_err(|e| format!("Failed to connect to Electrum server {}: {}", url, e))?;
+    Ok(CLIENT.get_or_init(|| client))
+}
+
+// Run a blocking Electrum request off the runtime threads
+async fn call<T, F>(url: &str, f: F) -> Result<T, String>
+where
+    F: FnOnce(&Client) -> Result<T, String> + Send + 'static,
+    T: Send + 'static,
+{
+    let url = url.to_string();
+    task::spawn_blocking(move || f(client(&url)?))
+        .await
+        .map_err(|e| format!("Electrum task failed: {}", e))?
+}
+
+pub async fn get_tip_height(url: &str) -> Result<u32, String> {
+    call(url, |client| {
+        let tip = client.block_headers_subscribe_raw().map_err(|e| e.to_string())?;
+        Ok(tip.height as u32)
+    })
+    .await
+}
+
+// Heights past the tip are reported like the RPC error so callers detect reaching the tip the same way
+pub async fn get_block_hash(url: &str, height: u32) -> Result<String, String> {
+    let header = call(url, move |client| {
+        let tip = client.block_headers_subscribe_raw().map_err(|e| e.to_string())?;
+        if height as usize > tip.height {
+            return Err(String::from("Block height out of range"));
+        }
+        client.block_header(height as usize).map_err(|e| e.to_string())
+    })
+    .await?;
+    Ok(header.block_hash().to_string())
+}
+
+// Height of a block by its hash, searching the headers back from the tip since Electrum has no lookup by hash
+pub async fn find_block_height(url: &str, block_hash: &str) -> Result<u32, String> {
+    let block_hash = block_hash.to_string();
+    call(url, move |client| {
+        let tip = client.block_headers_subscribe_raw().map_err(|e| e.to_string())?;
+        let mut end = tip.height + 1;
+        while end > 0 {
+            let start = end.saturating_sub(HEADER_BATCH_SIZE);
+            let headers = client.block_headers(start, end - start).map_err(|e| e.to_string())?.headers;
+            if let Some(pos) = headers.iter().position(|header| header.block_hash().to_string() == block_hash) {
+                return Ok((start + pos) as u32);
+            }
+            end = start;
+        }
+        Err(format!("Block {} not found in the Electrum server's chain", block_hash))
+    })
+    .await
+}
+
+// Assemble the block from its header and transactions, the merkle root check guards against a partial listing
+pub async fn get_block(url: &str, height: u32, block_hash: &str) -> Result<String, String> {
+    let block = call(url, move |client| {
+        let header: Header = client.block_header(height as usize).map_err(|e| e.to_string())?;
+        let txids = block_txids(client, height)?;
+        let txdata = client
+            .batch_transaction_get_raw(&txids)
+            .map_err(|e| e.to_string())?
+            .iter()
+            .map(|raw| deserialize::<Transaction>(raw).map_err(|e| e.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Block { header, txdata })
+    })
+    .await?;
+
+    if block.block_hash().to_string() != block_hash || !block.check_merkle_root() {
+        return Err(format!("Electrum server returned an inconsistent block for {}", block_hash));
+    }
+    Ok(serialize_hex(&block))
+}
+
+// Txids of the block in order, the listing ends at the first position the server rejects
+fn block_txids(client: &Client, height: u32) -> Result<Vec<Txid>, String> {
+    let mut txids = Vec::new();
+    loop {
+        let start = txids.len();
+        let mut batch = Batch::default();
+        for pos in start..start + TXID_BATCH_SIZE {
+            batch.raw(String::from("blockchain.transaction.id_from_pos"), id_from_pos_params(height, pos));
+        }
+
+        match client.batch_call(&batch) {
+            Ok(values) => {
+                for value in values {
+                    txids.push(parse_txid(&value)?);
+                }
+            }
+            // The batch ran past the last transaction, find the end one position at a time
+            Err(_) => {
+                for pos in start..start + TXID_BATCH_SIZE {
+                    match client.raw_call("blockchain.transaction.id_from_pos", id_from_pos_params(height, pos)) {
+                        Ok(value) => txids.push(parse_txid(&value)?),
+                        Err(_) => return Ok(txids),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn id_from_pos_params(height: u32, pos: usize) -> Vec<Param> {
+    vec![Param::U32(height), Param::Usize(pos)]
+}
+
+fn parse_txid(value: &serde_json::Value) -> Result<Txid, String> {
+    let txid = value.as_str().ok_or_else(|| format!("Unexpected id_from_pos response: {}", value))?;
+    Txid::from_str(txid).map_err(|e| format!("Invalid txid {}: {}", txid, e))
+}
+
+pub async fn get_transaction(url: &str, txid: &str) -> Result<String, String> {
+    let txid = Txid::from_str(txid).map_err(|e| format!("Invalid txid {}: {}", txid, e))?;
+    call(url, move |client| client.transaction_get_raw(&txid).map(hex::encode).map_err(|e| e.to_string())).await
+}
+
+// One batched request, falling back to individual requests so one unknown txid doesn't fail the rest
+pub async fn get_transactions(url: &str, txids: Vec<String>) -> Result<Vec<Option<String>>, String> {
+    let txids = txids
+        .iter()
+        .map(|txid| Txid::from_str(txid).map_err(|e| format!("Invalid txid {}: {}", txid, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    call(url, move |client| match client.batch_transaction_get_raw(&txids) {
+        Ok(raw_transactions) => Ok(raw_transactions.iter().map(|raw| Some(hex::encode(raw))).collect()),
+        Err(_) => Ok(txids
+            .iter()
+            .map(|txid| client.transaction_get_raw(txid).map(hex::encode).ok())
+            .collect()),
+    })
+    .await
+}
+
+pub async fn broadcast(url: &str, tx_hex: &str) -> Result<String, String> {
+    let raw_tx = hex::decode(tx_hex).map_err(|e| format!("Invalid transaction hex: {}", e))?;
+    let txid = call(url, move |client| client.transaction_broadcast_raw(&raw_tx).map_err(|e| e.to_string())).await?;
+    Ok(txid.to_string())
+}
+
+// Headers and transactions from an Electrum server, which has no block prevout listing
+#[derive(Debug, Clone)]
+pub struct ElectrumSource {
+    url: String,
+    db_path: String,
+    /// Height of the hashes handed out by get_block_hash until their block is fetched, Electrum addresses blocks by height
+    heights: Arc<Mutex<HashMap<String, u32>>>,
+}
+
+impl ElectrumSource {
+    pub fn new(url: &str, db_path: &str) -> Self {
+        Self { url: url.to_string(), db_path: db_path.to_string(), heights: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    // Hashes from before a restart, such as failed blocks being retried, are looked up in the database and
+    // otherwise in the server's headers
+    async fn block_height(&self, block_hash: &str) -> Result<u32, String> {
+        if let Some(height) = self.heights.lock().unwrap().get(block_hash) {
+            return Ok(*height);
+        }
+
+        let (db_path, hash) = (self.db_path.clone(), block_hash.to_string());
+        let stored = task::spawn_blocking(move || Database::new(&db_path).and_then(|db| db.get_block_height(&hash)))
+            .await
+            .map_err(|e| format!("Electrum task failed: {}", e))?
+            .map_err(|e| format!("Failed to look up the height of block {}: {}", block_hash, e))?;
+        let height = match stored {
+            Some(height) => height,
+            None => find_block_height(&self.url, block_hash).await?,
+        };
+        self.heights.lock().unwrap().insert(block_hash.to_string(), height);
+        Ok(height)
+    }
+}
+
+#[async_trait]
+impl ChainSource for ElectrumSource {
+    async fn get_block_count(&self) -> Result<u32, String> {
+        get_tip_height(&self.url).await
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<String, String> {
+        let block_hash = get_block_hash(&self.url, height).await?;
+        self.heights.lock().unwrap().insert(block_hash.clone(), height);
+        Ok(block_hash)
+    }
+
+    async fn get_block(&self, block_hash: &str) -> Result<String, String> {
+        let height = self.block_height(block_hash).await?;
+        let block = get_block(&self.url, height, block_hash).await?;
+        // Kept until the block arrives so a failed fetch is retried without another lookup
+        self.heights.lock().unwrap().remove(block_hash);
+        Ok(block)
+    }
+
+    async fn get_prevouts(&self, _block_hash: &str) -> Result<Vec<PreviousScript>, String> {
+        Err(String::from("Electrum servers don't provide block prevouts"))
+    }
+
+    async fn get_transaction(&self, txid: &str) -> Result<String, String> {
+        get_transaction(&self.url, txid).await
+    }
+
+    async fn get_transactions(&self, txids: Vec<String>) -> Result<Vec<Option<String>>, String> {
+        get_transactions(&self.url, txids).await
+    }
+
+    async fn broadcast(&self, tx_hex: &str) -> Result<String, String> {
+        broadcast(&self.url, tx_hex).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_txid() {
+        let txid = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+        assert_eq!(parse_txid(&serde_json::json!(txid)).unwrap().to_string(), txid);
+        assert!(parse_txid(&serde_json::json!({"merkle": []})).is_err());
+        assert!(parse_txid(&serde_json::json!("zz")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_block_height_from_database() {
+        let db_path = std::env::temp_dir().join(format!("tweak-indexer-electrum-{}.db", std::process::id()));
+        let db_path = db_path.to_str().unwrap();
+        let _ = std::fs::remove_file(db_path);
+        let db = Database::new(db_path).unwrap();
+        db.record_failed_block(7, "aa", "timed out").unwrap();
+        db.close();
+
+        // A fresh source knows no heights, the failed block's height comes from the database without the server
+        let source = ElectrumSource::new("tcp://127.0.0.1:1", db_path);
+        assert_eq!(source.block_height("aa").await, Ok(7));
+        assert_eq!(source.heights.lock().unwrap().get("aa"), Some(&7));
+        std::fs::remove_file(db_path).unwrap();
+    }
+}
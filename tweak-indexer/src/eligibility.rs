@@ -0,0 +1,228 @@
+use bitcoin::{ScriptBuf, TxIn, WitnessVersion};
+use silentpayments::secp256k1::PublicKey;
+use silentpayments::utils::receiving;
+use tracing::warn;
+use crate::chain::ChainError;
+
+// BIP352 input eligibility in one place: which inputs may provide a key before their prevouts are known, which prevouts
+// make a transaction ineligible and how the key of an eligible input is read.
+
+// First byte of a taproot annex, which is dropped before reading the witness
+pub const TAPROOT_ANNEX_PREFIX: u8 = 0x50;
+
+// BIP352 input types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputType {
+    P2tr,
+    P2wpkh,
+    P2shP2wpkh,
+    P2pkh,
+}
+
+// Which BIP352 input type the input's scriptSig and witness could spend, judged without its prevout.
+// Shapes that can't be told apart are kept.
+pub fn input_type_by_shape(input: &TxIn) -> Option<InputType> {
+    let script_sig = input.script_sig.as_bytes();
+    let witness: Vec<&[u8]> = input.witness.iter().collect();
+    if script_sig.is_empty() {
+        // Native segwit, an annex is only allowed in taproot spends
+        let witness = match witness.split_last() {
+            Some((last, rest)) if !rest.is_empty() && last.first() == Some(&TAPROOT_ANNEX_PREFIX) => rest,
+            _ => &witness[..],
+        };
+        return match witness {
+            // P2TR key path
+            [signature] if signature.len() == 64 || signature.len() == 65 => Some(InputType::P2tr),
+            // P2WPKH
+            [_, pubkey] if pubkey.len() == 33 => Some(InputType::P2wpkh),
+            // P2TR script path, the control block is 33 bytes plus 32 per merkle branch whatever the leaf version
+            [_, .., control_block] if control_block.len() >= 33 && (control_block.len() - 33) % 32 == 0 => Some(InputType::P2tr),
+            _ => None,
+        };
+    }
+    if witness.is_empty() {
+        // P2PKH needs room for a compressed key
+        return (script_sig.len() >= 33).then_some(InputType::P2pkh);
+    }
+    // P2SH-P2WPKH: a single push of the witness v0 key hash program
+    (script_sig.len() == 23 && script_sig[..3] == [0x16, 0x00, 0x14] && witness.len() == 2 && witness[1].len() == 33).then_some(InputType::P2shP2wpkh)
+}
+
+// Whether the input could spend one of the BIP352 input types (P2TR, P2WPKH, P2SH-P2WPKH, P2PKH)
+pub fn may_be_eligible_input(input: &TxIn) -> bool {
+    input_type_by_shape(input).is_some()
+}
+
+//Determine if this spend script is using segwit version 2 or higher
+pub fn is_segwit_gt_v1(script_pubkey: &ScriptBuf) -> bool {
+    if let Some(version) = script_pubkey.witness_version() {
+        match version {
+            WitnessVersion::V0 | WitnessVersion::V1 => false, // v0 and v1 accepted
+            _ => true, // reject all other versions v2, ...
+        }
+    } else {
+        false // Not segwit pass
+    }
+}
+
+// Key an input contributes to the tweak. Ok(None) for inputs that are skipped (P2WSH, bare scripts, uncompressed keys,
+// script path spends with the NUMS internal key), an error for inputs that make the whole transaction ineligible.
+pub fn input_pubkey(input: &TxIn, previous_script: &ScriptBuf) -> Result<Option<PublicKey>, ChainError> {
+    // Filter transactions by BIP352 consensus on allowed transactions
+    if is_segwit_gt_v1(previous_script) {
+        warn!("Segwit > v1: {}", previous_script.to_hex_string());
+        return Err(ChainError::SegWitVersionGE2);
+    }
+    receiving::get_pubkey_from_input(&input.script_sig.to_bytes(), &input.witness.to_vec(), &previous_script.to_bytes())
+        .map_err(|_| ChainError::PubKeyFromInput)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::opcodes::all::*;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::script::PushBytesBuf;
+    use bitcoin::{OutPoint, Witness};
+    use crate::mock;
+
+    // BIP341 NUMS point H, an internal key nobody knows the secret of
+    const NUMS_H: &str = "50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0";
+
+    fn input(script_sig: ScriptBuf, witness: Vec<Vec<u8>>) -> TxIn {
+        let outpoint = OutPoint::new("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b".parse().unwrap(), 0);
+        TxIn { previous_output: outpoint, script_sig, witness: Witness::from_slice(&witness), ..Default::default() }
+    }
+
+    fn push(bytes: Vec<u8>) -> ScriptBuf {
+        Builder::new().push_slice(PushBytesBuf::try_from(bytes).unwrap()).into_script()
+    }
+
+    fn key(secret: u8) -> Vec<u8> {
+        mock::pubkey(secret).to_bytes().to_vec()
+    }
+
+    // Output key of a P2TR prevout, with the even y BIP352 reads it with
+    fn taproot(secret: u8) -> (ScriptBuf, [u8; 33]) {
+        let x_only = mock::pubkey(secret).0.x_only_public_key().0.serialize();
+        let script_pubkey = ScriptBuf::from_bytes([vec![0x51, 0x20], x_only.to_vec()].concat());
+        let mut even = [0x02; 33];
+        even[1..].copy_from_slice(&x_only);
+        (script_pubkey, even)
+    }
+
+    fn p2sh_p2wpkh(secret: u8) -> (TxIn, ScriptBuf) {
+        let redeem_script = mock::p2wpkh_script(secret);
+        let input = input(push(redeem_script.to_bytes()), vec![vec![0x30; 71], key(secret)]);
+        (input, ScriptBuf::new_p2sh(&redeem_script.script_hash()))
+    }
+
+    fn pubkey(input: &TxIn, previous_script: &ScriptBuf) -> Option<[u8; 33]> {
+        input_pubkey(input, previous_script).unwrap().map(|pubkey| pubkey.serialize())
+    }
+
+    #[test]
+    fn test_input_type_by_shape() {
+        let p2pkh = input(Builder::new().push_slice([0x30; 71]).push_slice(<[u8; 33]>::try_from(key(1)).unwrap()).into_script(), vec![]);
+        assert_eq!(input_type_by_shape(&p2pkh), Some(InputType::P2pkh));
+        assert_eq!(input_type_by_shape(&p2sh_p2wpkh(1).0), Some(InputType::P2shP2wpkh));
+        assert_eq!(input_type_by_shape(&input(ScriptBuf::new(), vec![vec![0x30; 71], key(1)])), Some(InputType::P2wpkh));
+        assert_eq!(input_type_by_shape(&input(ScriptBuf::new(), vec![vec![1; 64]])), Some(InputType::P2tr));
+        assert_eq!(input_type_by_shape(&input(ScriptBuf::new(), vec![vec![1; 65]])), Some(InputType::P2tr));
+        // The annex is dropped before the key path signature is found
+        assert_eq!(input_type_by_shape(&input(ScriptBuf::new(), vec![vec![1; 64], vec![TAPROOT_ANNEX_PREFIX, 1]])), Some(InputType::P2tr));
+        let control_block = [vec![0xc1], vec![2; 32], vec![3; 32]].concat();
+        assert_eq!(input_type_by_shape(&input(ScriptBuf::new(), vec![vec![1; 64], vec![0x51], control_block])), Some(InputType::P2tr));
+        // Leaf versions other than tapscript are script path spends too
+        let future_leaf = [vec![0xc2], vec![2; 32]].concat();
+        assert_eq!(input_type_by_shape(&input(ScriptBuf::new(), vec![vec![1; 64], vec![0x51], future_leaf])), Some(InputType::P2tr));
+    }
+
+    #[test]
+    fn test_ineligible_shapes() {
+        // P2WSH multisig
+        assert_eq!(input_type_by_shape(&input(ScriptBuf::new(), vec![vec![], vec![0x30; 71], vec![0x30; 71], vec![0x52; 71]])), None);
+        // Neither a signature nor a control block
+        assert_eq!(input_type_by_shape(&input(ScriptBuf::new(), vec![vec![1; 32]])), None);
+        assert_eq!(input_type_by_shape(&input(ScriptBuf::new(), vec![vec![1; 64], vec![0x51], [vec![0xc0], vec![2; 40]].concat()])), None);
+        assert_eq!(input_type_by_shape(&input(ScriptBuf::new(), vec![])), None);
+        // Too short for a P2PKH key, or a P2SH redeem script other than P2WPKH
+        assert_eq!(input_type_by_shape(&input(Builder::new().push_opcode(OP_PUSHBYTES_0).push_slice([0x30; 20]).into_script(), vec![])), None);
+        let p2sh_p2wsh = push([vec![0x00, 0x20], vec![0xab; 32]].concat());
+        assert_eq!(input_type_by_shape(&input(p2sh_p2wsh, vec![vec![0x30; 71], key(1)])), None);
+        assert!(!may_be_eligible_input(&input(ScriptBuf::new(), vec![vec![1; 32]])));
+    }
+
+    #[test]
+    fn test_is_segwit_gt_v1() {
+        // Test empty script
+        assert!(!is_segwit_gt_v1(&Builder::new().into_script()));
+
+        // Test with SegWit version 0
+        let script_pubkey_v0 = Builder::new().push_opcode(OP_PUSHBYTES_0).into_script();
+        assert!(!is_segwit_gt_v1(&script_pubkey_v0));
+
+        // Test with 0x0101
+        let script_pubkey_v1 = Builder::new().push_opcode(OP_PUSHBYTES_1).push_slice([0]).into_script();
+        assert!(!is_segwit_gt_v1(&script_pubkey_v1));
+
+        // Test with Taproot version 1
+        let script_pubkey_v1 = Builder::new().push_opcode(OP_PUSHNUM_1).push_slice([1,2,3,4]).into_script();
+        assert!(!is_segwit_gt_v1(&script_pubkey_v1));
+
+        // Test with future version 2
+        let script_pubkey_v2 = Builder::new().push_opcode(OP_PUSHNUM_2).push_slice([1,2,3,4,5,6]).into_script();
+        assert!(is_segwit_gt_v1(&script_pubkey_v2));
+
+        // Test with P2SH script
+        let p2sh_script = Builder::new().push_opcode(OP_HASH160).push_slice([0x8b, 0xc9, 0xba, 0xf0, 0xcc, 0x16, 0x73, 0xad, 0x8e, 0xdd, 0x14, 0xbe, 0x27, 0xff, 0x2f, 0x07, 0x2f, 0x92, 0xb1, 0x05]).push_opcode(OP_EQUAL).into_script();
+        assert!(!is_segwit_gt_v1(&p2sh_script));
+    }
+
+    #[test]
+    fn test_input_pubkey() {
+        let p2pkh = input(Builder::new().push_slice([0x30; 71]).push_slice(<[u8; 33]>::try_from(key(1)).unwrap()).into_script(), vec![]);
+        let p2pkh_script = ScriptBuf::new_p2pkh(&mock::pubkey(1).pubkey_hash());
+        assert_eq!(pubkey(&p2pkh, &p2pkh_script).map(Vec::from), Some(key(1)));
+
+        let (p2sh_input, p2sh_script) = p2sh_p2wpkh(2);
+        assert_eq!(pubkey(&p2sh_input, &p2sh_script).map(Vec::from), Some(key(2)));
+
+        assert_eq!(pubkey(&mock::p2wpkh_input(p2pkh.previous_output, 3), &mock::p2wpkh_script(3)).map(Vec::from), Some(key(3)));
+    }
+
+    #[test]
+    fn test_taproot_input_pubkey() {
+        let (script_pubkey, output_key) = taproot(4);
+        assert_eq!(pubkey(&input(ScriptBuf::new(), vec![vec![1; 64]]), &script_pubkey), Some(output_key));
+        assert_eq!(pubkey(&input(ScriptBuf::new(), vec![vec![1; 64], vec![TAPROOT_ANNEX_PREFIX, 1]]), &script_pubkey), Some(output_key));
+
+        // A script path spend gives the output key unless the internal key is the NUMS point
+        let internal_key = taproot(5).1[1..].to_vec();
+        let script_path = input(ScriptBuf::new(), vec![vec![1; 64], vec![0x51], [vec![0xc0], internal_key].concat()]);
+        assert_eq!(pubkey(&script_path, &script_pubkey), Some(output_key));
+        let future_leaf = input(ScriptBuf::new(), vec![vec![1; 64], vec![0x51], [vec![0xc2], taproot(5).1[1..].to_vec()].concat()]);
+        assert_eq!(input_type_by_shape(&future_leaf), Some(InputType::P2tr));
+        assert_eq!(pubkey(&future_leaf, &script_pubkey), Some(output_key));
+        let nums: Vec<u8> = bitcoin::hex::FromHex::from_hex(NUMS_H).unwrap();
+        let nums_path = input(ScriptBuf::new(), vec![vec![1; 64], vec![0x51], [vec![0xc0], nums].concat()]);
+        assert_eq!(pubkey(&nums_path, &script_pubkey), None);
+    }
+
+    #[test]
+    fn test_ineligible_input_pubkey() {
+        // P2WSH and bare multisig inputs are skipped, the rest of the transaction still counts
+        let p2wsh_script = ScriptBuf::new_p2wsh(&Builder::new().push_opcode(OP_PUSHNUM_1).into_script().wscript_hash());
+        assert_eq!(pubkey(&input(ScriptBuf::new(), vec![vec![], vec![0x51]]), &p2wsh_script), None);
+        let multisig = Builder::new().push_opcode(OP_PUSHNUM_1).push_slice(<[u8; 33]>::try_from(key(1)).unwrap()).push_opcode(OP_PUSHNUM_1).push_opcode(OP_CHECKMULTISIG).into_script();
+        assert_eq!(pubkey(&input(push(vec![0x30; 71]), vec![]), &multisig), None);
+
+        // An uncompressed key in a P2PKH spend is not used
+        let uncompressed = bitcoin::PublicKey::new_uncompressed(mock::pubkey(6).0);
+        let p2pkh = input(Builder::new().push_slice([0x30; 71]).push_key(&uncompressed).into_script(), vec![]);
+        assert_eq!(pubkey(&p2pkh, &ScriptBuf::new_p2pkh(&uncompressed.pubkey_hash())), None);
+
+        // Spending a segwit v2 output makes the whole transaction ineligible
+        assert!(matches!(input_pubkey(&input(ScriptBuf::new(), vec![vec![1; 64]]), &mock::segwit_v2_script()), Err(ChainError::SegWitVersionGE2)));
+    }
+}
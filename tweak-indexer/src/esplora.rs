@@ -0,0 +1,159 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use crate::chain::{ChainSource, PreviousScript, RawBlock};
+use crate::http::{self, HttpError};
+
+// Esplora returns the transactions of a block in pages of this size
+const TXS_PER_PAGE: usize = 25;
+
+#[derive(Deserialize, Debug)]
+struct EsploraBlock {
+    tx_count: usize,
+}
+
+#[derive(Deserialize, Debug)]
+struct EsploraTransaction {
+    vin: Vec<EsploraInput>,
+}
+
+#[derive(Deserialize, Debug)]
+struct EsploraInput {
+    txid: String,
+    vout: u32,
+    is_coinbase: bool,
+    prevout: Option<EsploraPrevout>,
+}
+
+#[derive(Deserialize, Debug)]
+struct EsploraPrevout {
+    scriptpubkey: String,
+}
+
+async fn get_text(url: String) -> Result<String, HttpError> {
+    let body = http::get(url).await?;
+    Ok(String::from_utf8_lossy(&body).trim().to_string())
+}
+
+async fn get_json<T: for<'de> Deserialize<'de>>(url: String) -> Result<T, String> {
+    let body = http::get(url).await.map_err(|e| e.to_string())?;
+    serde_json::from_slice(&body).map_err(|e| format!("Failed to parse Esplora response: {}", e))
+}
+
+pub async fn get_tip_height(base_url: &str) -> Result<u32, String> {
+    let height = get_text(format!("{}/blocks/tip/height", base_url)).await.map_err(|e| e.to_string())?;
+    height.parse().map_err(|e| format!("Invalid tip height {}: {}", height, e))
+}
+
+// Unknown heights answer 404, reported like the RPC error so callers detect reaching the tip the same way
+pub async fn get_block_hash(base_url: &str, height: u32) -> Result<String, String> {
+    match get_text(format!("{}/block-height/{}", base_url, height)).await {
+        Ok(block_hash) => Ok(block_hash),
+        Err(HttpError::Status(404, _)) => Err(String::from("Block height out of range")),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+pub async fn get_block(base_url: &str, block_hash: &str) -> Result<Vec<u8>, String> {
+    http::get(format!("{}/block/{}/raw", base_url, block_hash)).await.map_err(|e| e.to_string())
+}
+
+pub async fn get_transaction(base_url: &str, txid: &str) -> Result<String, String> {
+    get_text(format!("{}/tx/{}/hex", base_url, txid)).await.map_err(|e| e.to_string())
+}
+
+// Prevout scripts of every non coinbase input, collected page by page from the block's transaction listing
+pub async fn get_block_prevouts(base_url: &str, block_hash: &str) -> Result<Vec<PreviousScript>, String> {
+    let block: EsploraBlock = get_json(format!("{}/block/{}", base_url, block_hash)).await?;
+
+    let mut previous_scripts = Vec::new();
+    for start_index in (0..block.tx_count).step_by(TXS_PER_PAGE) {
+        let transactions: Vec<EsploraTransaction> = get_json(format!("{}/block/{}/txs/{}", base_url, block_hash, start_index)).await?;
+        previous_scripts.extend(previous_scripts_from_transactions(transactions));
+    }
+    Ok(previous_scripts)
+}
+
+fn previous_scripts_from_transactions(transactions: Vec<EsploraTransaction>) -> Vec<PreviousScript> {
+    transactions
+        .into_iter()
+        .flat_map(|tx| tx.vin)
+        .filter(|input| !input.is_coinbase)
+        .filter_map(|input| {
+            Some(PreviousScript {
+                txid: input.txid,
+                vout: input.vout,
+                script: input.prevout?.scriptpubkey,
+            })
+        })
+        .collect()
+}
+
+// Submitting the hex to /tx answers with the txid
+pub async fn broadcast(base_url: &str, tx_hex: &str) -> Result<String, String> {
+    let txid = http::post(format!("{}/tx", base_url), tx_hex.to_string()).await.map_err(|e| e.to_string())?;
+    Ok(String::from_utf8_lossy(&txid).trim().to_string())
+}
+
+// Blocks, hashes and prevouts from an Esplora/electrs HTTP API, no node required
+#[derive(Debug, Clone)]
+pub struct EsploraSource {
+    url: String,
+}
+
+impl EsploraSource {
+    pub fn new(url: &str) -> Self {
+        Self { url: url.trim_end_matches('/').to_string() }
+    }
+}
+
+// Esplora has no batch endpoint, get_transactions keeps the one by one default
+#[async_trait]
+impl ChainSource for EsploraSource {
+    async fn get_block_count(&self) -> Result<u32, String> {
+        get_tip_height(&self.url).await
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<String, String> {
+        get_block_hash(&self.url, height).await
+    }
+
+    async fn get_block(&self, block_hash: &str) -> Result<String, String> {
+        get_block(&self.url, block_hash).await.map(hex::encode)
+    }
+
+    async fn get_raw_block(&self, block_hash: &str) -> Result<RawBlock, String> {
+        get_block(&self.url, block_hash).await.map(RawBlock::Bytes)
+    }
+
+    async fn get_prevouts(&self, block_hash: &str) -> Result<Vec<PreviousScript>, String> {
+        get_block_prevouts(&self.url, block_hash).await
+    }
+
+    async fn get_transaction(&self, txid: &str) -> Result<String, String> {
+        get_transaction(&self.url, txid).await
+    }
+
+    async fn broadcast(&self, tx_hex: &str) -> Result<String, String> {
+        broadcast(&self.url, tx_hex).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_previous_scripts_from_transactions() {
+        let transactions: Vec<EsploraTransaction> = serde_json::from_str(r#"[
+            {"txid": "c0", "vin": [{"txid": "0000", "vout": 4294967295, "is_coinbase": true, "prevout": null, "scriptsig": "03"}]},
+            {"txid": "t1", "vin": [
+                {"txid": "aa", "vout": 1, "is_coinbase": false, "prevout": {"scriptpubkey": "5120ff", "scriptpubkey_type": "v1_p2tr", "value": 1000}}
+            ]}
+        ]"#).unwrap();
+
+        // Coinbase input is skipped
+        let previous_scripts = previous_scripts_from_transactions(transactions);
+        assert_eq!(previous_scripts.len(), 1);
+        assert_eq!((previous_scripts[0].txid.as_str(), previous_scripts[0].vout, previous_scripts[0].script.as_str()), ("aa", 1, "5120ff"));
+    }
+}
@@ -0,0 +1,131 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+use crate::database::{Database, Tweak};
+
+#[derive(Serialize, Debug)]
+pub struct ChunkEntry {
+    pub file: String,
+    pub start_height: u32,
+    pub end_height: u32,
+    pub tweak_count: usize,
+    pub sha256: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Manifest {
+    pub chunk_size: u32,
+    pub chunks: Vec<ChunkEntry>,
+}
+
+// One line per tweak (height,block_hash,tx_id,tweak) in canonical order, so the same data always gives the same bytes.
+// Within a block tweaks keep their stored order, which is transaction order in the block.
+pub fn serialize_chunk(tweaks: &[(u32, Tweak)]) -> Vec<u8> {
+    let mut ordered: Vec<&(u32, Tweak)> = tweaks.iter().collect();
+    ordered.sort_by_key(|(height, _)| *height);
+
+    let mut serialized = Vec::new();
+    for (height, tweak) in ordered {
+        serialized.extend_from_slice(format!("{},{},{},{}\n", height, tweak.block_hash, tweak.tx_id, tweak.tweak).as_bytes());
+    }
+    serialized
+}
+
+// Write every fully indexed chunk of heights to its own file and list the chunk hashes in manifest.json.
+// Chunks are aligned to multiples of chunk_size so independent operators produce the same boundaries.
+pub fn export_chunks(db: &Database, out_dir: &str, chunk_size: u32) -> Result<Manifest, Box<dyn Error>> {
+    fs::create_dir_all(out_dir)?;
+    let mut manifest = Manifest { chunk_size, chunks: Vec::new() };
+
+    let lowest = db.get_lowest_block()?;
+    let highest = db.get_highest_block()?;
+    if highest == 0 {
+        warn!("No blocks indexed, nothing to export");
+        return Ok(manifest);
+    }
+
+    for chunk in (lowest / chunk_size)..=(highest / chunk_size) {
+        let start_height = chunk * chunk_size;
+        let end_height = start_height + chunk_size - 1;
+
+        // Partially indexed chunks would differ between operators, only publish complete ones
+        let block_count = db.get_block_count_in_range(start_height, end_height)?;
+        if block_count < chunk_size {
+            info!("Skipping incomplete chunk {}-{} ({} of {} blocks indexed)", start_height, end_height, block_count, chunk_size);
+            continue;
+        }
+
+        let tweaks = db.get_tweaks_in_range(start_height, end_height)?;
+        let serialized = serialize_chunk(&tweaks);
+        let file = format!("tweaks-{:08}-{:08}.csv", start_height, end_height);
+        fs::write(Path::new(out_dir).join(&file), &serialized)?;
+
+        let sha256 = hex::encode(Sha256::digest(&serialized));
+        info!("Exported chunk {} with {} tweaks, sha256 {}", file, tweaks.len(), sha256);
+        manifest.chunks.push(ChunkEntry {
+            file,
+            start_height,
+            end_height,
+            tweak_count: tweaks.len(),
+            sha256,
+        });
+    }
+
+    fs::write(Path::new(out_dir).join("manifest.json"), serde_json::to_string_pretty(&manifest)?)?;
+    Ok(manifest)
+}
+
+// Write every tweak with an id above since_id (id,height,block_hash,tx_index,tx_id,tweak per line) for incremental
+// replication. Returns the highest id written, the consumer passes it as the next cursor.
+pub fn export_since_id(db: &Database, out_dir: &str, since_id: i64) -> Result<i64, Box<dyn Error>> {
+    fs::create_dir_all(out_dir)?;
+
+    let tweaks = db.get_tweaks_after_id(since_id)?;
+    let mut serialized = Vec::new();
+    for (id, height, tweak) in tweaks.iter() {
+        let tx_index = tweak.tx_index.map(|index| index.to_string()).unwrap_or_default();
+        serialized.extend_from_slice(format!("{},{},{},{},{},{}\n", id, height, tweak.block_hash, tx_index, tweak.tx_id, tweak.tweak).as_bytes());
+    }
+
+    let file = format!("tweaks-since-{}.csv", since_id);
+    fs::write(Path::new(out_dir).join(&file), &serialized)?;
+
+    let max_id = tweaks.last().map_or(since_id, |(id, _, _)| *id);
+    info!("Exported {} tweaks to {}", tweaks.len(), file);
+    Ok(max_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tweak(block_hash: &str, tx_id: &str, tweak: &str) -> Tweak {
+        Tweak {
+            block_hash: block_hash.to_string(),
+            tx_index: None,
+            tx_id: tx_id.to_string(),
+            tweak: tweak.to_string(),
+            max_output_value: None,
+            merkle_proof: None,
+            outputs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_serialize_chunk_orders_by_height_then_block_order() {
+        let tweaks = vec![
+            (800001, tweak("h2", "cc", "03cc")),
+            (800000, tweak("h1", "bb", "02bb")),
+            (800000, tweak("h1", "aa", "02aa")),
+        ];
+
+        // Heights are ordered, tweaks within a block keep their transaction order
+        assert_eq!(
+            String::from_utf8(serialize_chunk(&tweaks)).unwrap(),
+            "800000,h1,bb,02bb\n800000,h1,aa,02aa\n800001,h2,cc,03cc\n"
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use bitcoin::consensus::encode::serialize_hex;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use crate::chain::{ChainSource, PreviousScript, RawBlock};
+
+// Chain responses captured from a live backend, replayed by tests and --backend replay without a node
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Fixture {
+    #[serde(default)]
+    pub block_count: Option<u32>,
+    #[serde(default)]
+    pub block_hashes: BTreeMap<u32, String>,
+    #[serde(default)]
+    pub blocks: BTreeMap<String, String>,
+    #[serde(default)]
+    pub prevouts: BTreeMap<String, Vec<PreviousScript>>,
+    #[serde(default)]
+    pub transactions: BTreeMap<String, String>,
+}
+
+impl Fixture {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let json = fs::read_to_string(path).map_err(|e| format!("Failed to read fixture {}: {}", path.display(), e))?;
+        serde_json::from_str(&json).map_err(|e| format!("Invalid fixture {}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        fs::write(path, json).map_err(|e| format!("Failed to write fixture {}: {}", path.display(), e))
+    }
+}
+
+// Passes calls through to another backend and appends every response to a fixture file.
+// The file is rewritten after each response, meant for capturing a handful of blocks.
+pub struct RecordingSource {
+    inner: Arc<dyn ChainSource>,
+    path: PathBuf,
+    fixture: Mutex<Fixture>,
+}
+
+impl RecordingSource {
+    // Responses already in the file are kept
+    pub fn new(inner: Arc<dyn ChainSource>, path: PathBuf) -> Result<Self, String> {
+        let fixture = if path.exists() { Fixture::load(&path)? } else { Fixture::default() };
+        Ok(Self { inner, path, fixture: Mutex::new(fixture) })
+    }
+
+    fn record(&self, update: impl FnOnce(&mut Fixture)) {
+        let mut fixture = self.fixture.lock().unwrap();
+        update(&mut fixture);
+        if let Err(err) = fixture.save(&self.path) {
+            warn!("{}", err);
+        }
+    }
+}
+
+#[async_trait]
+impl ChainSource for RecordingSource {
+    async fn get_block_count(&self) -> Result<u32, String> {
+        let block_count = self.inner.get_block_count().await?;
+        self.record(|fixture| fixture.block_count = Some(block_count));
+        Ok(block_count)
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<String, String> {
+        let block_hash = self.inner.get_block_hash(height).await?;
+        self.record(|fixture| {
+            fixture.block_hashes.insert(height, block_hash.clone());
+        });
+        Ok(block_hash)
+    }
+
+    async fn get_block(&self, block_hash: &str) -> Result<String, String> {
+        let block_hex = self.inner.get_block(block_hash).await?;
+        self.record(|fixture| {
+            fixture.blocks.insert(block_hash.to_string(), block_hex.clone());
+        });
+        Ok(block_hex)
+    }
+
+    async fn get_prevouts(&self, block_hash: &str) -> Result<Vec<PreviousScript>, String> {
+        let previous_scripts = self.inner.get_prevouts(block_hash).await?;
+        self.record(|fixture| {
+            fixture.prevouts.insert(block_hash.to_string(), previous_scripts.clone());
+        });
+        Ok(previous_scripts)
+    }
+
+    async fn get_transaction(&self, txid: &str) -> Result<String, String> {
+        let tx_hex = self.inner.get_transaction(txid).await?;
+        self.record(|fixture| {
+            fixture.transactions.insert(txid.to_string(), tx_hex.clone());
+        });
+        Ok(tx_hex)
+    }
+
+    async fn get_transactions(&self, txids: Vec<String>) -> Result<Vec<Option<String>>, String> {
+        let transactions = self.inner.get_transactions(txids.clone()).await?;
+        self.record(|fixture| {
+            for (txid, tx_hex) in txids.iter().zip(transactions.iter()) {
+                if let Some(tx_hex) = tx_hex {
+                    fixture.transactions.insert(txid.clone(), tx_hex.clone());
+                }
+            }
+        });
+        Ok(transactions)
+    }
+
+    // Fixtures store blocks as hex, so the block is decoded here to be re-encoded for the recording
+    async fn get_block_with_prevouts(&self, block_hash: &str) -> Result<(RawBlock, Vec<PreviousScript>), String> {
+        let (raw_block, previous_scripts) = self.inner.get_block_with_prevouts(block_hash).await?;
+        let block = raw_block.decode()?;
+        self.record(|fixture| {
+            fixture.blocks.insert(block_hash.to_string(), serialize_hex(&block));
+            fixture.prevouts.insert(block_hash.to_string(), previous_scripts.clone());
+        });
+        Ok((RawBlock::Decoded(block), previous_scripts))
+    }
+
+    async fn broadcast(&self, tx_hex: &str) -> Result<String, String> {
+        self.inner.broadcast(tx_hex).await
+    }
+}
+
+// Answers from a recorded fixture, anything that was not recorded is an error
+pub struct ReplaySource {
+    fixture: Fixture,
+}
+
+impl ReplaySource {
+    pub fn new(fixture: Fixture) -> Self {
+        Self { fixture }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        Ok(Self::new(Fixture::load(path)?))
+    }
+}
+
+#[async_trait]
+impl ChainSource for ReplaySource {
+    async fn get_block_count(&self) -> Result<u32, String> {
+        self.fixture
+            .block_count
+            .or_else(|| self.fixture.block_hashes.keys().next_back().copied())
+            .ok_or_else(|| String::from("Block count not in fixture"))
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<String, String> {
+        if let Some(block_hash) = self.fixture.block_hashes.get(&height) {
+            return Ok(block_hash.clone());
+        }
+        if height > self.get_block_count().await.unwrap_or_default() {
+            return Err(String::from("Block height out of range"));
+        }
+        Err(format!("Block hash at height {} not in fixture", height))
+    }
+
+    async fn get_block(&self, block_hash: &str) -> Result<String, String> {
+        self.fixture.blocks.get(block_hash).cloned().ok_or_else(|| format!("Block {} not in fixture", block_hash))
+    }
+
+    async fn get_prevouts(&self, block_hash: &str) -> Result<Vec<PreviousScript>, String> {
+        self.fixture.prevouts.get(block_hash).cloned().ok_or_else(|| format!("Prevouts of block {} not in fixture", block_hash))
+    }
+
+    async fn get_transaction(&self, txid: &str) -> Result<String, String> {
+        self.fixture.transactions.get(txid).cloned().ok_or_else(|| format!("Transaction {} not in fixture", txid))
+    }
+
+    async fn broadcast(&self, _tx_hex: &str) -> Result<String, String> {
+        Err(String::from("Transactions cannot be broadcast from a fixture"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/regtest_block_1.json");
+    const BLOCK_HASH: &str = "891ed099a62ddb0eb1e5d1a7aae55b0307bdcece4d580a459a53327d4c793a8a";
+
+    #[tokio::test]
+    async fn test_replay() {
+        let source = ReplaySource::load(Path::new(FIXTURE)).unwrap();
+        assert_eq!(source.get_block_count().await, Ok(1));
+        assert_eq!(source.get_block_hash(1).await.unwrap(), BLOCK_HASH);
+        assert_eq!(source.get_block_hash(2).await, Err(String::from("Block height out of range")));
+        assert_eq!(source.get_prevouts(BLOCK_HASH).await.unwrap().len(), 1);
+        assert!(source.get_block(BLOCK_HASH).await.is_ok());
+        assert!(source.get_transaction("00").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_record_replay_round_trip() {
+        let path = std::env::temp_dir().join(format!("tweak-indexer-fixture-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let live: Arc<dyn ChainSource> = Arc::new(ReplaySource::load(Path::new(FIXTURE)).unwrap());
+        let recorder = RecordingSource::new(live, path.clone()).unwrap();
+        let block_hash = recorder.get_block_hash(1).await.unwrap();
+        let (raw_block, previous_scripts) = recorder.get_block_with_prevouts(&block_hash).await.unwrap();
+        let block_hex = serialize_hex(&raw_block.decode().unwrap());
+
+        let replay = ReplaySource::load(&path).unwrap();
+        assert_eq!(replay.get_block_hash(1).await.unwrap(), block_hash);
+        assert_eq!(replay.get_block(&block_hash).await.unwrap(), block_hex);
+        assert_eq!(replay.get_prevouts(&block_hash).await.unwrap().len(), previous_scripts.len());
+        // Only what was requested gets recorded
+        assert!(replay.get_block_hash(0).await.is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+use std::fmt;
+use std::future::Future;
+use tokio::{task, time};
+use crate::{proxy, retry, rpc};
+
+#[derive(Debug)]
+pub enum HttpError {
+    Transport(String),
+    Status(i32, String),
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpError::Transport(err) => write!(f, "HTTP request failed: {}", err),
+            HttpError::Status(code, body) => write!(f, "HTTP error {}: {}", code, body),
+        }
+    }
+}
+
+// Connection problems and server side errors clear up once the server is back, a 404 for an unknown block does not
+fn is_transient(err: &HttpError) -> bool {
+    match err {
+        HttpError::Transport(_) => true,
+        HttpError::Status(code, _) => *code == 429 || *code >= 500,
+    }
+}
+
+// GET the url off the runtime threads, retrying transient failures with the node retry policy
+pub async fn get(url: String) -> Result<Vec<u8>, HttpError> {
+    retry::with_backoff(&rpc::retry_policy(), "HTTP request", is_transient, || {
+        let url = url.clone();
+        async move {
+            rpc::throttle().await;
+            with_deadline(task::spawn_blocking(move || send("GET", &url, String::new()))).await
+        }
+    })
+    .await
+}
+
+// POST the body once, a retried submission could be applied twice
+pub async fn post(url: String, body: String) -> Result<Vec<u8>, HttpError> {
+    rpc::throttle().await;
+    with_deadline(task::spawn_blocking(move || send("POST", &url, body))).await
+}
+
+// minreq's timeout covers each socket operation, this bounds the whole request including a slowly trickling body
+async fn with_deadline(request: impl Future<Output = Result<Result<Vec<u8>, HttpError>, task::JoinError>>) -> Result<Vec<u8>, HttpError> {
+    match time::timeout(rpc::request_timeout(), request).await {
+        Ok(joined) => joined.map_err(|e| HttpError::Transport(format!("HTTP task failed: {}", e)))?,
+        Err(_) => Err(HttpError::Transport(String::from("HTTP request timed out"))),
+    }
+}
+
+fn send(method: &str, url: &str, body: String) -> Result<Vec<u8>, HttpError> {
+    let (status_code, response) = match rpc::proxy() {
+        Some(proxy) => proxy::request(proxy, method, url, &[], body.as_bytes(), rpc::request_timeout())
+            .map_err(|e| HttpError::Transport(format!("{} via {}: {}", url, proxy, e)))?,
+        None => {
+            let request = if method == "POST" { minreq::post(url).with_body(body) } else { minreq::get(url) };
+            let response = request
+                .with_timeout(rpc::request_timeout().as_secs())
+                .send()
+                .map_err(|e| HttpError::Transport(format!("{}: {}", url, e)))?;
+            (response.status_code, response.into_bytes())
+        }
+    };
+
+    if status_code != 200 {
+        return Err(HttpError::Status(status_code, String::from_utf8_lossy(&response).trim().to_string()));
+    }
+
+    Ok(response)
+}
@@ -0,0 +1,28 @@
+// Indexer internals, shared by the binary and the benchmarks
+#[cfg(test)]
+mod bip352_vectors;
+pub mod blkfile;
+pub mod cache;
+pub mod chain;
+pub mod crosscheck;
+pub mod database;
+pub mod electrum;
+pub mod eligibility;
+pub mod esplora;
+pub mod export;
+pub mod fixture;
+mod http;
+pub mod lock;
+#[cfg(test)]
+mod mock;
+pub mod p2p;
+pub mod progress;
+mod proxy;
+pub mod ratelimit;
+pub mod rest;
+pub mod retry;
+pub mod rpc;
+pub mod scan;
+pub mod shutdown;
+pub mod snapshot;
+pub mod zmq;
@@ -0,0 +1,65 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+// Exclusive advisory lock on <database>.lock, held for as long as the indexer runs so a second indexer
+// writing to the same database refuses to start. The operating system releases it when the process exits.
+pub struct InstanceLock {
+    _file: File,
+}
+
+pub fn lock_path(db_path: &str) -> String {
+    format!("{}.lock", db_path)
+}
+
+pub fn acquire(db_path: &str) -> Result<InstanceLock, String> {
+    let path = lock_path(db_path);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(|e| format!("Unable to open lock file {}: {}", path, e))?;
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let mut holder = String::new();
+            let _ = file.read_to_string(&mut holder);
+            let holder = match holder.trim() {
+                "" => String::from("another process"),
+                pid => format!("process {}", pid),
+            };
+            return Err(format!("Another indexer ({}) is already using {}, stop it before starting a new one", holder, db_path));
+        }
+        Err(TryLockError::Error(err)) => return Err(format!("Unable to lock {}: {}", path, err)),
+    }
+
+    // Record who holds the lock for the error above
+    file.set_len(0)
+        .and_then(|_| file.seek(SeekFrom::Start(0)))
+        .and_then(|_| write!(file, "{}", std::process::id()))
+        .map_err(|e| format!("Unable to write lock file {}: {}", path, e))?;
+    Ok(InstanceLock { _file: file })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_instance_refused() {
+        let db_path = std::env::temp_dir().join(format!("tweak-indexer-lock-{}.db", std::process::id()));
+        let db_path = db_path.to_str().unwrap();
+
+        let lock = acquire(db_path).unwrap();
+        let err = acquire(db_path).err().unwrap();
+        assert!(err.contains(&format!("process {}", std::process::id())), "{}", err);
+
+        // Released when the first instance goes away
+        drop(lock);
+        let lock = acquire(db_path).unwrap();
+        drop(lock);
+        std::fs::remove_file(lock_path(db_path)).unwrap();
+    }
+}
@@ -1,37 +1,412 @@
-use std::{process::exit, thread::sleep, time::Duration};
-use clap::Parser;
-use database::Database;
-use tracing::{error, info, warn, Level};
+use std::{collections::{HashSet, VecDeque}, io::IsTerminal, path::{Path, PathBuf}, process::exit, str::FromStr, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::{Duration, Instant}};
+use bitcoin::{block::Block, consensus::encode::deserialize_hex};
+use clap::{Parser, Subcommand, ValueEnum};
+use tracing::{debug, error, info, warn, Level};
 use tracing_subscriber::{filter, fmt, layer::SubscriberExt, EnvFilter, Layer, Registry};
 use tracing_appender::rolling;
-use tokio;
+use tokio::task::{self, JoinHandle};
+use tokio::time::sleep;
+use tweak_indexer::{blkfile, cache, chain, crosscheck, database, electrum, esplora, export, fixture, lock, p2p, progress, rest, retry, rpc, scan, shutdown, snapshot, zmq};
+use tweak_indexer::chain::ChainSource;
+use tweak_indexer::database::Database;
 
-mod chain;
-mod database;
+// How often startup rechecks a node that is loading or syncing
+const NODE_READY_POLL: Duration = Duration::from_secs(60);
+// Seconds between tip checks in continuous mode
+const DEFAULT_POLL_INTERVAL: u64 = 300;
+
+// Wait for another indexer process holding the database lock when sharding
+const SHARD_BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+// Queue workers lease this many consecutive heights at a time, for long enough to index them
+const QUEUE_LEASE_HEIGHTS: u32 = 100;
+const QUEUE_LEASE: Duration = Duration::from_secs(600);
+// Claims of a sharded run not touched for this long belong to a worker that died and are handed out again
+const SHARD_CLAIM_STALE_AFTER: Duration = Duration::from_secs(600);
+
+// More blocks failing in a row means the backend is gone rather than one bad block
+const MAX_CONSECUTIVE_BLOCK_FAILURES: u32 = 10;
+
+// Blocks and tweaks stored by this run, reported when it stops
+static BLOCKS_STORED: AtomicU64 = AtomicU64::new(0);
+static TWEAKS_STORED: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Parser)]
 #[command(long_about)]
 struct Cli {
-    /// If omitted will index indefinitely from (709632) or latest block indexed
+    /// If omitted will index indefinitely from (709632 on mainnet) or latest block indexed
     #[arg(long)]
     start_height: Option<u32>,
-    /// Specify which block to stop indexing before exiting
+    /// Specify which block to stop indexing before exiting, tip-N stops N blocks below the node's tip
     #[arg(long)]
-    end_height: Option<u32>,
+    end_height: Option<EndHeight>,
     /// Number of blocks to index before exiting
     #[arg(long)]
     blocks: Option<u32>,
-    /// Use this when most transactions in block are Taproot for faster performance (~ >750000)
+    /// How prevout scripts are fetched, auto decides per block from its share of Taproot transactions
+    #[arg(long, value_enum, default_value_t = Prevouts::Auto)]
+    prevouts: Prevouts,
+    /// Same as --prevouts block, fetch every prevout with the block
     #[arg(short,long)]
     seek_prev_outs: bool,
+    /// Maximum concurrent fallback RPC calls to the node (match bitcoind rpcthreads)
+    #[arg(long, default_value_t = chain::DEFAULT_MAX_INFLIGHT_RPC)]
+    max_inflight_rpc: usize,
+    /// Transactions of a block waiting on backend lookups at once (default two per core)
+    #[arg(long)]
+    max_inflight_tx: Option<usize>,
+    /// Async runtime threads running downloads, backend calls and database writes (default one per core)
+    #[arg(long)]
+    workers: Option<usize>,
+    /// Upcoming blocks downloaded and decoded while the current one is processed, also the number of RPC connections to the node
+    #[arg(long, default_value_t = rpc::DEFAULT_RPC_CONNECTIONS)]
+    fetch_workers: usize,
+    /// Show a progress bar on stderr, on by default when stdout is a terminal
+    #[arg(long)]
+    progress: bool,
+    /// Outputs of indexed blocks kept in memory so spends of them need no backend lookup, 0 disables the cache
+    #[arg(long, default_value_t = cache::DEFAULT_SCRIPT_CACHE_ENTRIES)]
+    script_cache_entries: usize,
+    /// Threads computing tweaks of transactions whose prevouts are known (default one per core)
+    #[arg(long)]
+    tweak_threads: Option<usize>,
+    /// Every N blocks indexed, recompute a recently stored block and compare it with the database
+    #[arg(long)]
+    checkpoint_verify_interval: Option<u32>,
+    /// Exit when a checkpoint verification finds stored tweaks that differ from recomputed ones
+    #[arg(long)]
+    halt_on_divergence: bool,
+    /// Record per-block fetch/extract/write durations and transaction counts in the block_timings table
+    #[arg(long)]
+    record_timings: bool,
+    /// Record per-block counts of Taproot outputs, candidate and tweaked transactions and eligible inputs by type
+    /// in the eligibility_stats table
+    #[arg(long)]
+    record_stats: bool,
+    /// Delete tweaks whose Taproot outputs were all spent more than this many blocks below the highest stored block,
+    /// checked after every batch or poll
+    #[arg(long)]
+    prune_spent_after: Option<u32>,
+    /// Store the merkle branch of each tweaked transaction so the service can return inclusion proofs
+    #[arg(long)]
+    merkle_proofs: bool,
+    /// Measure the node's effective RPC parallelism at startup and log a recommended --max-inflight-rpc
+    #[arg(long)]
+    rpc_concurrency_probe: bool,
+    /// Start indexing while the node is still in initial block download instead of waiting for it to sync
+    #[arg(long)]
+    allow_ibd: bool,
+    /// On a pruned node, start from the lowest available height instead of exiting when the start height was pruned
+    #[arg(long)]
+    clamp_to_prune_height: bool,
+    /// Chain to index, selects the default start height, database file, RPC port and cookie directory
+    #[arg(long, value_enum, default_value_t = Network::Mainnet)]
+    network: Network,
+    /// Host of the node's JSON-RPC server, may include an http:// or https:// scheme
+    #[arg(long, default_value = rpc::DEFAULT_RPC_HOST)]
+    rpc_host: String,
+    /// Port of the node's JSON-RPC server (default 8332, 18332 testnet, 38332 signet, 18443 regtest)
+    #[arg(long)]
+    rpc_port: Option<u16>,
+    /// RPC username (rpcuser), requires --rpc-password
+    #[arg(long)]
+    rpc_user: Option<String>,
+    /// RPC password (rpcpassword), requires --rpc-user
+    #[arg(long)]
+    rpc_password: Option<String>,
+    /// Cookie file used when no user/password is given (default ~/.bitcoin/.cookie, or the network subdirectory)
+    #[arg(long)]
+    rpc_cookie_file: Option<PathBuf>,
+    /// Times a failed node request is retried with exponential backoff before giving up (connection errors, node warming up)
+    #[arg(long, default_value_t = retry::DEFAULT_MAX_RETRIES)]
+    rpc_retries: u32,
+    /// Seconds a single request to the node or backend may take before it is abandoned and retried
+    #[arg(long, default_value_t = rpc::DEFAULT_REQUEST_TIMEOUT_SECS)]
+    request_timeout: u64,
+    /// Most requests per second sent to the node (RPC and REST) or Esplora, so a node that also serves wallets stays responsive during historical sync
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_requests_per_second: Option<u32>,
+    /// SOCKS5 proxy as host:port (e.g. Tor at 127.0.0.1:9050) for RPC, REST and Esplora requests, hostnames such as .onion are resolved by the proxy
+    #[arg(long)]
+    proxy: Option<String>,
+    /// Where raw blocks are downloaded from, rest requires bitcoind to run with -rest, esplora and electrum need no node
+    #[arg(long, value_enum, default_value_t = Backend::Rpc)]
+    backend: Backend,
+    /// Base URL of the Esplora API used by --backend esplora (e.g. https://blockstream.info/api)
+    #[arg(long)]
+    esplora_url: Option<String>,
+    /// Peer used by --backend p2p as host:port (default 127.0.0.1 on the --network port)
+    #[arg(long)]
+    p2p_peer: Option<String>,
+    /// Electrum server used by --backend electrum (e.g. ssl://electrum.blockstream.info:50002 or tcp://127.0.0.1:50001)
+    #[arg(long)]
+    electrum_url: Option<String>,
+    /// Fixture file read by --backend replay
+    #[arg(long)]
+    fixture_file: Option<PathBuf>,
+    /// Write every backend response to this fixture file, for replaying a run with --backend replay
+    #[arg(long)]
+    record_fixtures: Option<PathBuf>,
+    /// Node blocks directory (e.g. ~/.bitcoin/blocks), the initial sync reads blk*.dat directly and switches to the backend once caught up
+    #[arg(long)]
+    blocks_dir: Option<PathBuf>,
+    /// UTXO set written by bitcoin-cli dumptxoutset (Bitcoin Core 28+), blocks after its base take prevouts from the loaded set (ignored with --blocks-dir)
+    #[arg(long)]
+    utxo_snapshot: Option<PathBuf>,
+    /// Seconds between checks for new blocks in continuous mode, also the longest wait for a ZMQ notification (1-3600)
+    #[arg(long, default_value_t = DEFAULT_POLL_INTERVAL, value_parser = clap::value_parser!(u64).range(1..=3600))]
+    poll_interval: u64,
+    /// Stay this many blocks behind the node's tip in continuous mode, blocks that deep are rarely reorganized away
+    #[arg(long, default_value_t = 0)]
+    min_confirmations: u32,
+    /// Megabytes of transactions and prevout scripts a block's tweak computation may copy at once,
+    /// larger blocks are processed in chunks (default unlimited)
+    #[arg(long)]
+    memory_budget_mb: Option<usize>,
+    /// Skip transactions whose Taproot outputs are all below this many sats, the largest output value is stored with each tweak either way
+    #[arg(long)]
+    dust_limit: Option<u64>,
+    /// Index new blocks at zero confirmations and recheck the last N stored blocks on every poll,
+    /// rolling back any the node no longer has in its chain
+    #[arg(long, conflicts_with = "min_confirmations", value_parser = clap::value_parser!(u32).range(1..))]
+    speculative_depth: Option<u32>,
+    /// Cooperate with other indexer processes on the same database, each claims ranges of this many heights
+    /// between --start-height and --end-height (default the tip) until all are indexed
+    #[arg(long, conflicts_with = "worker")]
+    shard_size: Option<u32>,
+    /// Index heights from the queue filled by the enqueue command, leasing runs of them until none are left
+    #[arg(long)]
+    worker: bool,
+    /// Name recorded with this process's range claims and queue leases, a restarted worker resumes its unfinished claims (default host-pid)
+    #[arg(long)]
+    worker_id: Option<String>,
+    /// bitcoind -zmqpubhashblock/-zmqpubrawblock endpoint, continuous mode indexes new blocks on arrival instead of polling
+    #[arg(long)]
+    zmq_block_endpoint: Option<String>,
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Backend {
+    /// getblock over JSON-RPC
+    Rpc,
+    /// /rest/block/<hash>.bin on the RPC port
+    Rest,
+    /// Blocks, hashes and prevouts from an Esplora/electrs HTTP API
+    Esplora,
+    /// Headers and transactions from an Electrum server, prevouts fetched per transaction
+    Electrum,
+    /// Headers and blocks from a peer over the Bitcoin wire protocol, prevouts from the node's RPC
+    P2p,
+    /// Responses recorded with --record-fixtures, no node needed
+    Replay,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Prevouts {
+    /// Fetch all prevouts with blocks where enough transactions have a Taproot output, look them up otherwise
+    Auto,
+    /// Fetch every prevout with the block (getblock verbosity 3)
+    Block,
+    /// Look up the spent transactions of Taproot candidates with batched getrawtransaction (needs -txindex)
+    Lookup,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Network {
+    Mainnet,
+    Testnet,
+    Signet,
+    Regtest,
+}
+
+impl Network {
+    fn bitcoin_network(self) -> bitcoin::Network {
+        match self {
+            Network::Mainnet => bitcoin::Network::Bitcoin,
+            Network::Testnet => bitcoin::Network::Testnet,
+            Network::Signet => bitcoin::Network::Signet,
+            Network::Regtest => bitcoin::Network::Regtest,
+        }
+    }
+
+    // Chain name reported by getblockchaininfo
+    fn chain_name(self) -> &'static str {
+        match self {
+            Network::Mainnet => "main",
+            Network::Testnet => "test",
+            Network::Signet => "signet",
+            Network::Regtest => "regtest",
+        }
+    }
+
+    // First Taproot block on mainnet, test networks are short enough to index from genesis
+    fn default_start_height(self) -> u32 {
+        match self {
+            Network::Mainnet => 709632,
+            _ => 0,
+        }
+    }
+
+    // Each network gets its own database so tweaks from different chains never mix
+    fn db_path(self) -> String {
+        match self {
+            Network::Mainnet => String::from("blocks.db"),
+            network => format!("blocks-{}.db", network.chain_name()),
+        }
+    }
+}
+
+// Last height of a batch run, either absolute or relative to the tip when indexing starts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EndHeight {
+    Height(u32),
+    BelowTip(u32),
+}
+
+impl FromStr for EndHeight {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (depth, relative) = match value.strip_prefix("tip") {
+            Some("") => ("0", true),
+            Some(depth) => (depth.strip_prefix('-').ok_or_else(|| format!("Expected a height or tip-N, got {}", value))?, true),
+            None => (value, false),
+        };
+        let height = depth.parse::<u32>().map_err(|e| format!("Invalid end height {}: {}", value, e))?;
+        Ok(if relative { EndHeight::BelowTip(height) } else { EndHeight::Height(height) })
+    }
+}
+
+impl EndHeight {
+    async fn resolve(self, source: &dyn ChainSource) -> Result<u32, String> {
+        match self {
+            EndHeight::Height(height) => Ok(height),
+            EndHeight::BelowTip(depth) => Ok(source.get_block_count().await?.saturating_sub(depth)),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Write indexed tweaks as deterministic height-range chunk files plus a manifest of chunk hashes
+    Export {
+        /// Directory for the chunk files and manifest.json
+        #[arg(long)]
+        out_dir: String,
+        /// Number of block heights per chunk
+        #[arg(long, default_value_t = 10000)]
+        chunk_size: u32,
+        /// Instead of chunks, write only tweaks with an id above this cursor and report the max id emitted
+        #[arg(long)]
+        since_id: Option<i64>,
+    },
+    /// Relay a raw transaction through the selected backend and print its txid
+    Broadcast {
+        /// Serialized transaction hex
+        tx_hex: String,
+    },
+    /// Recompute the stored tweaks of a height range and report blocks that differ from the database
+    Verify {
+        /// First height to verify
+        #[arg(long)]
+        from: u32,
+        /// Last height to verify
+        #[arg(long)]
+        to: u32,
+    },
+    /// Queue the heights of a range that aren't indexed yet for indexers started with --worker
+    Enqueue {
+        /// First height to queue
+        #[arg(long)]
+        from: u32,
+        /// Last height to queue
+        #[arg(long)]
+        to: u32,
+    },
+    /// Report the stored Taproot outputs in a height range that pay a silent payment address, one JSON line each
+    Scan {
+        /// Scan private key of the address (hex)
+        #[arg(long)]
+        scan_key: String,
+        /// Spend public key of the address (compressed hex)
+        #[arg(long)]
+        spend_pubkey: String,
+        /// Also find outputs paid to label M of the address, repeatable, the change label 0 is always scanned for
+        #[arg(long = "label")]
+        labels: Vec<u32>,
+        /// First height to scan
+        #[arg(long)]
+        from: u32,
+        /// Last height to scan
+        #[arg(long)]
+        to: u32,
+    },
+    /// Compare the stored tweaks of a height range with another tweak service and report blocks that differ
+    Crosscheck {
+        /// Tweak service base URL (/tweaks/<hash> is appended) or a URL template with {height} or {hash},
+        /// e.g. a BlindBit oracle's https://host/tweaks/{height}
+        #[arg(long)]
+        remote: String,
+        /// First height to compare
+        #[arg(long)]
+        from: u32,
+        /// Last height to compare
+        #[arg(long)]
+        to: u32,
+    },
+    /// Fetch and process the blocks recorded in failed_blocks again
+    RetryFailed,
+    /// Delete the stored blocks and tweaks in a height range and index them again
+    Reindex {
+        /// First height to reindex
+        #[arg(long)]
+        from: u32,
+        /// Last height to reindex
+        #[arg(long)]
+        to: u32,
+    },
 }
 
 struct StartupParams {
+    network: Network,
     start_height: u32,
-    end_height: u32,
+    end_height: EndHeight,
     continuous_index: bool,
     db_path: String,
-    seek_prev_outs: bool,
+    prevouts: Prevouts,
+    max_inflight_rpc: usize,
+    max_inflight_tx: Option<usize>,
+    fetch_workers: usize,
+    script_cache_entries: usize,
+    tweak_threads: Option<usize>,
+    rpc_concurrency_probe: bool,
+    allow_ibd: bool,
+    clamp_to_prune_height: bool,
+    checkpoint_verify_interval: Option<u32>,
+    halt_on_divergence: bool,
+    record_timings: bool,
+    record_stats: bool,
+    merkle_proofs: bool,
+    prune_spent_after: Option<u32>,
+    rpc: rpc::RpcConfig,
+    backend: Backend,
+    esplora_url: Option<String>,
+    electrum_url: Option<String>,
+    p2p_peer: Option<String>,
+    fixture_file: Option<PathBuf>,
+    record_fixtures: Option<PathBuf>,
+    blocks_dir: Option<PathBuf>,
+    utxo_snapshot: Option<PathBuf>,
+    zmq_block_endpoint: Option<String>,
+    poll_interval: Duration,
+    min_confirmations: u32,
+    memory_budget_mb: Option<usize>,
+    dust_limit: Option<u64>,
+    speculative_depth: Option<u32>,
+    shard_size: Option<u32>,
+    queue_worker: bool,
+    worker_id: String,
 }
 
 fn setup_logging() {
@@ -41,6 +416,7 @@ fn setup_logging() {
     // Console log layer
     let stdout_layer = fmt::layer()
         .pretty() // Makes console logs readable
+        .with_writer(progress::ConsoleWriter) // Keeps the progress bar intact
         .with_filter(EnvFilter::from_default_env()); // Uses RUST_LOG
 
     // File layer for warnings & errors only
@@ -56,179 +432,1946 @@ fn setup_logging() {
     tracing::subscriber::set_global_default(subscriber).expect("Failed to set global subscriber");
 }
 
-fn auto_index(db: &Database) -> (u32, u32) {
+async fn auto_index(db: &Database, source: &dyn ChainSource, network: Network, min_confirmations: u32) -> Result<(u32, u32), String> {
 
-    let starting_block= db.get_highest_block().map_or_else(
-        |err| {
-            error!("Failed to fetch highest block: {}", err);
-            exit(1);
-        },
-        |highest_block| if highest_block > 0 { highest_block } else { 709632 }, //Default to first Taproot block
-    );
+    let highest_block = db.get_highest_block().map_err(|err| format!("Failed to fetch highest block: {}", err))?;
+    let starting_block = if highest_block > 0 { highest_block } else { network.default_start_height() };
 
-    let mut last_block = match chain::get_block_count() {
-        Ok(block_count) => block_count.parse().expect("Failed to parse current block count"),
-        Err(err) => {
-            error!("Error fetching block count: {}", err);
-            exit(1);
-        }
-    };
+    let mut last_block = source
+        .get_block_count()
+        .await
+        .map_err(|err| format!("Error fetching block count: {}", err))?
+        .saturating_sub(min_confirmations);
 
     if last_block < starting_block {
         last_block = starting_block
     }
 
-    (starting_block, last_block)
+    Ok((starting_block, last_block))
 }
 
-fn handle_inputs() -> StartupParams {
+fn handle_inputs(cli: Cli) -> StartupParams {
 
-    let cli = Cli::parse();
+    let start_height = cli.start_height.unwrap_or_default();
 
-    let start_height = if let Some(height) = cli.start_height {
+    let end_height = if let Some(height) = cli.end_height {
         height
     } else {
-        0
+        let block_count = cli.blocks.unwrap_or(10);
+        EndHeight::Height(start_height + block_count)
     };
 
-    let end_height = if let Some(height) = cli.end_height {
-        height
-    } else {
-        let block_count = if let Some(count) = cli.blocks {
-            count
-        } else {
-            10
-        };
-        start_height + block_count
+    // reindex runs once over its range, shards cover the whole range up to the tip unless told otherwise
+    let (start_height, end_height) = match cli.command {
+        Some(Commands::Reindex { from, to }) => (from, EndHeight::Height(to)),
+        _ if cli.shard_size.is_some() => (
+            cli.start_height.unwrap_or_else(|| cli.network.default_start_height()),
+            cli.end_height.unwrap_or(EndHeight::BelowTip(cli.min_confirmations)),
+        ),
+        _ => (start_height, end_height),
     };
 
     StartupParams{ 
-        start_height: start_height, 
-        end_height: end_height, 
-        continuous_index: start_height == 0, 
-        db_path: String::from("blocks.db"),
-        seek_prev_outs: cli.seek_prev_outs,
+        network: cli.network,
+        start_height, 
+        end_height, 
+        continuous_index: start_height == 0 && cli.shard_size.is_none() && !cli.worker && !matches!(cli.command, Some(Commands::Reindex { .. })), 
+        db_path: cli.network.db_path(),
+        prevouts: match (cli.seek_prev_outs, cli.prevouts, cli.backend) {
+            (true, _, _) => Prevouts::Block,
+            // Electrum servers only serve transactions
+            (_, Prevouts::Auto, Backend::Electrum) => Prevouts::Lookup,
+            (_, prevouts, _) => prevouts,
+        },
+        max_inflight_rpc: cli.max_inflight_rpc,
+        max_inflight_tx: cli.max_inflight_tx.filter(|tasks| *tasks > 0),
+        fetch_workers: cli.fetch_workers.max(1),
+        script_cache_entries: cli.script_cache_entries,
+        tweak_threads: cli.tweak_threads.filter(|threads| *threads > 0),
+        rpc_concurrency_probe: cli.rpc_concurrency_probe,
+        allow_ibd: cli.allow_ibd,
+        clamp_to_prune_height: cli.clamp_to_prune_height,
+        checkpoint_verify_interval: cli.checkpoint_verify_interval.filter(|interval| *interval > 0),
+        halt_on_divergence: cli.halt_on_divergence,
+        record_timings: cli.record_timings,
+        record_stats: cli.record_stats,
+        merkle_proofs: cli.merkle_proofs,
+        prune_spent_after: cli.prune_spent_after,
+        rpc: rpc::RpcConfig {
+            host: cli.rpc_host,
+            port: cli.rpc_port.unwrap_or_else(|| rpc::default_rpc_port(cli.network.bitcoin_network())),
+            user: cli.rpc_user,
+            password: cli.rpc_password,
+            cookie_file: cli.rpc_cookie_file,
+            max_retries: cli.rpc_retries,
+            timeout: Duration::from_secs(cli.request_timeout.max(1)),
+            connections: cli.fetch_workers.max(1),
+            proxy: cli.proxy,
+            network: cli.network.bitcoin_network(),
+            max_requests_per_second: cli.max_requests_per_second,
+        },
+        backend: cli.backend,
+        esplora_url: cli.esplora_url,
+        electrum_url: cli.electrum_url,
+        p2p_peer: cli.p2p_peer,
+        fixture_file: cli.fixture_file,
+        record_fixtures: cli.record_fixtures,
+        blocks_dir: cli.blocks_dir,
+        utxo_snapshot: cli.utxo_snapshot,
+        zmq_block_endpoint: cli.zmq_block_endpoint,
+        poll_interval: Duration::from_secs(cli.poll_interval),
+        min_confirmations: cli.min_confirmations,
+        memory_budget_mb: cli.memory_budget_mb.filter(|budget| *budget > 0),
+        dust_limit: cli.dust_limit,
+        speculative_depth: cli.speculative_depth,
+        shard_size: cli.shard_size.filter(|size| *size > 0),
+        queue_worker: cli.worker,
+        worker_id: cli.worker_id.unwrap_or_else(default_worker_id),
     }
 }
 
-async fn index_blocks(startup: StartupParams) {
+// Host name and process id, unique among workers sharing a database
+fn default_worker_id() -> String {
+    let host = std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| String::from("localhost"));
+    format!("{}-{}", host, std::process::id())
+}
 
-    let db = match Database::new(&startup.db_path) {
+// Claim the next range of a sharded run, None once every height has been claimed
+fn claim_shard(db: &Database, startup: &StartupParams, size: u32, from: u32, to: u32) -> Option<(u32, u32)> {
+    match db.claim_range(&startup.worker_id, from, to, size, SHARD_CLAIM_STALE_AFTER) {
+        Ok(Some((first, last))) => {
+            info!("Worker {} claimed heights {} to {}", startup.worker_id, first, last);
+            Some((first, last))
+        }
+        Ok(None) => {
+            info!("Every height from {} to {} has been claimed, worker {} is done", from, to, startup.worker_id);
+            None
+        }
+        Err(err) => {
+            error!("Failed to claim a height range: {}", err);
+            exit(1);
+        }
+    }
+}
+
+fn enqueue_range(db_path: &str, from: u32, to: u32) {
+    let db = match Database::new(db_path) {
         Ok(db) => db,
         Err(err) => {
             error!("Not able to open database: {}", err);
             exit(1);
         }
     };
+    match db.enqueue_heights(from, to) {
+        Ok(added) => info!("Queued {} heights between {} and {} for workers", added, from, to),
+        Err(err) => {
+            error!("Failed to queue heights {} to {}: {}", from, to, err);
+            exit(1);
+        }
+    }
+    db.close();
+}
 
-    let mut current_block = startup.start_height;
-    let mut last_block = startup.end_height;
-    
-    loop {
-        // determine next block based on last block processed in db
-        if startup.continuous_index {
-            (current_block, last_block) = auto_index(&db);
+// Lease the next run of queued heights, None once the queue has nothing left to hand out
+fn lease_from_queue(db: &Database, startup: &StartupParams) -> Option<(u32, u32)> {
+    match db.lease_heights(&startup.worker_id, QUEUE_LEASE_HEIGHTS, QUEUE_LEASE) {
+        Ok(Some((first, last))) => {
+            info!("Worker {} leased heights {} to {}", startup.worker_id, first, last);
+            Some((first, last))
+        }
+        Ok(None) => {
+            info!("Queue drained, worker {} is done", startup.worker_id);
+            None
+        }
+        Err(err) => {
+            error!("Failed to lease heights from the queue: {}", err);
+            exit(1);
         }
+    }
+}
 
-        let mut chain = chain::Chain::new();
-        while current_block <= last_block {
-            let block_hash = match chain::get_block_hash(current_block) {
-                Ok(block_hash_str) => block_hash_str,
-                Err(err) => {
-                    if err.contains("height out of range") {
-                        info!("At current block height");
-                        break;
-                    } else {
-                        error!("Error fetching block hash: {}", err);
-                        exit(1);
-                    }
-                }
-            };
+// Take a stored height off the queue and keep the worker's remaining leases alive
+fn finish_queued_height(db: &Database, startup: &StartupParams, height: u32) {
+    if let Err(err) = db.dequeue_height(height).and_then(|_| db.renew_leases(&startup.worker_id, QUEUE_LEASE)) {
+        warn!("Failed to update the queue after height {}: {}", height, err);
+    }
+}
 
-            // check if the block has been handled
-            if db.get_block(&block_hash).is_ok_and(|x| x.len() > 0) {
-                info!("******** Already processed block hash {}, height: {} ********", block_hash, current_block);
-                current_block += 1;
-                continue;
+// Chain configured with the run's RPC and transaction concurrency, memory budget and dust limit
+fn new_chain(source: &Arc<dyn ChainSource>, startup: &StartupParams) -> chain::Chain {
+    let mut chain = chain::Chain::new(source.clone());
+    chain.set_max_inflight_rpc(startup.max_inflight_rpc);
+    if let Some(tasks) = startup.max_inflight_tx {
+        chain.set_tx_workers(tasks);
+    }
+    if let Some(budget_mb) = startup.memory_budget_mb {
+        chain.set_memory_budget(budget_mb.saturating_mul(1024 * 1024));
+    }
+    if let Some(sats) = startup.dust_limit {
+        chain.set_dust_limit(sats);
+    }
+    chain
+}
+
+// Compare the last `depth` stored blocks with the backend's chain and roll back from the lowest one that was
+// reorganized out, returns that height. Deeper blocks are considered final.
+async fn repair_speculative_tip(db: &Database, source: &dyn ChainSource, depth: u32) -> Result<Option<u32>, String> {
+    let highest = db.get_highest_block().map_err(|e| e.to_string())?;
+    let stored = db.get_block_hashes_in_range(highest.saturating_sub(depth.saturating_sub(1)), highest).map_err(|e| e.to_string())?;
+    for (height, stored_hash) in stored {
+        let reorganized = match source.get_block_hash(height).await {
+            Ok(block_hash) => block_hash != stored_hash,
+            // The chain got shorter
+            Err(err) if err.contains("height out of range") => true,
+            Err(err) => return Err(err),
+        };
+        if reorganized {
+            let removed = db.archive_blocks_in_range(height, u32::MAX).map_err(|e| e.to_string())?;
+            warn!("Block {} at height {} was reorganized out, rolled back {} blocks", stored_hash, height, removed);
+            return Ok(Some(height));
+        }
+    }
+    Ok(None)
+}
+
+// Walk down from `height` while the stored blocks are off the backend's chain and roll back from the lowest of them,
+// returns that height. None when the stored block at `height` is still on the backend's chain.
+async fn rollback_divergence(db: &Database, source: &dyn ChainSource, height: u32) -> Result<Option<u32>, String> {
+    let mut lowest = None;
+    for height in (0..=height).rev() {
+        let Some((_, stored_hash)) = db.get_block_hashes_in_range(height, height).map_err(|e| e.to_string())?.pop() else {
+            break;
+        };
+        let on_chain = match source.get_block_hash(height).await {
+            Ok(block_hash) => block_hash == stored_hash,
+            Err(err) if err.contains("height out of range") => false,
+            Err(err) => return Err(err),
+        };
+        if on_chain {
+            break;
+        }
+        lowest = Some(height);
+    }
+    if let Some(height) = lowest {
+        let removed = db.archive_blocks_in_range(height, u32::MAX).map_err(|e| e.to_string())?;
+        warn!("Stored blocks from height {} are off the backend's chain, rolled back {} blocks", height, removed);
+    }
+    Ok(lowest)
+}
+
+// The node switched branches when the highest stored block is no longer on its chain, every stored block off the
+// new branch is rolled back so it is indexed again, returns the lowest rolled back height
+async fn repair_reorganized_tip(db: &Database, source: &dyn ChainSource) -> Result<Option<u32>, String> {
+    let highest = db.get_highest_block().map_err(|e| e.to_string())?;
+    if highest == 0 {
+        return Ok(None);
+    }
+    rollback_divergence(db, source, highest).await
+}
+
+// Consecutive stored blocks that don't link mean one side of the break was reorganized out, roll it back
+async fn repair_chain_linkage(db: &Database, source: &dyn ChainSource) -> Result<(), String> {
+    let Some(height) = db.get_lowest_unlinked_height().map_err(|e| e.to_string())? else {
+        return Ok(());
+    };
+    warn!("Stored block at height {} does not build on the stored block below it", height);
+    if rollback_divergence(db, source, height).await?.is_none() && rollback_divergence(db, source, height - 1).await?.is_none() {
+        warn!("Both blocks around height {} are on the backend's chain, leaving them", height);
+    }
+    Ok(())
+}
+
+// Cut-through pruning of tweaks spent more than `depth` blocks below the highest stored block
+fn prune_spent_tweaks(db: &Database, depth: u32) {
+    let highest = db.get_highest_block().unwrap_or_default();
+    if highest <= depth {
+        return;
+    }
+    match db.prune_spent_tweaks(highest - depth) {
+        Ok(0) => {}
+        Ok(pruned) => info!("Pruned {} tweaks whose outputs were all spent by height {}", pruned, highest - depth),
+        Err(err) => warn!("Failed to prune spent tweaks: {}", err),
+    }
+}
+
+// Record where indexing of this database began, the first run decides the coverage start
+fn record_coverage_start(db: &Database, height: u32) {
+    match db.get_meta(database::META_COVERAGE_START) {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            info!("Recording coverage start height {}", height);
+            if let Err(err) = db.set_meta(database::META_COVERAGE_START, &height.to_string()) {
+                error!("Failed to record coverage start: {}", err);
             }
+        }
+        Err(err) => error!("Failed to read coverage start: {}", err),
+    }
+}
 
-            let block_hex = match chain::get_block(&block_hash) {
-                Ok(block_str) => block_str,
-                Err(err) => {
-                    error!("Error fetching block: {}", err);
-                    exit(1);
-                }
-            };
+async fn probe_rpc_concurrency(source: Arc<dyn ChainSource>) {
+    info!("Probing node RPC concurrency");
+    match chain::probe_rpc_concurrency(source, 32, 20).await {
+        Ok(results) => {
+            for (level, calls_per_sec) in results.iter() {
+                info!("RPC concurrency {}: {:.1} calls/sec", level, calls_per_sec);
+            }
+            info!("Recommended --max-inflight-rpc {}", chain::recommend_concurrency(&results));
+        }
+        Err(err) => warn!("RPC concurrency probe failed: {}", err),
+    }
+}
 
-            if startup.seek_prev_outs {
-                match chain::get_block_input_transactions(&block_hash) {
-                    Ok(prev_scripts) => chain.set_previous_scripts(prev_scripts),
-                    Err(err) => {
-                        error!("Error fetching prev out scripts: {}", err);
-                        exit(1);
-                    }
-                }
+// Wait until the node answers and, unless allow_ibd, has finished initial block download
+async fn wait_for_node_ready(allow_ibd: bool) -> chain::BlockchainInfo {
+    loop {
+        match chain::get_blockchain_info().await {
+            Ok(info) if !info.initial_block_download => {
+                info!("Node ready at height {}", info.blocks);
+                return info;
+            }
+            Ok(info) if allow_ibd => {
+                warn!("Node is in initial block download ({}/{} blocks, verification progress {:.2}%), indexing behind the tip",
+                    info.blocks, info.headers, info.verification_progress * 100.0);
+                return info;
+            }
+            Ok(info) => info!("Waiting for node initial block download: {}/{} blocks, verification progress {:.2}%",
+                info.blocks, info.headers, info.verification_progress * 100.0),
+            Err(err) => warn!("Waiting for node to become ready: {}", err),
+        }
+        tokio::select! {
+            _ = sleep(NODE_READY_POLL) => {}
+            _ = shutdown::wait() => {
+                info!("Shutdown requested while waiting for the node");
+                exit(0);
             }
-            
-            info!("Processing block hash {}, height: {}", block_hash, current_block);
+        }
+    }
+}
 
-            match chain.process_transactions(&block_hex).await {
-                Ok(tweaks) => {
-                    let has_tweaks = !tweaks.is_empty();
-                    info!("recording tweaks {}", tweaks.len());
-                    for tweak in tweaks {
-                        let _ = db.insert_tweak(&database::Tweak { 
-                            block_hash: block_hash.clone(),
-                            tx_id: tweak.tx_id, 
-                            tweak: tweak.tweak 
-                        });
-                    }
-                    let _ = db.insert_block(&database::Block { 
-                        height: current_block, 
-                        hash: block_hash, 
-                        has_tweaks: has_tweaks,
-                    });
-                },
-                Err(err) => warn!("Not storing block: {}", err)
+// Lowest height a pruned node still has block data for, None when the node keeps every block
+fn prune_floor(info: &chain::BlockchainInfo) -> Option<u32> {
+    if !info.pruned {
+        return None;
+    }
+    let prune_height = info.prune_height.unwrap_or(0);
+    info!("Node is pruned, blocks available from height {} to {}", prune_height, info.blocks);
+    Some(prune_height)
+}
+
+// Starting height adjusted for pruning, heights below the floor cannot be fetched from the node
+fn clamp_start_height(start_height: u32, prune_floor: Option<u32>, clamp: bool) -> Result<u32, String> {
+    match prune_floor {
+        Some(floor) if start_height < floor => {
+            if clamp {
+                Ok(floor)
+            } else {
+                Err(format!("Start height {} is below the node's prune height {}, use --clamp-to-prune-height to start at {}", start_height, floor, floor))
             }
-            current_block += 1;
         }
+        _ => Ok(start_height),
+    }
+}
 
-        if startup.continuous_index {
-            info!("Sleeping for 5 minutes, then try again");
-            sleep(Duration::from_secs(300));
-        } else {
-            db.close();
-            return;
+// Detect the node version and refuse to run when it lacks getblock features in use
+// Returns the node version, 0 when it could not be detected
+async fn check_node_version(db: &Database, prevouts: Prevouts) -> u32 {
+    let network_info = match chain::get_network_info().await {
+        Ok(network_info) => network_info,
+        Err(err) => {
+            warn!("Unable to detect node version: {}", err);
+            return 0;
         }
+    };
+    info!("Connected to node {} (version {})", network_info.subversion, network_info.version);
+    if let Err(err) = db.set_meta(database::META_NODE_VERSION, &network_info.version.to_string()) {
+        warn!("Failed to record node version: {}", err);
     }
 
+    if network_info.version < chain::MIN_NODE_VERSION {
+        error!("Node version {} is too old, at least {} is required for getblock verbosity", network_info.version, chain::MIN_NODE_VERSION);
+        exit(1);
+    }
+    rpc::set_node_version(network_info.version);
+    if prevouts != Prevouts::Lookup && network_info.version < chain::MIN_PREVOUT_NODE_VERSION {
+        warn!("Node version {} does not support getblock verbosity 3, prevouts fetched with blocks use batched getrawtransaction", network_info.version);
+    }
+    network_info.version
 }
 
-#[tokio::main]
-async fn main() {
-    setup_logging();
-    index_blocks( handle_inputs()).await;
+// How to fetch prevouts on this node. Per transaction lookups and the getblock verbosity 2 fallback use
+// getrawtransaction, which only finds confirmed transactions with -txindex.
+fn prevout_strategy(has_txindex: bool, node_version: u32, prevouts: Prevouts) -> Result<Prevouts, String> {
+    if has_txindex || (prevouts == Prevouts::Block && node_version >= chain::MIN_PREVOUT_NODE_VERSION) {
+        return Ok(prevouts);
+    }
+    if node_version == 0 || node_version >= chain::MIN_PREVOUT_NODE_VERSION {
+        return Ok(Prevouts::Block);
+    }
+    Err(format!(
+        "Node version {} runs without -txindex and does not support getblock verbosity 3, restart bitcoind with -txindex=1 or upgrade to 23.0 or later",
+        node_version
+    ))
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::chain::{Chain,get_block_with_input};
+// Choose the prevout strategy from the node's txindex, exiting when neither strategy can work
+async fn check_txindex(node_version: u32, prevouts: Prevouts) -> Prevouts {
+    let has_txindex = match chain::get_txindex_info().await {
+        Ok(Some(txindex)) => {
+            if !txindex.synced {
+                warn!("Node txindex is still syncing (height {}), lookups of later transactions fail until it catches up", txindex.best_block_height);
+            }
+            true
+        }
+        Ok(None) => false,
+        Err(err) => {
+            warn!("Unable to detect txindex: {}", err);
+            return prevouts;
+        }
+    };
+    match prevout_strategy(has_txindex, node_version, prevouts) {
+        Ok(strategy) => {
+            if strategy != prevouts {
+                warn!("Node runs without -txindex, fetching prevouts with each block as with --prevouts block");
+            }
+            strategy
+        }
+        Err(err) => {
+            error!("{}", err);
+            exit(1);
+        }
+    }
+}
 
-    #[test]
-    fn test_process_transactions() {
-        let mut chain = Chain::new();
+// Chain source for the selected backend, recording its responses with --record-fixtures
+fn chain_source(startup: &StartupParams) -> Result<Arc<dyn ChainSource>, String> {
+    let source = backend_source(startup)?;
+    match &startup.record_fixtures {
+        Some(path) => Ok(Arc::new(fixture::RecordingSource::new(source, path.clone())?)),
+        None => Ok(source),
+    }
+}
+
+fn backend_source(startup: &StartupParams) -> Result<Arc<dyn ChainSource>, String> {
+    match startup.backend {
+        Backend::Rpc => Ok(Arc::new(rpc::RpcSource)),
+        Backend::Rest => Ok(Arc::new(rest::RestSource::new(startup.rpc.url()))),
+        Backend::Esplora => match &startup.esplora_url {
+            Some(url) => Ok(Arc::new(esplora::EsploraSource::new(url))),
+            None => Err(String::from("--backend esplora requires --esplora-url")),
+        },
+        Backend::Electrum => match &startup.electrum_url {
+            _ if startup.prevouts == Prevouts::Block => Err(String::from("--prevouts block is not supported by --backend electrum")),
+            Some(url) => Ok(Arc::new(electrum::ElectrumSource::new(url, &startup.db_path))),
+            None => Err(String::from("--backend electrum requires --electrum-url")),
+        },
+        Backend::P2p => {
+            let network = startup.network.bitcoin_network();
+            let peer = startup.p2p_peer.clone().unwrap_or_else(|| format!("127.0.0.1:{}", p2p::default_p2p_port(network)));
+            Ok(Arc::new(p2p::P2pSource::new(peer, network)))
+        }
+        Backend::Replay => match &startup.fixture_file {
+            Some(path) => Ok(Arc::new(fixture::ReplaySource::load(path)?)),
+            None => Err(String::from("--backend replay requires --fixture-file")),
+        },
+    }
+}
+
+// Decoded block plus its prevout scripts when the strategy fetches them with the block
+async fn download_block(source: Arc<dyn ChainSource>, prevouts: Prevouts, block_hash: String) -> Result<(Block, Option<Vec<chain::PreviousScript>>), String> {
+    let (block, previous_scripts) = fetch_block(source.clone(), prevouts, block_hash.clone()).await?;
+    if prevouts != Prevouts::Auto {
+        return Ok((block, previous_scripts));
+    }
+    let previous_scripts = auto_block_prevouts(source.as_ref(), &block, &block_hash, |_| false).await;
+    Ok((block, previous_scripts))
+}
+
+// Decoded block, with its prevout scripts only for --prevouts block, auto leaves the choice to auto_block_prevouts
+async fn fetch_block(source: Arc<dyn ChainSource>, prevouts: Prevouts, block_hash: String) -> Result<(Block, Option<Vec<chain::PreviousScript>>), String> {
+    let (raw_block, previous_scripts) = if prevouts == Prevouts::Block {
+        let (raw_block, previous_scripts) = source
+            .get_block_with_prevouts(&block_hash)
+            .await
+            .map_err(|e| format!("Error fetching prev out scripts: {}", e))?;
+        (raw_block, Some(previous_scripts))
+    } else {
+        (source.get_raw_block(&block_hash).await?, None)
+    };
+    // The raw data is dropped inside the decode task, before the prevouts are fetched
+    let block = task::spawn_blocking(move || raw_block.decode())
+        .await
+        .map_err(|e| format!("Block decode task failed: {}", e))?
+        .map_err(|e| format!("Failed to decode block {}: {}", block_hash, e))?;
+    chain::check_block_hash(&block, &block_hash)?;
+    Ok((block, previous_scripts))
+}
+
+// Prevouts of a Taproot heavy block, one prevout fetch beats looking up each candidate's spent transactions.
+// Spends of cached outputs need neither, None leaves the remaining candidates to per transaction lookups.
+async fn auto_block_prevouts(source: &dyn ChainSource, block: &Block, block_hash: &str, cached: impl Fn(&bitcoin::OutPoint) -> bool) -> Option<Vec<chain::PreviousScript>> {
+    if !chain::prefers_block_prevouts(block, cached) {
+        return None;
+    }
+    debug!("Fetching prevouts of block {} with the block", block_hash);
+    match source.get_prevouts(block_hash).await {
+        Ok(previous_scripts) => Some(previous_scripts),
+        Err(err) => {
+            warn!("Unable to fetch prevouts of block {}, looking them up per transaction: {}", block_hash, err);
+            None
+        }
+    }
+}
+
+// Tweaks that differ between the database and a recomputation of the block, as (txid, tweak) pairs
+#[derive(Debug, Default, PartialEq)]
+struct TweakDiff {
+    // Recomputed but not stored
+    missing: Vec<(String, String)>,
+    // Stored but not recomputed
+    unexpected: Vec<(String, String)>,
+}
+
+impl TweakDiff {
+    fn new(mut stored: Vec<(String, String)>, mut recomputed: Vec<(String, String)>) -> Self {
+        stored.sort();
+        recomputed.sort();
+        Self {
+            missing: recomputed.iter().filter(|tweak| stored.binary_search(tweak).is_err()).cloned().collect(),
+            unexpected: stored.iter().filter(|tweak| recomputed.binary_search(tweak).is_err()).cloned().collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.unexpected.is_empty()
+    }
+}
+
+// Refetch and recompute a stored block, returning how the result differs from the database
+async fn verify_block(db: &Database, source: &Arc<dyn ChainSource>, height: u32, startup: &StartupParams) -> Result<TweakDiff, String> {
+    let block_hash = source.get_block_hash(height).await?;
+    let stored = db.get_tweaks(&block_hash).map_err(|e| format!("Failed to read stored tweaks: {}", e))?;
+
+    let (block, previous_scripts) = download_block(source.clone(), startup.prevouts, block_hash).await?;
+    let mut chain = new_chain(source, startup);
+    if let Some(previous_scripts) = previous_scripts {
+        chain.set_previous_scripts(previous_scripts);
+    }
+    let recomputed = chain.process_block(&block).await.map_err(|e| e.to_string())?;
+
+    let mut diff = TweakDiff::new(
+        stored.into_iter().map(|t| (t.tx_id, t.tweak)).collect(),
+        recomputed.iter().map(|t| (t.tx_id.clone(), t.tweak.clone())).collect(),
+    );
+    // Tweaks removed by --prune-spent-after are not missing
+    let pruned_spent_by = db.get_meta(database::META_PRUNED_SPENT_BY).ok().flatten().and_then(|height| height.parse().ok());
+    if let Some(pruned_spent_by) = pruned_spent_by {
+        diff.missing.retain(|(tx_id, _)| !recomputed.iter().any(|t| t.tx_id == *tx_id && was_pruned(db, t, pruned_spent_by)));
+    }
+    Ok(diff)
+}
+
+// Pruning removes a tweak once all its Taproot outputs were spent at or below the pruned height
+fn was_pruned(db: &Database, tweak: &chain::Tweak, pruned_spent_by: u32) -> bool {
+    !tweak.outputs.is_empty()
+        && tweak.outputs.iter().all(|output| {
+            db.get_taproot_utxo(&tweak.tx_id, output.vout)
+                .ok()
+                .flatten()
+                .and_then(|utxo| utxo.spent_height)
+                .is_some_and(|spent_height| spent_height <= pruned_spent_by)
+        })
+}
 
-        let block_hash = "0000000000000000000149ba526848af34e4dbed814a85859753fadf5594e226";
+// Verify the block indexed one interval ago, catching extraction regressions in long running indexers
+async fn checkpoint_verify(db: &Database, source: &Arc<dyn ChainSource>, current_block: u32, interval: u32, startup: &StartupParams) {
+    let height = current_block.saturating_sub(interval);
+    let block_stored = source.get_block_hash(height)
+        .await
+        .map(|hash| db.get_block(&hash).is_ok_and(|x| !x.is_empty()))
+        .unwrap_or(false);
+    if !block_stored {
+        debug!("Skipping checkpoint verification, block {} not indexed", height);
+        return;
+    }
+
+    match verify_block(db, source, height, startup).await {
+        Ok(diff) if diff.is_empty() => info!("Checkpoint verification passed for block {}", height),
+        Ok(_) => {
+            error!("Checkpoint verification failed: stored tweaks for block {} differ from recomputed tweaks", height);
+            if startup.halt_on_divergence {
+                exit(1);
+            }
+        }
+        Err(err) => warn!("Checkpoint verification of block {} could not complete: {}", height, err),
+    }
+}
+
+// Recompute every stored block of a range and report those whose tweaks differ from the database, exits with an
+// error when any block did not match or could not be verified
+async fn verify_range(mut startup: StartupParams, source: Arc<dyn ChainSource>, from: u32, to: u32) {
+    let db = match Database::new(&startup.db_path) {
+        Ok(db) => db,
+        Err(err) => {
+            error!("Not able to open database: {}", err);
+            exit(1);
+        }
+    };
+    check_node(&db, &mut startup).await;
 
-        let block_hex = match get_block_with_input(&block_hash) {
-            Ok(block_str) => block_str,
+    let (mut verified, mut mismatched, mut not_indexed, mut failed) = (0, 0, 0, 0);
+    let mut progress = progress::Progress::new(from, to);
+    for height in from..=to {
+        if shutdown::requested() {
+            break;
+        }
+        let block_hash = match source.get_block_hash(height).await {
+            Ok(block_hash) => block_hash,
             Err(err) => {
-                err
+                error!("Block {} could not be verified: {}", height, err);
+                failed += 1;
+                continue;
             }
         };
+        if !db.get_block(&block_hash).is_ok_and(|x| !x.is_empty()) {
+            warn!("Block {} at height {} is not indexed", block_hash, height);
+            not_indexed += 1;
+            continue;
+        }
+
+        match verify_block(&db, &source, height, &startup).await {
+            Ok(diff) if diff.is_empty() => verified += 1,
+            Ok(diff) => {
+                error!("Block {} at height {}: {} recomputed tweaks not stored, {} stored tweaks not recomputed",
+                    block_hash, height, diff.missing.len(), diff.unexpected.len());
+                for (txid, tweak) in diff.missing.iter() {
+                    info!("  missing {} {}", txid, tweak);
+                }
+                for (txid, tweak) in diff.unexpected.iter() {
+                    info!("  unexpected {} {}", txid, tweak);
+                }
+                mismatched += 1;
+            }
+            Err(err) => {
+                error!("Block {} could not be verified: {}", height, err);
+                failed += 1;
+            }
+        }
+        progress.record(height, &block_hash, 0);
+        if let Some(line) = progress.report() {
+            info!("{}", line);
+        }
+    }
+    db.close();
+
+    info!("Verified heights {} to {}: {} matched, {} mismatched, {} not indexed, {} could not be verified",
+        from, to, verified, mismatched, not_indexed, failed);
+    if mismatched > 0 || failed > 0 {
+        exit(1);
+    }
+}
+
+// Drop the stored blocks of a range so indexing it again recomputes every block
+fn clear_reindex_range(db_path: &str, from: u32, to: u32) {
+    if from > to {
+        error!("Reindex range {} to {} is empty", from, to);
+        exit(1);
+    }
+    let db = match Database::new(db_path) {
+        Ok(db) => db,
+        Err(err) => {
+            error!("Not able to open database: {}", err);
+            exit(1);
+        }
+    };
+    match db.delete_blocks_in_range(from, to) {
+        Ok(removed) => info!("Removed {} stored blocks between heights {} and {}, reindexing them", removed, from, to),
+        Err(err) => {
+            error!("Failed to remove blocks {} to {}: {}", from, to, err);
+            exit(1);
+        }
+    }
+    db.close();
+}
+
+fn export_tweaks(db_path: &str, out_dir: &str, chunk_size: u32, since_id: Option<i64>) {
+    let db = match Database::new(db_path) {
+        Ok(db) => db,
+        Err(err) => {
+            error!("Not able to open database: {}", err);
+            exit(1);
+        }
+    };
+
+    if let Some(since_id) = since_id {
+        match export::export_since_id(&db, out_dir, since_id) {
+            Ok(max_id) => info!("Export cursor: max tweak id emitted {}", max_id),
+            Err(err) => {
+                error!("Export failed: {}", err);
+                exit(1);
+            }
+        }
+        db.close();
+        return;
+    }
+
+    match export::export_chunks(&db, out_dir, chunk_size.max(1)) {
+        Ok(manifest) => info!("Exported {} chunks to {}", manifest.chunks.len(), out_dir),
+        Err(err) => {
+            error!("Export failed: {}", err);
+            exit(1);
+        }
+    }
+    db.close();
+}
+
+// Print every stored output in the range paying the address, for auditing the index or recovering a wallet
+fn scan_address(db_path: &str, scan_key: &str, spend_pubkey: &str, labels: &[u32], from: u32, to: u32) {
+    let keys = match scan::ScanKeys::parse(scan_key, spend_pubkey, labels) {
+        Ok(keys) => keys,
+        Err(err) => {
+            error!("{}", err);
+            exit(1);
+        }
+    };
+    let db = match Database::new(db_path) {
+        Ok(db) => db,
+        Err(err) => {
+            error!("Not able to open database: {}", err);
+            exit(1);
+        }
+    };
+
+    match scan::scan_range(&db, &keys, from, to) {
+        Ok(matches) => {
+            for found in matches.iter() {
+                match serde_json::to_string(found) {
+                    Ok(line) => println!("{}", line),
+                    Err(err) => error!("Failed to encode match in {}: {}", found.tx_id, err),
+                }
+            }
+        }
+        Err(err) => {
+            error!("Scan failed: {}", err);
+            exit(1);
+        }
+    }
+    db.close();
+}
+
+// Compare the stored tweaks of every indexed block in the range with the remote's, exits non-zero when any differ
+async fn crosscheck_remote(db_path: &str, remote: &str, from: u32, to: u32) {
+    let db = match Database::new(db_path) {
+        Ok(db) => db,
+        Err(err) => {
+            error!("Not able to open database: {}", err);
+            exit(1);
+        }
+    };
+    let blocks = match db.get_block_hashes_in_range(from, to) {
+        Ok(blocks) => blocks,
+        Err(err) => {
+            error!("Failed to read stored blocks: {}", err);
+            exit(1);
+        }
+    };
+
+    let (mut matched, mut divergent, mut failed) = (0, 0, 0);
+    for (height, block_hash) in blocks.iter() {
+        if shutdown::requested() {
+            break;
+        }
+        let local = match db.get_tweaks(block_hash) {
+            Ok(tweaks) => tweaks.into_iter().map(|tweak| tweak.tweak).collect(),
+            Err(err) => {
+                error!("Block {} could not be read: {}", height, err);
+                failed += 1;
+                continue;
+            }
+        };
+        let remote_tweaks = match crosscheck::fetch_remote_tweaks(crosscheck::remote_url(remote, *height, block_hash)).await {
+            Ok(tweaks) => tweaks,
+            Err(err) => {
+                error!("Block {} could not be fetched from the remote: {}", height, err);
+                failed += 1;
+                continue;
+            }
+        };
+        let diff = crosscheck::TweakSetDiff::new(local, remote_tweaks);
+        if diff.is_empty() {
+            matched += 1;
+            continue;
+        }
+        error!("Block {} at height {}: {} tweaks only stored here, {} only on the remote", block_hash, height, diff.local_only.len(), diff.remote_only.len());
+        for tweak in diff.local_only.iter() {
+            info!("  local only {}", tweak);
+        }
+        for tweak in diff.remote_only.iter() {
+            info!("  remote only {}", tweak);
+        }
+        divergent += 1;
+    }
+    db.close();
+
+    info!("Crosschecked {} stored blocks of heights {} to {}: {} matched, {} divergent, {} could not be compared",
+        blocks.len(), from, to, matched, divergent, failed);
+    if divergent > 0 || failed > 0 {
+        exit(1);
+    }
+}
+
+type BlockDownload = JoinHandle<Result<(Block, Option<Vec<chain::PreviousScript>>), String>>;
+
+// Blocks being downloaded and decoded ahead of the indexing loop, in height order
+struct Prefetcher {
+    downloads: VecDeque<(u32, String, BlockDownload)>,
+    next_height: u32,
+}
+
+impl Prefetcher {
+    fn new(height: u32) -> Self {
+        Self { downloads: VecDeque::new(), next_height: height }
+    }
+
+    // Keep up to --fetch-workers downloads running for the heights after current, skipping indexed blocks
+    async fn fill(&mut self, db: &Database, source: &Arc<dyn ChainSource>, startup: &StartupParams, current: u32, last: u32) {
+        self.next_height = self.next_height.max(current + 1);
+        while self.downloads.len() < startup.fetch_workers && self.next_height <= last {
+            let height = self.next_height;
+            let Ok(block_hash) = source.get_block_hash(height).await else {
+                return;
+            };
+            self.next_height += 1;
+            if db.get_block(&block_hash).is_ok_and(|x| !x.is_empty()) {
+                continue;
+            }
+            let download = task::spawn(fetch_block(source.clone(), startup.prevouts, block_hash.clone()));
+            self.downloads.push_back((height, block_hash, download));
+        }
+    }
+
+    // The prefetched download of this block, stale ones (skipped heights or a reorged hash) are dropped
+    fn take(&mut self, height: u32, block_hash: &str) -> Option<BlockDownload> {
+        while let Some((queued_height, queued_hash, download)) = self.downloads.pop_front() {
+            if queued_height > height {
+                self.downloads.push_front((queued_height, queued_hash, download));
+                return None;
+            }
+            if queued_height == height && queued_hash == block_hash {
+                return Some(download);
+            }
+            download.abort();
+        }
+        None
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        for (_, _, download) in &self.downloads {
+            download.abort();
+        }
+    }
+}
+
+// Subscribe to block announcements, polling remains the fallback when the endpoint is unreachable
+async fn connect_block_notifier(endpoint: &str) -> Option<zmq::BlockNotifier> {
+    match zmq::BlockNotifier::connect(endpoint).await {
+        Ok(notifier) => {
+            info!("Listening for block notifications on {}", endpoint);
+            Some(notifier)
+        }
+        Err(err) => {
+            warn!("ZMQ unavailable, falling back to polling: {}", err);
+            None
+        }
+    }
+}
+
+// Wait for a new block announcement, or poll again after the poll interval
+async fn wait_for_next_block(block_notifier: &mut Option<zmq::BlockNotifier>, poll_interval: Duration) {
+    let Some(notifier) = block_notifier else {
+        info!("Sleeping for {:?}, then try again", poll_interval);
+        tokio::select! {
+            _ = sleep(poll_interval) => {}
+            _ = shutdown::wait() => {}
+        }
+        return;
+    };
+
+    info!("Waiting up to {:?} for a block notification", poll_interval);
+    let notification = tokio::select! {
+        notification = notifier.wait_for_block(poll_interval) => notification,
+        _ = shutdown::wait() => return,
+    };
+    match notification {
+        Ok(Some(block_hash)) => info!("Notified of new block {}", block_hash),
+        Ok(None) => debug!("No block notification received, polling"),
+        Err(err) => {
+            warn!("Block notification failed, falling back to polling: {}", err);
+            *block_notifier = None;
+        }
+    }
+}
+
+// Close the database after the last stored block and report what this run did
+fn stop_indexing(db: Database, next_height: u32, started: Instant) {
+    db.close();
+    info!(
+        "Stopped after storing {} blocks with {} tweaks in {:.1?}, the next run resumes at height {}",
+        BLOCKS_STORED.load(Ordering::Relaxed),
+        TWEAKS_STORED.load(Ordering::Relaxed),
+        started.elapsed(),
+        next_height
+    );
+}
+
+// Store the block's tweaks, Taproot outputs, spent outpoint index and filters, then the block row that marks it indexed, marking the stored outputs it spends
+fn store_block(db: &Database, height: u32, block_hash: &str, block: &Block, tweaks: Vec<chain::Tweak>, merkle_proofs: bool) -> Result<(), chain::BlockError> {
+    info!("recording tweaks {}", tweaks.len());
+    chain::check_block_hash(block, block_hash).map_err(|err| chain::BlockError::Fetch(format!("block at height {}: {}", height, err)))?;
+    let merkle_tree = merkle_proofs.then(|| chain::MerkleTree::new(block));
+    let stored = database::Block {
+        height,
+        hash: block_hash.to_string(),
+        has_tweaks: !tweaks.is_empty(),
+        prev_hash: Some(block.header.prev_blockhash.to_string()),
+        time: Some(block.header.time),
+    };
+    let tweaks: Vec<database::Tweak> = tweaks
+        .into_iter()
+        .map(|tweak| database::Tweak {
+            block_hash: block_hash.to_string(),
+            tx_index: Some(tweak.tx_index),
+            tx_id: tweak.tx_id,
+            tweak: tweak.tweak,
+            max_output_value: Some(tweak.max_output_value),
+            merkle_proof: merkle_tree.as_ref().map(|tree| tree.branch(tweak.tx_index)),
+            outputs: tweak.outputs,
+        })
+        .collect();
+    let created = chain::created_taproot_outputs(block)
+        .into_iter()
+        .map(|(outpoint, output)| database::TaprootUtxo {
+            tx_id: outpoint.txid.to_string(),
+            vout: outpoint.vout,
+            script_pubkey: output.script_pubkey.to_hex_string(),
+            value: output.value.to_sat(),
+            height,
+            spent_height: None,
+        })
+        .collect();
+    let outpoints = database::BlockOutpoints {
+        created,
+        spent: chain::spent_outpoints(block),
+        spent_index: chain::spent_outpoint_index(block),
+        filters: vec![
+            (database::FILTER_NEW_UTXOS, chain::new_utxo_filter(block)),
+            (database::FILTER_SPENT, chain::spent_filter(block)),
+        ],
+    };
+    db.insert_block_with_outpoints(&stored, &tweaks, &outpoints)
+        .map_err(|err| chain::BlockError::Store(format!("block {} at height {}: {}", block_hash, height, err)))?;
+    BLOCKS_STORED.fetch_add(1, Ordering::Relaxed);
+    TWEAKS_STORED.fetch_add(tweaks.len() as u64, Ordering::Relaxed);
+    Ok(())
+}
+
+// Record a retryable block failure and move on, stop on fatal ones or when blocks keep failing in a row
+fn handle_block_error(db: &Database, height: u32, block_hash: &str, err: &chain::BlockError, consecutive_failures: &mut u32) {
+    if err.is_fatal() {
+        error!("{}", err);
+        exit(1);
+    }
+    record_failed_block(db, height, block_hash, &err.to_string());
+    *consecutive_failures += 1;
+    if *consecutive_failures >= MAX_CONSECUTIVE_BLOCK_FAILURES {
+        error!("{} blocks failed in a row up to height {}, stopping: {}", consecutive_failures, height, err);
+        exit(1);
+    }
+}
 
-        println!("json: {:?}",block_hex);
-        let has_tweaks = chain.process_transactions(&block_hex);
+// Keep a block that could not be processed in failed_blocks so it is retried later
+fn record_failed_block(db: &Database, height: u32, block_hash: &str, err: &str) {
+    warn!("Not storing block: {}", err);
+    if let Err(err) = db.record_failed_block(height, block_hash, err) {
+        error!("Failed to record failed block at height {}: {}", height, err);
+    }
+}
+
+// Keep the transactions left out of a stored block in deferred_txs for a later retry
+fn record_deferred(db: &Database, height: u32, block_hash: &str, deferred: Vec<chain::DeferredTransaction>) {
+    for tx in deferred {
+        let deferred_tx = database::DeferredTx { block_hash: block_hash.to_string(), height, tx_index: tx.tx_index, tx_id: tx.tx_id, error: tx.error, attempts: 1 };
+        if let Err(err) = db.record_deferred_tx(&deferred_tx) {
+            error!("Failed to record deferred tx {} at height {}: {}", deferred_tx.tx_id, height, err);
+        }
+    }
+}
+
+// Compute the tweak of a deferred transaction again, returns its tweak count once stored
+async fn retry_deferred_tx(db: &Database, source: &Arc<dyn ChainSource>, startup: &StartupParams, deferred: &database::DeferredTx) -> Result<usize, String> {
+    let tx: bitcoin::Transaction = deserialize_hex(&source.get_transaction(&deferred.tx_id).await?).map_err(|e| e.to_string())?;
+    let tweaks = new_chain(source, startup).process_transaction(deferred.tx_index, &tx).await.map_err(|e| e.to_string())?;
+    let tweaks: Vec<database::Tweak> = tweaks
+        .into_iter()
+        .map(|tweak| database::Tweak { block_hash: deferred.block_hash.clone(), tx_index: Some(tweak.tx_index), tx_id: tweak.tx_id, tweak: tweak.tweak, max_output_value: Some(tweak.max_output_value), merkle_proof: None, outputs: tweak.outputs })
+        .collect();
+    db.resolve_deferred_tx(&deferred.block_hash, &deferred.tx_id, &tweaks).map_err(|e| e.to_string())?;
+    Ok(tweaks.len())
+}
+
+// Try the transactions recorded in deferred_txs again, returns how many still fail
+async fn retry_deferred_txs(db: &Database, source: &Arc<dyn ChainSource>, startup: &StartupParams) -> usize {
+    let deferred = match db.get_deferred_txs() {
+        Ok(deferred) => deferred,
+        Err(err) => {
+            error!("Failed to read deferred transactions: {}", err);
+            return 0;
+        }
+    };
+    if deferred.is_empty() {
+        return 0;
+    }
+    info!("Retrying {} transactions with unresolved prevouts", deferred.len());
+    let mut remaining = 0;
+    for deferred_tx in deferred {
+        if shutdown::requested() {
+            break;
+        }
+        match retry_deferred_tx(db, source, startup, &deferred_tx).await {
+            Ok(tweaks) => info!("Recovered {} tweaks of tx {} at height {}", tweaks, deferred_tx.tx_id, deferred_tx.height),
+            Err(error) => {
+                warn!("Tx {} at height {} still has unresolved prevouts: {}", deferred_tx.tx_id, deferred_tx.height, error);
+                if let Err(err) = db.record_deferred_tx(&database::DeferredTx { error, ..deferred_tx }) {
+                    error!("Failed to record deferred tx: {}", err);
+                }
+                remaining += 1;
+            }
+        }
+    }
+    remaining
+}
+
+// Fetch and process a block again, returns its tweak count once stored
+async fn retry_block(db: &Database, source: &Arc<dyn ChainSource>, startup: &StartupParams, height: u32) -> Result<usize, String> {
+    let block_hash = source.get_block_hash(height).await?;
+    let (block, block_prevouts) = download_block(source.clone(), startup.prevouts, block_hash.clone()).await?;
+    let mut chain = new_chain(source, startup);
+    chain.set_previous_scripts(block_prevouts.unwrap_or_default());
+    let tweaks = chain.process_block(&block).await.map_err(|e| e.to_string())?;
+    let count = tweaks.len();
+    store_block(db, height, &block_hash, &block, tweaks, startup.merkle_proofs).map_err(|e| e.to_string())?;
+    record_deferred(db, height, &block_hash, chain.take_deferred());
+    Ok(count)
+}
+
+// Try the blocks recorded in failed_blocks again, returns how many still fail
+async fn retry_failed_blocks(db: &Database, source: &Arc<dyn ChainSource>, startup: &StartupParams) -> usize {
+    let failed = match db.get_failed_blocks() {
+        Ok(failed) => failed,
+        Err(err) => {
+            error!("Failed to read failed blocks: {}", err);
+            return 0;
+        }
+    };
+    if failed.is_empty() {
+        return 0;
+    }
+    info!("Retrying {} blocks that failed before", failed.len());
+    let mut remaining = 0;
+    for failed_block in failed {
+        if shutdown::requested() {
+            break;
+        }
+        match retry_block(db, source, startup, failed_block.height).await {
+            Ok(tweaks) => info!("Stored block at height {} with {} tweaks after {} failed attempts", failed_block.height, tweaks, failed_block.attempts),
+            Err(err) => {
+                record_failed_block(db, failed_block.height, &failed_block.hash, &err);
+                remaining += 1;
+            }
+        }
+    }
+    remaining
+}
+
+// retry-failed subcommand, exits non-zero while any block or deferred transaction still fails
+async fn retry_failed(mut startup: StartupParams, source: Arc<dyn ChainSource>) {
+    let db = match Database::new(&startup.db_path) {
+        Ok(db) => db,
+        Err(err) => {
+            error!("Not able to open database: {}", err);
+            exit(1);
+        }
+    };
+    check_node(&db, &mut startup).await;
+    let remaining_blocks = retry_failed_blocks(&db, &source, &startup).await;
+    let remaining_txs = retry_deferred_txs(&db, &source, &startup).await;
+    db.close();
+    if remaining_blocks > 0 || remaining_txs > 0 {
+        error!("{} blocks and {} transactions still failed", remaining_blocks, remaining_txs);
+        exit(1);
+    }
+}
+
+// Process and store a block whose prevouts the chain already holds, recording a failure and the progress
+async fn index_decoded_block(db: &Database, chain: &mut chain::Chain, height: u32, block: &Block, startup: &StartupParams, progress: &mut progress::Progress, consecutive_failures: &mut u32) {
+    let block_hash = block.block_hash().to_string();
+    let indexed = match chain.process_block(block).await {
+        Ok(tweaks) => {
+            let count = tweaks.len();
+            store_block(db, height, &block_hash, block, tweaks, startup.merkle_proofs).map(|_| count)
+        }
+        Err(err) => Err(chain::BlockError::Compute(err.to_string())),
+    };
+    let block_tweaks = match indexed {
+        Ok(count) => {
+            record_deferred(db, height, &block_hash, chain.take_deferred());
+            *consecutive_failures = 0;
+            count
+        }
+        Err(err) => {
+            handle_block_error(db, height, &block_hash, &err, consecutive_failures);
+            0
+        }
+    };
+    progress.record(height, &block_hash, block_tweaks);
+    if let Some(line) = progress.report() {
+        info!("{}", line);
+    }
+}
+
+// Index from the node's blk*.dat files, building the UTXO set from genesis so every prevout resolves without RPC.
+// Returns the last height indexed, None when the files can't be used and the backend takes over right away.
+async fn index_block_files(db: &Database, source: &Arc<dyn ChainSource>, startup: &StartupParams, blocks_dir: &Path, start_height: u32, end_height: Option<u32>) -> Option<u32> {
+    let files = match blkfile::BlockFiles::open(blocks_dir, startup.network.bitcoin_network()) {
+        Ok(files) => files,
+        Err(err) => {
+            warn!("Not reading block files: {}", err);
+            return None;
+        }
+    };
+    let best_chain = files.best_chain();
+    let Some(tip_height) = (best_chain.len() as u32).checked_sub(1) else {
+        warn!("Block files don't reach back to genesis (pruned node?), indexing from the backend");
+        return None;
+    };
+    let last_height = end_height.map_or(tip_height.saturating_sub(startup.min_confirmations), |end| end.min(tip_height));
+    info!("Indexing heights {} to {} from block files", start_height, last_height);
+    record_coverage_start(db, start_height);
+
+    let mut chain = new_chain(source, startup);
+    let mut utxos = blkfile::UtxoMap::default();
+    let mut progress = progress::Progress::new(start_height, last_height);
+    let mut last_indexed = None;
+    let mut consecutive_failures = 0;
+    for (height, block_hash) in best_chain.iter().enumerate().take(last_height as usize + 1) {
+        let height = height as u32;
+        if shutdown::requested() {
+            break;
+        }
+        let block = match files.read_block(block_hash) {
+            Ok(block) => block,
+            Err(err) => {
+                warn!("Stopping block file sync at height {}: {}", height, err);
+                break;
+            }
+        };
+        let spent = utxos.connect_block(&block);
+        if height < start_height {
+            if height.is_multiple_of(10000) {
+                info!("Building UTXO set from block files: height {}, {} unspent outputs", height, utxos.count());
+            }
+            continue;
+        }
+
+        let block_hash = block_hash.to_string();
+        last_indexed = Some(height);
+        if db.get_block(&block_hash).is_ok_and(|x| !x.is_empty()) {
+            progress.record(height, &block_hash, 0);
+            continue;
+        }
+        chain.set_previous_scripts(previous_scripts(spent));
+
+        info!("Processing block hash {}, height: {} from block files", block_hash, height);
+        index_decoded_block(db, &mut chain, height, &block, startup, &mut progress, &mut consecutive_failures).await;
+    }
+    last_indexed
+}
+
+// Scripts spent from an in-memory UTXO set, in the form the chain's prevout lookup takes
+fn previous_scripts(spent: Vec<(bitcoin::OutPoint, bitcoin::ScriptBuf)>) -> Vec<chain::PreviousScript> {
+    spent
+        .into_iter()
+        .map(|(outpoint, script)| chain::PreviousScript {
+            txid: outpoint.txid.to_string(),
+            vout: outpoint.vout,
+            script: script.to_hex_string(),
+        })
+        .collect()
+}
+
+// Height of the snapshot's base block, which must be on the backend's active chain
+async fn snapshot_base_height(source: &Arc<dyn ChainSource>, base_hash: &str) -> Result<u32, String> {
+    let (block, _) = download_block(source.clone(), Prevouts::Lookup, base_hash.to_string()).await?;
+    let height = block.bip34_block_height().map_err(|e| format!("Snapshot base block {} has no BIP34 height: {}", base_hash, e))? as u32;
+    if source.get_block_hash(height).await? != base_hash {
+        return Err(format!("Snapshot base block {} is not on the active chain", base_hash));
+    }
+    Ok(height)
+}
+
+// Index from a UTXO snapshot: blocks after its base are downloaded from the backend and take their prevouts from
+// the loaded set, returns the last height handled
+async fn index_from_snapshot(db: &Database, source: &Arc<dyn ChainSource>, startup: &StartupParams, snapshot_path: &Path, start_height: u32, end_height: Option<u32>) -> Option<u32> {
+    info!("Loading UTXO snapshot {}", snapshot_path.display());
+    let path = snapshot_path.to_path_buf();
+    let network = startup.network.bitcoin_network();
+    let loaded = task::spawn_blocking(move || snapshot::load(&path, network))
+        .await
+        .unwrap_or_else(|e| Err(format!("Snapshot load task failed: {}", e)));
+    let (base_hash, mut utxos) = match loaded {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            warn!("Not using UTXO snapshot: {}", err);
+            return None;
+        }
+    };
+    let base_height = match snapshot_base_height(source, &base_hash.to_string()).await {
+        Ok(height) => height,
+        Err(err) => {
+            warn!("Not using UTXO snapshot: {}", err);
+            return None;
+        }
+    };
+    if start_height <= base_height {
+        warn!("UTXO snapshot is at height {}, past start height {}, not using it", base_height, start_height);
+        return None;
+    }
+    let tip_height = match source.get_block_count().await {
+        Ok(height) => height,
+        Err(err) => {
+            warn!("Not using UTXO snapshot: {}", err);
+            return None;
+        }
+    };
+    let last_height = end_height.map_or(tip_height.saturating_sub(startup.min_confirmations), |end| end.min(tip_height));
+    info!("Loaded {} unspent outputs at height {}, indexing heights {} to {}", utxos.count(), base_height, start_height, last_height);
+    record_coverage_start(db, start_height);
+
+    let mut chain = new_chain(source, startup);
+    let mut progress = progress::Progress::new(start_height, last_height);
+    let mut last_indexed = None;
+    let mut consecutive_failures = 0;
+    for height in base_height + 1..=last_height {
+        if shutdown::requested() {
+            break;
+        }
+        let block = match source.get_block_hash(height).await {
+            Ok(block_hash) => download_block(source.clone(), Prevouts::Lookup, block_hash).await,
+            Err(err) => Err(err),
+        };
+        let block = match block {
+            Ok((block, _)) => block,
+            Err(err) => {
+                warn!("Stopping snapshot sync at height {}: {}", height, err);
+                break;
+            }
+        };
+        // Blocks between the snapshot and the start height only bring the UTXO set up to date
+        let spent = utxos.connect_block(&block);
+        if height < start_height {
+            continue;
+        }
+
+        let block_hash = block.block_hash().to_string();
+        last_indexed = Some(height);
+        if db.get_block(&block_hash).is_ok_and(|x| !x.is_empty()) {
+            progress.record(height, &block_hash, 0);
+            continue;
+        }
+        chain.set_previous_scripts(previous_scripts(spent));
+
+        info!("Processing block hash {}, height: {} from UTXO snapshot", block_hash, height);
+        index_decoded_block(db, &mut chain, height, &block, startup, &mut progress, &mut consecutive_failures).await;
+    }
+    last_indexed
+}
+
+// Wait for the node and settle the prevout strategy it supports, returning its prune floor.
+// Esplora and Electrum servers and fixtures have no node to wait for, check or prune, P2P peers may not offer RPC.
+async fn check_node(db: &Database, startup: &mut StartupParams) -> Option<u32> {
+    if matches!(startup.backend, Backend::Esplora | Backend::Electrum | Backend::P2p | Backend::Replay) {
+        return None;
+    }
+    let blockchain_info = wait_for_node_ready(startup.allow_ibd).await;
+    if blockchain_info.chain != startup.network.chain_name() {
+        error!("Node is on chain {} but --network {:?} expects {}", blockchain_info.chain, startup.network, startup.network.chain_name());
+        exit(1);
+    }
+    let node_version = check_node_version(db, startup.prevouts).await;
+    startup.prevouts = check_txindex(node_version, startup.prevouts).await;
+    prune_floor(&blockchain_info)
+}
+
+async fn index_blocks(mut startup: StartupParams, source: Arc<dyn ChainSource>) {
+    let started = Instant::now();
+
+    let db = match Database::new(&startup.db_path) {
+        Ok(db) => db,
+        Err(err) => {
+            error!("Not able to open database: {}", err);
+            exit(1);
+        }
+    };
+
+    let prune_floor = check_node(&db, &mut startup).await;
+    if let Err(err) = repair_chain_linkage(&db, source.as_ref()).await {
+        warn!("Unable to repair the stored chain: {}", err);
+    }
+
+    let mut current_block = startup.start_height;
+    let mut last_block = match startup.end_height.resolve(source.as_ref()).await {
+        Ok(height) => height,
+        Err(err) => {
+            error!("Unable to resolve end height: {}", err);
+            exit(1);
+        }
+    };
+    if let EndHeight::BelowTip(depth) = startup.end_height {
+        info!("Indexing up to height {}, {} blocks below the tip", last_block, depth);
+    }
+    let mut blocks_since_verify = 0;
+    let mut consecutive_failures = 0;
+    let mut block_notifier = match &startup.zmq_block_endpoint {
+        Some(endpoint) if startup.continuous_index => connect_block_notifier(endpoint).await,
+        _ => None,
+    };
+
+    // Sharded runs claim their ranges from the whole span between the start and end heights
+    let shard_span = (current_block, last_block);
+    let mut claim = None;
+    if startup.shard_size.is_some() || startup.queue_worker {
+        if let Err(err) = db.set_busy_timeout(SHARD_BUSY_TIMEOUT) {
+            error!("Failed to set the database busy timeout: {}", err);
+            exit(1);
+        }
+        if startup.blocks_dir.is_some() || startup.utxo_snapshot.is_some() {
+            warn!("Block files and UTXO snapshots are not used by shards and queue workers, indexing from the backend");
+            startup.blocks_dir = None;
+            startup.utxo_snapshot = None;
+        }
+    }
+
+    // Initial sync from local data before switching to the backend
+    let (start_height, end_height) = if startup.continuous_index {
+        let highest_block = db.get_highest_block().unwrap_or_default();
+        (if highest_block > 0 { highest_block } else { startup.network.default_start_height() }, None)
+    } else {
+        (current_block, Some(last_block))
+    };
+    if let Some(blocks_dir) = &startup.blocks_dir {
+        if let Some(height) = index_block_files(&db, &source, &startup, blocks_dir, start_height, end_height).await {
+            info!("Block files indexed up to height {}, continuing from the backend", height);
+            current_block = current_block.max(height + 1);
+        }
+    } else if let Some(snapshot_path) = &startup.utxo_snapshot {
+        if let Some(height) = index_from_snapshot(&db, &source, &startup, snapshot_path, start_height, end_height).await {
+            info!("Indexed up to height {} from the UTXO snapshot, continuing from the backend", height);
+            current_block = current_block.max(height + 1);
+        }
+    }
+    if shutdown::requested() {
+        stop_indexing(db, current_block, started);
+        return;
+    }
+    // Prevouts fetched with every block make the cache redundant
+    let cache_entries = if startup.prevouts == Prevouts::Block { 0 } else { startup.script_cache_entries };
+    let mut script_cache = cache::ScriptCache::new(cache_entries);
+
+    loop {
+        // determine next block based on last block processed in db
+        if startup.continuous_index {
+            // Zero confirmation blocks are rechecked to the speculative depth, otherwise a reorg shows at the tip
+            let repaired = match startup.speculative_depth {
+                Some(depth) => repair_speculative_tip(&db, source.as_ref(), depth).await,
+                None => repair_reorganized_tip(&db, source.as_ref()).await,
+            };
+            if let Err(err) = repaired {
+                warn!("Unable to recheck the latest blocks: {}", err);
+            }
+            retry_failed_blocks(&db, &source, &startup).await;
+            retry_deferred_txs(&db, &source, &startup).await;
+            (current_block, last_block) = match auto_index(&db, source.as_ref(), startup.network, startup.min_confirmations).await {
+                Ok(range) => range,
+                Err(err) => {
+                    warn!("{}, trying again", err);
+                    wait_for_next_block(&mut block_notifier, startup.poll_interval).await;
+                    continue;
+                }
+            };
+        }
+        if let Some(size) = startup.shard_size {
+            let Some((first, last)) = claim_shard(&db, &startup, size, shard_span.0, shard_span.1) else {
+                db.close();
+                return;
+            };
+            claim = Some(first);
+            (current_block, last_block) = (first, last);
+        }
+        if startup.queue_worker {
+            let Some(leased) = lease_from_queue(&db, &startup) else {
+                db.close();
+                return;
+            };
+            (current_block, last_block) = leased;
+        }
+        current_block = match clamp_start_height(current_block, prune_floor, startup.clamp_to_prune_height) {
+            Ok(height) if height != current_block => {
+                warn!("Start height {} has been pruned, starting at {}", current_block, height);
+                height
+            }
+            Ok(height) => height,
+            Err(err) => {
+                error!("{}", err);
+                exit(1);
+            }
+        };
+        record_coverage_start(&db, current_block);
+
+        let mut chain = new_chain(&source, &startup);
+        let mut prefetcher = Prefetcher::new(current_block + 1);
+        let mut progress = progress::Progress::new(current_block, last_block);
+        'heights: while current_block <= last_block && !shutdown::requested() {
+            let block_hash = match source.get_block_hash(current_block).await {
+                Ok(block_hash_str) => block_hash_str,
+                Err(err) if err.contains("height out of range") => {
+                    info!("At current block height");
+                    break;
+                }
+                Err(err) => {
+                    handle_block_error(&db, current_block, "", &chain::BlockError::Fetch(err), &mut consecutive_failures);
+                    current_block += 1;
+                    continue;
+                }
+            };
+
+            // Another block stored at this height was reorganized out, roll back to where the stored chain rejoins the node's
+            if let Some((_, stored_hash)) = db.get_block_hashes_in_range(current_block, current_block).unwrap_or_default().pop().filter(|(_, stored_hash)| *stored_hash != block_hash) {
+                match rollback_divergence(&db, source.as_ref(), current_block).await {
+                    Ok(Some(height)) => {
+                        current_block = height;
+                        prefetcher = Prefetcher::new(height + 1);
+                    }
+                    Ok(None) => {
+                        let err = format!("stored block {} is still on the backend's chain", stored_hash);
+                        handle_block_error(&db, current_block, &block_hash, &chain::BlockError::Fetch(err), &mut consecutive_failures);
+                        current_block += 1;
+                    }
+                    Err(err) => {
+                        handle_block_error(&db, current_block, &block_hash, &chain::BlockError::Fetch(err), &mut consecutive_failures);
+                        current_block += 1;
+                    }
+                }
+                continue;
+            }
+
+            // check if the block has been handled
+            if db.get_block(&block_hash).is_ok_and(|x| !x.is_empty()) {
+                info!("******** Already processed block hash {}, height: {} ********", block_hash, current_block);
+                if startup.queue_worker {
+                    finish_queued_height(&db, &startup, current_block);
+                }
+                progress.record(current_block, &block_hash, 0);
+                current_block += 1;
+                continue;
+            }
+
+            let indexed: Result<usize, chain::BlockError> = 'block: {
+                let fetch_started = Instant::now();
+                let download = match prefetcher.take(current_block, &block_hash) {
+                    Some(handle) => handle
+                        .await
+                        .unwrap_or_else(|e| Err(format!("Block download task failed: {}", e))),
+                    None => fetch_block(source.clone(), startup.prevouts, block_hash.clone()).await,
+                };
+                let block = match download {
+                    Ok((block, block_prevouts)) => {
+                        // A block that doesn't build on the stored block below it means that one was reorganized out
+                        let parent = if current_block > 0 { db.get_block_hashes_in_range(current_block - 1, current_block - 1).unwrap_or_default().pop() } else { None };
+                        if let Some((_, parent_hash)) = parent.filter(|(_, parent_hash)| *parent_hash != block.header.prev_blockhash.to_string()) {
+                            warn!("Block {} at height {} does not build on stored block {}", block_hash, current_block, parent_hash);
+                            match rollback_divergence(&db, source.as_ref(), current_block - 1).await {
+                                Ok(Some(height)) => {
+                                    current_block = height;
+                                    prefetcher = Prefetcher::new(height + 1);
+                                    continue 'heights;
+                                }
+                                Ok(None) => break 'block Err(chain::BlockError::Fetch(format!("block does not build on stored block {} of the backend's chain", parent_hash))),
+                                Err(err) => break 'block Err(chain::BlockError::Fetch(err)),
+                            }
+                        }
+                        // Outputs of earlier blocks spent here come from the cache when the block has no prevouts
+                        let cached = script_cache.connect_block(&block);
+                        debug!("{} prevouts found in the script cache, {} outputs cached", cached.len(), script_cache.count());
+                        let block_prevouts = match block_prevouts {
+                            None if startup.prevouts == Prevouts::Auto => {
+                                let spent: HashSet<bitcoin::OutPoint> = cached.iter().map(|(outpoint, _)| *outpoint).collect();
+                                auto_block_prevouts(source.as_ref(), &block, &block_hash, |outpoint| spent.contains(outpoint)).await
+                            }
+                            block_prevouts => block_prevouts,
+                        };
+                        chain.set_previous_scripts(block_prevouts.unwrap_or_else(|| previous_scripts(cached)));
+                        block
+                    }
+                    Err(err) => break 'block Err(chain::BlockError::Fetch(err)),
+                };
+                let fetch_ms = fetch_started.elapsed().as_millis() as u64;
+
+                // Download the next blocks while this one is processed
+                prefetcher.fill(&db, &source, &startup, current_block, last_block).await;
+
+                info!("Processing block hash {}, height: {}", block_hash, current_block);
+
+                let extract_started = Instant::now();
+                let tweaks = match chain.process_block(&block).await {
+                    Ok(tweaks) => tweaks,
+                    Err(err) => break 'block Err(chain::BlockError::Compute(err.to_string())),
+                };
+                let count = tweaks.len();
+                let extract_ms = extract_started.elapsed().as_millis() as u64;
+                let write_started = Instant::now();
+                if let Err(err) = store_block(&db, current_block, &block_hash, &block, tweaks, startup.merkle_proofs) {
+                    break 'block Err(err);
+                }
+                record_deferred(&db, current_block, &block_hash, chain.take_deferred());
+                if startup.queue_worker {
+                    finish_queued_height(&db, &startup, current_block);
+                }
+                if startup.record_timings {
+                    let _ = db.insert_block_timing(&database::BlockTiming {
+                        height: current_block,
+                        fetch_ms,
+                        extract_ms,
+                        write_ms: write_started.elapsed().as_millis() as u64,
+                        tx_count: block.txdata.len() as u32,
+                        eligible_tx_count: chain::candidate_count(&block) as u32,
+                    });
+                }
+                if startup.record_stats {
+                    let stats = chain::block_stats(&block);
+                    let _ = db.insert_eligibility_stats(&database::EligibilityStats {
+                        height: current_block,
+                        taproot_outputs: stats.taproot_outputs,
+                        candidate_txs: stats.candidate_txs,
+                        tweaked_txs: count as u32,
+                        p2tr_inputs: stats.p2tr_inputs,
+                        p2wpkh_inputs: stats.p2wpkh_inputs,
+                        p2sh_p2wpkh_inputs: stats.p2sh_p2wpkh_inputs,
+                        p2pkh_inputs: stats.p2pkh_inputs,
+                    });
+                }
+                Ok(count)
+            };
+            let block_tweaks = match indexed {
+                Ok(count) => {
+                    consecutive_failures = 0;
+                    count
+                }
+                Err(err) => {
+                    handle_block_error(&db, current_block, &block_hash, &err, &mut consecutive_failures);
+                    0
+                }
+            };
+
+            if let Some(interval) = startup.checkpoint_verify_interval {
+                blocks_since_verify += 1;
+                if blocks_since_verify >= interval {
+                    blocks_since_verify = 0;
+                    checkpoint_verify(&db, &source, current_block, interval, &startup).await;
+                }
+            }
+            if let Some(first) = claim {
+                if let Err(err) = db.touch_claim(first, &startup.worker_id) {
+                    warn!("Failed to refresh the claim on heights from {}: {}", first, err);
+                }
+            }
+            progress.record(current_block, &block_hash, block_tweaks);
+            if let Some(line) = progress.report() {
+                info!("{}", line);
+            }
+            current_block += 1;
+        }
+
+        if shutdown::requested() {
+            stop_indexing(db, current_block, started);
+            return;
+        }
+        if let Some(depth) = startup.prune_spent_after {
+            prune_spent_tweaks(&db, depth);
+        }
+        if let Some(first) = claim {
+            // A range cut short (the backend ran out of blocks) stays claimed and is picked up again on restart
+            if current_block <= last_block {
+                warn!("Stopped at height {} before the end of the claimed range {} to {}", current_block, first, last_block);
+                db.close();
+                return;
+            }
+            if let Err(err) = db.complete_claim(first) {
+                error!("Failed to mark heights {} to {} indexed: {}", first, last_block, err);
+                exit(1);
+            }
+        } else if startup.queue_worker {
+            if current_block <= last_block {
+                warn!("Stopped at height {} before the end of the leased heights up to {}", current_block, last_block);
+                db.close();
+                return;
+            }
+        } else if startup.continuous_index {
+            wait_for_next_block(&mut block_notifier, startup.poll_interval).await;
+        } else {
+            db.close();
+            return;
+        }
+    }
+
+}
+
+fn main() {
+    setup_logging();
+    let cli = Cli::parse();
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if let Some(workers) = cli.workers.filter(|workers| *workers > 0) {
+        runtime.worker_threads(workers);
+    }
+    match runtime.enable_all().build() {
+        Ok(runtime) => runtime.block_on(run(cli)),
+        Err(err) => {
+            error!("Unable to start the async runtime: {}", err);
+            exit(1);
+        }
+    }
+}
+
+async fn run(cli: Cli) {
+    if let Some(Commands::Export { out_dir, chunk_size, since_id }) = &cli.command {
+        export_tweaks(&cli.network.db_path(), out_dir, *chunk_size, *since_id);
+        return;
+    }
+    if let Some(Commands::Enqueue { from, to }) = &cli.command {
+        enqueue_range(&cli.network.db_path(), *from, *to);
+        return;
+    }
+    if let Some(Commands::Scan { scan_key, spend_pubkey, labels, from, to }) = &cli.command {
+        scan_address(&cli.network.db_path(), scan_key, spend_pubkey, labels, *from, *to);
+        return;
+    }
+
+    let broadcast_tx = match &cli.command {
+        Some(Commands::Broadcast { tx_hex }) => Some(tx_hex.clone()),
+        _ => None,
+    };
+    let crosscheck = match &cli.command {
+        Some(Commands::Crosscheck { remote, from, to }) => Some((remote.clone(), *from, *to)),
+        _ => None,
+    };
+    let verify_range_heights = match &cli.command {
+        Some(Commands::Verify { from, to }) => Some((*from, *to)),
+        _ => None,
+    };
+    let reindex_range = match &cli.command {
+        Some(Commands::Reindex { from, to }) => Some((*from, *to)),
+        _ => None,
+    };
+    let retry_failed_only = matches!(cli.command, Some(Commands::RetryFailed));
+    let show_progress_bar = cli.progress || std::io::stdout().is_terminal();
+    let startup = handle_inputs(cli);
+    if let Err(err) = rpc::init(&startup.rpc) {
+        error!("Unable to configure RPC connection: {}", err);
+        exit(1);
+    }
+    // The remote is reached with the configured proxy, timeouts and rate limit, the node is not needed
+    if let Some((remote, from, to)) = crosscheck {
+        crosscheck_remote(&startup.db_path, &remote, from, to).await;
+        return;
+    }
+    if let Some(threads) = startup.tweak_threads {
+        if let Err(err) = rayon::ThreadPoolBuilder::new().num_threads(threads).build_global() {
+            error!("Unable to start {} tweak threads: {}", threads, err);
+            exit(1);
+        }
+    }
+    let source = match chain_source(&startup) {
+        Ok(source) => source,
+        Err(err) => {
+            error!("Unable to configure chain backend: {}", err);
+            exit(1);
+        }
+    };
+    if let Some(tx_hex) = broadcast_tx {
+        match source.broadcast(&tx_hex).await {
+            Ok(txid) => println!("{}", txid),
+            Err(err) => {
+                error!("Broadcast failed: {}", err);
+                exit(1);
+            }
+        }
+        return;
+    }
+    if startup.rpc_concurrency_probe {
+        probe_rpc_concurrency(source.clone()).await;
+    }
+    // Shards and queue workers share the database on purpose, verify only reads it
+    let _instance_lock = if startup.shard_size.is_none() && !startup.queue_worker && verify_range_heights.is_none() {
+        match lock::acquire(&startup.db_path) {
+            Ok(lock) => Some(lock),
+            Err(err) => {
+                error!("{}", err);
+                exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    if let Some((from, to)) = reindex_range {
+        clear_reindex_range(&startup.db_path, from, to);
+    }
+    if show_progress_bar {
+        progress::enable_bar();
+    }
+    shutdown::listen();
+    match verify_range_heights {
+        Some((from, to)) => verify_range(startup, source, from, to).await,
+        None if retry_failed_only => retry_failed(startup, source).await,
+        None => index_blocks(startup, source).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::Arc;
+    use crate::chain::{Chain,ChainSource};
+    use crate::fixture::ReplaySource;
+    use clap::Parser;
+    use crate::database::{self, Database};
+    use crate::{auto_index, clamp_start_height, repair_speculative_tip, repair_chain_linkage, repair_reorganized_tip, rollback_divergence, download_block, handle_inputs, store_block, verify_block, EndHeight, prevout_strategy, Cli, Network, Prefetcher, Prevouts, TweakDiff};
+
+    // Recorded regtest chain of the genesis block and block 1
+    fn regtest_source() -> ReplaySource {
+        ReplaySource::load(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/regtest_block_1.json"))).unwrap()
+    }
+
+    #[test]
+    fn test_tweak_diff() {
+        let tweak = |txid: &str, tweak: &str| (txid.to_string(), tweak.to_string());
+        assert!(TweakDiff::new(vec![tweak("b", "2"), tweak("a", "1")], vec![tweak("a", "1"), tweak("b", "2")]).is_empty());
+
+        let diff = TweakDiff::new(vec![tweak("a", "1"), tweak("b", "2")], vec![tweak("a", "1"), tweak("b", "3"), tweak("c", "4")]);
+        assert_eq!(diff.missing, vec![tweak("b", "3"), tweak("c", "4")]);
+        assert_eq!(diff.unexpected, vec![tweak("b", "2")]);
+    }
+
+    #[test]
+    fn test_poll_interval_bounds() {
+        let parse = |args: &[&str]| Cli::try_parse_from([&["tweak-indexer"], args].concat());
+        assert_eq!(parse(&[]).unwrap().poll_interval, 300);
+        assert_eq!(parse(&["--poll-interval", "15"]).unwrap().poll_interval, 15);
+        assert!(parse(&["--poll-interval", "0"]).is_err());
+        assert!(parse(&["--poll-interval", "86400"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_auto_index_min_confirmations() {
+        let source = regtest_source();
+        let db = Database::new(":memory:").unwrap();
+
+        // The fixture's tip is block 1, one confirmation deep keeps the indexer at genesis
+        assert_eq!(auto_index(&db, &source, Network::Regtest, 0).await, Ok((0, 1)));
+        assert_eq!(auto_index(&db, &source, Network::Regtest, 1).await, Ok((0, 0)));
+        assert_eq!(auto_index(&db, &source, Network::Regtest, 6).await, Ok((0, 0)));
+    }
+
+    #[tokio::test]
+    async fn test_end_height() {
+        assert_eq!("800000".parse(), Ok(EndHeight::Height(800000)));
+        assert_eq!("tip-6".parse(), Ok(EndHeight::BelowTip(6)));
+        assert_eq!("tip".parse(), Ok(EndHeight::BelowTip(0)));
+        assert!("tip6".parse::<EndHeight>().is_err());
+        assert!("tip-".parse::<EndHeight>().is_err());
+        assert!("tip+6".parse::<EndHeight>().is_err());
+
+        // Relative heights count down from the fixture's tip at block 1
+        let source = regtest_source();
+        assert_eq!(EndHeight::BelowTip(1).resolve(&source).await, Ok(0));
+        assert_eq!(EndHeight::BelowTip(6).resolve(&source).await, Ok(0));
+        assert_eq!(EndHeight::Height(5).resolve(&source).await, Ok(5));
+    }
+
+    #[tokio::test]
+    async fn test_repair_speculative_tip() {
+        let source = regtest_source();
+        let block_1 = source.get_block_hash(1).await.unwrap();
+        let db = Database::new(":memory:").unwrap();
+        let store = |height: u32, hash: &str| db.insert_block_with_tweaks(&database::Block { height, hash: hash.to_string(), has_tweaks: false, prev_hash: None, time: None }, &[]).unwrap();
+
+        // Blocks still on the chain stay
+        store(1, &block_1);
+        assert_eq!(repair_speculative_tip(&db, &source, 6).await, Ok(None));
+
+        // A block past the backend's tip is rolled back, as is a replaced one when within the depth
+        store(2, "stale");
+        assert_eq!(repair_speculative_tip(&db, &source, 6).await, Ok(Some(2)));
+        assert_eq!(db.get_highest_block().unwrap(), 1);
+        db.delete_blocks_in_range(1, 1).unwrap();
+        store(1, "replaced");
+        assert_eq!(repair_speculative_tip(&db, &source, 1).await, Ok(Some(1)));
+        assert_eq!(db.get_highest_block().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_repair_chain_linkage() {
+        let source = regtest_source();
+        let block_1 = source.get_block_hash(1).await.unwrap();
+        let db = Database::new(":memory:").unwrap();
+        let store = |height: u32, hash: &str, prev_hash: &str| {
+            let block = database::Block { height, hash: hash.to_string(), has_tweaks: false, prev_hash: Some(prev_hash.to_string()), time: None };
+            db.insert_block_with_tweaks(&block, &[]).unwrap();
+        };
+
+        // A linked chain is left alone
+        store(1, &block_1, "00");
+        store(2, "next", &block_1);
+        repair_chain_linkage(&db, &source).await.unwrap();
+        assert_eq!(db.get_highest_block().unwrap(), 2);
+
+        // The side of the break off the backend's chain is rolled back, the block on it stays
+        db.delete_blocks_in_range(2, 2).unwrap();
+        store(2, "stale", "other");
+        repair_chain_linkage(&db, &source).await.unwrap();
+        assert_eq!(db.get_highest_block().unwrap(), 1);
+        assert_eq!(rollback_divergence(&db, &source, 1).await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn test_repair_reorganized_tip() {
+        let source = regtest_source();
+        let block_1 = source.get_block_hash(1).await.unwrap();
+        let db = Database::new(":memory:").unwrap();
+        let store = |height: u32, hash: &str| db.insert_block_with_tweaks(&database::Block { height, hash: hash.to_string(), has_tweaks: true, prev_hash: None, time: None }, &[]).unwrap();
+
+        // Nothing stored or a tip on the chain needs no repair
+        assert_eq!(repair_reorganized_tip(&db, &source).await, Ok(None));
+        store(1, &block_1);
+        assert_eq!(repair_reorganized_tip(&db, &source).await, Ok(None));
+
+        // Orphaned blocks are rolled back whatever their depth, down to the block still on the chain
+        for (height, hash) in [(2, "orphan-2"), (3, "orphan-3"), (4, "orphan-4")] {
+            store(height, hash);
+        }
+        assert_eq!(repair_reorganized_tip(&db, &source).await, Ok(Some(2)));
+        assert_eq!(db.get_highest_block().unwrap(), 1);
+        assert!(db.get_block("orphan-3").unwrap().is_empty());
+        assert_eq!(db.get_stale_blocks().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_clamp_start_height() {
+        // Unpruned nodes and heights above the floor are untouched
+        assert_eq!(clamp_start_height(709632, None, false), Ok(709632));
+        assert_eq!(clamp_start_height(850000, Some(800000), false), Ok(850000));
+
+        // Pruned start heights either move up to the floor or are refused
+        assert_eq!(clamp_start_height(709632, Some(800000), true), Ok(800000));
+        assert!(clamp_start_height(709632, Some(800000), false).is_err());
+    }
+
+    #[test]
+    fn test_network_defaults() {
+        // Mainnet keeps the historical start height and database name
+        assert_eq!(Network::Mainnet.default_start_height(), 709632);
+        assert_eq!(Network::Mainnet.db_path(), "blocks.db");
+
+        assert_eq!(Network::Signet.default_start_height(), 0);
+        assert_eq!(Network::Signet.db_path(), "blocks-signet.db");
+        assert_eq!(Network::Regtest.bitcoin_network(), bitcoin::Network::Regtest);
+    }
+
+    #[test]
+    fn test_prevout_strategy() {
+        // With txindex either strategy works
+        assert_eq!(prevout_strategy(true, 220000, Prevouts::Lookup), Ok(Prevouts::Lookup));
+        assert_eq!(prevout_strategy(true, 220000, Prevouts::Auto), Ok(Prevouts::Auto));
+        assert_eq!(prevout_strategy(true, 270000, Prevouts::Block), Ok(Prevouts::Block));
+        // Without it the prevouts have to come with the block
+        assert_eq!(prevout_strategy(false, 270000, Prevouts::Lookup), Ok(Prevouts::Block));
+        assert_eq!(prevout_strategy(false, 270000, Prevouts::Auto), Ok(Prevouts::Block));
+        assert_eq!(prevout_strategy(false, 270000, Prevouts::Block), Ok(Prevouts::Block));
+        assert!(prevout_strategy(false, 220000, Prevouts::Block).is_err());
+        assert!(prevout_strategy(false, 220000, Prevouts::Auto).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_prefetcher_take() {
+        let mut prefetcher = Prefetcher::new(10);
+        for (height, hash) in [(10, "a"), (12, "c"), (13, "d")] {
+            let download = tokio::spawn(async { Err(String::from("not downloaded")) });
+            prefetcher.downloads.push_back((height, hash.to_string(), download));
+        }
+
+        assert!(prefetcher.take(10, "a").is_some());
+        // Height 11 was already indexed and never queued
+        assert!(prefetcher.take(11, "b").is_none());
+        assert_eq!(prefetcher.downloads.len(), 2);
+        // A reorged hash drops the stale download
+        assert!(prefetcher.take(12, "c2").is_none());
+        assert!(prefetcher.take(13, "d").is_some());
+        assert!(prefetcher.downloads.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_verify_pruned_block() {
+        let source: Arc<dyn ChainSource> = Arc::new(regtest_source());
+        let startup = handle_inputs(Cli::parse_from(["tweak-indexer", "--network", "regtest", "--prevouts", "block"]));
+        let db = Database::new(":memory:").unwrap();
+        let block_hash = source.get_block_hash(1).await.unwrap();
+        let (block, previous_scripts) = download_block(source.clone(), Prevouts::Block, block_hash.clone()).await.unwrap();
+        let mut chain = Chain::new(source.clone());
+        chain.set_previous_scripts(previous_scripts.unwrap());
+        let tweaks = chain.process_block(&block).await.unwrap();
+        let spent: Vec<(String, u32)> = tweaks.iter().flat_map(|t| t.outputs.iter().map(|output| (t.tx_id.clone(), output.vout))).collect();
+        store_block(&db, 1, &block_hash, &block, tweaks, false).unwrap();
+        assert!(verify_block(&db, &source, 1, &startup).await.unwrap().is_empty());
+
+        // Block 2 spends the tweaked outputs, pruning at its height removes the tweak without verification missing it
+        let spender = database::Block { height: 2, hash: String::from("spender"), has_tweaks: false, prev_hash: None, time: None };
+        db.insert_block_with_outpoints(&spender, &[], &database::BlockOutpoints { spent, ..Default::default() }).unwrap();
+        assert_eq!(db.prune_spent_tweaks(2).unwrap(), 1);
+        assert!(db.get_tweaks(&block_hash).unwrap().is_empty());
+        assert!(verify_block(&db, &source, 1, &startup).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions() {
+        let source: Arc<dyn ChainSource> = Arc::new(regtest_source());
+        let block_hash = source.get_block_hash(1).await.unwrap();
+
+        // Prevouts looked up per transaction and fetched with the block give the same tweaks
+        for prevouts in [Prevouts::Lookup, Prevouts::Block, Prevouts::Auto] {
+            let mut chain = Chain::new(source.clone());
+            let (block, previous_scripts) = download_block(source.clone(), prevouts, block_hash.clone()).await.unwrap();
+            if let Some(previous_scripts) = previous_scripts {
+                chain.set_previous_scripts(previous_scripts);
+            }
+            let tweaks = chain.process_block(&block).await.unwrap();
+            assert_eq!(tweaks.len(), 1);
+            assert_eq!(tweaks[0].tx_index, 1);
+            assert_eq!(tweaks[0].tx_id, "fb8b03e369eeea1e024e302d58960a23aa07c6e815ea8b0c5811cc32e3401c15");
+            assert_eq!(tweaks[0].tweak, "021712ee902bb4c8664d63cd0d08878497b308cac31ed1a8e7dd3e5b855fe60c25");
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use async_trait::async_trait;
+use bitcoin::block::Block;
+use bitcoin::consensus::encode::serialize_hex;
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use bitcoin::{absolute, transaction, Amount, CompressedPublicKey, Network, OutPoint, ScriptBuf, Transaction, TxIn, TxOut, Txid, Witness};
+use crate::chain::{ChainSource, PreviousScript};
+
+// In memory chain for unit tests, loaded with hand-crafted blocks and transactions.
+// Starts at the regtest genesis block, transactions not added are unknown to it like pruned or unindexed ones.
+pub struct MockChain {
+    blocks: Vec<Block>,
+    transactions: HashMap<Txid, Transaction>,
+}
+
+impl Default for MockChain {
+    fn default() -> Self {
+        Self { blocks: vec![bitcoin::constants::genesis_block(Network::Regtest)], transactions: HashMap::new() }
+    }
+}
+
+impl MockChain {
+    // A transaction the backend can look up without it being in a block
+    pub fn with_transaction(mut self, tx: Transaction) -> Self {
+        self.transactions.insert(tx.compute_txid(), tx);
+        self
+    }
+
+    // A transaction returned when another txid is requested, like a misbehaving backend
+    pub fn with_transaction_for(mut self, txid: Txid, tx: Transaction) -> Self {
+        self.transactions.insert(txid, tx);
+        self
+    }
+
+    // Mine the transactions on top of the tip, the coinbase is added
+    pub fn with_block(mut self, transactions: Vec<Transaction>) -> Self {
+        let height = self.blocks.len() as u32;
+        let tip = self.blocks.last().unwrap();
+        let mut header = tip.header;
+        header.prev_blockhash = tip.block_hash();
+        header.time += 600;
+
+        let mut block = Block { header, txdata: [vec![coinbase(height)], transactions].concat() };
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        for tx in block.txdata.iter() {
+            self.transactions.insert(tx.compute_txid(), tx.clone());
+        }
+        self.blocks.push(block);
+        self
+    }
+
+    pub fn block(&self, height: u32) -> &Block {
+        &self.blocks[height as usize]
+    }
+
+    fn find_block(&self, block_hash: &str) -> Result<&Block, String> {
+        self.blocks
+            .iter()
+            .find(|block| block.block_hash().to_string() == block_hash)
+            .ok_or_else(|| format!("Block {} not found", block_hash))
+    }
+}
+
+#[async_trait]
+impl ChainSource for MockChain {
+    async fn get_block_count(&self) -> Result<u32, String> {
+        Ok(self.blocks.len() as u32 - 1)
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<String, String> {
+        self.blocks
+            .get(height as usize)
+            .map(|block| block.block_hash().to_string())
+            .ok_or_else(|| String::from("Block height out of range"))
+    }
+
+    async fn get_block(&self, block_hash: &str) -> Result<String, String> {
+        Ok(serialize_hex(self.find_block(block_hash)?))
+    }
+
+    // Prevouts of unknown transactions are left out
+    async fn get_prevouts(&self, block_hash: &str) -> Result<Vec<PreviousScript>, String> {
+        let block = self.find_block(block_hash)?;
+        Ok(block
+            .txdata
+            .iter()
+            .flat_map(|tx| tx.input.iter())
+            .filter_map(|input| {
+                let output = self.transactions.get(&input.previous_output.txid)?.output.get(input.previous_output.vout as usize)?;
+                Some(PreviousScript {
+                    txid: input.previous_output.txid.to_string(),
+                    vout: input.previous_output.vout,
+                    script: output.script_pubkey.to_hex_string(),
+                })
+            })
+            .collect())
+    }
+
+    async fn get_transaction(&self, txid: &str) -> Result<String, String> {
+        let txid: Txid = txid.parse().map_err(|e| format!("Invalid txid {}: {}", txid, e))?;
+        self.transactions.get(&txid).map(serialize_hex).ok_or_else(|| format!("Transaction {} not found", txid))
+    }
+
+    async fn broadcast(&self, tx_hex: &str) -> Result<String, String> {
+        let tx: Transaction = bitcoin::consensus::encode::deserialize_hex(tx_hex).map_err(|e| e.to_string())?;
+        Ok(tx.compute_txid().to_string())
+    }
+}
+
+pub fn pubkey(secret: u8) -> CompressedPublicKey {
+    let secret_key = SecretKey::from_slice(&[secret; 32]).unwrap();
+    CompressedPublicKey(PublicKey::from_secret_key(&Secp256k1::new(), &secret_key))
+}
+
+pub fn coinbase(height: u32) -> Transaction {
+    let script_sig = bitcoin::script::Builder::new().push_int(height as i64).push_int(0).into_script();
+    transaction(vec![TxIn { previous_output: OutPoint::null(), script_sig, ..Default::default() }], vec![taproot_output()])
+}
+
+pub fn transaction(input: Vec<TxIn>, output: Vec<TxOut>) -> Transaction {
+    Transaction { version: transaction::Version::TWO, lock_time: absolute::LockTime::ZERO, input, output }
+}
+
+// Funding transaction paying to the given scripts, spending an outpoint that is not on the mock chain
+pub fn funding(scripts: Vec<ScriptBuf>) -> Transaction {
+    let input = TxIn { previous_output: OutPoint::new(Txid::from_raw_hash(bitcoin::hashes::Hash::all_zeros()), 0), ..Default::default() };
+    transaction(vec![input], scripts.into_iter().map(|script_pubkey| TxOut { value: Amount::from_sat(100_000), script_pubkey }).collect())
+}
+
+pub fn p2wpkh_script(secret: u8) -> ScriptBuf {
+    ScriptBuf::new_p2wpkh(&pubkey(secret).wpubkey_hash())
+}
+
+// Spend of a P2WPKH output, the signature is a placeholder since tweaks only read the key
+pub fn p2wpkh_input(previous_output: OutPoint, secret: u8) -> TxIn {
+    let mut witness = Witness::new();
+    witness.push([0x30; 71]);
+    witness.push(pubkey(secret).to_bytes());
+    TxIn { previous_output, witness, ..Default::default() }
+}
+
+pub fn taproot_output() -> TxOut {
+    let script_pubkey = ScriptBuf::from_hex("5120a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c").unwrap();
+    TxOut { value: Amount::from_sat(90_000), script_pubkey }
+}
+
+// Witness version 2 program, its spends make the transaction ineligible under BIP352
+pub fn segwit_v2_script() -> ScriptBuf {
+    ScriptBuf::from_hex("5220a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c").unwrap()
+}
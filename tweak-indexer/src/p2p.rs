@@ -0,0 +1,509 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use bitcoin::block::{Block, Header};
+use bitcoin::consensus::encode::{deserialize_hex, serialize_hex, Decodable, Encodable};
+use bitcoin::hashes::Hash;
+use bitcoin::p2p::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::p2p::message_blockdata::{GetHeadersMessage, Inventory};
+use bitcoin::p2p::message_network::VersionMessage;
+use bitcoin::p2p::{Address, Magic, ServiceFlags};
+use bitcoin::params::Params;
+use bitcoin::pow::Work;
+use bitcoin::{BlockHash, CompactTarget, Network, Target, Transaction};
+use tokio::task;
+use tracing::{debug, info};
+use crate::chain::{ChainSource, PreviousScript, RawBlock};
+use crate::retry;
+use crate::rpc::{self, RpcSource};
+
+// Peers answer getheaders with at most this many headers
+const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+const USER_AGENT: &str = "/sp-tweak-indexer:0.1.0/";
+
+// bitcoind's default listening port for the network
+pub fn default_p2p_port(network: Network) -> u16 {
+    match network {
+        Network::Testnet => 18333,
+        Network::Testnet4 => 48333,
+        Network::Signet => 38333,
+        Network::Regtest => 18444,
+        _ => 8333,
+    }
+}
+
+#[derive(Debug)]
+pub enum PeerError {
+    Connection(String),
+    NotFound(String),
+    Invalid(String),
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerError::Connection(err) | PeerError::NotFound(err) | PeerError::Invalid(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+// A dropped connection is fixed by reconnecting, a block the peer doesn't have or data failing validation is not
+fn is_transient(err: &PeerError) -> bool {
+    matches!(err, PeerError::Connection(_))
+}
+
+// Connection speaking the wire protocol, requests are answered one at a time
+struct Peer {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    magic: Magic,
+}
+
+impl Peer {
+    fn connect(peer_addr: &str, network: Network) -> Result<Peer, PeerError> {
+        let socket_addr = peer_addr
+            .to_socket_addrs()
+            .map_err(|e| PeerError::Connection(format!("Invalid peer address {}: {}", peer_addr, e)))?
+            .next()
+            .ok_or_else(|| PeerError::Connection(format!("Peer address {} did not resolve", peer_addr)))?;
+        // A silent peer is dropped after the request timeout and the request retried on a new connection
+        let timeout = rpc::request_timeout();
+        let stream = TcpStream::connect_timeout(&socket_addr, timeout)
+            .map_err(|e| PeerError::Connection(format!("Failed to connect to peer {}: {}", peer_addr, e)))?;
+        stream.set_read_timeout(Some(timeout)).map_err(|e| PeerError::Connection(e.to_string()))?;
+        stream.set_write_timeout(Some(timeout)).map_err(|e| PeerError::Connection(e.to_string()))?;
+
+        let mut peer = Peer {
+            reader: BufReader::new(stream.try_clone().map_err(|e| PeerError::Connection(e.to_string()))?),
+            writer: stream,
+            magic: network.magic(),
+        };
+        peer.handshake(socket_addr)?;
+        info!("Connected to peer {}", peer_addr);
+        Ok(peer)
+    }
+
+    // Exchange version and verack, the peer must serve witness blocks for the tweaks to be computable
+    fn handshake(&mut self, socket_addr: SocketAddr) -> Result<(), PeerError> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+        let nonce = RandomState::new().build_hasher().finish();
+        let sender = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        self.send(NetworkMessage::Version(VersionMessage::new(
+            ServiceFlags::NONE,
+            timestamp,
+            Address::new(&socket_addr, ServiceFlags::NONE),
+            Address::new(&sender, ServiceFlags::NONE),
+            nonce,
+            String::from(USER_AGENT),
+            0,
+        )))?;
+
+        let (mut version_received, mut verack_received) = (false, false);
+        while !(version_received && verack_received) {
+            match self.receive()? {
+                NetworkMessage::Version(version) => {
+                    if !version.services.has(ServiceFlags::WITNESS) {
+                        return Err(PeerError::Invalid(format!("Peer {} does not serve witness data", version.user_agent)));
+                    }
+                    debug!("Peer version {} {}", version.version, version.user_agent);
+                    self.send(NetworkMessage::Verack)?;
+                    version_received = true;
+                }
+                NetworkMessage::Verack => verack_received = true,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, payload: NetworkMessage) -> Result<(), PeerError> {
+        let mut message = Vec::new();
+        RawNetworkMessage::new(self.magic, payload)
+            .consensus_encode(&mut message)
+            .map_err(|e| PeerError::Connection(e.to_string()))?;
+        self.writer.write_all(&message).map_err(|e| PeerError::Connection(format!("Failed to send to peer: {}", e)))
+    }
+
+    // Next message from the peer, answering pings so the connection stays open
+    fn receive(&mut self) -> Result<NetworkMessage, PeerError> {
+        loop {
+            let message = RawNetworkMessage::consensus_decode(&mut self.reader)
+                .map_err(|e| PeerError::Connection(format!("Failed to read from peer: {}", e)))?;
+            if *message.magic() != self.magic {
+                return Err(PeerError::Invalid(String::from("Peer is on a different network")));
+            }
+            match message.into_payload() {
+                NetworkMessage::Ping(nonce) => self.send(NetworkMessage::Pong(nonce))?,
+                payload => return Ok(payload),
+            }
+        }
+    }
+
+    fn get_headers(&mut self, locator: Vec<BlockHash>) -> Result<Vec<Header>, PeerError> {
+        self.send(NetworkMessage::GetHeaders(GetHeadersMessage::new(locator, BlockHash::all_zeros())))?;
+        loop {
+            if let NetworkMessage::Headers(headers) = self.receive()? {
+                return Ok(headers);
+            }
+        }
+    }
+
+    fn get_block(&mut self, block_hash: BlockHash) -> Result<Block, PeerError> {
+        self.send(NetworkMessage::GetData(vec![Inventory::WitnessBlock(block_hash)]))?;
+        loop {
+            match self.receive()? {
+                NetworkMessage::Block(block) if block.block_hash() == block_hash => return Ok(block),
+                NetworkMessage::NotFound(_) => return Err(PeerError::NotFound(format!("Peer does not have block {}", block_hash))),
+                _ => {}
+            }
+        }
+    }
+}
+
+// What the header chain keeps of each header, enough to check the difficulty of the headers following it
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ChainHeader {
+    hash: BlockHash,
+    bits: CompactTarget,
+    time: u32,
+}
+
+impl ChainHeader {
+    fn new(header: &Header) -> Self {
+        Self { hash: header.block_hash(), bits: header.bits, time: header.time }
+    }
+}
+
+// Hashes of the best header chain by height, the locator samples it densely near the tip and sparsely towards genesis
+fn block_locator(chain: &[ChainHeader]) -> Vec<BlockHash> {
+    let mut locator = Vec::new();
+    let mut step = 1;
+    let mut height = chain.len() as i64 - 1;
+    while height > 0 {
+        locator.push(chain[height as usize].hash);
+        if locator.len() >= 10 {
+            step *= 2;
+        }
+        height -= step;
+    }
+    locator.push(chain[0].hash);
+    locator
+}
+
+// Difficulty bits the header following the chain must carry, bitcoind's GetNextWorkRequired
+fn required_bits(chain: &[ChainHeader], header: &Header, network: Network) -> CompactTarget {
+    let params = Params::new(network);
+    let height = chain.len();
+    let prev = chain[height - 1];
+    let interval = params.difficulty_adjustment_interval() as usize;
+
+    if height % interval != 0 {
+        if !params.allow_min_difficulty_blocks {
+            return prev.bits;
+        }
+        // Testnets allow a minimum difficulty block after 20 minutes without one, the next block returns to the
+        // difficulty of the last block that was not mined at the minimum
+        let pow_limit = params.max_attainable_target.to_compact_lossy();
+        if header.time as u64 > prev.time as u64 + params.pow_target_spacing * 2 {
+            return pow_limit;
+        }
+        let mut height = height - 1;
+        while height > 0 && height % interval != 0 && chain[height].bits == pow_limit {
+            height -= 1;
+        }
+        return chain[height].bits;
+    }
+
+    if params.no_pow_retargeting {
+        return prev.bits;
+    }
+    let first = chain[height - interval];
+    let timespan = (prev.time as u64).saturating_sub(first.time as u64);
+    // BIP94 retargets testnet4 from the first block of the period, which can't be a minimum difficulty block
+    let last_bits = if network == Network::Testnet4 { first.bits } else { prev.bits };
+    CompactTarget::from_next_work_required(last_bits, timespan, &params)
+}
+
+// Total work of the headers, None for no headers
+fn chain_work(chain: &[ChainHeader]) -> Option<Work> {
+    chain.iter().map(|header| Target::from_compact(header.bits).to_work()).reduce(|total, work| total + work)
+}
+
+// Append headers that connect to the tip, each must carry the expected difficulty and meet it
+fn extend_chain(chain: &mut Vec<ChainHeader>, headers: &[Header], network: Network) -> Result<(), PeerError> {
+    let pow_limit = Params::new(network).max_attainable_target;
+    for header in headers {
+        let invalid = |reason: String| PeerError::Invalid(format!("Invalid header {}: {}", header.block_hash(), reason));
+        if header.prev_blockhash != chain[chain.len() - 1].hash {
+            return Err(invalid(String::from("does not connect to the previous header")));
+        }
+        if header.target() > pow_limit {
+            return Err(invalid(format!("difficulty bits {:08x} are below the network minimum", header.bits.to_consensus())));
+        }
+        let required = required_bits(chain, header, network);
+        if header.bits != required {
+            return Err(invalid(format!("difficulty bits {:08x}, expected {:08x}", header.bits.to_consensus(), required.to_consensus())));
+        }
+        header.validate_pow(header.target()).map_err(|e| invalid(e.to_string()))?;
+        chain.push(ChainHeader::new(header));
+    }
+    Ok(())
+}
+
+// Append headers received in answer to a locator. A branch forking below the tip replaces the blocks after the fork
+// only if it has more work, returns whether the known chain changed.
+fn connect_headers(chain: &mut Vec<ChainHeader>, headers: &[Header], network: Network) -> Result<bool, PeerError> {
+    let Some(first) = headers.first() else {
+        return Ok(false);
+    };
+    let fork_height = chain
+        .iter()
+        .rposition(|header| header.hash == first.prev_blockhash)
+        .ok_or_else(|| PeerError::Invalid(format!("Peer sent headers not connecting to the known chain at {}", first.prev_blockhash)))?;
+
+    // Check the whole branch before giving up the known chain, restoring it if the branch fails or has less work
+    let replaced = chain.split_off(fork_height + 1);
+    match extend_chain(chain, headers, network) {
+        Ok(()) if chain_work(&chain[fork_height + 1..]) > chain_work(&replaced) => Ok(true),
+        result => {
+            chain.truncate(fork_height + 1);
+            chain.extend(replaced);
+            result.map(|_| false)
+        }
+    }
+}
+
+struct PeerState {
+    peer: Option<Peer>,
+    chain: Vec<ChainHeader>,
+}
+
+// Headers and blocks from a peer over the wire protocol, prevouts and transactions still come from the node's RPC
+pub struct P2pSource {
+    peer_addr: String,
+    network: Network,
+    state: Arc<Mutex<PeerState>>,
+    rpc: RpcSource,
+}
+
+impl P2pSource {
+    pub fn new(peer_addr: String, network: Network) -> Self {
+        let genesis = ChainHeader::new(&bitcoin::blockdata::constants::genesis_block(network).header);
+        Self {
+            peer_addr,
+            network,
+            state: Arc::new(Mutex::new(PeerState { peer: None, chain: vec![genesis] })),
+            rpc: RpcSource,
+        }
+    }
+
+    // Run a request on the blocking pool with the peer connected, reconnecting and retrying after a connection failure
+    async fn with_peer<T, F>(&self, f: F) -> Result<T, String>
+    where
+        F: Fn(&mut Peer, &mut Vec<ChainHeader>) -> Result<T, PeerError> + Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        let f = Arc::new(f);
+        retry::with_backoff(&rpc::retry_policy(), "P2P request", is_transient, || {
+            let (f, state, peer_addr, network) = (f.clone(), self.state.clone(), self.peer_addr.clone(), self.network);
+            async move {
+                task::spawn_blocking(move || {
+                    let mut state = state.lock().unwrap();
+                    let PeerState { peer, chain } = &mut *state;
+                    if peer.is_none() {
+                        *peer = Some(Peer::connect(&peer_addr, network)?);
+                    }
+                    let result = f(peer.as_mut().unwrap(), chain);
+                    // A missing block leaves the connection usable
+                    if matches!(result, Err(PeerError::Connection(_) | PeerError::Invalid(_))) {
+                        *peer = None;
+                    }
+                    result
+                })
+                .await
+                .map_err(|e| PeerError::Connection(format!("P2P task failed: {}", e)))?
+            }
+        })
+        .await
+        .map_err(|e| e.to_string())
+    }
+
+    // Download headers until the peer has no more, returning the best height
+    async fn sync_headers(&self) -> Result<u32, String> {
+        let network = self.network;
+        self.with_peer(move |peer, chain| {
+            loop {
+                let headers = peer.get_headers(block_locator(chain))?;
+                // A branch with less work than the known chain is not followed
+                if !connect_headers(chain, &headers, network)? || headers.len() < MAX_HEADERS_PER_MESSAGE {
+                    return Ok(chain.len() as u32 - 1);
+                }
+                debug!("Synced headers to height {}", chain.len() - 1);
+            }
+        })
+        .await
+    }
+
+    fn known_hash(&self, height: u32) -> Option<BlockHash> {
+        self.state.lock().unwrap().chain.get(height as usize).map(|header| header.hash)
+    }
+}
+
+#[async_trait]
+impl ChainSource for P2pSource {
+    async fn get_block_count(&self) -> Result<u32, String> {
+        self.sync_headers().await
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<String, String> {
+        if self.known_hash(height).is_none() {
+            self.sync_headers().await?;
+        }
+        self.known_hash(height)
+            .map(|hash| hash.to_string())
+            .ok_or_else(|| String::from("Block height out of range"))
+    }
+
+    async fn get_block(&self, block_hash: &str) -> Result<String, String> {
+        let block = self.get_raw_block(block_hash).await?.decode()?;
+        Ok(serialize_hex(&block))
+    }
+
+    // Blocks arrive decoded from the peer, hand them over without a hex round trip
+    async fn get_raw_block(&self, block_hash: &str) -> Result<RawBlock, String> {
+        let block_hash: BlockHash = block_hash.parse().map_err(|e| format!("Invalid block hash {}: {}", block_hash, e))?;
+        let block = self.with_peer(move |peer, _| peer.get_block(block_hash)).await?;
+        if !block.check_merkle_root() || !block.check_witness_commitment() {
+            return Err(format!("Peer sent block {} with invalid commitments", block_hash));
+        }
+        Ok(RawBlock::Decoded(block))
+    }
+
+    async fn get_prevouts(&self, block_hash: &str) -> Result<Vec<PreviousScript>, String> {
+        self.rpc.get_prevouts(block_hash).await
+    }
+
+    async fn get_transaction(&self, txid: &str) -> Result<String, String> {
+        self.rpc.get_transaction(txid).await
+    }
+
+    async fn get_transactions(&self, txids: Vec<String>) -> Result<Vec<Option<String>>, String> {
+        self.rpc.get_transactions(txids).await
+    }
+
+    // Relayed to the peer unannounced, peers don't acknowledge transactions they accept
+    async fn broadcast(&self, tx_hex: &str) -> Result<String, String> {
+        let tx: Transaction = deserialize_hex(tx_hex).map_err(|e| format!("Invalid transaction hex: {}", e))?;
+        let txid = tx.compute_txid().to_string();
+        self.with_peer(move |peer, _| peer.send(NetworkMessage::Tx(tx.clone()))).await?;
+        Ok(txid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::block::Version;
+    use bitcoin::TxMerkleNode;
+
+    // Regtest headers carry almost no work, a few nonces find a valid one
+    fn mine(prev: &Header, salt: u32) -> Header {
+        let mut header = Header {
+            version: Version::TWO,
+            prev_blockhash: prev.block_hash(),
+            merkle_root: TxMerkleNode::from_byte_array([salt as u8; 32]),
+            time: prev.time + 600,
+            bits: prev.bits,
+            nonce: 0,
+        };
+        while header.validate_pow(header.target()).is_err() {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    fn hashes(chain: &[ChainHeader]) -> Vec<BlockHash> {
+        chain.iter().map(|header| header.hash).collect()
+    }
+
+    #[test]
+    fn test_connect_headers() {
+        let genesis = bitcoin::blockdata::constants::genesis_block(Network::Regtest).header;
+        let a1 = mine(&genesis, 1);
+        let a2 = mine(&a1, 1);
+        let mut chain = vec![ChainHeader::new(&genesis)];
+
+        assert!(connect_headers(&mut chain, &[a1, a2], Network::Regtest).unwrap());
+        assert_eq!(hashes(&chain), vec![genesis.block_hash(), a1.block_hash(), a2.block_hash()]);
+
+        // A competing branch from height 1 with more work replaces a2
+        let b2 = mine(&a1, 2);
+        let b3 = mine(&b2, 2);
+        assert!(connect_headers(&mut chain, &[b2, b3], Network::Regtest).unwrap());
+        assert_eq!(hashes(&chain), vec![genesis.block_hash(), a1.block_hash(), b2.block_hash(), b3.block_hash()]);
+
+        // One with no more work than the blocks it would replace is not followed
+        let c2 = mine(&a1, 3);
+        let c3 = mine(&c2, 3);
+        assert!(!connect_headers(&mut chain, &[c2, c3], Network::Regtest).unwrap());
+        assert_eq!(hashes(&chain), vec![genesis.block_hash(), a1.block_hash(), b2.block_hash(), b3.block_hash()]);
+
+        // Headers that skip a block, lack the work or carry other difficulty bits are refused
+        let mut unmined = mine(&b3, 3);
+        unmined.bits = bitcoin::CompactTarget::from_consensus(0x1d00ffff);
+        assert!(connect_headers(&mut chain, &[unmined], Network::Regtest).is_err());
+        assert!(connect_headers(&mut chain, &[mine(&mine(&b3, 4), 4)], Network::Regtest).is_err());
+        let mut harder = mine(&b3, 5);
+        harder.bits = bitcoin::CompactTarget::from_consensus(0x207ffffe);
+        while harder.validate_pow(harder.target()).is_err() {
+            harder.nonce += 1;
+        }
+        assert!(matches!(connect_headers(&mut chain, &[harder], Network::Regtest), Err(PeerError::Invalid(_))));
+        assert_eq!(chain.len(), 4);
+    }
+
+    #[test]
+    fn test_required_bits() {
+        let header = |bits: u32, time: u32| ChainHeader { hash: BlockHash::all_zeros(), bits: CompactTarget::from_consensus(bits), time };
+        let next = |time: u32| Header {
+            version: Version::TWO,
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time,
+            bits: CompactTarget::from_consensus(0),
+            nonce: 0,
+        };
+
+        // Mainnet keeps the difficulty within a period and retargets from the period's timespan, half of two weeks
+        // doubles the difficulty
+        let mut chain: Vec<ChainHeader> = (0..2015).map(|i| header(0x1d00ffff, i * 300)).collect();
+        assert_eq!(required_bits(&chain, &next(2015 * 300), Network::Bitcoin).to_consensus(), 0x1d00ffff);
+        chain.push(header(0x1d00ffff, 7 * 24 * 60 * 60));
+        assert_eq!(required_bits(&chain, &next(7 * 24 * 60 * 60 + 600), Network::Bitcoin).to_consensus(), 0x1c7fff80);
+
+        // Testnet allows the minimum difficulty after 20 minutes, then returns to the last regular difficulty
+        let chain = vec![header(0x1d00ffff, 0), header(0x1c0fffff, 600), header(0x1d00ffff, 1200)];
+        assert_eq!(required_bits(&chain, &next(1200 + 1201), Network::Testnet).to_consensus(), 0x1d00ffff);
+        assert_eq!(required_bits(&chain, &next(1800), Network::Testnet).to_consensus(), 0x1c0fffff);
+    }
+
+    #[test]
+    fn test_block_locator() {
+        let chain: Vec<ChainHeader> = (0..100u8)
+            .map(|i| ChainHeader { hash: BlockHash::from_byte_array([i; 32]), bits: CompactTarget::from_consensus(0x207fffff), time: 0 })
+            .collect();
+        let hashes = hashes(&chain);
+        let locator = block_locator(&chain);
+
+        // Dense near the tip, always ending at genesis
+        assert_eq!(&locator[..10], &hashes[90..].iter().rev().copied().collect::<Vec<_>>()[..]);
+        assert_eq!(locator.last(), Some(&hashes[0]));
+        assert!(locator.len() < 20);
+        assert_eq!(block_locator(&chain[..1]), vec![hashes[0]]);
+    }
+}
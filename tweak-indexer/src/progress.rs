@@ -0,0 +1,164 @@
+use std::io::{self, Write};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use indicatif::{ProgressBar, ProgressStyle};
+use tracing_subscriber::fmt::MakeWriter;
+
+// How often a sync logs its progress
+pub const PROGRESS_INTERVAL: Duration = Duration::from_secs(30);
+
+// Interactive progress bar on stderr, shown once enable_bar is called
+static BAR: OnceLock<ProgressBar> = OnceLock::new();
+
+pub fn enable_bar() {
+    let style = ProgressStyle::with_template("[{elapsed_precise}] {wide_bar} {pos}/{len} blocks, {per_sec}, ETA {eta} {msg}")
+        .unwrap_or_else(|_| ProgressStyle::default_bar());
+    let _ = BAR.set(ProgressBar::new(0).with_style(style));
+}
+
+// Console writer for tracing, each event is written in one go with the bar cleared so lines don't clobber it
+pub struct ConsoleWriter;
+
+impl<'a> MakeWriter<'a> for ConsoleWriter {
+    type Writer = ConsoleLine;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        ConsoleLine(Vec::new())
+    }
+}
+
+pub struct ConsoleLine(Vec<u8>);
+
+impl Write for ConsoleLine {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ConsoleLine {
+    fn drop(&mut self) {
+        let write = || {
+            let _ = io::stdout().write_all(&self.0);
+        };
+        match BAR.get() {
+            Some(bar) => bar.suspend(write),
+            None => write(),
+        }
+    }
+}
+
+// Throughput and estimated time left of a sync towards a target height, averaged since it started
+pub struct Progress {
+    start_height: u32,
+    target_height: u32,
+    height: Option<u32>,
+    blocks: u64,
+    tweaks: u64,
+    started: Instant,
+    last_report: Instant,
+}
+
+impl Progress {
+    pub fn new(start_height: u32, target_height: u32) -> Self {
+        let now = Instant::now();
+        if let Some(bar) = BAR.get() {
+            bar.set_length(target_height.saturating_sub(start_height) as u64 + 1);
+            bar.set_position(0);
+            bar.reset_eta();
+        }
+        Self { start_height, target_height, height: None, blocks: 0, tweaks: 0, started: now, last_report: now }
+    }
+
+    // A height handled, blocks that were already indexed count with no tweaks
+    pub fn record(&mut self, height: u32, block_hash: &str, tweaks: usize) {
+        self.height = Some(height);
+        self.blocks += 1;
+        self.tweaks += tweaks as u64;
+        if let Some(bar) = BAR.get() {
+            bar.set_position(height.saturating_sub(self.start_height) as u64 + 1);
+            bar.set_message(block_hash.to_string());
+        }
+    }
+
+    // Progress line when PROGRESS_INTERVAL has passed since the last one
+    pub fn report(&mut self) -> Option<String> {
+        self.report_at(Instant::now())
+    }
+
+    fn report_at(&mut self, now: Instant) -> Option<String> {
+        if now.duration_since(self.last_report) < PROGRESS_INTERVAL {
+            return None;
+        }
+        self.last_report = now;
+        let height = self.height?;
+
+        let total = self.target_height.saturating_sub(self.start_height) as f64 + 1.0;
+        let done = height.saturating_sub(self.start_height) as f64 + 1.0;
+        let elapsed = now.duration_since(self.started).as_secs_f64().max(f64::EPSILON);
+        let blocks_per_sec = self.blocks as f64 / elapsed;
+        let eta = match self.target_height.saturating_sub(height) {
+            0 => String::from("done"),
+            _ if blocks_per_sec == 0.0 => String::from("unknown"),
+            remaining => format_duration(Duration::from_secs_f64(remaining as f64 / blocks_per_sec)),
+        };
+        Some(format!(
+            "Progress: height {} of {} ({:.2}%), {:.2} blocks/sec, {:.1} tweaks/sec, ETA {}",
+            height,
+            self.target_height,
+            (done / total * 100.0).min(100.0),
+            blocks_per_sec,
+            self.tweaks as f64 / elapsed,
+            eta
+        ))
+    }
+}
+
+// Compact duration such as 3d04h, 2h05m or 4m30s
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        3600..=86399 => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d{:02}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let mut progress = Progress::new(100, 199);
+        let started = progress.started;
+        for height in 100..150 {
+            progress.record(height, "00", 2);
+        }
+
+        // Nothing until the interval has passed
+        assert_eq!(progress.report_at(started + Duration::from_secs(10)), None);
+        let line = progress.report_at(started + Duration::from_secs(50)).unwrap();
+        assert_eq!(line, "Progress: height 149 of 199 (50.00%), 1.00 blocks/sec, 2.0 tweaks/sec, ETA 50s");
+        assert_eq!(progress.report_at(started + Duration::from_secs(60)), None);
+
+        for height in 150..200 {
+            progress.record(height, "00", 0);
+        }
+        let line = progress.report_at(started + Duration::from_secs(100)).unwrap();
+        assert!(line.ends_with("ETA done"), "{}", line);
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+        assert_eq!(format_duration(Duration::from_secs(270)), "4m30s");
+        assert_eq!(format_duration(Duration::from_secs(7500)), "2h05m");
+        assert_eq!(format_duration(Duration::from_secs(273600)), "3d04h");
+    }
+}
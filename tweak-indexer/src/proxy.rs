@@ -0,0 +1,180 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::time::Duration;
+use base64::Engine;
+use bitcoincore_rpc::jsonrpc;
+use socks::Socks5Stream;
+
+// Plain HTTP/1.1 over a SOCKS5 proxy. Hostnames are resolved by the proxy so onion services are reachable through Tor.
+pub fn request(proxy: &str, method: &str, url: &str, headers: &[(&str, String)], body: &[u8], timeout: Duration) -> io::Result<(i32, Vec<u8>)> {
+    let (host, port, path) = split_url(url)?;
+    let stream = Socks5Stream::connect(proxy, (host.as_str(), port))?.into_inner();
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: {}:{}\r\nConnection: close\r\nContent-Length: {}\r\n", method, path, host, port, body.len());
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+
+    let mut writer = &stream;
+    writer.write_all(head.as_bytes())?;
+    writer.write_all(body)?;
+    writer.flush()?;
+    read_response(BufReader::new(&stream))
+}
+
+// host, port and path of an http:// url, TLS is not spoken over the proxy
+fn split_url(url: &str) -> io::Result<(String, u16, String)> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", msg, url));
+    let rest = match url.split_once("://") {
+        Some(("http", rest)) => rest,
+        Some(_) => return Err(invalid("only http:// URLs can be used through the proxy")),
+        None => url,
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, port.parse().map_err(|_| invalid("invalid port"))?),
+        _ => (authority, 80),
+    };
+    if host.is_empty() {
+        return Err(invalid("missing host"));
+    }
+    Ok((host.trim_start_matches('[').trim_end_matches(']').to_string(), port, path.to_string()))
+}
+
+// Status code and body of a response, the connection is closed by the server after it
+fn read_response(mut reader: impl BufRead) -> io::Result<(i32, Vec<u8>)> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| invalid(format!("invalid HTTP status line: {}", status_line.trim())))?;
+
+    let mut content_length = None;
+    let mut chunked = false;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = Some(value.parse::<usize>().map_err(|_| invalid(format!("invalid Content-Length: {}", value)))?);
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                chunked = value.eq_ignore_ascii_case("chunked");
+            }
+        }
+    }
+
+    let mut body = Vec::new();
+    if chunked {
+        loop {
+            let mut size_line = String::new();
+            reader.read_line(&mut size_line)?;
+            let size_hex = size_line.trim().split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size_hex, 16).map_err(|_| invalid(format!("invalid chunk size: {}", size_line.trim())))?;
+            if size == 0 {
+                break;
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            reader.read_exact(&mut body[start..])?;
+            let mut crlf = [0u8; 2];
+            reader.read_exact(&mut crlf)?;
+        }
+    } else if let Some(length) = content_length {
+        body.resize(length, 0);
+        reader.read_exact(&mut body)?;
+    } else {
+        reader.read_to_end(&mut body)?;
+    }
+    Ok((status, body))
+}
+
+// JSON-RPC transport for bitcoincore-rpc that reaches the node through the proxy
+pub struct ProxyTransport {
+    proxy: String,
+    url: String,
+    authorization: Option<String>,
+    timeout: Duration,
+}
+
+impl ProxyTransport {
+    pub fn new(proxy: &str, url: &str, user: Option<String>, password: Option<String>, timeout: Duration) -> Self {
+        let authorization = user.map(|user| {
+            let credentials = format!("{}:{}", user, password.unwrap_or_default());
+            format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials))
+        });
+        Self { proxy: proxy.to_string(), url: url.to_string(), authorization, timeout }
+    }
+
+    fn post<R: for<'a> serde::Deserialize<'a>>(&self, body: &impl serde::Serialize) -> Result<R, jsonrpc::Error> {
+        let body = serde_json::to_vec(body)?;
+        let mut headers = vec![("Content-Type", String::from("application/json"))];
+        if let Some(authorization) = &self.authorization {
+            headers.push(("Authorization", authorization.clone()));
+        }
+        let (status, response) = request(&self.proxy, "POST", &self.url, &headers, &body, self.timeout)
+            .map_err(|e| jsonrpc::Error::Transport(Box::new(e)))?;
+
+        // bitcoind reports RPC errors with a non-200 status and a JSON body, anything else is a transport failure
+        serde_json::from_slice(&response).map_err(|e| {
+            let msg = format!("HTTP {} from {}: {}", status, self.url, e);
+            jsonrpc::Error::Transport(Box::new(io::Error::new(io::ErrorKind::InvalidData, msg)))
+        })
+    }
+}
+
+impl jsonrpc::Transport for ProxyTransport {
+    fn send_request(&self, request: jsonrpc::Request) -> Result<jsonrpc::Response, jsonrpc::Error> {
+        self.post(&request)
+    }
+
+    fn send_batch(&self, requests: &[jsonrpc::Request]) -> Result<Vec<jsonrpc::Response>, jsonrpc::Error> {
+        self.post(&requests)
+    }
+
+    fn fmt_target(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} via socks5://{}", self.url, self.proxy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_url() {
+        let (host, port, path) = split_url("http://abcdefghij.onion:8332/rest/block/00ff.bin").unwrap();
+        assert_eq!((host.as_str(), port, path.as_str()), ("abcdefghij.onion", 8332, "/rest/block/00ff.bin"));
+
+        let (host, port, path) = split_url("127.0.0.1:8332").unwrap();
+        assert_eq!((host.as_str(), port, path.as_str()), ("127.0.0.1", 8332, "/"));
+
+        let (host, port, _) = split_url("http://[::1]").unwrap();
+        assert_eq!((host.as_str(), port), ("::1", 80));
+
+        assert!(split_url("https://blockstream.info/api").is_err());
+    }
+
+    #[test]
+    fn test_read_response() {
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 5\r\n\r\nhello";
+        assert_eq!(read_response(&raw[..]).unwrap(), (200, b"hello".to_vec()));
+
+        let raw = b"HTTP/1.1 404 Not Found\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nnot \r\n5\r\nfound\r\n0\r\n\r\n";
+        assert_eq!(read_response(&raw[..]).unwrap(), (404, b"not found".to_vec()));
+
+        assert!(read_response(&b"garbage\r\n\r\n"[..]).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+// Spaces requests evenly so no more than the configured number start per second, waiting callers queue in arrival order
+pub struct RateLimiter {
+    interval: Duration,
+    // Earliest start of the next request
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_second: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(1) / requests_per_second.max(1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    // Wait for this request's turn, the slot is reserved before sleeping so concurrent callers get later ones
+    pub async fn acquire(&self) {
+        let start = {
+            let mut next = self.next.lock().unwrap();
+            let start = (*next).max(Instant::now());
+            *next = start + self.interval;
+            start
+        };
+        time::sleep_until(start).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_acquire_spaces_requests() {
+        let limiter = RateLimiter::new(50);
+        let started = Instant::now();
+        for _ in 0..5 {
+            limiter.acquire().await;
+        }
+        // The first request goes right away, the other 4 20ms apart
+        assert!(started.elapsed() >= Duration::from_millis(80));
+
+        // Idle time is not saved up for a burst later
+        time::sleep(Duration::from_millis(100)).await;
+        let resumed = Instant::now();
+        limiter.acquire().await;
+        limiter.acquire().await;
+        assert!(resumed.elapsed() >= Duration::from_millis(20));
+    }
+}
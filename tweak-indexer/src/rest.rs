@@ -0,0 +1,64 @@
+use async_trait::async_trait;
+use crate::chain::{ChainSource, PreviousScript, RawBlock};
+use crate::http;
+use crate::rpc::RpcSource;
+
+// Fetch a raw block from the node's REST interface (bitcoind -rest), served on the RPC port without authentication
+pub async fn get_block(base_url: &str, block_hash: &str) -> Result<Vec<u8>, String> {
+    http::get(format!("{}/rest/block/{}.bin", base_url, block_hash))
+        .await
+        .map_err(|e| format!("REST block fetch failed: {}", e))
+}
+
+// Blocks over REST, everything else over JSON-RPC to the same node
+#[derive(Debug, Clone)]
+pub struct RestSource {
+    url: String,
+    rpc: RpcSource,
+}
+
+impl RestSource {
+    pub fn new(url: String) -> Self {
+        Self { url, rpc: RpcSource }
+    }
+}
+
+#[async_trait]
+impl ChainSource for RestSource {
+    async fn get_block_count(&self) -> Result<u32, String> {
+        self.rpc.get_block_count().await
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<String, String> {
+        self.rpc.get_block_hash(height).await
+    }
+
+    async fn get_block(&self, block_hash: &str) -> Result<String, String> {
+        get_block(&self.url, block_hash).await.map(hex::encode)
+    }
+
+    async fn get_raw_block(&self, block_hash: &str) -> Result<RawBlock, String> {
+        get_block(&self.url, block_hash).await.map(RawBlock::Bytes)
+    }
+
+    async fn get_prevouts(&self, block_hash: &str) -> Result<Vec<PreviousScript>, String> {
+        self.rpc.get_prevouts(block_hash).await
+    }
+
+    // The prevout response already contains the whole block, skip the REST download
+    async fn get_block_with_prevouts(&self, block_hash: &str) -> Result<(RawBlock, Vec<PreviousScript>), String> {
+        self.rpc.get_block_with_prevouts(block_hash).await
+    }
+
+    async fn get_transaction(&self, txid: &str) -> Result<String, String> {
+        self.rpc.get_transaction(txid).await
+    }
+
+    async fn get_transactions(&self, txids: Vec<String>) -> Result<Vec<Option<String>>, String> {
+        self.rpc.get_transactions(txids).await
+    }
+
+    async fn broadcast(&self, tx_hex: &str) -> Result<String, String> {
+        self.rpc.broadcast(tx_hex).await
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::hash_map::RandomState;
+use std::fmt::Display;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::warn;
+
+pub const DEFAULT_MAX_RETRIES: u32 = 10;
+
+// Exponential backoff between attempts, 10 retries span roughly 4 minutes which covers a node restart
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: DEFAULT_MAX_RETRIES,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Backoff ceiling for the attempt, doubling from base_delay up to max_delay
+    fn ceiling(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay)
+    }
+
+    // Random delay in the upper half of the ceiling, so many clients don't retry in lockstep
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self.ceiling(attempt);
+        let half = ceiling / 2;
+        half + half.mul_f64(jitter())
+    }
+}
+
+// Uniform value in [0, 1) from the randomly keyed std hasher, avoids a rand dependency for jitter
+fn jitter() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Run op until it succeeds, fails with an error is_transient rejects, or the retries run out
+pub async fn with_backoff<T, E, Op, Fut>(policy: &RetryPolicy, what: &str, is_transient: impl Fn(&E) -> bool, mut op: Op) -> Result<T, E>
+where
+    E: Display,
+    Op: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Err(err) if attempt < policy.max_retries && is_transient(&err) => {
+                let delay = policy.delay(attempt);
+                attempt += 1;
+                warn!("{} failed, retry {}/{} in {:.1}s: {}", what, attempt, policy.max_retries, delay.as_secs_f64(), err);
+                sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_grows_and_is_capped() {
+        let policy = RetryPolicy::default();
+        for attempt in 0..20 {
+            let ceiling = policy.ceiling(attempt);
+            let delay = policy.delay(attempt);
+            assert!(delay >= ceiling / 2 && delay <= ceiling);
+        }
+        assert_eq!(policy.ceiling(0), Duration::from_millis(500));
+        assert_eq!(policy.ceiling(3), Duration::from_secs(4));
+        assert_eq!(policy.ceiling(40), Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn test_with_backoff() {
+        let policy = RetryPolicy { max_retries: 3, base_delay: Duration::ZERO, max_delay: Duration::ZERO };
+
+        // Transient errors are retried until success
+        let mut calls = 0;
+        let result: Result<u32, String> = with_backoff(&policy, "test", |_| true, || {
+            calls += 1;
+            let outcome = if calls < 3 { Err(String::from("connection refused")) } else { Ok(calls) };
+            async move { outcome }
+        }).await;
+        assert_eq!(result, Ok(3));
+
+        // Permanent errors are returned immediately
+        let mut calls = 0;
+        let result: Result<u32, String> = with_backoff(&policy, "test", |err: &String| err != "bad request", || {
+            calls += 1;
+            async { Err(String::from("bad request")) }
+        }).await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+
+        // Retries are bounded
+        let mut calls = 0;
+        let result: Result<u32, String> = with_backoff(&policy, "test", |_| true, || {
+            calls += 1;
+            async { Err(String::from("timeout")) }
+        }).await;
+        assert!(result.is_err());
+        assert_eq!(calls, 4);
+    }
+}
@@ -0,0 +1,579 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
+use async_trait::async_trait;
+use bitcoin::block::{Block, Header, Version};
+use bitcoin::consensus::encode::deserialize_hex;
+use bitcoin::hashes::Hash;
+use bitcoin::{BlockHash, CompactTarget, Network, Transaction, TxMerkleNode, Txid};
+use bitcoincore_rpc::{jsonrpc, Auth, Client, Error, RpcApi};
+use serde::Deserialize;
+use tokio::{task, time};
+use crate::chain::{self, ChainSource, PreviousScript, RawBlock};
+use crate::proxy::ProxyTransport;
+use crate::ratelimit::RateLimiter;
+use crate::retry::{self, RetryPolicy};
+
+pub const DEFAULT_RPC_HOST: &str = "127.0.0.1";
+pub const DEFAULT_RPC_PORT: u16 = 8332;
+// Limit on a single request to any backend, a verbose getblock of a full block can take tens of seconds
+pub const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 120;
+// Connections kept to the node, enough for the block prefetcher to download several blocks at once
+pub const DEFAULT_RPC_CONNECTIONS: usize = 4;
+
+// bitcoind's default rpcport for the network
+pub fn default_rpc_port(network: Network) -> u16 {
+    match network {
+        Network::Testnet => 18332,
+        Network::Signet => 38332,
+        Network::Regtest => 18443,
+        _ => DEFAULT_RPC_PORT,
+    }
+}
+
+// bitcoind answers with this code while it is still loading the block index
+const RPC_IN_WARMUP: i32 = -28;
+// Spent transactions requested per getrawtransaction batch when getblock lacks prevouts
+const PREVOUT_BATCH_SIZE: usize = 500;
+
+static CONFIG: OnceLock<RpcConfig> = OnceLock::new();
+// Pool of clients used round robin, a slot is dropped after a transient failure so the next attempt reconnects and rereads a rotated cookie
+static CLIENTS: RwLock<Vec<Option<Arc<Client>>>> = RwLock::new(Vec::new());
+static NEXT_CLIENT: AtomicUsize = AtomicUsize::new(0);
+// Version reported by getnetworkinfo, 0 until detected
+static NODE_VERSION: AtomicU32 = AtomicU32::new(0);
+static RATE_LIMITER: OnceLock<Option<RateLimiter>> = OnceLock::new();
+
+// Where and how to reach the node, user/password takes precedence over the cookie file
+#[derive(Debug, Clone)]
+pub struct RpcConfig {
+    pub host: String,
+    pub port: u16,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub cookie_file: Option<PathBuf>,
+    pub max_retries: u32,
+    pub timeout: Duration,
+    // Size of the client pool concurrent calls are spread over
+    pub connections: usize,
+    // SOCKS5 proxy (host:port) RPC and HTTP requests are routed through, e.g. Tor for an onion service
+    pub proxy: Option<String>,
+    // Selects the data directory the default cookie file is read from
+    pub network: Network,
+    // Cap on RPC and HTTP requests started per second, unlimited when None
+    pub max_requests_per_second: Option<u32>,
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        Self {
+            host: String::from(DEFAULT_RPC_HOST),
+            port: DEFAULT_RPC_PORT,
+            user: None,
+            password: None,
+            cookie_file: None,
+            max_retries: retry::DEFAULT_MAX_RETRIES,
+            timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECS),
+            connections: DEFAULT_RPC_CONNECTIONS,
+            proxy: None,
+            network: Network::Bitcoin,
+            max_requests_per_second: None,
+        }
+    }
+}
+
+impl RpcConfig {
+    pub fn url(&self) -> String {
+        if self.host.contains("://") {
+            format!("{}:{}", self.host, self.port)
+        } else {
+            format!("http://{}:{}", self.host, self.port)
+        }
+    }
+
+    pub fn auth(&self) -> Result<Auth, String> {
+        match (&self.user, &self.password) {
+            (Some(user), Some(password)) => Ok(Auth::UserPass(user.clone(), password.clone())),
+            (Some(_), None) | (None, Some(_)) => Err(String::from("RPC user and password must be given together")),
+            (None, None) => Ok(Auth::CookieFile(self.cookie_file.clone().unwrap_or_else(|| default_cookie_file(self.network)))),
+        }
+    }
+}
+
+// Cookie bitcoind writes to its default data directory when no rpcpassword is set, test networks use a subdirectory
+fn default_cookie_file(network: Network) -> PathBuf {
+    let home = env::var("HOME").unwrap_or_default();
+    let data_dir = PathBuf::from(home).join(".bitcoin");
+    match network {
+        Network::Testnet => data_dir.join("testnet3").join(".cookie"),
+        Network::Signet => data_dir.join("signet").join(".cookie"),
+        Network::Regtest => data_dir.join("regtest").join(".cookie"),
+        _ => data_dir.join(".cookie"),
+    }
+}
+
+fn config() -> &'static RpcConfig {
+    CONFIG.get_or_init(RpcConfig::default)
+}
+
+// Backoff applied to node requests, shared by the RPC and REST backends
+pub fn retry_policy() -> RetryPolicy {
+    RetryPolicy {
+        max_retries: config().max_retries,
+        ..RetryPolicy::default()
+    }
+}
+
+// Time a request may take before it is abandoned and retried, shared by every backend
+pub fn request_timeout() -> Duration {
+    config().timeout
+}
+
+// SOCKS5 proxy every RPC and HTTP request goes through, if configured
+pub fn proxy() -> Option<&'static str> {
+    config().proxy.as_deref()
+}
+
+// Wait until the configured request rate allows another request, every attempt of every backend request counts
+pub async fn throttle() {
+    let limiter = RATE_LIMITER.get_or_init(|| config().max_requests_per_second.map(RateLimiter::new));
+    if let Some(limiter) = limiter {
+        limiter.acquire().await;
+    }
+}
+
+// Record the node version so getblock requests stay within what the node supports
+pub fn set_node_version(version: u32) {
+    NODE_VERSION.store(version, Ordering::Relaxed);
+}
+
+// getblock verbosity 3 adds input prevouts (23.0), older nodes get verbosity 2 and the prevouts are looked up.
+// An undetected version assumes a current node.
+fn getblock_verbosity(node_version: u32) -> u8 {
+    if node_version == 0 || node_version >= chain::MIN_PREVOUT_NODE_VERSION {
+        3
+    } else {
+        2
+    }
+}
+
+// Set the endpoint used for every RPC call, must run before the first one
+pub fn init(config: &RpcConfig) -> Result<(), String> {
+    config.auth()?;
+    CONFIG.set(config.clone()).map_err(|_| String::from("RPC client already initialized"))
+}
+
+// Pool slot for the next call
+fn next_slot() -> usize {
+    NEXT_CLIENT.fetch_add(1, Ordering::Relaxed) % config().connections.max(1)
+}
+
+// JSON-RPC client in the given pool slot, connecting to the configured endpoint (default local node) on first use
+fn client(slot: usize) -> Result<Arc<Client>, Error> {
+    if let Some(Some(client)) = CLIENTS.read().unwrap().get(slot) {
+        return Ok(client.clone());
+    }
+
+    let client = Arc::new(connect(config())?);
+    let mut clients = CLIENTS.write().unwrap();
+    if clients.len() <= slot {
+        clients.resize(slot + 1, None);
+    }
+    clients[slot] = Some(client.clone());
+    Ok(client)
+}
+
+fn connect(config: &RpcConfig) -> Result<Client, Error> {
+    let (user, password) = config.auth().map_err(Error::ReturnedError)?.get_user_pass()?;
+    if let Some(proxy) = &config.proxy {
+        let transport = ProxyTransport::new(proxy, &config.url(), user, password, config.timeout);
+        return Ok(Client::from_jsonrpc(jsonrpc::Client::with_transport(transport)));
+    }
+    let mut transport = jsonrpc::minreq_http::Builder::new()
+        .url(&config.url())
+        .map_err(|e| Error::JsonRpc(e.into()))?
+        .timeout(config.timeout);
+    if let Some(user) = user {
+        transport = transport.basic_auth(user, password);
+    }
+    Ok(Client::from_jsonrpc(jsonrpc::Client::with_transport(transport.build())))
+}
+
+fn reset_client(slot: usize) {
+    if let Some(client) = CLIENTS.write().unwrap().get_mut(slot) {
+        *client = None;
+    }
+}
+
+fn timeout_error() -> Error {
+    let timed_out = std::io::Error::new(std::io::ErrorKind::TimedOut, "RPC request timed out");
+    Error::JsonRpc(jsonrpc::Error::Transport(Box::new(timed_out)))
+}
+
+// Errors a node restart or overload produces, worth retrying rather than giving up on
+fn is_transient(err: &Error) -> bool {
+    match err {
+        Error::JsonRpc(jsonrpc::Error::Transport(_)) | Error::Io(_) | Error::InvalidCookieFile => true,
+        Error::JsonRpc(jsonrpc::Error::Rpc(rpc_error)) => rpc_error.code == RPC_IN_WARMUP,
+        _ => false,
+    }
+}
+
+// Run a blocking RPC call on tokio's blocking pool so the runtime keeps serving other tasks meanwhile.
+// Transient failures and calls exceeding the request timeout are retried with backoff, reconnecting before each new attempt.
+pub async fn call<T, F>(f: F) -> Result<T, String>
+where
+    F: Fn(&Client) -> Result<T, Error> + Send + Sync + 'static,
+    T: Send + 'static,
+{
+    let f = Arc::new(f);
+    retry::with_backoff(&retry_policy(), "RPC call", is_transient, || {
+        let f = f.clone();
+        let slot = next_slot();
+        async move {
+            throttle().await;
+            // The transport times out on its own, this also bounds a response that keeps trickling in
+            let result = match time::timeout(request_timeout(), task::spawn_blocking(move || f(&*client(slot)?))).await {
+                Ok(joined) => joined.map_err(|e| Error::ReturnedError(format!("RPC task failed: {}", e)))?,
+                Err(_) => Err(timeout_error()),
+            };
+            if result.as_ref().is_err_and(is_transient) {
+                reset_client(slot);
+            }
+            result
+        }
+    })
+    .await
+    .map_err(|e| e.to_string())
+}
+
+// Subset of the getblock verbosity 3 response needed to rebuild the raw block and recover input prevout scripts
+#[derive(Deserialize, Debug)]
+struct VerboseBlock {
+    hash: String,
+    version: i32,
+    // Absent for the genesis block
+    previousblockhash: Option<String>,
+    merkleroot: String,
+    time: u32,
+    bits: String,
+    nonce: u32,
+    tx: Vec<VerboseTransaction>,
+}
+
+#[derive(Deserialize, Debug)]
+struct VerboseTransaction {
+    hex: String,
+    vin: Vec<VerboseInput>,
+}
+
+// Coinbase inputs carry no txid, vout or prevout
+#[derive(Deserialize, Debug)]
+struct VerboseInput {
+    txid: Option<String>,
+    vout: Option<u32>,
+    prevout: Option<VerbosePrevout>,
+}
+
+#[derive(Deserialize, Debug)]
+struct VerbosePrevout {
+    #[serde(rename = "scriptPubKey")]
+    script_pub_key: VerboseScriptPubKey,
+}
+
+#[derive(Deserialize, Debug)]
+struct VerboseScriptPubKey {
+    hex: String,
+}
+
+// Outputs spent by the block's non coinbase inputs, grouped by the transaction that created them
+fn spent_outpoints(block: &VerboseBlock) -> BTreeMap<String, Vec<u32>> {
+    let mut spent: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+    for input in block.tx.iter().flat_map(|tx| tx.vin.iter()) {
+        if let (Some(txid), Some(vout)) = (&input.txid, input.vout) {
+            spent.entry(txid.clone()).or_default().push(vout);
+        }
+    }
+    spent
+}
+
+// Reassemble the consensus encoded block from the header fields and transaction hex, checked against the reported hash
+fn block_from_verbose(block: &VerboseBlock) -> Result<Block, String> {
+    let prev_blockhash = match &block.previousblockhash {
+        Some(prev_blockhash) => parse_block_hash(prev_blockhash)?,
+        None => BlockHash::all_zeros(),
+    };
+    let merkle_root = TxMerkleNode::from_str(&block.merkleroot).map_err(|e| format!("Invalid merkle root {}: {}", block.merkleroot, e))?;
+    let bits = u32::from_str_radix(&block.bits, 16).map_err(|e| format!("Invalid bits {}: {}", block.bits, e))?;
+
+    let header = Header {
+        version: Version::from_consensus(block.version),
+        prev_blockhash,
+        merkle_root,
+        time: block.time,
+        bits: CompactTarget::from_consensus(bits),
+        nonce: block.nonce,
+    };
+    let txdata = block
+        .tx
+        .iter()
+        .map(|tx| deserialize_hex::<Transaction>(&tx.hex).map_err(|e| format!("Invalid transaction hex: {}", e)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let raw_block = Block { header, txdata };
+    if raw_block.block_hash().to_string() != block.hash {
+        return Err(format!("Rebuilt block hash {} does not match {}", raw_block.block_hash(), block.hash));
+    }
+    Ok(raw_block)
+}
+
+// Collect the prevout script of every non coinbase input in the block
+fn previous_scripts_from_block(block: VerboseBlock) -> Vec<PreviousScript> {
+    block
+        .tx
+        .into_iter()
+        .flat_map(|tx| tx.vin)
+        .filter_map(|input| {
+            Some(PreviousScript {
+                txid: input.txid?,
+                vout: input.vout?,
+                script: input.prevout?.script_pub_key.hex,
+            })
+        })
+        .collect()
+}
+
+fn parse_block_hash(block_hash: &str) -> Result<BlockHash, String> {
+    BlockHash::from_str(block_hash).map_err(|e| format!("Invalid block hash {}: {}", block_hash, e))
+}
+
+fn parse_txid(txid: &str) -> Result<Txid, String> {
+    Txid::from_str(txid).map_err(|e| format!("Invalid txid {}: {}", txid, e))
+}
+
+async fn get_verbose_block(block_hash: &str) -> Result<VerboseBlock, String> {
+    let block_hash = block_hash.to_string();
+    let verbosity = getblock_verbosity(NODE_VERSION.load(Ordering::Relaxed));
+    call(move |client| client.call("getblock", &[block_hash.as_str().into(), verbosity.into()])).await
+}
+
+// Everything over the node's JSON-RPC interface
+#[derive(Debug, Clone, Default)]
+pub struct RpcSource;
+
+impl RpcSource {
+    // Prevout scripts from the verbose block, fetching the spent transactions when the node didn't include prevouts
+    async fn previous_scripts(&self, block: VerboseBlock) -> Result<Vec<PreviousScript>, String> {
+        if getblock_verbosity(NODE_VERSION.load(Ordering::Relaxed)) == 3 {
+            return Ok(previous_scripts_from_block(block));
+        }
+
+        let spent = spent_outpoints(&block);
+        let txids: Vec<String> = spent.keys().cloned().collect();
+        let mut previous_scripts = Vec::new();
+        for batch in txids.chunks(PREVOUT_BATCH_SIZE) {
+            let transactions = self.get_transactions(batch.to_vec()).await?;
+            for (txid, tx_hex) in batch.iter().zip(transactions) {
+                // Unknown transactions are left to the per transaction fallback
+                let Some(tx) = tx_hex.and_then(|hex| deserialize_hex::<Transaction>(&hex).ok()) else {
+                    continue;
+                };
+                for vout in spent[txid].iter() {
+                    if let Some(output) = tx.output.get(*vout as usize) {
+                        previous_scripts.push(PreviousScript {
+                            txid: txid.clone(),
+                            vout: *vout,
+                            script: output.script_pubkey.to_hex_string(),
+                        });
+                    }
+                }
+            }
+        }
+        Ok(previous_scripts)
+    }
+}
+
+#[async_trait]
+impl ChainSource for RpcSource {
+    async fn get_block_count(&self) -> Result<u32, String> {
+        let block_count = call(|client| client.get_block_count()).await?;
+        Ok(block_count as u32)
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<String, String> {
+        let block_hash = call(move |client| client.get_block_hash(height as u64)).await?;
+        Ok(block_hash.to_string())
+    }
+
+    async fn get_block(&self, block_hash: &str) -> Result<String, String> {
+        let block_hash = parse_block_hash(block_hash)?;
+        call(move |client| client.get_block_hex(&block_hash)).await
+    }
+
+    // Long form getblock includes every input's prevout (faster than fetching each previous transaction)
+    async fn get_prevouts(&self, block_hash: &str) -> Result<Vec<PreviousScript>, String> {
+        self.previous_scripts(get_verbose_block(block_hash).await?).await
+    }
+
+    // The verbose response carries every transaction's hex, so the block is built from it without a second getblock
+    async fn get_block_with_prevouts(&self, block_hash: &str) -> Result<(RawBlock, Vec<PreviousScript>), String> {
+        let block = get_verbose_block(block_hash).await?;
+        let decoded = block_from_verbose(&block)?;
+        Ok((RawBlock::Decoded(decoded), self.previous_scripts(block).await?))
+    }
+
+    async fn get_transaction(&self, txid: &str) -> Result<String, String> {
+        let txid = parse_txid(txid)?;
+        call(move |client| client.get_raw_transaction_hex(&txid, None)).await
+    }
+
+    // One batched getrawtransaction round trip
+    async fn get_transactions(&self, txids: Vec<String>) -> Result<Vec<Option<String>>, String> {
+        if txids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        call(move |client| {
+            let client = client.get_jsonrpc_client();
+            let params: Vec<_> = txids.iter().map(|txid| jsonrpc::arg([txid])).collect();
+            let requests: Vec<_> = params.iter().map(|param| client.build_request("getrawtransaction", Some(param))).collect();
+            let responses = client.send_batch(&requests)?;
+
+            Ok(responses
+                .into_iter()
+                .map(|response| response.and_then(|r| r.result::<String>().ok()))
+                .collect())
+        })
+        .await
+    }
+
+    async fn broadcast(&self, tx_hex: &str) -> Result<String, String> {
+        let tx_hex = tx_hex.to_string();
+        let txid = call(move |client| client.send_raw_transaction(tx_hex.as_str())).await?;
+        Ok(txid.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::consensus::encode::serialize_hex;
+    use super::*;
+
+    #[test]
+    fn test_rpc_config() {
+        let config = RpcConfig::default();
+        assert_eq!(config.url(), "http://127.0.0.1:8332");
+        assert_eq!(config.auth().unwrap(), Auth::CookieFile(default_cookie_file(Network::Bitcoin)));
+
+        // Test networks read the cookie from their own data directory
+        let regtest = RpcConfig { network: Network::Regtest, ..RpcConfig::default() };
+        assert_eq!(regtest.auth().unwrap(), Auth::CookieFile(default_cookie_file(Network::Regtest)));
+        assert!(default_cookie_file(Network::Regtest).ends_with("regtest/.cookie"));
+        assert_eq!(default_rpc_port(Network::Signet), 38332);
+
+        let config = RpcConfig {
+            host: String::from("https://node.example"),
+            port: 18443,
+            cookie_file: Some(PathBuf::from("/data/.cookie")),
+            ..RpcConfig::default()
+        };
+        assert_eq!(config.url(), "https://node.example:18443");
+        assert_eq!(config.auth().unwrap(), Auth::CookieFile(PathBuf::from("/data/.cookie")));
+
+        // Explicit credentials win over the cookie file, but only as a pair
+        let config = RpcConfig {
+            user: Some(String::from("alice")),
+            password: Some(String::from("secret")),
+            ..config
+        };
+        assert_eq!(config.auth().unwrap(), Auth::UserPass(String::from("alice"), String::from("secret")));
+        assert!(RpcConfig { password: None, ..config }.auth().is_err());
+    }
+
+    #[test]
+    fn test_is_transient() {
+        let refused = std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "connection refused");
+        assert!(is_transient(&Error::JsonRpc(jsonrpc::Error::Transport(Box::new(refused)))));
+
+        let rpc_error = |code| Error::JsonRpc(jsonrpc::Error::Rpc(jsonrpc::error::RpcError { code, message: String::new(), data: None }));
+        assert!(is_transient(&rpc_error(RPC_IN_WARMUP)));
+        assert!(is_transient(&timeout_error()));
+        // Block height out of range is an answer, not a hiccup
+        assert!(!is_transient(&rpc_error(-8)));
+        assert!(!is_transient(&Error::UnexpectedStructure));
+    }
+
+    #[test]
+    fn test_previous_scripts_from_block() {
+        let block: VerboseBlock = serde_json::from_str(r#"{
+            "hash": "00", "version": 1, "merkleroot": "00", "time": 0, "bits": "1d00ffff", "nonce": 0,
+            "tx": [
+                {"txid": "c0", "hex": "", "vin": [{"coinbase": "03", "sequence": 4294967295}]},
+                {"txid": "t1", "hex": "", "vin": [
+                    {"txid": "aa", "vout": 1, "prevout": {"height": 1, "value": 0.1, "scriptPubKey": {"hex": "5120ff", "type": "witness_v1_taproot"}}},
+                    {"txid": "bb", "vout": 0, "prevout": {"height": 2, "value": 0.2, "scriptPubKey": {"hex": "0014ee", "type": "witness_v0_keyhash"}}}
+                ]}
+            ]
+        }"#).unwrap();
+
+        // Coinbase input is skipped, the others keep block order
+        let previous_scripts = previous_scripts_from_block(block);
+        assert_eq!(previous_scripts.len(), 2);
+        assert_eq!((previous_scripts[0].txid.as_str(), previous_scripts[0].vout, previous_scripts[0].script.as_str()), ("aa", 1, "5120ff"));
+        assert_eq!((previous_scripts[1].txid.as_str(), previous_scripts[1].vout, previous_scripts[1].script.as_str()), ("bb", 0, "0014ee"));
+    }
+
+    #[test]
+    fn test_getblock_verbosity() {
+        assert_eq!(getblock_verbosity(0), 3);
+        assert_eq!(getblock_verbosity(270000), 3);
+        assert_eq!(getblock_verbosity(chain::MIN_PREVOUT_NODE_VERSION), 3);
+        assert_eq!(getblock_verbosity(220100), 2);
+    }
+
+    #[test]
+    fn test_spent_outpoints() {
+        // Verbosity 2 response, inputs carry no prevout
+        let block: VerboseBlock = serde_json::from_str(r#"{
+            "hash": "00", "version": 1, "merkleroot": "00", "time": 0, "bits": "1d00ffff", "nonce": 0,
+            "tx": [
+                {"hex": "", "vin": [{"coinbase": "03", "sequence": 4294967295}]},
+                {"hex": "", "vin": [{"txid": "aa", "vout": 1}, {"txid": "bb", "vout": 0}]},
+                {"hex": "", "vin": [{"txid": "aa", "vout": 0}]}
+            ]
+        }"#).unwrap();
+
+        let spent = spent_outpoints(&block);
+        assert_eq!(spent.len(), 2);
+        assert_eq!(spent.get("aa"), Some(&vec![1, 0]));
+        assert_eq!(spent.get("bb"), Some(&vec![0]));
+        assert!(previous_scripts_from_block(block).is_empty());
+    }
+
+    #[test]
+    fn test_block_from_verbose() {
+        let genesis = bitcoin::blockdata::constants::genesis_block(Network::Bitcoin);
+        let verbose = |hash: String| -> VerboseBlock {
+            serde_json::from_value(serde_json::json!({
+                "hash": hash,
+                "version": genesis.header.version.to_consensus(),
+                "merkleroot": genesis.header.merkle_root.to_string(),
+                "time": genesis.header.time,
+                "bits": format!("{:08x}", genesis.header.bits.to_consensus()),
+                "nonce": genesis.header.nonce,
+                "tx": [{"hex": serialize_hex(&genesis.txdata[0]), "vin": [{"coinbase": "04ff"}]}],
+            })).unwrap()
+        };
+
+        // The rebuilt block is byte identical to getblock verbosity 0
+        let block = block_from_verbose(&verbose(genesis.block_hash().to_string())).unwrap();
+        assert_eq!(serialize_hex(&block), serialize_hex(&genesis));
+
+        // A response that doesn't rebuild to the reported hash is rejected
+        assert!(block_from_verbose(&verbose("00".repeat(32))).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+use std::error::Error;
+use std::str::FromStr;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{All, Parity, PublicKey, Scalar, Secp256k1, SecretKey, XOnlyPublicKey};
+use serde::Serialize;
+use tracing::{info, warn};
+use crate::database::{Database, Tweak};
+
+// BIP352 label m reserved for change
+pub const CHANGE_LABEL: u32 = 0;
+
+// Label m of an address, its labeled spend key is the spend key plus tweak * G
+pub struct Label {
+    pub m: u32,
+    tweak: Scalar,
+    point: PublicKey,
+}
+
+impl Label {
+    pub fn new(secp: &Secp256k1<All>, scan_key: &SecretKey, m: u32) -> Result<Self, String> {
+        let bytes = tagged_hash("BIP0352/Label", &[scan_key.secret_bytes().as_slice(), m.to_be_bytes().as_slice()]);
+        let secret = SecretKey::from_slice(&bytes).map_err(|e| format!("Invalid label {}: {}", m, e))?;
+        Ok(Self { m, tweak: Scalar::from(secret), point: secret.public_key(secp) })
+    }
+}
+
+// Keys of the silent payment address a scan looks for, with the labels its outputs may carry
+pub struct ScanKeys {
+    pub scan_key: SecretKey,
+    pub spend_pubkey: PublicKey,
+    pub labels: Vec<Label>,
+}
+
+impl ScanKeys {
+    // Hex scan private key and compressed spend public key. The change label is always scanned for,
+    // wallets send their change to it.
+    pub fn parse(scan_key: &str, spend_pubkey: &str, labels: &[u32]) -> Result<Self, String> {
+        let secp = Secp256k1::new();
+        let scan_key = SecretKey::from_str(scan_key).map_err(|e| format!("Invalid scan key: {}", e))?;
+        let spend_pubkey = PublicKey::from_str(spend_pubkey).map_err(|e| format!("Invalid spend public key: {}", e))?;
+        let mut label_ms = [&[CHANGE_LABEL][..], labels].concat();
+        label_ms.sort_unstable();
+        label_ms.dedup();
+        let labels = label_ms.into_iter().map(|m| Label::new(&secp, &scan_key, m)).collect::<Result<_, _>>()?;
+        Ok(Self { scan_key, spend_pubkey, labels })
+    }
+
+    // Label whose key turns P_k into the output, trying both keys with the output's x coordinate
+    fn find_label(&self, secp: &Secp256k1<All>, p_k: &PublicKey, output: &XOnlyPublicKey) -> Option<&Label> {
+        let negated_p_k = p_k.negate(secp);
+        [Parity::Even, Parity::Odd].into_iter().find_map(|parity| {
+            let label_point = PublicKey::from_x_only_public_key(*output, parity).combine(&negated_p_k).ok()?;
+            self.labels.iter().find(|label| label.point == label_point)
+        })
+    }
+}
+
+// Output of a tweaked transaction paying the address
+#[derive(Debug, PartialEq)]
+pub struct OutputMatch {
+    pub vout: u32,
+    pub label: Option<u32>,
+    // Added to the spend private key to spend the output, t_k plus the label tweak for labeled outputs
+    pub key_tweak: Scalar,
+}
+
+// Stored Taproot output paying the address
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ScanMatch {
+    pub height: u32,
+    pub block_hash: String,
+    pub tx_id: String,
+    pub vout: u32,
+    pub pubkey: String,
+    /// Label m the output was paid to, None for the unlabeled address
+    pub label: Option<u32>,
+    /// Tweak added to the spend key to spend the output
+    pub key_tweak: String,
+}
+
+// BIP340 style tagged hash of the concatenated data
+fn tagged_hash(tag: &str, data: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_byte_array());
+    engine.input(tag.as_byte_array());
+    for bytes in data {
+        engine.input(bytes);
+    }
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+// Outputs of a tweaked transaction paying the address or one of its labels. Per BIP352 output k is only
+// looked for once output k - 1 was found, so the scan stops at the first k without a match.
+pub fn match_outputs(secp: &Secp256k1<All>, keys: &ScanKeys, tweak: &Tweak) -> Result<Vec<OutputMatch>, String> {
+    let tweak_key = PublicKey::from_str(&tweak.tweak).map_err(|e| format!("Invalid tweak {} of {}: {}", tweak.tweak, tweak.tx_id, e))?;
+    let shared_secret = tweak_key.mul_tweak(secp, &Scalar::from(keys.scan_key)).map_err(|e| e.to_string())?;
+    let outputs: Vec<(u32, XOnlyPublicKey)> = tweak
+        .outputs
+        .iter()
+        .filter_map(|output| Some((output.vout, XOnlyPublicKey::from_str(&output.pubkey).ok()?)))
+        .collect();
+
+    let mut found = Vec::new();
+    for k in 0u32.. {
+        let t_k = Scalar::from_be_bytes(tagged_hash("BIP0352/SharedSecret", &[shared_secret.serialize().as_slice(), k.to_be_bytes().as_slice()]))
+            .map_err(|e| e.to_string())?;
+        let p_k = keys.spend_pubkey.add_exp_tweak(secp, &t_k).map_err(|e| e.to_string())?;
+        if let Some((vout, _)) = outputs.iter().find(|(_, pubkey)| *pubkey == p_k.x_only_public_key().0) {
+            found.push(OutputMatch { vout: *vout, label: None, key_tweak: t_k });
+            continue;
+        }
+        match outputs.iter().find_map(|(vout, pubkey)| Some((*vout, keys.find_label(secp, &p_k, pubkey)?))) {
+            Some((vout, label)) => {
+                let key_tweak = SecretKey::from_slice(&t_k.to_be_bytes())
+                    .and_then(|t_k| t_k.add_tweak(&label.tweak))
+                    .map_err(|e| e.to_string())?;
+                found.push(OutputMatch { vout, label: Some(label.m), key_tweak: Scalar::from(key_tweak) });
+            }
+            None => break,
+        }
+    }
+    Ok(found)
+}
+
+// Stored outputs between the heights (inclusive) paying the address, in block order
+pub fn scan_range(db: &Database, keys: &ScanKeys, from_height: u32, to_height: u32) -> Result<Vec<ScanMatch>, Box<dyn Error>> {
+    let secp = Secp256k1::new();
+    let mut matches = Vec::new();
+    let mut without_outputs = 0;
+
+    for (height, block_hash) in db.get_block_hashes_in_range(from_height, to_height)? {
+        for tweak in db.get_tweaks(&block_hash)? {
+            // Tweaks indexed before output keys were stored can't be matched
+            if tweak.outputs.is_empty() {
+                without_outputs += 1;
+                continue;
+            }
+            for found in match_outputs(&secp, keys, &tweak)? {
+                let pubkey = tweak.outputs.iter().find(|output| output.vout == found.vout).map(|output| output.pubkey.clone()).unwrap_or_default();
+                matches.push(ScanMatch {
+                    height,
+                    block_hash: block_hash.clone(),
+                    tx_id: tweak.tx_id.clone(),
+                    vout: found.vout,
+                    pubkey,
+                    label: found.label,
+                    key_tweak: hex::encode(found.key_tweak.to_be_bytes()),
+                });
+            }
+        }
+    }
+
+    if without_outputs > 0 {
+        warn!("{} tweaks between heights {} and {} have no stored output keys and were not scanned, reindex them to include them", without_outputs, from_height, to_height);
+    }
+    info!("Scanned heights {} to {}, {} outputs pay the address", from_height, to_height, matches.len());
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Block, TaprootOutput};
+
+    fn secret(byte: u8) -> SecretKey {
+        SecretKey::from_slice(&[byte; 32]).unwrap()
+    }
+
+    // Output k the sender derives from the tweak's private scalar and the recipient's scan public key
+    fn sent_output(secp: &Secp256k1<All>, tweak_secret: &SecretKey, scan_pubkey: &PublicKey, spend_pubkey: &PublicKey, k: u32) -> String {
+        let shared_secret = scan_pubkey.mul_tweak(secp, &Scalar::from(*tweak_secret)).unwrap();
+        let t_k = Scalar::from_be_bytes(tagged_hash("BIP0352/SharedSecret", &[shared_secret.serialize().as_slice(), k.to_be_bytes().as_slice()])).unwrap();
+        spend_pubkey.add_exp_tweak(secp, &t_k).unwrap().x_only_public_key().0.to_string()
+    }
+
+    #[test]
+    fn test_scan_range() {
+        let secp = Secp256k1::new();
+        let keys = ScanKeys { scan_key: secret(1), spend_pubkey: secret(2).public_key(&secp), labels: Vec::new() };
+        let scan_pubkey = keys.scan_key.public_key(&secp);
+        let tweak_secret = secret(3);
+
+        // Outputs k = 0 and k = 1 of the address around an unrelated one, k = 3 without k = 2 is never found
+        let outputs = vec![
+            TaprootOutput { vout: 0, pubkey: sent_output(&secp, &tweak_secret, &scan_pubkey, &keys.spend_pubkey, 1), value: Some(1000) },
+            TaprootOutput { vout: 1, pubkey: secret(4).x_only_public_key(&secp).0.to_string(), value: Some(1000) },
+            TaprootOutput { vout: 2, pubkey: sent_output(&secp, &tweak_secret, &scan_pubkey, &keys.spend_pubkey, 0), value: Some(1000) },
+            TaprootOutput { vout: 3, pubkey: sent_output(&secp, &tweak_secret, &scan_pubkey, &keys.spend_pubkey, 3), value: Some(1000) },
+        ];
+        let tweak = Tweak {
+            block_hash: String::from("aa"),
+            tx_index: Some(1),
+            tx_id: "01".repeat(32),
+            tweak: tweak_secret.public_key(&secp).to_string(),
+            max_output_value: Some(1000),
+            merkle_proof: None,
+            outputs,
+        };
+        let db = Database::new(":memory:").unwrap();
+        db.insert_block_with_tweaks(&Block { height: 10, hash: String::from("aa"), has_tweaks: true, prev_hash: None, time: None }, &[tweak]).unwrap();
+
+        let matches = scan_range(&db, &keys, 10, 10).unwrap();
+        assert_eq!(matches.iter().map(|found| found.vout).collect::<Vec<_>>(), vec![2, 0]);
+        assert_eq!(matches[0].tx_id, "01".repeat(32));
+
+        // Another address finds nothing, neither do heights without blocks
+        let other = ScanKeys { scan_key: secret(5), spend_pubkey: keys.spend_pubkey, labels: Vec::new() };
+        assert!(scan_range(&db, &other, 10, 10).unwrap().is_empty());
+        assert!(scan_range(&db, &keys, 11, 20).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_match_labeled_outputs() {
+        let secp = Secp256k1::new();
+        let scan_key = secret(1);
+        let spend_secret = secret(2);
+        let keys = ScanKeys::parse(&hex::encode(scan_key.secret_bytes()), &spend_secret.public_key(&secp).to_string(), &[3]).unwrap();
+        let labeled_spend_pubkey = |m| spend_secret.public_key(&secp).combine(&Label::new(&secp, &scan_key, m).unwrap().point).unwrap();
+        let tweak_secret = secret(4);
+        let scan_pubkey = scan_key.public_key(&secp);
+
+        // Change (label 0) as output 0, label 3 as output 1, an unscanned label 7 can't be output 2
+        let outputs = vec![
+            TaprootOutput { vout: 0, pubkey: sent_output(&secp, &tweak_secret, &scan_pubkey, &labeled_spend_pubkey(CHANGE_LABEL), 0), value: Some(1000) },
+            TaprootOutput { vout: 1, pubkey: sent_output(&secp, &tweak_secret, &scan_pubkey, &labeled_spend_pubkey(3), 1), value: Some(1000) },
+            TaprootOutput { vout: 2, pubkey: sent_output(&secp, &tweak_secret, &scan_pubkey, &labeled_spend_pubkey(7), 2), value: Some(1000) },
+        ];
+        let tweak = Tweak {
+            block_hash: String::from("aa"),
+            tx_index: Some(1),
+            tx_id: "01".repeat(32),
+            tweak: tweak_secret.public_key(&secp).to_string(),
+            max_output_value: None,
+            merkle_proof: None,
+            outputs,
+        };
+        let found = match_outputs(&secp, &keys, &tweak).unwrap();
+        assert_eq!(found.iter().map(|found| (found.vout, found.label)).collect::<Vec<_>>(), vec![(0, Some(CHANGE_LABEL)), (1, Some(3))]);
+
+        // The key tweak spends the output: the spend key plus the tweak has the output's x-only key
+        let spend_key = spend_secret.add_tweak(&found[1].key_tweak).unwrap();
+        assert_eq!(spend_key.x_only_public_key(&secp).0.to_string(), tweak.outputs[1].pubkey);
+    }
+}
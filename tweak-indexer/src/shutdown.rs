@@ -0,0 +1,100 @@
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+// Set on the first SIGINT or SIGTERM, indexing loops check it between blocks so a block is either stored whole or not at all
+struct Shutdown {
+    requested: AtomicBool,
+    notify: Notify,
+}
+
+impl Shutdown {
+    const fn new() -> Self {
+        Self { requested: AtomicBool::new(false), notify: Notify::const_new() }
+    }
+
+    // Returns whether shutdown had already been requested
+    fn request(&self) -> bool {
+        let already = self.requested.swap(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+        already
+    }
+
+    fn requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    async fn wait(&self) {
+        // Registered before the check so a request in between still wakes us
+        let notified = self.notify.notified();
+        if self.requested() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+static SHUTDOWN: Shutdown = Shutdown::new();
+
+// Trap SIGINT and SIGTERM. The first asks the indexer to stop after the current block, a second one exits right away.
+pub fn listen() {
+    tokio::spawn(async {
+        #[cfg(unix)]
+        let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(terminate) => Some(terminate),
+            Err(err) => {
+                warn!("Unable to listen for SIGTERM: {}", err);
+                None
+            }
+        };
+        loop {
+            #[cfg(unix)]
+            let signal = tokio::select! {
+                _ = tokio::signal::ctrl_c() => "SIGINT",
+                Some(_) = async { terminate.as_mut()?.recv().await } => "SIGTERM",
+            };
+            #[cfg(not(unix))]
+            let signal = {
+                let _ = tokio::signal::ctrl_c().await;
+                "SIGINT"
+            };
+
+            if SHUTDOWN.request() {
+                warn!("Received {} again, exiting without waiting for the current block", signal);
+                exit(130);
+            }
+            info!("Received {}, stopping after the current block", signal);
+        }
+    });
+}
+
+pub fn requested() -> bool {
+    SHUTDOWN.requested()
+}
+
+// Resolves once shutdown has been requested
+pub async fn wait() {
+    SHUTDOWN.wait().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_shutdown_request() {
+        let shutdown: &'static Shutdown = Box::leak(Box::new(Shutdown::new()));
+        assert!(!shutdown.requested());
+
+        let waiter = tokio::spawn(shutdown.wait());
+        tokio::task::yield_now().await;
+        assert!(!shutdown.request());
+        tokio::time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap();
+
+        // Later waits return immediately and a repeated signal is reported
+        shutdown.wait().await;
+        assert!(shutdown.request());
+    }
+}
@@ -0,0 +1,183 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use bitcoin::hashes::Hash;
+use bitcoin::{BlockHash, Network, OutPoint, PubkeyHash, ScriptBuf, ScriptHash, Txid};
+use tracing::info;
+use crate::blkfile::UtxoMap;
+
+// Header of a Bitcoin Core 28+ dumptxoutset file
+const SNAPSHOT_MAGIC: [u8; 5] = *b"utxo\xff";
+const SNAPSHOT_VERSION: u16 = 2;
+// Compressed scripts use sizes below this for P2PKH, P2SH and P2PK templates
+const SPECIAL_SCRIPTS: u64 = 6;
+const MAX_SCRIPT_SIZE: u64 = 10_000;
+
+// Load a UTXO snapshot written by dumptxoutset, returning the block it was taken at and its unspent outputs
+pub fn load(path: &Path, network: Network) -> Result<(BlockHash, UtxoMap), String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    read(BufReader::with_capacity(1 << 20, file), network).map_err(|e| format!("Invalid UTXO snapshot {}: {}", path.display(), e))
+}
+
+fn read(mut reader: impl Read, network: Network) -> io::Result<(BlockHash, UtxoMap)> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let magic: [u8; 5] = read_array(&mut reader)?;
+    if magic != SNAPSHOT_MAGIC {
+        return Err(invalid(String::from("missing snapshot header, only Bitcoin Core 28+ snapshots are supported")));
+    }
+    let version = u16::from_le_bytes(read_array(&mut reader)?);
+    if version != SNAPSHOT_VERSION {
+        return Err(invalid(format!("unsupported snapshot version {}", version)));
+    }
+    let network_magic: [u8; 4] = read_array(&mut reader)?;
+    if network_magic != network.magic().to_bytes() {
+        return Err(invalid(format!("snapshot was not taken on {}", network)));
+    }
+    let base_hash = BlockHash::from_byte_array(read_array(&mut reader)?);
+    let coins_count = u64::from_le_bytes(read_array(&mut reader)?);
+
+    // Coins are grouped by transaction: txid, number of coins, then each coin's vout and compressed output
+    let mut utxos = UtxoMap::default();
+    let mut loaded = 0u64;
+    while loaded < coins_count {
+        let txid = Txid::from_byte_array(read_array(&mut reader)?);
+        let coins = read_compact_size(&mut reader)?;
+        for _ in 0..coins {
+            let vout = u32::try_from(read_compact_size(&mut reader)?).map_err(|_| invalid(format!("invalid vout in {}", txid)))?;
+            // Height and coinbase flag, then the compressed amount, neither is needed for tweaks
+            read_varint(&mut reader)?;
+            read_varint(&mut reader)?;
+            let script = read_compressed_script(&mut reader)?;
+            utxos.insert(OutPoint::new(txid, vout), &script);
+
+            loaded += 1;
+            if loaded.is_multiple_of(10_000_000) {
+                info!("Loading UTXO snapshot: {} of {} coins", loaded, coins_count);
+            }
+        }
+    }
+    Ok((base_hash, utxos))
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+// Bitcoin's CompactSize length prefix
+fn read_compact_size(reader: &mut impl Read) -> io::Result<u64> {
+    Ok(match read_array::<1>(reader)?[0] {
+        0xfd => u16::from_le_bytes(read_array(reader)?) as u64,
+        0xfe => u32::from_le_bytes(read_array(reader)?) as u64,
+        0xff => u64::from_le_bytes(read_array(reader)?),
+        size => size as u64,
+    })
+}
+
+// Bitcoin Core's VARINT, base 128 most significant group first with an offset so every value has one encoding
+fn read_varint(reader: &mut impl Read) -> io::Result<u64> {
+    let mut value = 0u64;
+    loop {
+        let byte = read_array::<1>(reader)?[0];
+        if value > (u64::MAX >> 7) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "VARINT overflows"));
+        }
+        value = (value << 7) | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        value += 1;
+    }
+}
+
+// Script in Bitcoin Core's compressed form. P2PK outputs never provide input keys, their key is skipped.
+fn read_compressed_script(reader: &mut impl Read) -> io::Result<ScriptBuf> {
+    match read_varint(reader)? {
+        0 => Ok(ScriptBuf::new_p2pkh(&PubkeyHash::from_byte_array(read_array(reader)?))),
+        1 => Ok(ScriptBuf::new_p2sh(&ScriptHash::from_byte_array(read_array(reader)?))),
+        2..=5 => {
+            read_array::<32>(reader)?;
+            Ok(ScriptBuf::new())
+        }
+        size => {
+            let size = size - SPECIAL_SCRIPTS;
+            if size > MAX_SCRIPT_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("script of {} bytes", size)));
+            }
+            let mut script = vec![0u8; size as usize];
+            reader.read_exact(&mut script)?;
+            Ok(ScriptBuf::from_bytes(script))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut value: u64) -> Vec<u8> {
+        let mut bytes = vec![(value & 0x7f) as u8];
+        while value > 0x7f {
+            value = (value >> 7) - 1;
+            bytes.insert(0, (value & 0x7f) as u8 | 0x80);
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_read_varint() {
+        for (value, encoded) in [(0, vec![0x00]), (127, vec![0x7f]), (128, vec![0x80, 0x00]), (255, vec![0x80, 0x7f]), (16384, vec![0xff, 0x00])] {
+            assert_eq!(varint(value), encoded);
+            assert_eq!(read_varint(&mut &encoded[..]).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_read_snapshot() {
+        let base_hash = BlockHash::from_byte_array([7; 32]);
+        let txid = Txid::from_byte_array([1; 32]);
+        let taproot = ScriptBuf::from_hex("5120a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c").unwrap();
+
+        let mut snapshot = SNAPSHOT_MAGIC.to_vec();
+        snapshot.extend(SNAPSHOT_VERSION.to_le_bytes());
+        snapshot.extend(Network::Regtest.magic().to_bytes());
+        snapshot.extend(base_hash.to_byte_array());
+        snapshot.extend(3u64.to_le_bytes());
+        snapshot.extend(txid.to_byte_array());
+        snapshot.push(3);
+        // vout 0: P2PKH
+        snapshot.extend([0, 0x82, 0x01, 0x00, 0x00]);
+        snapshot.extend([0xab; 20]);
+        // vout 1: P2PK with a compressed key
+        snapshot.extend([1, 0x82, 0x01, 0x00, 0x02]);
+        snapshot.extend([0xcd; 32]);
+        // vout 5: P2TR stored as a raw script
+        snapshot.extend([5, 0x82, 0x01, 0x00]);
+        snapshot.extend(varint(taproot.len() as u64 + SPECIAL_SCRIPTS));
+        snapshot.extend(taproot.as_bytes());
+
+        let (hash, mut utxos) = read(&snapshot[..], Network::Regtest).unwrap();
+        assert_eq!(hash, base_hash);
+        assert_eq!(utxos.count(), 3);
+
+        let spend = |vout| bitcoin::TxIn { previous_output: OutPoint::new(txid, vout), ..Default::default() };
+        let block = bitcoin::Block {
+            header: bitcoin::constants::genesis_block(Network::Regtest).header,
+            txdata: vec![bitcoin::Transaction {
+                version: bitcoin::transaction::Version::TWO,
+                lock_time: bitcoin::absolute::LockTime::ZERO,
+                input: vec![spend(0), spend(1), spend(5)],
+                output: vec![],
+            }],
+        };
+        let spent = utxos.connect_block(&block);
+        assert_eq!(spent[0].1, ScriptBuf::new_p2pkh(&PubkeyHash::from_byte_array([0xab; 20])));
+        assert_eq!(spent[1].1, ScriptBuf::new());
+        assert_eq!(spent[2].1, taproot);
+
+        assert!(read(&snapshot[..], Network::Bitcoin).is_err());
+        assert!(read(&snapshot[..snapshot.len() - 1], Network::Regtest).is_err());
+    }
+}
@@ -0,0 +1,76 @@
+use std::time::Duration;
+use bitcoin::block::Header;
+use bitcoin::consensus::encode::deserialize;
+use tokio::time::timeout;
+use zeromq::{Socket, SocketRecv, SubSocket};
+
+// Topics published by bitcoind -zmqpubhashblock / -zmqpubrawblock
+const TOPICS: [&str; 2] = ["hashblock", "rawblock"];
+
+// Subscription to the node's block announcements, used to wake continuous indexing early
+pub struct BlockNotifier {
+    socket: SubSocket,
+}
+
+impl BlockNotifier {
+    pub async fn connect(endpoint: &str) -> Result<Self, String> {
+        let mut socket = SubSocket::new();
+        socket
+            .connect(endpoint)
+            .await
+            .map_err(|e| format!("Failed to connect to ZMQ endpoint {}: {}", endpoint, e))?;
+        for topic in TOPICS {
+            socket
+                .subscribe(topic)
+                .await
+                .map_err(|e| format!("Failed to subscribe to {}: {}", topic, e))?;
+        }
+        Ok(Self { socket })
+    }
+
+    // Wait for the next block announcement, returning its hash, or None once max_wait passes without one
+    pub async fn wait_for_block(&mut self, max_wait: Duration) -> Result<Option<String>, String> {
+        let message = match timeout(max_wait, self.socket.recv()).await {
+            Ok(message) => message.map_err(|e| format!("ZMQ receive failed: {}", e))?,
+            Err(_) => return Ok(None),
+        };
+
+        match (message.get(0), message.get(1)) {
+            (Some(topic), Some(body)) => Ok(block_hash_from_notification(topic, body)),
+            _ => Err(String::from("Malformed ZMQ notification")),
+        }
+    }
+}
+
+// hashblock carries the hash in display order, rawblock the serialized block starting with its header
+fn block_hash_from_notification(topic: &[u8], body: &[u8]) -> Option<String> {
+    match topic {
+        b"hashblock" => Some(hex::encode(body)),
+        b"rawblock" => {
+            let header: Header = deserialize(body.get(..80)?).ok()?;
+            Some(header.block_hash().to_string())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::Network;
+
+    #[test]
+    fn test_block_hash_from_notification() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let genesis_hash = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+
+        assert_eq!(block_hash_from_notification(b"rawblock", &serialize(&genesis)).as_deref(), Some(genesis_hash));
+        assert_eq!(block_hash_from_notification(b"hashblock", &hex::decode(genesis_hash).unwrap()).as_deref(), Some(genesis_hash));
+
+        // Truncated blocks and other topics are ignored
+        assert_eq!(block_hash_from_notification(b"rawblock", &[0; 10]), None);
+        assert_eq!(block_hash_from_notification(b"rawtx", &[0; 80]), None);
+    }
+}
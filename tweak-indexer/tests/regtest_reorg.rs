@@ -0,0 +1,114 @@
+// Reorg handling against a live regtest bitcoind: blocks are mined and indexed, invalidateblock replaces the tip
+// with a longer branch and the indexer must archive the orphans and index the new branch. The indexer binary runs
+// in a scratch directory so it gets its own blocks-regtest.db. Run with
+//   REGTEST_RPC_COOKIE=~/.bitcoin/regtest/.cookie cargo test -p tweak-indexer --features regtest-tests -- --test-threads 1
+// REGTEST_RPC_HOST and REGTEST_RPC_PORT default to 127.0.0.1:18443.
+#![cfg(feature = "regtest-tests")]
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::{Address, BlockHash, CompressedPublicKey, Network, PrivateKey};
+use bitcoincore_rpc::{Auth, Client, RpcApi};
+use tweak_indexer::database::Database;
+
+struct Node {
+    client: Client,
+    host: String,
+    port: String,
+    cookie: String,
+}
+
+impl Node {
+    fn connect() -> Self {
+        let host = env::var("REGTEST_RPC_HOST").unwrap_or_else(|_| String::from("127.0.0.1"));
+        let port = env::var("REGTEST_RPC_PORT").unwrap_or_else(|_| String::from("18443"));
+        let cookie = env::var("REGTEST_RPC_COOKIE").expect("REGTEST_RPC_COOKIE must point at the regtest node's cookie file");
+        let client = Client::new(&format!("http://{}:{}", host, port), Auth::CookieFile(PathBuf::from(&cookie))).unwrap();
+        Self { client, host, port, cookie }
+    }
+
+    fn tip(&self) -> u32 {
+        self.client.get_block_count().unwrap() as u32
+    }
+
+    // Blocks paying to a key derived from the seed, different seeds give different block hashes on each branch
+    fn mine(&self, blocks: u64, seed: u8) -> Vec<BlockHash> {
+        let key = PrivateKey::from_slice(&[seed; 32], Network::Regtest).unwrap();
+        let pubkey = CompressedPublicKey::from_private_key(&Secp256k1::new(), &key).unwrap();
+        self.client.generate_to_address(blocks, &Address::p2wpkh(&pubkey, Network::Regtest)).unwrap()
+    }
+
+    fn hashes(&self, from: u32, to: u32) -> Vec<(u32, String)> {
+        (from..=to).map(|height| (height, self.client.get_block_hash(height as u64).unwrap().to_string())).collect()
+    }
+
+    // One batch run of the indexer binary over the heights
+    fn index(&self, dir: &Path, from: u32, to: u32) {
+        let status = Command::new(env!("CARGO_BIN_EXE_tweak-indexer"))
+            .current_dir(dir)
+            .args(["--network", "regtest", "--rpc-host", &self.host, "--rpc-port", &self.port, "--rpc-cookie-file", &self.cookie])
+            .args(["--start-height", &from.to_string(), "--end-height", &to.to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+}
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("tweak-indexer-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_reorg_rolls_back_and_reindexes() {
+    let node = Node::connect();
+    let dir = scratch_dir("reorg");
+    let first = node.tip() + 1;
+    let original = node.mine(5, 1);
+    node.index(&dir, first, node.tip());
+
+    let db = Database::new(dir.join("blocks-regtest.db").to_str().unwrap()).unwrap();
+    assert_eq!(db.get_block_hashes_in_range(first, node.tip()).unwrap(), node.hashes(first, node.tip()));
+
+    // Replace the last three blocks with a longer branch
+    node.client.invalidate_block(&original[2]).unwrap();
+    node.mine(5, 2);
+    assert_eq!(node.tip(), first + 6);
+    node.index(&dir, first, node.tip());
+
+    // The stored chain follows the new branch and the orphans are archived
+    assert_eq!(db.get_block_hashes_in_range(first, node.tip()).unwrap(), node.hashes(first, node.tip()));
+    let mut stale: Vec<String> = db.get_stale_blocks().unwrap().into_iter().map(|(block, _)| block.hash).collect();
+    stale.sort();
+    let mut orphans: Vec<String> = original[2..].iter().map(|hash| hash.to_string()).collect();
+    orphans.sort();
+    assert_eq!(stale, orphans);
+    assert_eq!(db.get_lowest_unlinked_height().unwrap(), None);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_reorg_replacing_the_tip() {
+    let node = Node::connect();
+    let dir = scratch_dir("shorter");
+    let first = node.tip() + 1;
+    let original = node.mine(3, 3);
+    node.index(&dir, first, node.tip());
+
+    // The new branch replaces the top block with a different one at the same height, nothing longer
+    node.client.invalidate_block(&original[2]).unwrap();
+    node.mine(1, 4);
+    node.index(&dir, first, node.tip());
+
+    let db = Database::new(dir.join("blocks-regtest.db").to_str().unwrap()).unwrap();
+    assert_eq!(db.get_block_hashes_in_range(first, first + 2).unwrap(), node.hashes(first, first + 2));
+    assert_eq!(db.get_stale_blocks().unwrap().into_iter().map(|(block, _)| block.hash).collect::<Vec<_>>(), vec![original[2].to_string()]);
+
+    let _ = fs::remove_dir_all(&dir);
+}
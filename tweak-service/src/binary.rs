@@ -0,0 +1,114 @@
+use crate::database::{RangeTweak, TweakFilter};
+
+// Each record: height (u32 LE), index of the tweak within its block (u32 LE), txid (32 bytes), tweak (33 bytes)
+pub const RECORD_SIZE: usize = 4 + 4 + 32 + 33;
+
+// Parse a client supplied hex prefix, an odd number of digits matches on the leading nibble
+pub fn parse_prefix(prefix: &str) -> Result<String, String> {
+    if prefix.len() > 66 || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid tweak prefix: {}", prefix));
+    }
+    Ok(prefix.to_ascii_lowercase())
+}
+
+// Encode tweaks ordered by height into fixed size records, keeping only tweaks matching the prefix and filter.
+// The index is the tweak's position among all tweaks of its block, so filtered results still map back.
+pub fn encode_tweaks(tweaks: &[RangeTweak], prefix: Option<&str>, filter: &TweakFilter) -> Result<Vec<u8>, String> {
+    let mut encoded = Vec::with_capacity(tweaks.len() * RECORD_SIZE);
+    let mut current_height = None;
+    let mut index: u32 = 0;
+
+    for tweak in tweaks {
+        if current_height != Some(tweak.height) {
+            current_height = Some(tweak.height);
+            index = 0;
+        } else {
+            index += 1;
+        }
+
+        if let Some(prefix) = prefix {
+            if !tweak.tweak.to_ascii_lowercase().starts_with(prefix) {
+                continue;
+            }
+        }
+        if !filter.keeps(tweak.max_output_value, tweak.fully_spent) {
+            continue;
+        }
+
+        let tx_id = hex::decode(&tweak.tx_id).map_err(|e| format!("Invalid txid {}: {}", tweak.tx_id, e))?;
+        let tweak_bytes = hex::decode(&tweak.tweak).map_err(|e| format!("Invalid tweak {}: {}", tweak.tweak, e))?;
+        if tx_id.len() != 32 || tweak_bytes.len() != 33 {
+            return Err(format!("Unexpected tweak encoding for txid {}", tweak.tx_id));
+        }
+
+        encoded.extend_from_slice(&tweak.height.to_le_bytes());
+        encoded.extend_from_slice(&index.to_le_bytes());
+        encoded.extend_from_slice(&tx_id);
+        encoded.extend_from_slice(&tweak_bytes);
+    }
+
+    Ok(encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range_tweak(height: u32, tx_byte: &str, tweak: &str) -> RangeTweak {
+        RangeTweak {
+            height,
+            tx_id: tx_byte.repeat(32),
+            tweak: tweak.to_string(),
+            max_output_value: Some(1000),
+            fully_spent: false,
+        }
+    }
+
+    #[test]
+    fn test_encode_tweaks_with_prefix() {
+        let tweaks = vec![
+            range_tweak(800000, "aa", &format!("02{}", "11".repeat(32))),
+            range_tweak(800000, "bb", &format!("03{}", "22".repeat(32))),
+            range_tweak(800001, "cc", &format!("03{}", "33".repeat(32))),
+        ];
+
+        let all = encode_tweaks(&tweaks, None, &TweakFilter::default()).unwrap();
+        assert_eq!(all.len(), 3 * RECORD_SIZE);
+
+        // Only tweaks starting with 03 remain, indices still refer to position within the block
+        let filtered = encode_tweaks(&tweaks, Some("03"), &TweakFilter::default()).unwrap();
+        assert_eq!(filtered.len(), 2 * RECORD_SIZE);
+        assert_eq!(&filtered[0..4], &800000u32.to_le_bytes());
+        assert_eq!(&filtered[4..8], &1u32.to_le_bytes());
+        assert_eq!(&filtered[RECORD_SIZE..RECORD_SIZE + 4], &800001u32.to_le_bytes());
+        assert_eq!(&filtered[RECORD_SIZE + 4..RECORD_SIZE + 8], &0u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_encode_tweaks_with_filter() {
+        let tweaks = vec![
+            RangeTweak { max_output_value: Some(546), ..range_tweak(800000, "aa", &format!("02{}", "11".repeat(32))) },
+            range_tweak(800000, "bb", &format!("03{}", "22".repeat(32))),
+            RangeTweak { max_output_value: None, ..range_tweak(800000, "cc", &format!("03{}", "33".repeat(32))) },
+            RangeTweak { fully_spent: true, ..range_tweak(800000, "dd", &format!("03{}", "44".repeat(32))) },
+        ];
+
+        // The dust tweak is dropped, one of unknown value is kept
+        let filter = TweakFilter { dust_limit: Some(1000), cut_through: false };
+        let filtered = encode_tweaks(&tweaks, None, &filter).unwrap();
+        assert_eq!(filtered.len(), 3 * RECORD_SIZE);
+        assert_eq!(&filtered[4..8], &1u32.to_le_bytes());
+        assert_eq!(&filtered[RECORD_SIZE + 4..RECORD_SIZE + 8], &2u32.to_le_bytes());
+
+        // Cut-through also drops the tweak whose outputs are all spent
+        let filter = TweakFilter { dust_limit: Some(1000), cut_through: true };
+        assert_eq!(encode_tweaks(&tweaks, None, &filter).unwrap().len(), 2 * RECORD_SIZE);
+    }
+
+    #[test]
+    fn test_parse_prefix() {
+        assert_eq!(parse_prefix("02AB").unwrap(), "02ab");
+        assert!(parse_prefix("0x02").is_err());
+        assert!(parse_prefix(&"0".repeat(67)).is_err());
+    }
+}
@@ -1,12 +1,44 @@
 
-use rusqlite::{params, Connection, Result};
+use std::collections::HashMap;
+use rusqlite::{params, Connection, OpenFlags, Result, Row};
+use rusqlite::types::ValueRef;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Tweak {
     pub block_hash: String,
+    pub tx_index: Option<u32>,
     pub tx_id: String,
     pub tweak: String,
+    // Sats of the transaction's largest Taproot output, unknown for tweaks indexed before it was recorded
+    pub max_output_value: Option<u64>,
+    // Hex merkle branch of the transaction, only returned with ?proof=1 for blocks indexed with --merkle-proofs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merkle_proof: Option<String>,
+    // Taproot outputs of the transaction, only returned with ?outputs=1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outputs: Option<Vec<TweakOutput>>,
+    #[serde(skip)]
+    pub fully_spent: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TweakOutput {
+    pub vout: u32,
+    // x-only output key, hex encoded
+    pub pubkey: String,
+    // Sats, unknown for outputs indexed before it was recorded
+    pub value: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TweaksEnvelope {
+    pub block_hash: String,
+    pub height: Option<u32>,
+    // Header timestamp, unknown for blocks indexed before it was recorded
+    pub time: Option<u32>,
+    pub count: usize,
+    pub tweaks: Vec<Tweak>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -15,15 +47,83 @@ pub struct TweakMetrics {
     pub tweak_count: u32,
 }
 
+#[derive(Debug)]
+pub struct RangeTweak {
+    pub height: u32,
+    pub tx_id: String,
+    pub tweak: String,
+    pub max_output_value: Option<u64>,
+    pub fully_spent: bool,
+}
+
+// Filters a client may apply to the tweaks it is served
+#[derive(Debug, Default)]
+pub struct TweakFilter {
+    // Leave out tweaks whose Taproot outputs are all below this many sats
+    pub dust_limit: Option<u64>,
+    // Leave out tweaks whose Taproot outputs have all been spent
+    pub cut_through: bool,
+}
+
+impl TweakFilter {
+    // Tweaks of unknown value are kept since they may matter
+    pub fn keeps(&self, max_output_value: Option<u64>, fully_spent: bool) -> bool {
+        let above_dust = match (max_output_value, self.dust_limit) {
+            (Some(value), Some(limit)) => value >= limit,
+            _ => true,
+        };
+        above_dust && !(self.cut_through && fully_spent)
+    }
+}
+
+// Whether every stored Taproot output of the tweak's transaction is spent, false when none were stored.
+const FULLY_SPENT: &str = "(EXISTS (SELECT 1 FROM tweak_outputs o WHERE o.block_hash = tweaks.block_hash AND o.tx_id = tweaks.tx_id)
+    AND NOT EXISTS (SELECT 1 FROM tweak_outputs o WHERE o.block_hash = tweaks.block_hash AND o.tx_id = tweaks.tx_id AND o.spent_height IS NULL))";
+
+// Hex of a BLOB column, rows of databases the indexer hasn't migrated yet still hold hex TEXT
+fn hex_column(row: &Row, index: usize) -> Result<String> {
+    match row.get_ref(index)? {
+        ValueRef::Blob(bytes) => Ok(hex::encode(bytes)),
+        ValueRef::Text(text) => Ok(String::from_utf8_lossy(text).into_owned()),
+        value => Err(rusqlite::Error::InvalidColumnType(index, String::from("hex"), value.data_type())),
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BlockTiming {
+    pub height: u32,
+    pub fetch_ms: u64,
+    pub extract_ms: u64,
+    pub write_ms: u64,
+    pub total_ms: u64,
+    // Unknown for blocks recorded before the counts were
+    pub tx_count: Option<u32>,
+    pub eligible_tx_count: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct IndexInfo {
+    pub coverage_start: Option<u32>,
+    pub lowest_block: u32,
+    pub highest_block: u32,
+}
+
 // Function to fetch tweaks from SQLite
 pub fn fetch_tweaks(block_hash: String, db_path: &String) -> Result<Vec<Tweak>> {
     let conn = Connection::open(db_path)?;
-    let mut stmt = conn.prepare("SELECT block_hash, tx_id, tweak FROM tweaks WHERE block_hash = ?1")?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT block_hash, tx_index, tx_id, tweak, max_output_value, merkle_proof, {} FROM tweaks WHERE block_hash = ?1 ORDER BY tx_index, id",
+        FULLY_SPENT))?;
     let tweaks_iter = stmt.query_map(params![block_hash], |row| {
         Ok(Tweak {
             block_hash: row.get(0)?,
-            tx_id: row.get(1)?,
-            tweak: row.get(2)?,
+            tx_index: row.get(1)?,
+            tx_id: hex_column(row, 2)?,
+            tweak: hex_column(row, 3)?,
+            max_output_value: row.get(4)?,
+            merkle_proof: row.get::<_, Option<Vec<u8>>>(5)?.map(hex::encode),
+            outputs: None,
+            fully_spent: row.get(6)?,
         })
     })?;
     
@@ -31,6 +131,65 @@ pub fn fetch_tweaks(block_hash: String, db_path: &String) -> Result<Vec<Tweak>>
     Ok(tweaks)
 }
 
+// Taproot outputs of a block's tweaked transactions by txid, in output order
+pub fn fetch_tweak_outputs(block_hash: &str, db_path: &String) -> Result<HashMap<String, Vec<TweakOutput>>> {
+    let conn = Connection::open(db_path)?;
+    let mut stmt = conn.prepare("SELECT tx_id, vout, pubkey, value FROM tweak_outputs WHERE block_hash = ?1 ORDER BY tx_id, vout")?;
+    let outputs_iter = stmt.query_map(params![block_hash], |row| {
+        Ok((hex_column(row, 0)?, TweakOutput { vout: row.get(1)?, pubkey: row.get(2)?, value: row.get(3)? }))
+    })?;
+
+    let mut outputs: HashMap<String, Vec<TweakOutput>> = HashMap::new();
+    for (tx_id, output) in outputs_iter.filter_map(Result::ok) {
+        outputs.entry(tx_id).or_default().push(output);
+    }
+    Ok(outputs)
+}
+
+// Fetch tweaks for all blocks between the heights (inclusive), ordered by height then insertion order
+pub fn fetch_tweaks_in_range(from_height: u32, to_height: u32, db_path: &String) -> Result<Vec<RangeTweak>> {
+    let conn = Connection::open(db_path)?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT blocks.height, tweaks.tx_id, tweaks.tweak, tweaks.max_output_value, {} FROM tweaks
+         JOIN blocks ON blocks.hash = tweaks.block_hash
+         WHERE blocks.height BETWEEN ?1 AND ?2
+         ORDER BY blocks.height, tweaks.tx_index, tweaks.id",
+        FULLY_SPENT))?;
+    let tweaks_iter = stmt.query_map(params![from_height, to_height], |row| {
+        Ok(RangeTweak {
+            height: row.get(0)?,
+            tx_id: hex_column(row, 1)?,
+            tweak: hex_column(row, 2)?,
+            max_output_value: row.get(3)?,
+            fully_spent: row.get(4)?,
+        })
+    })?;
+
+    let tweaks = tweaks_iter.filter_map(Result::ok).collect();
+    Ok(tweaks)
+}
+
+// Spent outpoint index of a block, None when the block is unknown or was indexed before the index existed
+pub fn fetch_spent_outpoints(block_hash: &str, db_path: &String) -> Result<Option<Vec<u8>>> {
+    let conn = Connection::open(db_path)?;
+    let mut stmt = conn.prepare("SELECT data FROM spent_outpoints WHERE block_hash = ?1")?;
+    let data: Option<Vec<u8>> = stmt.query_row(params![block_hash], |row| row.get(0)).ok();
+
+    Ok(data)
+}
+
+// Filter types the indexer stores in block_filters
+pub const FILTER_TYPES: &[&str] = &["new-utxos", "spent"];
+
+// Compact filter of a block, None when the block is unknown or was indexed before the filter existed
+pub fn fetch_filter(block_hash: &str, filter_type: &str, db_path: &String) -> Result<Option<Vec<u8>>> {
+    let conn = Connection::open(db_path)?;
+    let mut stmt = conn.prepare("SELECT data FROM block_filters WHERE block_hash = ?1 AND filter_type = ?2")?;
+    let data: Option<Vec<u8>> = stmt.query_row(params![block_hash, filter_type], |row| row.get(0)).ok();
+
+    Ok(data)
+}
+
 pub fn get_tweak_metrics(db_path: &String) -> Result<Vec<TweakMetrics>> {
     let conn = Connection::open(db_path)?;
     let mut stmt = conn.prepare("SELECT block_hash, count(tweak) FROM tweaks GROUP BY block_hash order by count(tweak) desc")?;
@@ -45,10 +204,212 @@ pub fn get_tweak_metrics(db_path: &String) -> Result<Vec<TweakMetrics>> {
     Ok(tweaks)
 }
 
+// Slowest indexed blocks recorded with --record-timings, by total time spent
+pub fn get_slowest_blocks(limit: u32, db_path: &String) -> Result<Vec<BlockTiming>> {
+    let conn = Connection::open(db_path)?;
+    let mut stmt = conn.prepare(
+        "SELECT height, fetch_ms, extract_ms, write_ms, fetch_ms + extract_ms + write_ms AS total_ms, tx_count, eligible_tx_count
+         FROM block_timings ORDER BY total_ms DESC LIMIT ?1")?;
+    let timings_iter = stmt.query_map(params![limit], |row| {
+        Ok(BlockTiming {
+            height: row.get(0)?,
+            fetch_ms: row.get(1)?,
+            extract_ms: row.get(2)?,
+            write_ms: row.get(3)?,
+            total_ms: row.get(4)?,
+            tx_count: row.get(5)?,
+            eligible_tx_count: row.get(6)?,
+        })
+    })?;
+
+    let timings = timings_iter.filter_map(Result::ok).collect();
+    Ok(timings)
+}
+
+// Height and header timestamp of a stored block
+pub fn get_block_height(block_hash: &str, db_path: &String) -> Result<Option<(u32, Option<u32>)>> {
+    let conn = Connection::open(db_path)?;
+    let mut stmt = conn.prepare("SELECT height, time FROM blocks WHERE hash = ?1")?;
+    let block: Option<(u32, Option<u32>)> = stmt.query_row(params![block_hash], |row| Ok((row.get(0)?, row.get(1)?))).ok();
+
+    Ok(block)
+}
+
+// Lowest stored height of a block timestamped at or after the unix time, where a client syncing since then starts
+pub fn get_height_since(time: u32, db_path: &String) -> Result<Option<u32>> {
+    let conn = Connection::open(db_path)?;
+    conn.query_row("SELECT min(height) FROM blocks WHERE time >= ?1", params![time], |row| row.get(0))
+}
+
 pub fn get_highest_block(db_path: &String) -> Result<u32> {
     let conn = Connection::open(db_path)?;
     let mut stmt = conn.prepare("SELECT max(height) FROM blocks")?;
     let highest_block: Option<u32> = stmt.query_row([], |row| row.get(0)).ok();
 
     Ok(highest_block.unwrap_or(0))
-}
\ No newline at end of file
+}
+pub fn get_lowest_block(db_path: &String) -> Result<u32> {
+    let conn = Connection::open(db_path)?;
+    let mut stmt = conn.prepare("SELECT min(height) FROM blocks")?;
+    let lowest_block: Option<u32> = stmt.query_row([], |row| row.get(0)).ok();
+
+    Ok(lowest_block.unwrap_or(0))
+}
+
+// Lowest height the indexer recorded complete data from, which can be below the lowest stored block
+pub fn get_coverage_start(db_path: &String) -> Result<Option<u32>> {
+    let conn = Connection::open(db_path)?;
+    let mut stmt = conn.prepare("SELECT value FROM meta WHERE key = 'coverage_start'")?;
+    let coverage_start: Option<String> = stmt.query_row([], |row| row.get(0)).ok();
+
+    Ok(coverage_start.and_then(|value| value.parse().ok()))
+}
+
+pub fn get_index_info(db_path: &String) -> Result<IndexInfo> {
+    Ok(IndexInfo {
+        coverage_start: get_coverage_start(db_path)?,
+        lowest_block: get_lowest_block(db_path)?,
+        highest_block: get_highest_block(db_path)?,
+    })
+}
+
+// Run representative queries so the SQLite and OS page caches hold the hot data before serving
+pub fn prewarm(db_path: &String) -> Result<usize> {
+    let highest_block = get_highest_block(db_path)?;
+    let recent_tweaks = fetch_tweaks_in_range(highest_block.saturating_sub(1000), highest_block, db_path)?;
+    get_tweak_metrics(db_path)?;
+
+    let conn = Connection::open(db_path)?;
+    conn.query_row("SELECT count(*), sum(length(tweak)) FROM tweaks", [], |row| row.get::<_, Option<i64>>(1))?;
+
+    Ok(recent_tweaks.len())
+}
+
+// Tables and columns the indexer is expected to have created
+const EXPECTED_SCHEMA: &[(&str, &[&str])] = &[
+    ("blocks", &["height", "hash", "has_tweaks", "time"]),
+    ("tweaks", &["id", "block_hash", "tx_id", "tweak", "tx_index", "max_output_value", "merkle_proof"]),
+    ("meta", &["key", "value"]),
+    ("tweak_outputs", &["block_hash", "tx_id", "vout", "pubkey", "spent_height", "value"]),
+    ("spent_outpoints", &["block_hash", "height", "data"]),
+    ("block_filters", &["block_hash", "filter_type", "height", "data"]),
+];
+
+// Schema version of a database the indexer has fully migrated, the number of its database::MIGRATIONS
+const EXPECTED_SCHEMA_VERSION: u32 = 5;
+
+// Indexes the handlers' lookups rely on, by table, with their columns in order
+const EXPECTED_INDEXES: &[(&str, &str, &[&str])] = &[
+    ("tweaks", "tweaks_block_tx", &["block_hash", "tx_id"]),
+    ("tweaks", "tweaks_block_order", &["block_hash", "tx_index"]),
+    ("blocks", "blocks_hash", &["hash"]),
+];
+
+// Check the database against the expected schema, returning a description of everything missing
+pub fn validate_schema(db_path: &String) -> Result<Vec<String>> {
+    // Open read only so a wrong path is reported instead of creating an empty database
+    let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    schema_problems(&conn)
+}
+
+fn schema_problems(conn: &Connection) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+
+    let version: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version < EXPECTED_SCHEMA_VERSION {
+        problems.push(format!("schema version {} is older than {}, start the indexer once to migrate it", version, EXPECTED_SCHEMA_VERSION));
+    } else if version > EXPECTED_SCHEMA_VERSION {
+        problems.push(format!("schema version {} is newer than {}, update the service", version, EXPECTED_SCHEMA_VERSION));
+    }
+
+    for (table, columns) in EXPECTED_SCHEMA {
+        let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+        let existing: Vec<String> = stmt
+            .query_map([], |row| row.get(1))?
+            .filter_map(Result::ok)
+            .collect();
+
+        if existing.is_empty() {
+            problems.push(format!("missing table '{}'", table));
+            continue;
+        }
+
+        for column in columns.iter() {
+            if !existing.iter().any(|c| c == column) {
+                problems.push(format!("missing column '{}.{}'", table, column));
+            }
+        }
+    }
+
+    for (table, index, columns) in EXPECTED_INDEXES {
+        let mut stmt = conn.prepare(&format!("PRAGMA index_list({})", table))?;
+        let indexes: Vec<String> = stmt
+            .query_map([], |row| row.get(1))?
+            .filter_map(Result::ok)
+            .collect();
+        if !indexes.iter().any(|name| name == index) {
+            problems.push(format!("missing index '{}' on '{}'", index, table));
+            continue;
+        }
+
+        let mut stmt = conn.prepare(&format!("PRAGMA index_info({})", index))?;
+        let indexed: Vec<String> = stmt
+            .query_map([], |row| row.get(2))?
+            .filter_map(Result::ok)
+            .collect();
+        if indexed != *columns {
+            problems.push(format!("index '{}' covers ({}) instead of ({})", index, indexed.join(", "), columns.join(", ")));
+        }
+    }
+
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Database with every expected table, column and index at the expected version
+    fn expected_database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        for (table, columns) in EXPECTED_SCHEMA {
+            conn.execute(&format!("CREATE TABLE {} ({})", table, columns.join(", ")), []).unwrap();
+        }
+        for (table, index, columns) in EXPECTED_INDEXES {
+            conn.execute(&format!("CREATE INDEX {} ON {} ({})", index, table, columns.join(", ")), []).unwrap();
+        }
+        conn.execute_batch(&format!("PRAGMA user_version = {}", EXPECTED_SCHEMA_VERSION)).unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_schema_problems() {
+        let conn = expected_database();
+        assert!(schema_problems(&conn).unwrap().is_empty());
+
+        conn.execute_batch("ALTER TABLE blocks RENAME COLUMN has_tweaks TO tweaked").unwrap();
+        assert_eq!(schema_problems(&conn).unwrap(), vec![String::from("missing column 'blocks.has_tweaks'")]);
+    }
+
+    #[test]
+    fn test_schema_version() {
+        let conn = expected_database();
+        conn.execute_batch(&format!("PRAGMA user_version = {}", EXPECTED_SCHEMA_VERSION - 1)).unwrap();
+        let problems = schema_problems(&conn).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with(&format!("schema version {} is older", EXPECTED_SCHEMA_VERSION - 1)));
+
+        conn.execute_batch(&format!("PRAGMA user_version = {}", EXPECTED_SCHEMA_VERSION + 1)).unwrap();
+        assert!(schema_problems(&conn).unwrap()[0].ends_with("update the service"));
+    }
+
+    #[test]
+    fn test_lookup_indexes() {
+        let conn = expected_database();
+        conn.execute_batch("DROP INDEX blocks_hash").unwrap();
+        assert_eq!(schema_problems(&conn).unwrap(), vec![String::from("missing index 'blocks_hash' on 'blocks'")]);
+
+        conn.execute_batch("CREATE INDEX blocks_hash ON blocks (height)").unwrap();
+        assert_eq!(schema_problems(&conn).unwrap(), vec![String::from("index 'blocks_hash' covers (height) instead of (hash)")]);
+    }
+}
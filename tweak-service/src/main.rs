@@ -1,17 +1,150 @@
 
-use warp::{Filter, Rejection, Reply};
-use warp::reply::{html,json};
+use std::process::exit;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use clap::Parser;
+use serde::Deserialize;
+use warp::{reject, Filter, Rejection, Reply};
+use warp::http::{Response, StatusCode};
+use warp::reply::{html,json,with_status};
 use rusqlite::Result;
 
+mod binary;
 mod database;
 
-async fn get_tweaks(block_hash: String, db_path: String) -> Result<impl Reply, Rejection> {
-    match database::fetch_tweaks(block_hash, &db_path) {
-        Ok(tweaks) => Ok(json(&tweaks)),
+// Largest height span served by a single binary range request
+const MAX_RANGE_BLOCKS: u32 = 1000;
+
+#[derive(Parser)]
+#[command(long_about)]
+struct Cli {
+    /// Check the database schema and exit without serving
+    #[arg(long)]
+    validate_schema: bool,
+    /// Warm the database caches on startup, answering 503 until done
+    #[arg(long)]
+    prewarm: bool,
+}
+
+#[derive(Debug)]
+struct NotReady;
+impl reject::Reject for NotReady {}
+
+#[derive(Deserialize)]
+struct TweaksQuery {
+    envelope: Option<u8>,
+    dust_limit: Option<u64>,
+    cut_through: Option<u8>,
+    proof: Option<u8>,
+    outputs: Option<u8>,
+}
+
+async fn get_tweaks(block_hash: String, query: TweaksQuery, db_path: String) -> Result<impl Reply, Rejection> {
+    let mut tweaks = match database::fetch_tweaks(block_hash.clone(), &db_path) {
+        Ok(tweaks) => tweaks,
+        Err(err) => return Ok(json(&err.to_string())),
+    };
+    let filter = database::TweakFilter { dust_limit: query.dust_limit, cut_through: query.cut_through.unwrap_or(0) != 0 };
+    tweaks.retain(|tweak| filter.keeps(tweak.max_output_value, tweak.fully_spent));
+    // Merkle branches are only returned with ?proof=1
+    if query.proof.unwrap_or(0) == 0 {
+        tweaks.iter_mut().for_each(|tweak| tweak.merkle_proof = None);
+    }
+    // ?outputs=1 adds each transaction's Taproot outputs so a light wallet can match them without another source
+    if query.outputs.unwrap_or(0) != 0 {
+        let mut outputs = match database::fetch_tweak_outputs(&block_hash, &db_path) {
+            Ok(outputs) => outputs,
+            Err(err) => return Ok(json(&err.to_string())),
+        };
+        for tweak in tweaks.iter_mut() {
+            tweak.outputs = Some(outputs.remove(&tweak.tx_id).unwrap_or_default());
+        }
+    }
+
+    // Bare array by default, ?envelope=1 wraps the tweaks with block metadata
+    if query.envelope.unwrap_or(0) == 0 {
+        return Ok(json(&tweaks));
+    }
+
+    match database::get_block_height(&block_hash, &db_path) {
+        Ok(block) => Ok(json(&database::TweaksEnvelope {
+            block_hash,
+            height: block.map(|(height, _)| height),
+            time: block.and_then(|(_, time)| time),
+            count: tweaks.len(),
+            tweaks,
+        })),
         Err(err) => Ok(json(&err.to_string())),
     }
 }
 
+#[derive(Deserialize)]
+struct RangeQuery {
+    prefix: Option<String>,
+    dust_limit: Option<u64>,
+    cut_through: Option<u8>,
+}
+
+async fn get_tweaks_binary(from_height: u32, to_height: u32, query: RangeQuery, db_path: String) -> Result<warp::reply::Response, Rejection> {
+    if to_height < from_height || to_height - from_height >= MAX_RANGE_BLOCKS {
+        let message = format!("Height range must be ascending and at most {} blocks", MAX_RANGE_BLOCKS);
+        return Ok(with_status(json(&message), StatusCode::BAD_REQUEST).into_response());
+    }
+
+    let prefix = match query.prefix.as_deref().map(binary::parse_prefix).transpose() {
+        Ok(prefix) => prefix,
+        Err(err) => return Ok(with_status(json(&err), StatusCode::BAD_REQUEST).into_response()),
+    };
+
+    let tweaks = match database::fetch_tweaks_in_range(from_height, to_height, &db_path) {
+        Ok(tweaks) => tweaks,
+        Err(err) => return Ok(json(&err.to_string()).into_response()),
+    };
+
+    let filter = database::TweakFilter { dust_limit: query.dust_limit, cut_through: query.cut_through.unwrap_or(0) != 0 };
+    match binary::encode_tweaks(&tweaks, prefix.as_deref(), &filter) {
+        Ok(body) => Ok(Response::builder()
+            .header("content-type", "application/octet-stream")
+            .body(body.into())
+            .unwrap()),
+        Err(err) => Ok(with_status(json(&err), StatusCode::INTERNAL_SERVER_ERROR).into_response()),
+    }
+}
+
+async fn get_spent_index(block_hash: String, db_path: String) -> Result<warp::reply::Response, Rejection> {
+    match database::fetch_spent_outpoints(&block_hash, &db_path) {
+        Ok(Some(data)) => Ok(Response::builder()
+            .header("content-type", "application/octet-stream")
+            .body(data.into())
+            .unwrap()),
+        Ok(None) => Ok(with_status(json(&"No spent outpoint index for this block"), StatusCode::NOT_FOUND).into_response()),
+        Err(err) => Ok(json(&err.to_string()).into_response()),
+    }
+}
+
+#[derive(Deserialize)]
+struct FilterQuery {
+    hex: Option<u8>,
+}
+
+async fn get_filter(filter_type: String, block_hash: String, query: FilterQuery, db_path: String) -> Result<warp::reply::Response, Rejection> {
+    if !database::FILTER_TYPES.contains(&filter_type.as_str()) {
+        let message = format!("Unknown filter type, expected one of: {}", database::FILTER_TYPES.join(", "));
+        return Ok(with_status(json(&message), StatusCode::NOT_FOUND).into_response());
+    }
+
+    match database::fetch_filter(&block_hash, &filter_type, &db_path) {
+        // Binary by default, ?hex=1 returns the filter as a hex string
+        Ok(Some(data)) if query.hex.unwrap_or(0) != 0 => Ok(json(&hex::encode(data)).into_response()),
+        Ok(Some(data)) => Ok(Response::builder()
+            .header("content-type", "application/octet-stream")
+            .body(data.into())
+            .unwrap()),
+        Ok(None) => Ok(with_status(json(&"No filter of this type for this block"), StatusCode::NOT_FOUND).into_response()),
+        Err(err) => Ok(json(&err.to_string()).into_response()),
+    }
+}
+
 async fn get_tweak_metrics(db_path: String) -> Result<impl Reply, Rejection> {
     match database::get_tweak_metrics(&db_path) {
         Ok(tweaks) => {
@@ -37,6 +170,69 @@ async fn get_status(db_path: String) -> Result<impl Reply, Rejection> {
     }
 }
 
+async fn get_height_since(time: u32, db_path: String) -> Result<impl Reply, Rejection> {
+    match database::get_height_since(time, &db_path) {
+        Ok(height) => Ok(json(&height)),
+        Err(err) => Ok(json(&err.to_string())),
+    }
+}
+
+async fn get_timings(db_path: String) -> Result<impl Reply, Rejection> {
+    match database::get_slowest_blocks(100, &db_path) {
+        Ok(timings) => Ok(json(&timings)),
+        Err(err) => Ok(json(&err.to_string())),
+    }
+}
+
+async fn get_info(db_path: String) -> Result<impl Reply, Rejection> {
+    match database::get_index_info(&db_path) {
+        Ok(info) => Ok(json(&info)),
+        Err(err) => Ok(json(&err.to_string())),
+    }
+}
+
+// Report any schema mismatch and exit, so the service never runs against a wrong or old database
+fn check_schema(db_path: &String) {
+    match database::validate_schema(db_path) {
+        Ok(problems) if problems.is_empty() => println!("Database schema OK: {}", db_path),
+        Ok(problems) => {
+            eprintln!("Database schema mismatch in {}:", db_path);
+            for problem in problems {
+                eprintln!("  - {}", problem);
+            }
+            exit(1);
+        }
+        Err(err) => {
+            eprintln!("Unable to validate database {}: {}", db_path, err);
+            exit(1);
+        }
+    }
+}
+
+// Reject every request while the service is still warming up
+fn with_ready(ready: Arc<AtomicBool>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .and_then(move || {
+            let ready = ready.clone();
+            async move {
+                if ready.load(Ordering::Acquire) {
+                    Ok(())
+                } else {
+                    Err(reject::custom(NotReady))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+async fn handle_not_ready(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<NotReady>().is_some() {
+        Ok(with_status(json(&"Service warming up"), StatusCode::SERVICE_UNAVAILABLE))
+    } else {
+        Err(rejection)
+    }
+}
+
 // Middleware to inject `db_path` into handler
 fn with_db_path(db_path: String) -> impl Filter<Extract = (String,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || db_path.clone())
@@ -44,10 +240,29 @@ fn with_db_path(db_path: String) -> impl Filter<Extract = (String,), Error = std
 
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
     let db_path = String::from("blocks.db");
+
+    check_schema(&db_path);
+    if cli.validate_schema {
+        return;
+    }
+
     let tweaks_route = warp::path!("tweaks" / String)
+    .and(warp::query::<TweaksQuery>())
     .and(with_db_path(db_path.clone()))
     .and_then(get_tweaks);
+    let tweaks_binary_route = warp::path!("tweaks" / "range" / u32 / u32)
+    .and(warp::query::<RangeQuery>())
+    .and(with_db_path(db_path.clone()))
+    .and_then(get_tweaks_binary);
+    let spent_index_route = warp::path!("spent_index" / String)
+    .and(with_db_path(db_path.clone()))
+    .and_then(get_spent_index);
+    let filter_route = warp::path!("filter" / String / String)
+    .and(warp::query::<FilterQuery>())
+    .and(with_db_path(db_path.clone()))
+    .and_then(get_filter);
     let tweak_metrics = warp::path!("block_stats")
     .and(with_db_path(db_path.clone()))
     .and_then(get_tweak_metrics);
@@ -55,9 +270,44 @@ async fn main() {
     .and(with_db_path(db_path.clone()))
     .and_then(get_status);
 
-    let routes = tweaks_route
+    let height_since_route = warp::path!("height_since" / u32)
+    .and(with_db_path(db_path.clone()))
+    .and_then(get_height_since);
+
+    let info_route = warp::path!("info")
+    .and(with_db_path(db_path.clone()))
+    .and_then(get_info);
+
+    let timings_route = warp::path!("timings")
+    .and(with_db_path(db_path.clone()))
+    .and_then(get_timings);
+
+    let ready = Arc::new(AtomicBool::new(!cli.prewarm));
+    if cli.prewarm {
+        let ready = ready.clone();
+        let db_path = db_path.clone();
+        tokio::task::spawn_blocking(move || {
+            println!("Prewarming database caches");
+            match database::prewarm(&db_path) {
+                Ok(tweak_count) => println!("Prewarm complete, {} recent tweaks loaded", tweak_count),
+                Err(err) => eprintln!("Prewarm failed, serving anyway: {}", err),
+            }
+            ready.store(true, Ordering::Release);
+        });
+    }
+
+    let routes = tweaks_binary_route
+    .or(tweaks_route)
+    .or(spent_index_route)
+    .or(filter_route)
     .or(status_route)
+    .or(height_since_route)
+    .or(info_route)
+    .or(timings_route)
     .or(tweak_metrics);
+    let routes = with_ready(ready)
+    .and(routes)
+    .recover(handle_not_ready);
 
     warp::serve(routes).run(([0, 0, 0, 0], 3030)).await;
 }
\ No newline at end of file